@@ -0,0 +1,19 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+use std::env;
+
+fn feature(name: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
+
+fn main() {
+    // features only add to each other, so `tiny` leaves WASI out through `cfg(libc_wasi)`,
+    // like wamr-sys leaves it out of WAMR
+    println!("cargo:rustc-check-cfg=cfg(libc_wasi)");
+    if feature("LIBC_WASI") && !feature("TINY") {
+        println!("cargo:rustc-cfg=libc_wasi");
+    }
+}
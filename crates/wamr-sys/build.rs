@@ -9,34 +9,107 @@ extern crate cmake;
 use cmake::Config;
 use std::{env, path::PathBuf};
 
+fn flag(enabled: bool) -> &'static str {
+    if enabled {
+        "1"
+    } else {
+        "0"
+    }
+}
+
 fn main() {
-    let wamr_root = env::current_dir().unwrap();
-    let wamr_root = wamr_root.join("wasm-micro-runtime");
+    let sys_root = env::current_dir().unwrap();
+    let wamr_root = sys_root.join("wasm-micro-runtime");
     assert!(wamr_root.exists());
 
     let is_espidf = env::var("CARGO_CFG_TARGET_OS").unwrap() != "espidf";
 
     if is_espidf {
         let enable_llvm_jit = if cfg!(feature = "llvmjit") { "1" } else { "0" };
+        let threads = flag(cfg!(feature = "threads"));
+        let tiny = cfg!(feature = "tiny");
+        // WAMR, with the shims of `src/shim.c`, see `CMakeLists.txt`
+        println!("cargo:rerun-if-changed=CMakeLists.txt");
+        println!("cargo:rerun-if-changed=src/shim.c");
+        let mut config = Config::new(&sys_root);
+        if tiny {
+            config.profile("MinSizeRel");
+        }
+        if cfg!(feature = "sgx") {
+            config.define("WAMR_BUILD_PLATFORM", "linux-sgx");
+        }
+        // WAMR only ships a stub of the string objects, which always fails
+        println!("cargo:rerun-if-env-changed=WAMR_STRINGREF_IMPL_SOURCE");
+        if let Ok(source) = env::var("WAMR_STRINGREF_IMPL_SOURCE") {
+            config.define("WAMR_STRINGREF_IMPL_SOURCE", source);
+        }
         // TODO: define LLVM_DIR
-        let dst = Config::new(&wamr_root)
+        let dst = config
             // running mode
             .define("WAMR_BUILD_AOT", "1")
             .define("WAMR_BUILD_INTERP", "1")
-            .define("WAMR_BUILD_FAST_INTERP", "1")
+            // the debug engine only works with the classic interpreter, which is also smaller
+            .define(
+                "WAMR_BUILD_FAST_INTERP",
+                flag(!cfg!(feature = "debug") && !tiny),
+            )
             .define("WAMR_BUILD_JIT", enable_llvm_jit)
+            // WAMR is lazy when left undefined, so the JIT is only lazy with `lazy-jit`, and
+            // compiles every function while loading otherwise. Lazy, it compiles them on
+            // background threads, which `wamr_sys_pending_jit_functions()` tracks
+            .define("WAMR_BUILD_LAZY_JIT", flag(cfg!(feature = "lazy-jit")))
             // mvp
             .define("WAMR_BUILD_BULK_MEMORY", "1")
             .define("WAMR_BUILD_REF_TYPES", "1")
-            .define("WAMR_BUILD_SIMD", "1")
-            // wasi
-            .define("WAMR_BUILD_LIBC_WASI", "1")
+            .define("WAMR_BUILD_SIMD", flag(cfg!(feature = "simd")))
+            // proposals
+            .define("WAMR_BUILD_SHARED_MEMORY", threads)
+            .define("WAMR_BUILD_THREAD_MGR", threads)
+            .define("WAMR_BUILD_GC", flag(cfg!(feature = "gc")))
+            .define("WAMR_BUILD_STRINGREF", flag(cfg!(feature = "stringref")))
+            // diagnostics
+            .define("WAMR_BUILD_DEBUG_INTERP", flag(cfg!(feature = "debug")))
+            .define(
+                "WAMR_BUILD_DUMP_CALL_STACK",
+                flag(cfg!(feature = "dump-call-stack")),
+            )
+            .define(
+                "WAMR_DISABLE_HW_BOUND_CHECK",
+                flag(cfg!(feature = "no-hw-bound-check")),
+            )
+            .define(
+                "WAMR_BUILD_INSTRUCTION_METERING",
+                flag(cfg!(feature = "instruction-metering")),
+            )
+            .define(
+                "WAMR_BUILD_PERF_PROFILING",
+                flag(cfg!(feature = "perf-profiling")),
+            )
+            .define(
+                "WAMR_BUILD_CUSTOM_NAME_SECTION",
+                flag(cfg!(feature = "perf-profiling")),
+            )
+            // linking
+            .define(
+                "WAMR_BUILD_MULTI_MODULE",
+                flag(cfg!(feature = "multi-module")),
+            )
+            // wasi, which `tiny` leaves out even when the default features ask for it
+            .define(
+                "WAMR_BUILD_LIBC_WASI",
+                flag(cfg!(feature = "libc-wasi") && !tiny),
+            )
+            // never used by the SDK
+            .define("WAMR_BUILD_APP_FRAMEWORK", "0")
             // `nostdlib`
             .define("WAMR_BUILD_LIBC_BUILTIN", "1")
             .build_target("iwasm_static")
             .build();
 
-        println!("cargo:rustc-link-search=native={}/build", dst.display());
+        println!(
+            "cargo:rustc-link-search=native={}/build/wasm-micro-runtime",
+            dst.display()
+        );
         println!("cargo:rustc-link-lib=static=vmlib");
     }
 
@@ -128,13 +201,20 @@ fn main() {
     let wamr_header = wamr_root.join("core/iwasm/include/wasm_export.h");
     assert!(wamr_header.exists());
 
-    let bindings = bindgen::Builder::default()
+    let mut builder = bindgen::Builder::default()
         .ctypes_prefix("::core::ffi")
         .use_core()
         .header(wamr_header.into_os_string().into_string().unwrap())
-        .derive_default(true)
-        .generate()
-        .expect("Unable to generate bindings");
+        .derive_default(true);
+    // the objects of the GC proposal have their own API
+    if cfg!(feature = "gc") {
+        let gc_header = wamr_root.join("core/iwasm/include/gc_export.h");
+        builder = builder.header(gc_header.into_os_string().into_string().unwrap());
+    }
+    if cfg!(feature = "stringref") {
+        builder = builder.clang_arg("-DWASM_ENABLE_STRINGREF=1");
+    }
+    let bindings = builder.generate().expect("Unable to generate bindings");
     let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     bindings
         .write_to_file(out_path.join("bindings.rs"))
@@ -11,3 +11,10 @@
 
 // This matches bindgen::Builder output
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+// the shims of `src/shim.c`, built into WAMR
+#[cfg(feature = "lazy-jit")]
+extern "C" {
+    /// the number of functions of `module` the lazy LLVM JIT hasn't compiled yet
+    pub fn wamr_sys_pending_jit_functions(module: wasm_module_t) -> u32;
+}
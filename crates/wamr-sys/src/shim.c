@@ -0,0 +1,35 @@
+/*
+ * Copyright (C) 2023 Liquid Reply GmbH. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+/* what the SDK needs of WAMR beyond *wasm_export.h*, built into WAMR with its
+ * definitions, as it reads its internal structures */
+
+#include "wasm_runtime_common.h"
+
+/* the number of functions of a .wasm the lazy LLVM JIT hasn't compiled yet, on its
+ * background threads. 0 for an .aot, or if the JIT isn't lazy */
+uint32
+wamr_sys_pending_jit_functions(wasm_module_t module)
+{
+    uint32 pending = 0;
+#if WASM_ENABLE_JIT != 0 && WASM_ENABLE_LAZY_JIT != 0
+    WASMModule *wasm_module = (WASMModule *)module;
+    uint32 i;
+
+    if (module->module_type != Wasm_Module_Bytecode
+        || !wasm_module->func_ptrs_compiled)
+        return 0;
+
+    for (i = 0; i < wasm_module->function_count; i++) {
+        /* set by the threads once they compiled the function */
+        if (!__atomic_load_n(&wasm_module->func_ptrs_compiled[i],
+                             __ATOMIC_ACQUIRE))
+            pending++;
+    }
+#else
+    (void)module;
+#endif
+    return pending;
+}
@@ -0,0 +1,355 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! resource usage aggregated over all calls into one or several instances, like the
+//! instances of a tenant. attach one via `Instance::set_resource_account()`
+//!
+//! typed host functions are counted by the trampoline WAMR calls them through, and raw
+//! ones report themselves via `Caller::record_host_call()`.
+//!
+//! WAMR doesn't report the instructions it executed, so with
+//! `RuntimeBuilder::meter_fuel()` each .wasm is instrumented while it is loaded, see
+//! `instrument`: the instructions of a run without branches are counted before its last
+//! one, by a call to a host function imported from `FUEL_MODULE`. The fuel of a run cut
+//! short by a trap isn't counted, nor the instructions of an .aot, while those of other
+//! instrumentations are.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use wamr_sys::{wasm_module_inst_t, wasm_runtime_get_custom_data};
+
+use crate::{
+    binary::{write_i32_leb, write_u32_leb},
+    coverage, fault,
+    instrument::{self, Hook},
+    memory_profile,
+    native_module::{NativeExports, NativeModule},
+    replay,
+    user_data::ExecEnv,
+    ErrorMessage,
+};
+
+/// the module the metered code imports its hook from
+pub const FUEL_MODULE: &str = "wamr_fuel";
+const FUEL_HOOK: &str = "consume";
+
+/// the type of the hook, `(instructions: i32) -> ()`
+const FUEL_HOOK_TYPE: [u8; 4] = [0x60, 0x01, 0x7f, 0x00];
+
+#[derive(Debug, Default)]
+pub(crate) struct AccountCounters {
+    calls: AtomicU64,
+    traps: AtomicU64,
+    host_calls: AtomicU64,
+    fuel: AtomicU64,
+    execution_nanos: AtomicU64,
+    memory_high_watermark: AtomicU64,
+}
+
+impl AccountCounters {
+    pub(crate) fn record_call(&self, elapsed: Duration, trapped: bool, memory_size: usize) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        if trapped {
+            self.traps.fetch_add(1, Ordering::Relaxed);
+        }
+        self.execution_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.memory_high_watermark
+            .fetch_max(memory_size as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_host_call(&self) {
+        self.host_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_fuel(&self, instructions: u64) {
+        self.fuel.fetch_add(instructions, Ordering::Relaxed);
+    }
+}
+
+/// the counters of the account of `instance`, if any
+fn counters<'a>(instance: wasm_module_inst_t) -> Option<&'a AccountCounters> {
+    let counters = unsafe { wasm_runtime_get_custom_data(instance) as *const AccountCounters };
+    unsafe { counters.as_ref() }
+}
+
+/// count a host call into the account of `instance`, if any
+pub(crate) fn record_host_call(instance: wasm_module_inst_t) {
+    if let Some(counters) = counters(instance) {
+        counters.record_host_call();
+    }
+}
+
+/// whether the host functions of `module_name` are hooks of instrumented code, rather than
+/// host calls of the guest
+pub(crate) fn is_hook_module(module_name: &str) -> bool {
+    [
+        coverage::MODULE,
+        memory_profile::MODULE,
+        replay::MODULE,
+        fault::MODULE,
+        FUEL_MODULE,
+    ]
+    .contains(&module_name)
+}
+
+/// `binary` counting the instructions it executes
+pub(crate) fn instrument(binary: &[u8]) -> Result<Vec<u8>, ErrorMessage> {
+    let hook = Hook {
+        module: FUEL_MODULE,
+        name: FUEL_HOOK,
+        func_type: &FUEL_HOOK_TYPE,
+        locals: &[],
+    };
+
+    // the instructions since the last branch, or the entry
+    let mut run = 0;
+    instrument::instrument(binary, &hook, |site, out| {
+        if site.index == 0 {
+            run = 0;
+        }
+        run += 1;
+        let ends_run = matches!(
+            site.opcode,
+            // block, loop, if, else, try, catch, throw, rethrow, throw_ref, end, br, br_if,
+            // br_table, return, unreachable, return_call, return_call_indirect,
+            // return_call_ref, delegate, catch_all, try_table
+            0x02..=0x0f | 0x00 | 0x12 | 0x13 | 0x15 | 0x18 | 0x19 | 0x1f
+            // br_on_null, br_on_non_null, br_on_cast and br_on_cast_fail
+            | 0xd5 | 0xd6 | 0xfb0018 | 0xfb0019
+        );
+        if !ends_run {
+            return Ok(());
+        }
+
+        // i32.const run, call hook
+        out.push(0x41);
+        write_i32_leb(out, run);
+        out.push(0x10);
+        write_u32_leb(out, site.hook);
+        run = 0;
+        Ok(())
+    })
+}
+
+/// the hook the metered code calls
+pub(crate) struct FuelMeter;
+
+impl NativeModule for FuelMeter {
+    fn module_name(&self) -> &str {
+        FUEL_MODULE
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(FUEL_HOOK, consume as fn(ExecEnv, i32));
+    }
+}
+
+fn consume(env: ExecEnv, instructions: i32) {
+    if let Some(counters) = counters(env.module_inst()) {
+        counters.record_fuel(instructions as u32 as u64);
+    }
+}
+
+/// a snapshot of the figures of a `ResourceAccount`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// the number of calls into the instances
+    pub calls: u64,
+    /// the number of calls which trapped
+    pub traps: u64,
+    /// the number of host functions calls, counted by the trampolines of typed host
+    /// functions, or reported via `Caller::record_host_call()`
+    pub host_calls: u64,
+    /// the number of instructions executed, with `RuntimeBuilder::meter_fuel()`
+    pub fuel: u64,
+    /// the wall-clock time spent in calls into the instances
+    pub execution_time: Duration,
+    /// the largest linear memory of an instance after a call, in bytes
+    pub memory_high_watermark: u64,
+}
+
+/// a shared handle to resource usage counters. Clones count into the same figures
+#[derive(Debug, Clone, Default)]
+pub struct ResourceAccount {
+    counters: Arc<AccountCounters>,
+}
+
+impl ResourceAccount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the figures so far
+    pub fn usage(&self) -> ResourceUsage {
+        let counters = &self.counters;
+        ResourceUsage {
+            calls: counters.calls.load(Ordering::Relaxed),
+            traps: counters.traps.load(Ordering::Relaxed),
+            host_calls: counters.host_calls.load(Ordering::Relaxed),
+            fuel: counters.fuel.load(Ordering::Relaxed),
+            execution_time: Duration::from_nanos(counters.execution_nanos.load(Ordering::Relaxed)),
+            memory_high_watermark: counters.memory_high_watermark.load(Ordering::Relaxed),
+        }
+    }
+
+    /// return the figures so far and start again from zero
+    pub fn reset(&self) -> ResourceUsage {
+        let counters = &self.counters;
+        ResourceUsage {
+            calls: counters.calls.swap(0, Ordering::Relaxed),
+            traps: counters.traps.swap(0, Ordering::Relaxed),
+            host_calls: counters.host_calls.swap(0, Ordering::Relaxed),
+            fuel: counters.fuel.swap(0, Ordering::Relaxed),
+            execution_time: Duration::from_nanos(
+                counters.execution_nanos.swap(0, Ordering::Relaxed),
+            ),
+            memory_high_watermark: counters.memory_high_watermark.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn counters(&self) -> &Arc<AccountCounters> {
+        &self.counters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        binary, function::Function, instance::Instance, instruction::decode_body, module::Module,
+        runtime::Runtime, value::WasmValue, RuntimeError,
+    };
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_resource_account() {
+        let account = ResourceAccount::new();
+        let shared = account.clone();
+
+        shared
+            .counters()
+            .record_call(Duration::from_millis(3), false, 65536);
+        account
+            .counters()
+            .record_call(Duration::from_millis(2), true, 4096);
+        account.counters().record_host_call();
+        account.counters().record_fuel(12);
+
+        let usage = ResourceUsage {
+            calls: 2,
+            traps: 1,
+            host_calls: 1,
+            fuel: 12,
+            execution_time: Duration::from_millis(5),
+            memory_high_watermark: 65536,
+        };
+        assert_eq!(shared.usage(), usage);
+        assert_eq!(account.reset(), usage);
+        assert_eq!(shared.usage(), ResourceUsage::default());
+    }
+
+    #[test]
+    fn test_instrument_fuel() {
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let metered = instrument(&binary).unwrap();
+        let imports = binary::imports(&metered).unwrap();
+        assert_eq!(
+            imports
+                .iter()
+                .map(|i| (i.module, i.name))
+                .collect::<Vec<_>>(),
+            [(FUEL_MODULE, FUEL_HOOK)]
+        );
+        let bodies = binary::function_bodies(&metered).unwrap();
+        let instructions = decode_body(&metered, bodies[0].clone()).unwrap();
+        let opcodes: Vec<u32> = instructions.iter().map(|i| i.opcode).collect();
+        // local.get, local.get, i32.add, i32.const 4, call hook, end
+        assert_eq!(opcodes, [0x20, 0x20, 0x6a, 0x41, 0x10, 0x0b]);
+        assert_eq!(metered[instructions[3].offset + 1], 4);
+    }
+
+    #[test]
+    fn test_fuel_and_host_calls() -> Result<(), RuntimeError> {
+        fn extra(_env: ExecEnv) -> i32 {
+            100
+        }
+
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .meter_fuel()
+            .register_typed_host_function("extra", extra as fn(ExecEnv) -> i32)
+            .build()?;
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path())?;
+        let mut instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+        let account = ResourceAccount::new();
+        instance.set_resource_account(account.clone());
+
+        let function = Function::find_export_func(&instance, "add")?;
+        let result = function.call_args(&instance, &[WasmValue::I32(8), WasmValue::I32(8)])?;
+        assert_eq!(result, WasmValue::I32(116));
+
+        let usage = account.usage();
+        assert_eq!(usage.host_calls, 1);
+        assert!(usage.fuel > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_resource_account() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "add")?;
+        let account = ResourceAccount::new();
+
+        for _ in 0..2 {
+            let mut instance = Instance::new(&runtime, &module, 1024, ())?;
+            instance.set_resource_account(account.clone());
+            let function = Function::find_export_func(&instance, "add")?;
+            function.call_args(&instance, &[WasmValue::I32(1), WasmValue::I32(2)])?;
+        }
+
+        let usage = account.usage();
+        assert_eq!(usage.calls, 2);
+        assert_eq!(usage.traps, 0);
+        assert_eq!(usage.memory_high_watermark, 0);
+
+        Ok(())
+    }
+}
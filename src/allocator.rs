@@ -0,0 +1,325 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! statistics of the memory WAMR allocates for itself and for instances, to tell an OOM
+//! inside WAMR apart from an exhausted guest heap or a host limit.
+//! get them via `Runtime::allocator_stats()`
+//!
+//! the pool allocator keeps its own statistics. The system allocator doesn't, so
+//! `RuntimeBuilder::use_instrumented_allocator()` hands WAMR allocation functions which
+//! count on top of the global allocator of Rust.
+//!
+//! a callback set via `RuntimeBuilder::memory_watermarks()` is told when the bytes
+//! allocated cross a high watermark, and then a low one, like to evict idle instances
+//! before WAMR runs out of memory.
+
+use std::{
+    alloc::{self, Layout},
+    ffi::{c_uint, c_void},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use wamr_sys::{mem_alloc_info_t, wasm_runtime_get_mem_alloc_info};
+
+/// a snapshot of the allocator statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AllocatorStats {
+    /// the bytes allocated right now
+    pub allocated: usize,
+    /// the most bytes allocated at once
+    pub peak: usize,
+    /// the failed allocations, `None` if the allocator doesn't count them, like the pool
+    pub failures: Option<u64>,
+}
+
+/// a watermark of `RuntimeBuilder::memory_watermarks()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watermark {
+    /// the allocations grew up to the high watermark
+    High,
+    /// the allocations shrank down to the low watermark, after reaching the high one
+    Low,
+}
+
+/// the allocations crossing a watermark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatermarkCrossing {
+    pub watermark: Watermark,
+    /// the bytes allocated right then
+    pub allocated: usize,
+}
+
+/// told about the crossings of the watermarks
+pub type WatermarkCallback = Arc<dyn Fn(WatermarkCrossing) + Send + Sync>;
+
+/// where WAMR allocates from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AllocatorKind {
+    System,
+    Pool,
+    Instrumented,
+}
+
+impl AllocatorKind {
+    pub(crate) fn stats(&self) -> Option<AllocatorStats> {
+        match self {
+            AllocatorKind::System => None,
+            AllocatorKind::Pool => {
+                let mut info = mem_alloc_info_t::default();
+                match unsafe { wasm_runtime_get_mem_alloc_info(&mut info) } {
+                    true => Some(AllocatorStats {
+                        allocated: (info.total_size - info.total_free_size) as usize,
+                        peak: info.highmark_size as usize,
+                        failures: None,
+                    }),
+                    false => None,
+                }
+            }
+            AllocatorKind::Instrumented => Some(AllocatorStats {
+                allocated: ALLOCATED.load(Ordering::Relaxed),
+                peak: PEAK.load(Ordering::Relaxed),
+                failures: Some(FAILURES.load(Ordering::Relaxed)),
+            }),
+        }
+    }
+}
+
+/// the high and low watermarks, and which side of them the allocations are
+struct Watermarks {
+    high: AtomicUsize,
+    low: AtomicUsize,
+    above: AtomicBool,
+}
+
+impl Watermarks {
+    const fn new() -> Self {
+        Watermarks {
+            high: AtomicUsize::new(usize::MAX),
+            low: AtomicUsize::new(0),
+            above: AtomicBool::new(false),
+        }
+    }
+
+    fn set(&self, high: usize, low: usize) {
+        self.high.store(high, Ordering::Relaxed);
+        self.low.store(low.min(high), Ordering::Relaxed);
+        self.above.store(false, Ordering::Relaxed);
+    }
+
+    /// the watermark `allocated` crossed, if any, only once until the other one is crossed
+    fn crossing(&self, allocated: usize) -> Option<Watermark> {
+        let (above, watermark) = match self.above.load(Ordering::Relaxed) {
+            false if allocated >= self.high.load(Ordering::Relaxed) => (true, Watermark::High),
+            true if allocated <= self.low.load(Ordering::Relaxed) => (false, Watermark::Low),
+            _ => return None,
+        };
+        self.above
+            .compare_exchange(!above, above, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+            .then_some(watermark)
+    }
+}
+
+// process-wide, like the allocator of WAMR
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+static WATERMARKS: Watermarks = Watermarks::new();
+static WATERMARK_CALLBACK: Mutex<Option<WatermarkCallback>> = Mutex::new(None);
+// a pool doesn't tell about its allocations, so it is polled
+static POLLED_POOL: AtomicBool = AtomicBool::new(false);
+// whether WAMR allocates from a pool, as the runtime which initialized it asked
+static POOLED: AtomicBool = AtomicBool::new(false);
+
+/// tell whether WAMR allocates from a pool, once a runtime initialized it
+pub(crate) fn set_pooled(pooled: bool) {
+    POOLED.store(pooled, Ordering::Relaxed);
+}
+
+/// whether the linear memories are private anonymous memory, which WAMR maps itself with
+/// hardware bound checks on 64-bit hosts, or takes from the heap of the process. Not when
+/// they come from a pool, which the host may have mapped any way, shared or from a file
+pub(crate) fn private_linear_memories() -> bool {
+    cfg!(all(
+        target_pointer_width = "64",
+        not(feature = "no-hw-bound-check")
+    )) || !POOLED.load(Ordering::Relaxed)
+}
+
+/// set the watermarks, or unset them with `None`
+pub(crate) fn set_watermarks(watermarks: Option<(usize, usize, WatermarkCallback)>, pool: bool) {
+    let mut callback = WATERMARK_CALLBACK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    match watermarks {
+        Some((high, low, watermark_callback)) => {
+            *callback = Some(watermark_callback);
+            WATERMARKS.set(high, low);
+        }
+        None => {
+            *callback = None;
+            WATERMARKS.set(usize::MAX, 0);
+        }
+    }
+    POLLED_POOL.store(pool && callback.is_some(), Ordering::Relaxed);
+}
+
+/// whether calls have to poll the pool for its watermarks
+pub(crate) fn polls_pool_watermarks() -> bool {
+    POLLED_POOL.load(Ordering::Relaxed)
+}
+
+/// tell the callback if `allocated` crossed a watermark. It runs on the allocating thread,
+/// maybe inside WAMR
+fn check_watermarks(allocated: usize) {
+    let Some(watermark) = WATERMARKS.crossing(allocated) else {
+        return;
+    };
+    let callback = WATERMARK_CALLBACK
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    if let Some(callback) = callback {
+        callback(WatermarkCrossing {
+            watermark,
+            allocated,
+        });
+    }
+}
+
+/// check the watermarks against the statistics of a memory pool, if any is polled. Called
+/// after what allocates the most, instantiations and calls
+pub(crate) fn poll_pool_watermarks() {
+    if !POLLED_POOL.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(stats) = AllocatorKind::Pool.stats() {
+        check_watermarks(stats.allocated);
+    }
+}
+
+/// the size of an allocation is kept in front of it, in a header which keeps the alignment
+/// malloc guarantees
+const HEADER_SIZE: usize = 16;
+
+fn layout(size: usize) -> Option<Layout> {
+    Layout::from_size_align(size.checked_add(HEADER_SIZE)?, HEADER_SIZE).ok()
+}
+
+fn record_allocation(size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(allocated, Ordering::Relaxed);
+    check_watermarks(allocated);
+}
+
+fn record_failure() -> *mut c_void {
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+    ptr::null_mut()
+}
+
+/// # Safety
+///
+/// `base` is the start of an allocation of `size` bytes, plus the header
+unsafe fn finish_allocation(base: *mut u8, size: usize) -> *mut c_void {
+    (base as *mut usize).write(size);
+    record_allocation(size);
+    base.add(HEADER_SIZE) as *mut c_void
+}
+
+pub(crate) extern "C" fn instrumented_malloc(size: c_uint) -> *mut c_void {
+    let size = size as usize;
+    let base = match layout(size) {
+        Some(layout) => unsafe { alloc::alloc(layout) },
+        None => ptr::null_mut(),
+    };
+    match base.is_null() {
+        true => record_failure(),
+        false => unsafe { finish_allocation(base, size) },
+    }
+}
+
+pub(crate) extern "C" fn instrumented_realloc(ptr: *mut c_void, size: c_uint) -> *mut c_void {
+    if ptr.is_null() {
+        return instrumented_malloc(size);
+    }
+
+    let size = size as usize;
+    unsafe {
+        let base = (ptr as *mut u8).sub(HEADER_SIZE);
+        let old_size = *(base as *const usize);
+        let base = match layout(size) {
+            Some(_) => alloc::realloc(base, layout(old_size).unwrap(), size + HEADER_SIZE),
+            None => ptr::null_mut(),
+        };
+        // the old allocation is left as it was
+        if base.is_null() {
+            return record_failure();
+        }
+        ALLOCATED.fetch_sub(old_size, Ordering::Relaxed);
+        finish_allocation(base, size)
+    }
+}
+
+pub(crate) extern "C" fn instrumented_free(ptr: *mut c_void) {
+    if ptr.is_null() {
+        return;
+    }
+
+    unsafe {
+        let base = (ptr as *mut u8).sub(HEADER_SIZE);
+        let size = *(base as *const usize);
+        alloc::dealloc(base, layout(size).unwrap());
+        let allocated = ALLOCATED.fetch_sub(size, Ordering::Relaxed) - size;
+        check_watermarks(allocated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instrumented_allocator() {
+        let before = AllocatorKind::Instrumented.stats().unwrap();
+
+        let ptr = instrumented_malloc(100);
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % HEADER_SIZE, 0);
+        unsafe { ptr::write_bytes(ptr as *mut u8, 0xab, 100) };
+
+        let ptr = instrumented_realloc(ptr, 1000);
+        assert_eq!(unsafe { *(ptr as *const u8).add(99) }, 0xab);
+        let stats = AllocatorKind::Instrumented.stats().unwrap();
+        assert_eq!(stats.allocated, before.allocated + 1000);
+        assert!(stats.peak >= before.allocated + 1000);
+
+        instrumented_free(ptr);
+        let stats = AllocatorKind::Instrumented.stats().unwrap();
+        assert_eq!(stats.allocated, before.allocated);
+        assert_eq!(stats.failures, Some(0));
+
+        assert_eq!(AllocatorKind::System.stats(), None);
+    }
+
+    #[test]
+    fn test_watermarks() {
+        let watermarks = Watermarks::new();
+        assert_eq!(watermarks.crossing(usize::MAX - 1), None);
+
+        watermarks.set(1000, 500);
+        assert_eq!(watermarks.crossing(999), None);
+        assert_eq!(watermarks.crossing(600), None);
+        assert_eq!(watermarks.crossing(1000), Some(Watermark::High));
+        assert_eq!(watermarks.crossing(2000), None);
+        assert_eq!(watermarks.crossing(501), None);
+        assert_eq!(watermarks.crossing(400), Some(Watermark::Low));
+        assert_eq!(watermarks.crossing(100), None);
+        assert_eq!(watermarks.crossing(1500), Some(Watermark::High));
+    }
+}
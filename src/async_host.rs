@@ -0,0 +1,160 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! host functions completing a Rust future, like an HTTP fetch with an async client.
+//! call `async_host::complete()` from a host function
+//!
+//! a host call can't suspend the wasm stack, so the thread of the guest is parked while
+//! the future runs on the `Executor` set via `RuntimeBuilder::set_executor()`, and the
+//! guest resumes with the output once the future completes. Without an executor, the
+//! future is polled on the thread of the guest, which only suits futures not tied to the
+//! reactor of an async runtime.
+//!
+//! the executor has to run the future on another thread than the guest, otherwise they
+//! wait for each other, like a single-threaded runtime entered by the guest thread.
+
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    sync::{mpsc, Arc, Mutex, PoisonError},
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use crate::RuntimeError;
+
+/// a future handed over to an `Executor`
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// where the futures of host functions run, like the handle of an async runtime
+pub trait Executor: Send + Sync {
+    fn spawn(&self, task: Task);
+}
+
+/// an `Executor` via a closure, like `move |task| { handle.spawn(task); }`
+impl<F: Fn(Task) + Send + Sync> Executor for F {
+    fn spawn(&self, task: Task) {
+        self(task)
+    }
+}
+
+static EXECUTOR: Mutex<Option<Arc<dyn Executor>>> = Mutex::new(None);
+
+pub(crate) fn set_executor(executor: Option<Arc<dyn Executor>>) {
+    *EXECUTOR.lock().unwrap_or_else(PoisonError::into_inner) = executor;
+}
+
+fn executor() -> Option<Arc<dyn Executor>> {
+    EXECUTOR
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// poll `future` on the current thread, which is parked until the future is woken
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+/// run `future` on the executor of the runtime, if any, and wait for its output
+///
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if the executor dropped the future before it
+/// completed.
+pub fn complete<F>(future: F) -> Result<F::Output, RuntimeError>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match executor() {
+        Some(executor) => complete_on(executor.as_ref(), future),
+        None => Ok(block_on(future)),
+    }
+}
+
+/// run `future` on `executor`, and wait for its output
+///
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if the executor dropped the future before it
+/// completed.
+pub fn complete_on<E, F>(executor: &E, future: F) -> Result<F::Output, RuntimeError>
+where
+    E: Executor + ?Sized,
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(1);
+    executor.spawn(Box::pin(async move {
+        let _ = sender.send(future.await);
+    }));
+    receiver.recv().map_err(|_| {
+        RuntimeError::ExecutionError(message!(
+            "the executor dropped the future before it completed",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// ready on the second poll, once woken by another thread
+    struct Delayed(Option<thread::JoinHandle<()>>);
+
+    impl Future for Delayed {
+        type Output = u32;
+
+        fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<u32> {
+            if self.0.is_some() {
+                return Poll::Ready(42);
+            }
+            let waker = context.waker().clone();
+            self.0 = Some(thread::spawn(move || {
+                thread::sleep(Duration::from_millis(10));
+                waker.wake();
+            }));
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn test_complete_on() {
+        assert_eq!(block_on(Delayed(None)), 42);
+
+        let threads = |task: Task| {
+            thread::spawn(move || block_on(task));
+        };
+        assert_eq!(complete_on(&threads, Delayed(None)).unwrap(), 42);
+
+        let dropping = |task: Task| drop(task);
+        assert!(matches!(
+            complete_on(&dropping, async { 42 }),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! precompile and validate modules with the defaults of the SDK build
+//!
+//! ```text
+//! wamr-sdk compile in.wasm -o out.aot [--target aarch64] [--cpu cortex-a53]
+//!     [--opt-level 3] [--size-level 3]
+//! wamr-sdk validate in.wasm...
+//! ```
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use wamr_rust_sdk::{compiler::AotCompiler, module::Module, runtime::Runtime};
+
+const USAGE: &str = "usage:
+  wamr-sdk compile <in.wasm> -o <out.aot> [--target <arch>] [--cpu <cpu>] [--opt-level <0-3>] [--size-level <0-3>]
+  wamr-sdk validate <in.wasm>...";
+
+fn compile(args: &[String]) -> Result<(), String> {
+    let mut compiler = AotCompiler::new();
+    let mut input = None;
+    let mut output = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            input = Some(PathBuf::from(arg));
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing the value of {}", arg))?;
+        let level = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid {} {}", arg, value))
+        };
+        match arg.as_str() {
+            "-o" => output = Some(PathBuf::from(value)),
+            "--target" => compiler = compiler.target(value),
+            "--cpu" => compiler = compiler.cpu(value),
+            "--opt-level" => compiler = compiler.opt_level(level()?),
+            "--size-level" => compiler = compiler.size_level(level()?),
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+
+    let input = input.ok_or("missing the input .wasm")?;
+    let output = output.unwrap_or_else(|| input.with_extension("aot"));
+    compiler.compile(&input, &output).map_err(|e| e.to_string())
+}
+
+fn validate(files: &[String]) -> Result<(), String> {
+    if files.is_empty() {
+        return Err(String::from("missing the .wasm to validate"));
+    }
+
+    let runtime = Runtime::new().map_err(|e| e.to_string())?;
+    let mut failed = false;
+    for file in files {
+        match Module::from_file(&runtime, Path::new(file)) {
+            Ok(_) => println!("{}: ok", file),
+            Err(e) => {
+                println!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    match failed {
+        true => Err(String::from("invalid modules")),
+        false => Ok(()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let result = match args.first().map(String::as_str) {
+        Some("compile") => compile(&args[1..]),
+        Some("validate") => validate(&args[1..]),
+        _ => Err(String::from(USAGE)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,870 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a minimal reader of the wasm binary format.
+//! enough to inspect the parts of a module WAMR doesn't expose via *wasm_export.h*
+
+use std::ops::Range;
+
+use crate::ErrorMessage;
+
+pub const SECTION_CUSTOM: u8 = 0;
+pub const SECTION_TYPE: u8 = 1;
+pub const SECTION_IMPORT: u8 = 2;
+pub const SECTION_FUNCTION: u8 = 3;
+pub const SECTION_TABLE: u8 = 4;
+pub const SECTION_MEMORY: u8 = 5;
+pub const SECTION_GLOBAL: u8 = 6;
+pub const SECTION_EXPORT: u8 = 7;
+pub const SECTION_START: u8 = 8;
+pub const SECTION_ELEMENT: u8 = 9;
+pub const SECTION_CODE: u8 = 10;
+
+const WASM_MAGIC: [u8; 4] = [0x00, 0x61, 0x73, 0x6d];
+const WASM_VERSION: [u8; 4] = [0x01, 0x00, 0x00, 0x00];
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ErrorMessage> {
+        match self.buf.get(self.pos) {
+            Some(byte) => {
+                self.pos += 1;
+                Ok(*byte)
+            }
+            None => Err(message!("unexpected end at offset {}", self.pos)),
+        }
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], ErrorMessage> {
+        let end = self.pos.checked_add(len);
+        match end.and_then(|end| self.buf.get(self.pos..end)) {
+            Some(bytes) => {
+                self.pos += len;
+                Ok(bytes)
+            }
+            None => Err(message!(
+                "unexpected end reading {} bytes at offset {}",
+                len,
+                self.pos
+            )),
+        }
+    }
+
+    pub fn read_u64_leb(&mut self) -> Result<u64, ErrorMessage> {
+        let mut result: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            if shift >= 64 || (shift == 63 && byte > 1) {
+                return Err(message!("integer too large at offset {}", self.pos));
+            }
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+            shift += 7;
+        }
+    }
+
+    /// skip a signed or unsigned LEB128 integer of at most 64 bits
+    pub fn skip_leb(&mut self) -> Result<(), ErrorMessage> {
+        for _ in 0..10 {
+            if self.read_u8()? & 0x80 == 0 {
+                return Ok(());
+            }
+        }
+        Err(message!("integer too large at offset {}", self.pos))
+    }
+
+    pub fn read_u32_leb(&mut self) -> Result<u32, ErrorMessage> {
+        let value = self.read_u64_leb()?;
+        u32::try_from(value).map_err(|_| message!("integer too large at offset {}", self.pos))
+    }
+
+    pub fn read_name(&mut self) -> Result<&'a str, ErrorMessage> {
+        let len = self.read_u32_leb()? as usize;
+        let bytes = self.read_bytes(len)?;
+        std::str::from_utf8(bytes)
+            .map_err(|_| message!("invalid utf-8 name at offset {}", self.pos))
+    }
+}
+
+/// skip a value type, abbreviated or not
+pub fn skip_valtype(reader: &mut Reader) -> Result<(), ErrorMessage> {
+    match reader.read_u8()? {
+        // (ref ht) and (ref null ht)
+        0x63 | 0x64 => reader.skip_leb(),
+        _ => Ok(()),
+    }
+}
+
+pub struct Section<'a> {
+    pub id: u8,
+    /// the offset of the payload in the binary
+    pub offset: usize,
+    pub payload: &'a [u8],
+}
+
+/// split a wasm binary into its sections
+pub fn sections(binary: &[u8]) -> Result<Vec<Section<'_>>, ErrorMessage> {
+    let mut reader = Reader::new(binary);
+    if reader.read_bytes(4)? != WASM_MAGIC {
+        return Err(message!("magic header not detected"));
+    }
+    if reader.read_bytes(4)? != WASM_VERSION {
+        return Err(message!("unknown binary version"));
+    }
+
+    let mut sections = Vec::new();
+    while !reader.is_empty() {
+        let id = reader.read_u8()?;
+        let size = reader.read_u32_leb()? as usize;
+        let offset = reader.position();
+        let payload = reader.read_bytes(size)?;
+        sections.push(Section {
+            id,
+            offset,
+            payload,
+        });
+    }
+    Ok(sections)
+}
+
+#[allow(dead_code)]
+pub struct Limits {
+    pub minimum: u64,
+    pub maximum: Option<u64>,
+    pub shared: bool,
+    pub memory64: bool,
+}
+
+pub fn read_limits(reader: &mut Reader) -> Result<Limits, ErrorMessage> {
+    let flags = reader.read_u8()?;
+    if flags > 0x07 {
+        return Err(message!("invalid limits flags {:#x}", flags));
+    }
+
+    let minimum = reader.read_u64_leb()?;
+    let maximum = match flags & 0x01 {
+        0 => None,
+        _ => Some(reader.read_u64_leb()?),
+    };
+    Ok(Limits {
+        minimum,
+        maximum,
+        shared: flags & 0x02 != 0,
+        memory64: flags & 0x04 != 0,
+    })
+}
+
+#[allow(dead_code)]
+pub enum ImportKind {
+    Func(u32),
+    Table(Limits),
+    Memory(Limits),
+    Global,
+    Tag,
+}
+
+#[allow(dead_code)]
+pub struct Import<'a> {
+    pub module: &'a str,
+    pub name: &'a str,
+    pub kind: ImportKind,
+}
+
+fn read_import_kind(reader: &mut Reader) -> Result<ImportKind, ErrorMessage> {
+    match reader.read_u8()? {
+        0x00 => Ok(ImportKind::Func(reader.read_u32_leb()?)),
+        0x01 => {
+            reader.read_u8()?;
+            Ok(ImportKind::Table(read_limits(reader)?))
+        }
+        0x02 => Ok(ImportKind::Memory(read_limits(reader)?)),
+        0x03 => {
+            reader.read_bytes(2)?;
+            Ok(ImportKind::Global)
+        }
+        0x04 => {
+            reader.read_u8()?;
+            reader.read_u32_leb()?;
+            Ok(ImportKind::Tag)
+        }
+        kind => Err(message!("invalid import kind {:#x}", kind)),
+    }
+}
+
+/// all entries of the import section
+pub fn imports(binary: &[u8]) -> Result<Vec<Import<'_>>, ErrorMessage> {
+    let mut imports = Vec::new();
+    for section in sections(binary)? {
+        if section.id != SECTION_IMPORT {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            let module = reader.read_name()?;
+            let name = reader.read_name()?;
+            let kind = read_import_kind(&mut reader)?;
+            imports.push(Import { module, name, kind });
+        }
+    }
+    Ok(imports)
+}
+
+/// the payload of the custom section called `name`, if any
+pub fn custom_section<'a>(binary: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, ErrorMessage> {
+    for section in sections(binary)? {
+        if section.id != SECTION_CUSTOM {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        if reader.read_name()? == name {
+            return Ok(Some(&section.payload[reader.position()..]));
+        }
+    }
+    Ok(None)
+}
+
+/// the `(field, name, version)` of each tool listed by the `producers` section, like
+/// `("language", "Rust", "")` or `("processed-by", "rustc", "1.75.0")`
+pub fn producers(binary: &[u8]) -> Result<Vec<(&str, &str, &str)>, ErrorMessage> {
+    let payload = match custom_section(binary, "producers")? {
+        Some(payload) => payload,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut reader = Reader::new(payload);
+    let mut producers = Vec::new();
+    for _ in 0..reader.read_u32_leb()? {
+        let field = reader.read_name()?;
+        for _ in 0..reader.read_u32_leb()? {
+            let name = reader.read_name()?;
+            let version = reader.read_name()?;
+            producers.push((field, name, version));
+        }
+    }
+    Ok(producers)
+}
+
+/// the name of each function, imported or defined, by index, like WAMR names them: the
+/// field of an import, else the name in the `name` section, else the name of an export
+#[cfg_attr(not(feature = "perf-profiling"), allow(dead_code))]
+pub fn function_names(binary: &[u8]) -> Result<Vec<(u32, &str)>, ErrorMessage> {
+    let mut names: Vec<(u32, &str)> = Vec::new();
+    let mut imported = 0;
+    for import in imports(binary)? {
+        if let ImportKind::Func(_) = import.kind {
+            names.push((imported, import.name));
+            imported += 1;
+        }
+    }
+
+    let mut defined: Vec<(u32, &str)> = Vec::new();
+    if let Some(payload) = custom_section(binary, "name")? {
+        let mut reader = Reader::new(payload);
+        while !reader.is_empty() {
+            let id = reader.read_u8()?;
+            let size = reader.read_u32_leb()?;
+            let mut subsection = Reader::new(reader.read_bytes(size as usize)?);
+            // the function names
+            if id != 1 {
+                continue;
+            }
+            for _ in 0..subsection.read_u32_leb()? {
+                let index = subsection.read_u32_leb()?;
+                let name = subsection.read_name()?;
+                if index >= imported {
+                    defined.push((index, name));
+                }
+            }
+        }
+    }
+
+    for section in sections(binary)? {
+        if section.id != SECTION_EXPORT {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        for _ in 0..reader.read_u32_leb()? {
+            let name = reader.read_name()?;
+            let kind = reader.read_u8()?;
+            let index = reader.read_u32_leb()?;
+            if kind == 0x00 && index >= imported && defined.iter().all(|(i, _)| *i != index) {
+                defined.push((index, name));
+            }
+        }
+    }
+
+    defined.sort_by_key(|(index, _)| *index);
+    names.extend(defined);
+    Ok(names)
+}
+
+/// a copy of a wasm binary, with the module of each import renamed to what `rename`
+/// returns for its `(module, name)`, if anything. The index spaces are left as they are
+pub fn rename_imports<'a, F>(binary: &'a [u8], rename: F) -> Result<Vec<u8>, ErrorMessage>
+where
+    F: Fn(&'a str, &'a str) -> Option<String>,
+{
+    let mut renamed = Vec::from(&binary[..8]);
+    for section in sections(binary)? {
+        if section.id != SECTION_IMPORT {
+            renamed.push(section.id);
+            write_u32_leb(&mut renamed, section.payload.len() as u32);
+            renamed.extend_from_slice(section.payload);
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        let mut payload = Vec::new();
+        write_u32_leb(&mut payload, count);
+        for _ in 0..count {
+            let module = reader.read_name()?;
+            let name = reader.read_name()?;
+            let start = reader.position();
+            read_import_kind(&mut reader)?;
+
+            write_name(
+                &mut payload,
+                &rename(module, name).unwrap_or_else(|| module.to_string()),
+            );
+            write_name(&mut payload, name);
+            payload.extend_from_slice(&section.payload[start..reader.position()]);
+        }
+
+        renamed.push(section.id);
+        write_u32_leb(&mut renamed, payload.len() as u32);
+        renamed.extend_from_slice(&payload);
+    }
+    Ok(renamed)
+}
+
+/// a copy of a wasm binary which doesn't run anything at instantiation: the start
+/// section is dropped and the start function exported as `start_export`, and an
+/// `_initialize` export is renamed to `initialize_export`
+pub fn defer_start(
+    binary: &[u8],
+    start_export: &str,
+    initialize_export: &str,
+) -> Result<Vec<u8>, ErrorMessage> {
+    let sections = sections(binary)?;
+    let mut start = None;
+    for section in &sections {
+        if section.id == SECTION_START {
+            start = Some(Reader::new(section.payload).read_u32_leb()?);
+        }
+    }
+
+    let write_section = |buf: &mut Vec<u8>, id: u8, payload: &[u8]| {
+        buf.push(id);
+        write_u32_leb(buf, payload.len() as u32);
+        buf.extend_from_slice(payload);
+    };
+    // the exports of the start function, appended to the export section
+    let start_exports = |buf: &mut Vec<u8>| {
+        if let Some(start) = start {
+            write_name(buf, start_export);
+            buf.push(0x00);
+            write_u32_leb(buf, start);
+        }
+    };
+
+    // an export section for a module without one
+    let start_only = || {
+        let mut payload = Vec::new();
+        write_u32_leb(&mut payload, 1);
+        start_exports(&mut payload);
+        payload
+    };
+
+    let mut deferred = Vec::from(&binary[..8]);
+    let mut exported = false;
+    for section in &sections {
+        // the export section goes before the start, element, data count, code and data ones
+        let after_exports = (SECTION_START..=12).contains(&section.id);
+        if !exported && after_exports && start.is_some() {
+            write_section(&mut deferred, SECTION_EXPORT, &start_only());
+            exported = true;
+        }
+
+        match section.id {
+            SECTION_START => {}
+            SECTION_EXPORT => {
+                let mut reader = Reader::new(section.payload);
+                let count = reader.read_u32_leb()?;
+                let mut payload = Vec::new();
+                write_u32_leb(&mut payload, count + start.is_some() as u32);
+                for _ in 0..count {
+                    let name = reader.read_name()?;
+                    let kind = reader.read_u8()?;
+                    let index = reader.read_u32_leb()?;
+
+                    let renamed = match (name, kind) {
+                        ("_initialize", 0x00) => initialize_export,
+                        _ => name,
+                    };
+                    write_name(&mut payload, renamed);
+                    payload.push(kind);
+                    write_u32_leb(&mut payload, index);
+                }
+                start_exports(&mut payload);
+                write_section(&mut deferred, SECTION_EXPORT, &payload);
+                exported = true;
+            }
+            _ => write_section(&mut deferred, section.id, section.payload),
+        }
+    }
+    if !exported && start.is_some() {
+        write_section(&mut deferred, SECTION_EXPORT, &start_only());
+    }
+    Ok(deferred)
+}
+
+/// the header of a wasm binary, to write sections after
+#[cfg_attr(not(feature = "multi-module"), allow(dead_code))]
+pub fn header() -> Vec<u8> {
+    [WASM_MAGIC, WASM_VERSION].concat()
+}
+
+pub fn write_u32_leb(buf: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg_attr(not(feature = "multi-module"), allow(dead_code))]
+pub fn write_i32_leb(buf: &mut Vec<u8>, mut value: i32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+pub fn write_name(buf: &mut Vec<u8>, name: &str) {
+    write_u32_leb(buf, name.len() as u32);
+    buf.extend_from_slice(name.as_bytes());
+}
+
+/// the limits of the first memory, imported or defined
+pub fn memory_limits(binary: &[u8]) -> Result<Option<Limits>, ErrorMessage> {
+    Ok(all_memory_limits(binary)?.into_iter().next())
+}
+
+/// the limits of all memories, imported and defined
+pub fn all_memory_limits(binary: &[u8]) -> Result<Vec<Limits>, ErrorMessage> {
+    let mut memories = Vec::new();
+    for import in imports(binary)? {
+        if let ImportKind::Memory(limits) = import.kind {
+            memories.push(limits);
+        }
+    }
+
+    for section in sections(binary)? {
+        if section.id != SECTION_MEMORY {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            memories.push(read_limits(&mut reader)?);
+        }
+    }
+    Ok(memories)
+}
+
+/// the limits of all tables, imported and defined
+pub fn table_limits(binary: &[u8]) -> Result<Vec<Limits>, ErrorMessage> {
+    let mut tables = Vec::new();
+    for import in imports(binary)? {
+        if let ImportKind::Table(limits) = import.kind {
+            tables.push(limits);
+        }
+    }
+
+    for section in sections(binary)? {
+        if section.id != SECTION_TABLE {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            let reftype = reader.read_u8()?;
+            // a table with an initializer expression, from the GC proposal
+            if reftype == 0x40 {
+                return Err(message!("tables with initializers aren't supported"));
+            }
+            // (ref ht) and (ref null ht)
+            if reftype == 0x63 || reftype == 0x64 {
+                reader.skip_leb()?;
+            }
+            tables.push(read_limits(&mut reader)?);
+        }
+    }
+    Ok(tables)
+}
+
+/// the number of imported functions, which come first in the function index space
+pub fn imported_function_count(binary: &[u8]) -> Result<u32, ErrorMessage> {
+    Ok(imports(binary)?
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Func(_)))
+        .count() as u32)
+}
+
+/// the number of functions, imported and defined
+pub fn function_count(binary: &[u8]) -> Result<u64, ErrorMessage> {
+    let mut count = imported_function_count(binary)? as u64;
+
+    for section in sections(binary)? {
+        if section.id == SECTION_FUNCTION {
+            count += Reader::new(section.payload).read_u32_leb()? as u64;
+        }
+    }
+    Ok(count)
+}
+
+/// the number of parameters of each type of the type section. Only function types are
+/// supported, not the composite types of the GC proposal
+pub fn type_param_counts(binary: &[u8]) -> Result<Vec<u32>, ErrorMessage> {
+    let mut types = Vec::new();
+    for section in sections(binary)? {
+        if section.id != SECTION_TYPE {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            let form = reader.read_u8()?;
+            if form != 0x60 {
+                return Err(message!("unsupported type form {:#x}", form));
+            }
+            let params = reader.read_u32_leb()?;
+            for _ in 0..params {
+                skip_valtype(&mut reader)?;
+            }
+            let results = reader.read_u32_leb()?;
+            for _ in 0..results {
+                skip_valtype(&mut reader)?;
+            }
+            types.push(params);
+        }
+    }
+    Ok(types)
+}
+
+/// the signature of each type of the type section, as WAMR writes the ones of host
+/// functions, like `(iI)f`. References are written `r`
+pub fn type_signatures(binary: &[u8]) -> Result<Vec<String>, ErrorMessage> {
+    fn push_valtype(reader: &mut Reader, signature: &mut String) -> Result<(), ErrorMessage> {
+        let valtype = reader.read_u8()?;
+        signature.push(match valtype {
+            0x7f => 'i',
+            0x7e => 'I',
+            0x7d => 'f',
+            0x7c => 'F',
+            0x7b => 'V',
+            // (ref ht) and (ref null ht)
+            0x63 | 0x64 => {
+                reader.skip_leb()?;
+                'r'
+            }
+            _ => 'r',
+        });
+        Ok(())
+    }
+
+    let mut signatures = Vec::new();
+    for section in sections(binary)? {
+        if section.id != SECTION_TYPE {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            let form = reader.read_u8()?;
+            if form != 0x60 {
+                return Err(message!("unsupported type form {:#x}", form));
+            }
+            let mut signature = String::from("(");
+            let params = reader.read_u32_leb()?;
+            for _ in 0..params {
+                push_valtype(&mut reader, &mut signature)?;
+            }
+            signature.push(')');
+            let results = reader.read_u32_leb()?;
+            for _ in 0..results {
+                push_valtype(&mut reader, &mut signature)?;
+            }
+            signatures.push(signature);
+        }
+    }
+    Ok(signatures)
+}
+
+/// the type index of every function, imported and defined
+pub fn function_types(binary: &[u8]) -> Result<Vec<u32>, ErrorMessage> {
+    let mut functions: Vec<u32> = imports(binary)?
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Func(type_index) => Some(type_index),
+            _ => None,
+        })
+        .collect();
+
+    for section in sections(binary)? {
+        if section.id != SECTION_FUNCTION {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            functions.push(reader.read_u32_leb()?);
+        }
+    }
+    Ok(functions)
+}
+
+/// the ranges of the defined function bodies in the binary, locals included
+pub fn function_bodies(binary: &[u8]) -> Result<Vec<Range<usize>>, ErrorMessage> {
+    let mut bodies = Vec::new();
+    for section in sections(binary)? {
+        if section.id != SECTION_CODE {
+            continue;
+        }
+
+        let mut reader = Reader::new(section.payload);
+        let count = reader.read_u32_leb()?;
+        for _ in 0..count {
+            let size = reader.read_u32_leb()? as usize;
+            let start = section.offset + reader.position();
+            reader.read_bytes(size)?;
+            bodies.push(start..start + size);
+        }
+    }
+    Ok(bodies)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_leb() {
+        let mut reader = Reader::new(&[0xe5, 0x8e, 0x26, 0x7f, 0x80]);
+        assert_eq!(reader.read_u32_leb(), Ok(624485));
+        assert_eq!(reader.read_u32_leb(), Ok(127));
+        assert!(reader.read_u32_leb().is_err());
+
+        let mut reader = Reader::new(&[0xff, 0xff, 0xff, 0xff, 0x7f]);
+        assert!(reader.read_u32_leb().is_err());
+    }
+
+    #[test]
+    fn test_write_leb() {
+        let mut buf = Vec::new();
+        write_u32_leb(&mut buf, 624485);
+        assert_eq!(buf, [0xe5, 0x8e, 0x26]);
+
+        buf.clear();
+        write_i32_leb(&mut buf, -123456);
+        assert_eq!(buf, [0xc0, 0xbb, 0x78]);
+
+        buf.clear();
+        write_i32_leb(&mut buf, 64);
+        assert_eq!(buf, [0xc0, 0x00]);
+    }
+
+    #[test]
+    fn test_sections() {
+        assert!(sections(&[0x00, 0x61, 0x73]).is_err());
+        assert!(sections(&[0x00, 0x61, 0x73, 0x6d, 0x02, 0x00, 0x00, 0x00]).is_err());
+
+        // (module
+        //   (memory (export "memory") 1 2 shared)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x02,
+            0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let sections = sections(&binary).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].id, SECTION_MEMORY);
+
+        let limits = memory_limits(&binary).unwrap().unwrap();
+        assert_eq!(limits.minimum, 1);
+        assert_eq!(limits.maximum, Some(2));
+        assert!(limits.shared);
+        assert!(!limits.memory64);
+        assert_eq!(function_count(&binary), Ok(0));
+    }
+
+    #[test]
+    fn test_all_memory_limits() {
+        // (module
+        //   (import "env" "memory" (memory 1 1))
+        //   (memory 1)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x02, 0x10, 0x01, 0x03, 0x65, 0x6e,
+            0x76, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x01, 0x01, 0x01, 0x05, 0x03,
+            0x01, 0x00, 0x01,
+        ];
+        let limits = all_memory_limits(&binary).unwrap();
+        assert_eq!(limits.len(), 2);
+        assert_eq!(limits[0].maximum, Some(1));
+        assert_eq!(limits[1].maximum, None);
+        assert_eq!(memory_limits(&binary).unwrap().unwrap().maximum, Some(1));
+    }
+
+    #[test]
+    fn test_function_bodies() {
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let bodies = function_bodies(&binary).unwrap();
+        assert_eq!(bodies, vec![34..41]);
+        assert_eq!(
+            &binary[bodies[0].clone()],
+            &[0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b]
+        );
+        assert_eq!(imported_function_count(&binary), Ok(0));
+    }
+
+    #[test]
+    fn test_defer_start() {
+        // (module (func) (func) (start 1))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x03, 0x02, 0x00, 0x00, 0x08, 0x01, 0x01, 0x0a, 0x07, 0x02, 0x02, 0x00, 0x0b,
+            0x02, 0x00, 0x0b,
+        ];
+        let deferred = defer_start(&binary, "start", "initialize").unwrap();
+        let deferred_sections = sections(&deferred).unwrap();
+        let ids: Vec<u8> = deferred_sections.iter().map(|section| section.id).collect();
+        assert_eq!(
+            ids,
+            vec![SECTION_TYPE, SECTION_FUNCTION, SECTION_EXPORT, SECTION_CODE]
+        );
+        assert_eq!(
+            deferred_sections[2].payload,
+            &[0x01, 0x05, b's', b't', b'a', b'r', b't', 0x00, 0x01]
+        );
+
+        // without a start function, only `_initialize` is renamed
+        let mut reactor = header();
+        reactor.extend_from_slice(&[0x07, 0x0f, 0x01, 0x0b]);
+        reactor.extend_from_slice(b"_initialize");
+        reactor.extend_from_slice(&[0x00, 0x00]);
+        let deferred = defer_start(&reactor, "start", "init").unwrap();
+        assert_eq!(
+            sections(&deferred).unwrap()[0].payload,
+            &[0x01, 0x04, b'i', b'n', b'i', b't', 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_producers() {
+        // (module
+        //   (@producers
+        //     (language "Rust" "")
+        //     (processed-by "rustc" "1.75.0")
+        //     (processed-by "wasm-opt" "116")
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x43, 0x09, 0x70, 0x72, 0x6f,
+            0x64, 0x75, 0x63, 0x65, 0x72, 0x73, 0x02, 0x08, 0x6c, 0x61, 0x6e, 0x67, 0x75, 0x61,
+            0x67, 0x65, 0x01, 0x04, 0x52, 0x75, 0x73, 0x74, 0x00, 0x0c, 0x70, 0x72, 0x6f, 0x63,
+            0x65, 0x73, 0x73, 0x65, 0x64, 0x2d, 0x62, 0x79, 0x02, 0x05, 0x72, 0x75, 0x73, 0x74,
+            0x63, 0x06, 0x31, 0x2e, 0x37, 0x35, 0x2e, 0x30, 0x08, 0x77, 0x61, 0x73, 0x6d, 0x2d,
+            0x6f, 0x70, 0x74, 0x03, 0x31, 0x31, 0x36,
+        ];
+        assert_eq!(
+            producers(&binary),
+            Ok(vec![
+                ("language", "Rust", ""),
+                ("processed-by", "rustc", "1.75.0"),
+                ("processed-by", "wasm-opt", "116"),
+            ])
+        );
+        assert_eq!(producers(&binary[..8]), Ok(Vec::new()));
+        assert!(producers(&binary[..40]).is_err());
+    }
+
+    #[test]
+    fn test_function_names() {
+        // (module
+        //   (import "env" "log" (func (param i32)))
+        //   (func $helper)
+        //   (func (export "run"))
+        //   (func $named (export "exported")))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x60, 0x01, 0x7f,
+            0x00, 0x60, 0x00, 0x00, 0x02, 0x0b, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x03, 0x6c, 0x6f,
+            0x67, 0x00, 0x00, 0x03, 0x04, 0x03, 0x01, 0x01, 0x01, 0x07, 0x12, 0x02, 0x03, 0x72,
+            0x75, 0x6e, 0x00, 0x02, 0x08, 0x65, 0x78, 0x70, 0x6f, 0x72, 0x74, 0x65, 0x64, 0x00,
+            0x03, 0x0a, 0x0a, 0x03, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b, 0x00,
+            0x17, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01, 0x10, 0x02, 0x01, 0x06, 0x68, 0x65, 0x6c,
+            0x70, 0x65, 0x72, 0x03, 0x05, 0x6e, 0x61, 0x6d, 0x65, 0x64,
+        ];
+        assert_eq!(
+            function_names(&binary),
+            Ok(vec![(0, "log"), (1, "helper"), (2, "run"), (3, "named")])
+        );
+        // without the name section
+        assert_eq!(
+            function_names(&binary[..69]),
+            Ok(vec![(0, "log"), (2, "run"), (3, "exported")])
+        );
+    }
+}
@@ -0,0 +1,381 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! calls from the imports of an instance into the exports of another, to compose
+//! pipelines of modules without the multi-module loader. Bridge them via
+//! `Runtime::bridge()`
+//!
+//! WAMR links the imports of a module when it is loaded, so a bridged import is imported
+//! from the module `bridge`, like `(import "bridge" "next" (func ...))`, and loading the
+//! module links it to a trampoline. Which export the trampoline forwards to is up to each
+//! instance, and can change after it is instantiated. An import called before it is
+//! bridged traps.
+//!
+//! parameters and results of type `i32`, `i64`, `f32` and `f64` are forwarded, and at most
+//! one result. The export runs on the thread of the importing call, so both instances
+//! belong to it.
+
+use std::{
+    ffi::{c_void, CStr, CString},
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{
+    wasm_exec_env_t, wasm_func_get_param_count, wasm_func_get_param_types,
+    wasm_func_get_result_count, wasm_func_get_result_types, wasm_func_type_get_param_count,
+    wasm_func_type_get_param_valkind, wasm_func_type_get_result_count,
+    wasm_func_type_get_result_valkind, wasm_func_type_t, wasm_function_inst_t,
+    wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC, wasm_import_t, wasm_module_inst_t,
+    wasm_runtime_call_wasm, wasm_runtime_clear_exception, wasm_runtime_get_exception,
+    wasm_runtime_get_function_attachment, wasm_runtime_get_import_count,
+    wasm_runtime_get_import_type, wasm_runtime_get_module, wasm_runtime_get_module_inst,
+    wasm_runtime_lookup_function, wasm_runtime_register_natives_raw, wasm_runtime_set_exception,
+    wasm_valkind_enum_WASM_F32, wasm_valkind_enum_WASM_F64, wasm_valkind_enum_WASM_I32,
+    wasm_valkind_enum_WASM_I64, wasm_valkind_t, NativeSymbol,
+};
+
+use crate::{binary, helper::exception_to_string, instance::Instance, RuntimeError};
+
+/// the module name bridged imports are imported from
+pub const BRIDGE_MODULE: &str = "bridge";
+
+/// a trampoline registered for an import name, kept until WAMR is destroyed
+struct Trampoline {
+    // WAMR keeps the addresses of both names
+    _module: CString,
+    name: CString,
+    _symbols: Box<[NativeSymbol]>,
+}
+
+// the symbols only point to the names next to them
+unsafe impl Send for Trampoline {}
+
+struct Route {
+    importer: usize,
+    import: String,
+    exporter: wasm_module_inst_t,
+    // the execution environment of `exporter` the calls run in
+    exec_env: wasm_exec_env_t,
+    function: wasm_function_inst_t,
+    params: Vec<wasm_valkind_t>,
+    result: Option<wasm_valkind_t>,
+}
+
+// only dereferenced on the thread of the instances
+unsafe impl Send for Route {}
+
+static TRAMPOLINES: Mutex<Vec<Trampoline>> = Mutex::new(Vec::new());
+static ROUTES: Mutex<Vec<Route>> = Mutex::new(Vec::new());
+
+fn trampolines() -> MutexGuard<'static, Vec<Trampoline>> {
+    TRAMPOLINES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn routes() -> MutexGuard<'static, Vec<Route>> {
+    ROUTES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// link the imports of `binary` from `BRIDGE_MODULE` to trampolines, before it is loaded.
+/// An AOT binary isn't parsed, so its imports have to be linked by a wasm one before
+pub(crate) fn register_imports(binary: &[u8]) {
+    let Ok(imports) = binary::imports(binary) else {
+        return;
+    };
+
+    let mut trampolines = trampolines();
+    for import in imports {
+        if import.module != BRIDGE_MODULE || !matches!(import.kind, binary::ImportKind::Func(_)) {
+            continue;
+        }
+        let Ok(name) = CString::new(import.name) else {
+            continue;
+        };
+        if trampolines.iter().any(|trampoline| trampoline.name == name) {
+            continue;
+        }
+
+        let module = CString::new(BRIDGE_MODULE).unwrap();
+        let mut symbols = Box::new([NativeSymbol {
+            symbol: name.as_ptr(),
+            func_ptr: trampoline as *mut c_void,
+            // raw, typed by the import
+            signature: std::ptr::null(),
+            attachment: name.as_ptr() as *mut c_void,
+        }]);
+        let registered =
+            unsafe { wasm_runtime_register_natives_raw(module.as_ptr(), symbols.as_mut_ptr(), 1) };
+        if registered {
+            trampolines.push(Trampoline {
+                _module: module,
+                name,
+                _symbols: symbols,
+            });
+        }
+    }
+}
+
+/// forget the trampolines, once WAMR is destroyed
+pub(crate) fn reset() {
+    trampolines().clear();
+}
+
+/// forget the routes from and to `instance`
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    routes().retain(|route| route.importer != instance as usize && route.exporter != instance);
+}
+
+/// whether calls from `importer` are forwarded into `exporter`
+pub(crate) fn routes_to(importer: wasm_module_inst_t, exporter: wasm_module_inst_t) -> bool {
+    routes()
+        .iter()
+        .any(|route| route.importer == importer as usize && route.exporter == exporter)
+}
+
+/// the parameter and result types of the import `name` of `instance` from `BRIDGE_MODULE`
+fn import_type(
+    instance: wasm_module_inst_t,
+    name: &str,
+) -> Option<(Vec<wasm_valkind_t>, Vec<wasm_valkind_t>)> {
+    let module = unsafe { wasm_runtime_get_module(instance) };
+    let count = unsafe { wasm_runtime_get_import_count(module) };
+    let func_type: wasm_func_type_t = (0..count).find_map(|index| {
+        let mut import: wasm_import_t = unsafe { mem::zeroed() };
+        unsafe { wasm_runtime_get_import_type(module, index, &mut import) };
+        if import.kind != wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC
+            || import.module_name.is_null()
+            || import.name.is_null()
+        {
+            return None;
+        }
+        let module_name = unsafe { CStr::from_ptr(import.module_name) };
+        let import_name = unsafe { CStr::from_ptr(import.name) };
+        match module_name.to_bytes() == BRIDGE_MODULE.as_bytes()
+            && import_name.to_bytes() == name.as_bytes()
+        {
+            true => Some(unsafe { import.u.func_type }),
+            false => None,
+        }
+    })?;
+
+    let params = (0..unsafe { wasm_func_type_get_param_count(func_type) })
+        .map(|index| unsafe { wasm_func_type_get_param_valkind(func_type, index) })
+        .collect();
+    let results = (0..unsafe { wasm_func_type_get_result_count(func_type) })
+        .map(|index| unsafe { wasm_func_type_get_result_valkind(func_type, index) })
+        .collect();
+    Some((params, results))
+}
+
+/// the parameter and result types of `function` of `instance`
+fn export_type(
+    instance: wasm_module_inst_t,
+    function: wasm_function_inst_t,
+) -> (Vec<wasm_valkind_t>, Vec<wasm_valkind_t>) {
+    let mut params = vec![0; unsafe { wasm_func_get_param_count(function, instance) } as usize];
+    unsafe { wasm_func_get_param_types(function, instance, params.as_mut_ptr()) };
+    let mut results = vec![0; unsafe { wasm_func_get_result_count(function, instance) } as usize];
+    unsafe { wasm_func_get_result_types(function, instance, results.as_mut_ptr()) };
+    (params, results)
+}
+
+#[allow(non_upper_case_globals)]
+fn is_number(kind: &wasm_valkind_t) -> bool {
+    matches!(
+        *kind as u32,
+        wasm_valkind_enum_WASM_I32
+            | wasm_valkind_enum_WASM_I64
+            | wasm_valkind_enum_WASM_F32
+            | wasm_valkind_enum_WASM_F64
+    )
+}
+
+/// # Error
+///
+/// Return `RuntimeError::FunctionNotFound` if `exporter` doesn't export `export`, or
+/// `importer` doesn't import `import` from `BRIDGE_MODULE`, or `RuntimeError::TypeMismatch`
+/// if their types differ, or aren't made of numbers with at most one result.
+pub(crate) fn bridge<A, B>(
+    exporter: &Instance<A>,
+    export: &str,
+    importer: &Instance<B>,
+    import: &str,
+) -> Result<(), RuntimeError> {
+    let exec_env = exporter.exec_env().as_raw();
+    let exporter = exporter.get_inner_instance();
+    let importer = importer.get_inner_instance();
+
+    let export_name = CString::new(export).map_err(|_| RuntimeError::FunctionNotFound)?;
+    let function = unsafe { wasm_runtime_lookup_function(exporter, export_name.as_ptr()) };
+    if function.is_null() {
+        return Err(RuntimeError::FunctionNotFound);
+    }
+    let (params, results) = export_type(exporter, function);
+    let import_type = import_type(importer, import).ok_or(RuntimeError::FunctionNotFound)?;
+
+    if import_type != (params.clone(), results.clone()) {
+        return Err(RuntimeError::TypeMismatch(message!(
+            "the import {}.{} and the export {} have different types",
+            BRIDGE_MODULE,
+            import,
+            export
+        )));
+    }
+    if !params.iter().chain(&results).all(is_number) || results.len() > 1 {
+        return Err(RuntimeError::TypeMismatch(message!(
+            "only numbers and at most one result are bridged, not the types of {}",
+            export
+        )));
+    }
+
+    let mut routes = routes();
+    routes.retain(|route| route.importer != importer as usize || route.import != import);
+    routes.push(Route {
+        importer: importer as usize,
+        import: String::from(import),
+        exporter,
+        exec_env,
+        function,
+        params,
+        result: results.first().copied(),
+    });
+    Ok(())
+}
+
+fn trap(instance: wasm_module_inst_t, message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    unsafe { wasm_runtime_set_exception(instance, message.as_ptr()) };
+}
+
+/// a raw host function, getting its parameters, and returning its result, in 64-bit slots
+#[allow(non_upper_case_globals)]
+unsafe extern "C" fn trampoline(env: wasm_exec_env_t, slots: *mut u64) {
+    let import = CStr::from_ptr(wasm_runtime_get_function_attachment(env) as *const _);
+    let import = import.to_string_lossy();
+    let importer = wasm_runtime_get_module_inst(env);
+
+    let route = routes()
+        .iter()
+        .find(|route| route.importer == importer as usize && route.import == import)
+        .map(|route| {
+            (
+                route.exporter,
+                route.exec_env,
+                route.function,
+                route.params.clone(),
+                route.result,
+            )
+        });
+    let Some((exporter, exec_env, function, params, result)) = route else {
+        trap(
+            importer,
+            format!("the import {}.{} isn't bridged", BRIDGE_MODULE, import),
+        );
+        return;
+    };
+
+    // a 64-bit result takes 2 cells
+    let mut argv = Vec::with_capacity(params.len() * 2 + 2);
+    for (index, kind) in params.iter().enumerate() {
+        let slot = *slots.add(index);
+        match *kind as u32 {
+            wasm_valkind_enum_WASM_I32 | wasm_valkind_enum_WASM_F32 => argv.push(slot as u32),
+            _ => {
+                let bytes = slot.to_ne_bytes();
+                argv.push(u32::from_ne_bytes(bytes[..4].try_into().unwrap()));
+                argv.push(u32::from_ne_bytes(bytes[4..].try_into().unwrap()));
+            }
+        }
+    }
+    let argc = argv.len() as u32;
+    argv.resize(argv.len().max(2), 0);
+
+    if !wasm_runtime_call_wasm(exec_env, function, argc, argv.as_mut_ptr()) {
+        let exception = exception_to_string(wasm_runtime_get_exception(exporter));
+        wasm_runtime_clear_exception(exporter);
+        trap(importer, exception);
+        return;
+    }
+
+    match result.map(|kind| kind as u32) {
+        Some(wasm_valkind_enum_WASM_I32 | wasm_valkind_enum_WASM_F32) => {
+            *(slots as *mut u32) = argv[0];
+        }
+        Some(_) => {
+            let mut bytes = [0; 8];
+            bytes[..4].copy_from_slice(&argv[0].to_ne_bytes());
+            bytes[4..].copy_from_slice(&argv[1].to_ne_bytes());
+            *slots = u64::from_ne_bytes(bytes);
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
+        RuntimeError,
+    };
+
+    #[test]
+    fn test_bridge() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (func (export "double") (param i64) (result i64)
+        //     (i64.add (local.get 0) (local.get 0))
+        //   )
+        //   (func (export "neg") (param f32) (result f32) (f32.neg (local.get 0)))
+        // )
+        let exporting = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0b, 0x02, 0x60, 0x01, 0x7e,
+            0x01, 0x7e, 0x60, 0x01, 0x7d, 0x01, 0x7d, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x10,
+            0x02, 0x06, 0x64, 0x6f, 0x75, 0x62, 0x6c, 0x65, 0x00, 0x00, 0x03, 0x6e, 0x65, 0x67,
+            0x00, 0x01, 0x0a, 0x0f, 0x02, 0x07, 0x00, 0x20, 0x00, 0x20, 0x00, 0x7c, 0x0b, 0x05,
+            0x00, 0x20, 0x00, 0x8c, 0x0b,
+        ];
+        // (module
+        //   (import "bridge" "twice" (func $twice (param i64) (result i64)))
+        //   (func (export "run") (param i64) (result i64)
+        //     (i64.add (call $twice (local.get 0)) (i64.const 1))
+        //   )
+        // )
+        let importing = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7e,
+            0x01, 0x7e, 0x02, 0x10, 0x01, 0x06, 0x62, 0x72, 0x69, 0x64, 0x67, 0x65, 0x05, 0x74,
+            0x77, 0x69, 0x63, 0x65, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03,
+            0x72, 0x75, 0x6e, 0x00, 0x01, 0x0a, 0x0b, 0x01, 0x09, 0x00, 0x20, 0x00, 0x10, 0x00,
+            0x42, 0x01, 0x7c, 0x0b,
+        ];
+        let exporting = Module::from_buf(&runtime, &exporting, "exporting")?;
+        let importing = Module::from_buf(&runtime, &importing, "importing")?;
+        let exporter = Instance::new(&runtime, &exporting, 1024, ())?;
+        let importer = Instance::new(&runtime, &importing, 1024, ())?;
+        let run = Function::find_export_func(&importer, "run")?;
+
+        assert!(matches!(
+            run.call(&importer, &vec![WasmValue::I64(20)]),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+
+        runtime.bridge(&exporter, "double", &importer, "twice")?;
+        assert_eq!(
+            run.call(&importer, &vec![WasmValue::I64(20)])?,
+            WasmValue::I64(41)
+        );
+
+        assert!(matches!(
+            runtime.bridge(&exporter, "neg", &importer, "twice"),
+            Err(RuntimeError::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            runtime.bridge(&exporter, "double", &importer, "missing"),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,125 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! buffers returned by exports as a pointer and a length packed into one i64.
+//! get one via `ReturnedBuffer::from_packed_u64()`
+//!
+//! the convention puts the offset of the buffer in the linear memory in the low 32 bits,
+//! and its length in bytes in the high 32 bits, like `((len as u64) << 32) | ptr as u64`
+//! in a Rust guest.
+
+use crate::{instance::Instance, value::WasmValue, RuntimeError};
+
+/// the bytes of a buffer returned by a guest, copied out of the linear memory at once, so
+/// they stay valid whatever the guest does next
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReturnedBuffer {
+    offset: u32,
+    bytes: Vec<u8>,
+}
+
+impl ReturnedBuffer {
+    /// pack an offset and a length, the way an export returns them
+    pub fn pack(offset: u32, len: u32) -> u64 {
+        ((len as u64) << 32) | offset as u64
+    }
+
+    /// split a packed value into its offset and length
+    pub fn unpack(packed: u64) -> (u32, u32) {
+        (packed as u32, (packed >> 32) as u32)
+    }
+
+    /// copy the buffer described by `packed` out of the linear memory of `instance`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the buffer is out of bounds.
+    pub fn from_packed_u64<T>(instance: &Instance<T>, packed: u64) -> Result<Self, RuntimeError> {
+        let (offset, len) = Self::unpack(packed);
+        let mut bytes = vec![0u8; len as usize];
+        instance.memory().read(offset as u64, &mut bytes)?;
+        Ok(ReturnedBuffer { offset, bytes })
+    }
+
+    /// copy the buffer described by the result of an export
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if the result isn't an `I64`.
+    /// Return `RuntimeError::MemoryAccessError` if the buffer is out of bounds.
+    pub fn from_result<T>(instance: &Instance<T>, result: WasmValue) -> Result<Self, RuntimeError> {
+        Self::from_packed_u64(instance, u64::try_from(result)?)
+    }
+
+    /// where the buffer was in the linear memory, like to free it via the guest
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{function::Function, module::Module, runtime::Runtime};
+
+    #[test]
+    fn test_pack() {
+        let packed = ReturnedBuffer::pack(16, 5);
+        assert_eq!(packed, 0x0000_0005_0000_0010);
+        assert_eq!(ReturnedBuffer::unpack(packed), (16, 5));
+        assert_eq!(ReturnedBuffer::unpack(u64::MAX), (u32::MAX, u32::MAX));
+    }
+
+    #[test]
+    fn test_returned_buffer() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        //   (data (i32.const 16) "hello")
+        //   (func (export "greet") (result i64)
+        //     (i64.or (i64.shl (i64.const 5) (i64.const 32)) (i64.const 16))
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+            0x7e, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x12, 0x02, 0x06,
+            0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x05, 0x67, 0x72, 0x65, 0x65, 0x74,
+            0x00, 0x00, 0x0a, 0x0c, 0x01, 0x0a, 0x00, 0x42, 0x05, 0x42, 0x20, 0x86, 0x42, 0x10,
+            0x84, 0x0b, 0x0b, 0x0b, 0x01, 0x00, 0x41, 0x10, 0x0b, 0x05, 0x68, 0x65, 0x6c, 0x6c,
+            0x6f,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "greet")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let greet = Function::find_export_func(&instance, "greet")?;
+        let buffer = ReturnedBuffer::from_result(&instance, greet.call_args(&instance, &[])?)?;
+        assert_eq!(buffer.offset(), 16);
+        assert_eq!(buffer.as_slice(), b"hello");
+        assert_eq!(buffer.into_vec(), b"hello".to_vec());
+
+        let out_of_bounds = ReturnedBuffer::pack(65535, 2);
+        assert!(ReturnedBuffer::from_packed_u64(&instance, out_of_bounds).is_err());
+        assert!(ReturnedBuffer::from_result(&instance, WasmValue::I32(16)).is_err());
+
+        Ok(())
+    }
+}
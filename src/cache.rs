@@ -0,0 +1,237 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! warm instances of many modules kept within bounds, like the plugins of a service.
+//! build a cache via `InstanceCache::new()`, and get an instance via `InstanceCache::get()`
+//!
+//! a module is instantiated the first time its instance is asked for, and the instance is
+//! kept for the next times, until it is evicted: once idle for longer than the TTL, or
+//! least recently used once the cache holds too many instances, or too many bytes of
+//! linear memory. An evicted instance is instantiated afresh the next time, so its state
+//! is lost.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{instance::Instance, memory, module::Module, runtime::Runtime, RuntimeError};
+
+struct CachedInstance<T> {
+    instance: Instance<T>,
+    last_used: Instant,
+}
+
+/// instances of modules, instantiated on demand and evicted least recently used first
+pub struct InstanceCache<'a, T> {
+    runtime: &'a Runtime,
+    stack_size: u32,
+    heap_size: u32,
+    data: Box<dyn Fn(&str) -> T + 'a>,
+    max_count: Option<usize>,
+    max_bytes: Option<u64>,
+    ttl: Option<Duration>,
+    // dropped before the modules they depend on
+    instances: HashMap<String, CachedInstance<T>>,
+    modules: HashMap<String, Module>,
+}
+
+impl<'a, T: 'static> InstanceCache<'a, T> {
+    /// a cache without bounds, instantiating with `stack_size` and `heap_size`, and the
+    /// user data `data` returns for the key of the module
+    pub fn new<F>(runtime: &'a Runtime, stack_size: u32, heap_size: u32, data: F) -> Self
+    where
+        F: Fn(&str) -> T + 'a,
+    {
+        InstanceCache {
+            runtime,
+            stack_size,
+            heap_size,
+            data: Box::new(data),
+            max_count: None,
+            max_bytes: None,
+            ttl: None,
+            instances: HashMap::new(),
+            modules: HashMap::new(),
+        }
+    }
+
+    /// keep at most `max_count` instances
+    pub fn max_count(mut self, max_count: usize) -> Self {
+        self.max_count = Some(max_count);
+        self
+    }
+
+    /// keep instances of at most `max_bytes` of linear memory altogether. The instance
+    /// just asked for is kept anyway
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// evict instances idle for longer than `ttl`
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// add a module under `key`, replacing the one there, whose instance is evicted
+    pub fn insert_module(&mut self, key: &str, module: Module) {
+        self.instances.remove(key);
+        self.modules.insert(String::from(key), module);
+    }
+
+    /// remove the module under `key`, and its instance
+    pub fn remove_module(&mut self, key: &str) -> Option<Module> {
+        self.instances.remove(key);
+        self.modules.remove(key)
+    }
+
+    /// the instance of the module under `key`, instantiated unless it is in the cache.
+    /// Other instances may be evicted to stay within bounds
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::InstantiationFailure` if there is no module under `key`, or it
+    /// failed to be instantiated.
+    pub fn get(&mut self, key: &str) -> Result<&Instance<T>, RuntimeError> {
+        self.evict_expired();
+
+        if !self.instances.contains_key(key) {
+            let module = self.modules.get(key).ok_or_else(|| {
+                RuntimeError::InstantiationFailure(message!("no module {:?} in the cache", key))
+            })?;
+            let instance = Instance::new_with_args(
+                self.runtime,
+                module,
+                self.stack_size,
+                self.heap_size,
+                (self.data)(key),
+            )?;
+            self.instances.insert(
+                String::from(key),
+                CachedInstance {
+                    instance,
+                    last_used: Instant::now(),
+                },
+            );
+        }
+
+        let cached = self.instances.get_mut(key).unwrap();
+        cached.last_used = Instant::now();
+        self.evict_over_bounds(key);
+        Ok(&self.instances[key].instance)
+    }
+
+    /// whether the instance of the module under `key` is in the cache
+    pub fn contains(&self, key: &str) -> bool {
+        self.instances.contains_key(key)
+    }
+
+    /// the number of instances in the cache
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    /// the bytes of linear memory of the instances in the cache
+    pub fn resident_bytes(&self) -> u64 {
+        self.instances
+            .values()
+            .map(|cached| memory::data_size(cached.instance.get_inner_instance()) as u64)
+            .sum()
+    }
+
+    /// evict the instances idle for longer than the TTL, like from a periodic sweep.
+    /// Return how many were
+    pub fn evict_expired(&mut self) -> usize {
+        let Some(ttl) = self.ttl else {
+            return 0;
+        };
+        let before = self.instances.len();
+        self.instances
+            .retain(|_, cached| cached.last_used.elapsed() <= ttl);
+        before - self.instances.len()
+    }
+
+    /// evict the least recently used instances, but the one under `kept`, until the cache
+    /// is within bounds
+    fn evict_over_bounds(&mut self, kept: &str) {
+        loop {
+            let over_count = self
+                .max_count
+                .is_some_and(|max_count| self.instances.len() > max_count.max(1));
+            let over_bytes = self
+                .max_bytes
+                .is_some_and(|max_bytes| self.resident_bytes() > max_bytes);
+            if !over_count && !over_bytes {
+                return;
+            }
+
+            let least_recently_used = self
+                .instances
+                .iter()
+                .filter(|(key, _)| key.as_str() != kept)
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(key, _)| key.clone());
+            match least_recently_used {
+                Some(key) => self.instances.remove(&key),
+                None => return,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instance_cache() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+            0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let mut cache =
+            InstanceCache::new(&runtime, 1024, 0, |key: &str| key.to_string()).max_count(2);
+        for key in ["a", "b", "c"] {
+            cache.insert_module(key, Module::from_buf(&runtime, &binary, key)?);
+        }
+        assert!(matches!(
+            cache.get("missing"),
+            Err(RuntimeError::InstantiationFailure(_))
+        ));
+
+        let a = cache.get("a")?.id();
+        assert_eq!(cache.get("a")?.id(), a);
+        cache.get("b")?;
+        cache.get("a")?;
+        cache.get("c")?;
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.resident_bytes(), 2 * 65536);
+
+        let mut cache = cache.max_bytes(65536);
+        cache.get("b")?;
+        assert_eq!(cache.len(), 1);
+        assert!(cache.contains("b"));
+
+        let mut cache = cache.ttl(Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.evict_expired(), 1);
+        assert!(cache.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a single-producer single-consumer ring buffer of messages in the linear memory, to
+//! stream data between the host and a guest without a host call per message.
+//! get one via `WasmChannel::exported()` or `WasmChannel::reserve()`
+//!
+//! a channel carries messages one way, so a guest and the host exchanging both ways use
+//! two of them. Its layout, for the guest side to follow, is at a 4-byte aligned offset:
+//! - `head: u32`, the position of the next byte to read, only advanced by the consumer.
+//! - `tail: u32`, the position of the next byte to write, only advanced by the producer.
+//! - `capacity: u32`, the size of the data, a power of two.
+//! - the data, `capacity` bytes.
+//!
+//! positions grow forever, wrapping around at `u32::MAX`, and `position % capacity` is where
+//! they are in the data. A message is its length, as a little-endian `u32`, followed by its
+//! bytes, and may wrap around the end of the data. The producer writes a message and then
+//! advances `tail`, the consumer reads one and then advances `head`, both atomically, so
+//! the two sides may run on different threads with a shared memory.
+
+use std::{
+    marker::PhantomData,
+    mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use wamr_sys::{
+    wasm_module_inst_t, wasm_runtime_addr_app_to_native, wasm_runtime_module_free,
+    wasm_runtime_module_malloc, wasm_runtime_validate_app_addr,
+};
+
+use crate::{instance::Instance, mailbox::exported_address, RuntimeError};
+
+/// the size of `head`, `tail` and `capacity`, in front of the data
+pub const HEADER_SIZE: u32 = 12;
+
+const LENGTH_SIZE: u32 = mem::size_of::<u32>() as u32;
+
+/// a ring buffer of messages at a fixed offset of the linear memory of an instance
+pub struct WasmChannel<'a> {
+    instance: wasm_module_inst_t,
+    offset: u64,
+    capacity: u32,
+    // allocated from the app heap by `WasmChannel::reserve()`
+    owned: bool,
+    _instance: PhantomData<&'a ()>,
+}
+
+impl<'a> WasmChannel<'a> {
+    /// the channel at the address held by the exported global `symbol`, set up by the guest
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if there is no such global holding an
+    /// address, or no valid channel at that address.
+    pub fn exported<T>(instance: &'a Instance<T>, symbol: &str) -> Result<Self, RuntimeError> {
+        let offset = exported_address(instance, symbol)?;
+        // the header first, to read the capacity from
+        let mut channel = Self::at(instance.get_inner_instance(), offset, 0, false)?;
+        channel.capacity = channel.header(2).load(Ordering::Acquire);
+        if !channel.capacity.is_power_of_two() {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "channel capacity {} isn't a power of two",
+                channel.capacity
+            )));
+        }
+        channel.validate()?;
+        Ok(channel)
+    }
+
+    /// an empty channel of `capacity` bytes, a power of two, allocated from the app heap of
+    /// the instance, which has to be instantiated with a heap size, and freed once dropped.
+    /// Hand its offset over to the guest
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `capacity` isn't a power of two, or the app
+    /// heap is exhausted.
+    pub fn reserve<T>(instance: &'a Instance<T>, capacity: u32) -> Result<Self, RuntimeError> {
+        if !capacity.is_power_of_two() || capacity.checked_add(HEADER_SIZE).is_none() {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "channel capacity {} isn't a power of two",
+                capacity
+            )));
+        }
+
+        let inner_instance = instance.get_inner_instance();
+        let offset = unsafe {
+            wasm_runtime_module_malloc(
+                inner_instance,
+                (HEADER_SIZE + capacity) as _,
+                ptr::null_mut(),
+            )
+        };
+        if offset == 0 {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "failed to allocate {} bytes from the app heap",
+                HEADER_SIZE + capacity
+            )));
+        }
+
+        let channel = Self::at(inner_instance, offset as u64, capacity, true)?;
+        channel.header(0).store(0, Ordering::Relaxed);
+        channel.header(1).store(0, Ordering::Relaxed);
+        channel.header(2).store(capacity, Ordering::Release);
+        Ok(channel)
+    }
+
+    fn at(
+        instance: wasm_module_inst_t,
+        offset: u64,
+        capacity: u32,
+        owned: bool,
+    ) -> Result<Self, RuntimeError> {
+        let channel = WasmChannel {
+            instance,
+            offset,
+            capacity,
+            owned,
+            _instance: PhantomData,
+        };
+        if !offset.is_multiple_of(4) {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "unaligned channel: offset {}",
+                offset
+            )));
+        }
+        channel.validate()?;
+        Ok(channel)
+    }
+
+    fn validate(&self) -> Result<(), RuntimeError> {
+        let size = HEADER_SIZE as u64 + self.capacity as u64;
+        let valid =
+            unsafe { wasm_runtime_validate_app_addr(self.instance, self.offset as _, size as _) };
+        if !valid {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "out of bounds channel: offset {} capacity {}",
+                self.offset,
+                self.capacity
+            )));
+        }
+        Ok(())
+    }
+
+    /// the offset of the channel in the linear memory, to hand over to the guest
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// the size of the data, the longest message being 4 bytes shorter
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    // the memory only grows, so the channel stays in bounds, but it may move
+    fn native_ptr(&self, offset: u32) -> *mut u8 {
+        unsafe {
+            wasm_runtime_addr_app_to_native(self.instance, (self.offset + offset as u64) as _)
+                as *mut u8
+        }
+    }
+
+    /// `head`, `tail` or `capacity`
+    fn header(&self, index: u32) -> &AtomicU32 {
+        unsafe { &*(self.native_ptr(index * 4) as *const AtomicU32) }
+    }
+
+    fn copy_in(&self, position: u32, bytes: &[u8]) {
+        let start = position & (self.capacity - 1);
+        let first = bytes.len().min((self.capacity - start) as usize);
+        unsafe {
+            let data = self.native_ptr(HEADER_SIZE);
+            ptr::copy_nonoverlapping(bytes.as_ptr(), data.add(start as usize), first);
+            ptr::copy_nonoverlapping(bytes[first..].as_ptr(), data, bytes.len() - first);
+        }
+    }
+
+    fn copy_out(&self, position: u32, bytes: &mut [u8]) {
+        let start = position & (self.capacity - 1);
+        let first = bytes.len().min((self.capacity - start) as usize);
+        unsafe {
+            let data = self.native_ptr(HEADER_SIZE);
+            ptr::copy_nonoverlapping(data.add(start as usize), bytes.as_mut_ptr(), first);
+            let rest = bytes.len() - first;
+            ptr::copy_nonoverlapping(data, bytes[first..].as_mut_ptr(), rest);
+        }
+    }
+
+    /// append a message, as the producer. Return `false` if there isn't room for it yet
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::LimitExceeded` if the message would never fit.
+    pub fn send(&self, message: &[u8]) -> Result<bool, RuntimeError> {
+        let needed = LENGTH_SIZE as u64 + message.len() as u64;
+        if needed > self.capacity as u64 {
+            return Err(RuntimeError::LimitExceeded(message!(
+                "message of {} bytes over the channel capacity {}",
+                message.len(),
+                self.capacity
+            )));
+        }
+
+        let head = self.header(0).load(Ordering::Acquire);
+        let tail = self.header(1).load(Ordering::Relaxed);
+        let free = self.capacity - tail.wrapping_sub(head);
+        if needed > free as u64 {
+            return Ok(false);
+        }
+
+        self.copy_in(tail, &(message.len() as u32).to_le_bytes());
+        self.copy_in(tail.wrapping_add(LENGTH_SIZE), message);
+        self.header(1)
+            .store(tail.wrapping_add(needed as u32), Ordering::Release);
+        Ok(true)
+    }
+
+    /// take the oldest message, as the consumer, `None` if there is none yet
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the channel has been corrupted.
+    pub fn recv(&self) -> Result<Option<Vec<u8>>, RuntimeError> {
+        let tail = self.header(1).load(Ordering::Acquire);
+        let head = self.header(0).load(Ordering::Relaxed);
+        let used = tail.wrapping_sub(head);
+        if used == 0 {
+            return Ok(None);
+        }
+
+        let mut length = [0u8; LENGTH_SIZE as usize];
+        self.copy_out(head, &mut length);
+        let length = u32::from_le_bytes(length);
+        if used < LENGTH_SIZE || length > used - LENGTH_SIZE || used > self.capacity {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "corrupted channel: message of {} bytes with {} bytes used",
+                length,
+                used
+            )));
+        }
+
+        let mut message = vec![0u8; length as usize];
+        self.copy_out(head.wrapping_add(LENGTH_SIZE), &mut message);
+        self.header(0)
+            .store(head.wrapping_add(LENGTH_SIZE + length), Ordering::Release);
+        Ok(Some(message))
+    }
+}
+
+impl Drop for WasmChannel<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { wasm_runtime_module_free(self.instance, self.offset as _) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{module::Module, runtime::Runtime};
+
+    #[test]
+    fn test_channel() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+            0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "channel")?;
+        let instance = Instance::new_with_args(&runtime, &module, 1024, 4096, ())?;
+
+        assert!(WasmChannel::reserve(&instance, 100).is_err());
+        let channel = WasmChannel::reserve(&instance, 16)?;
+        assert_eq!(channel.recv()?, None);
+        assert!(channel.send(&[0; 13]).is_err());
+
+        // 15 bytes a round, so messages wrap around the end of the data
+        for round in 0..4u8 {
+            assert!(channel.send(&[round; 5])?);
+            assert!(!channel.send(&[round; 8])?);
+            assert!(channel.send(&[round; 2])?);
+            assert_eq!(channel.recv()?, Some(vec![round; 5]));
+            assert_eq!(channel.recv()?, Some(vec![round; 2]));
+        }
+        assert!(channel.send(b"")?);
+        assert_eq!(channel.recv()?, Some(Vec::new()));
+        assert_eq!(channel.recv()?, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,405 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! precompile .wasm into .aot via `wamrc`, the AOT compiler of WAMR, with the defaults
+//! matching how the SDK has been built, like SIMD or threads, so the .aot loads in it.
+//! compile via `AotCompiler::new().compile()`, or the `wamr-sdk` binary of the `cli`
+//! feature
+//!
+//! `wamrc` needs LLVM, which the SDK doesn't build, so it is run from the `PATH`, or from
+//! the `WAMRC` environment variable.
+//!
+//! `wamrc` only takes native symbols from shared libraries, see `AotCompiler::native_lib()`,
+//! so the host functions registered into a runtime are checked against the imports of the
+//! .wasm before compiling instead, see `AotCompiler::host_functions()`.
+
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    ops::BitOr,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    binary::{self, ImportKind},
+    host_function::HostSymbol,
+    runtime::Runtime,
+    ErrorMessage, RuntimeError,
+};
+
+/// the linear memory accesses addressed via the GS segment register, with the base of the
+/// linear memory in it, rather than by adding the base to each address. Only available on
+/// x86-64 Linux, it speeds up memory-heavy guests. Combine them with `|`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegueFlags(u32);
+
+impl SegueFlags {
+    pub const I32_LOAD: SegueFlags = SegueFlags(1 << 0);
+    pub const I64_LOAD: SegueFlags = SegueFlags(1 << 1);
+    pub const F32_LOAD: SegueFlags = SegueFlags(1 << 2);
+    pub const F64_LOAD: SegueFlags = SegueFlags(1 << 3);
+    pub const V128_LOAD: SegueFlags = SegueFlags(1 << 4);
+    pub const I32_STORE: SegueFlags = SegueFlags(1 << 8);
+    pub const I64_STORE: SegueFlags = SegueFlags(1 << 9);
+    pub const F32_STORE: SegueFlags = SegueFlags(1 << 10);
+    pub const F64_STORE: SegueFlags = SegueFlags(1 << 11);
+    pub const V128_STORE: SegueFlags = SegueFlags(1 << 12);
+
+    /// the names `wamrc` knows each flag by
+    const NAMES: [(SegueFlags, &'static str); 10] = [
+        (SegueFlags::I32_LOAD, "i32.load"),
+        (SegueFlags::I64_LOAD, "i64.load"),
+        (SegueFlags::F32_LOAD, "f32.load"),
+        (SegueFlags::F64_LOAD, "f64.load"),
+        (SegueFlags::V128_LOAD, "v128.load"),
+        (SegueFlags::I32_STORE, "i32.store"),
+        (SegueFlags::I64_STORE, "i64.store"),
+        (SegueFlags::F32_STORE, "f32.store"),
+        (SegueFlags::F64_STORE, "f64.store"),
+        (SegueFlags::V128_STORE, "v128.store"),
+    ];
+
+    /// no access, the default
+    pub fn empty() -> Self {
+        SegueFlags(0)
+    }
+
+    /// every load and store
+    pub fn all() -> Self {
+        SegueFlags::NAMES
+            .iter()
+            .fold(SegueFlags::empty(), |all, (flag, _)| all | *flag)
+    }
+
+    /// the bits, as given to WAMR
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(&self, other: SegueFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// the flags as given to `wamrc --enable-segue`, like `i32.load,i64.store`
+    pub fn to_wamrc(&self) -> String {
+        SegueFlags::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl BitOr for SegueFlags {
+    type Output = SegueFlags;
+
+    fn bitor(self, other: SegueFlags) -> SegueFlags {
+        SegueFlags(self.0 | other.0)
+    }
+}
+
+/// the options of `wamrc`, starting from the defaults of the SDK build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AotCompiler {
+    target: Option<String>,
+    cpu: Option<String>,
+    opt_level: Option<u32>,
+    size_level: Option<u32>,
+    segue: SegueFlags,
+    bounds_checks: Option<bool>,
+    native_libs: Vec<PathBuf>,
+    host_functions: Vec<HostSymbol>,
+}
+
+impl AotCompiler {
+    pub fn new() -> Self {
+        AotCompiler::default()
+    }
+
+    /// the architecture to compile for, like `x86_64` or `aarch64`, the host one by default
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(String::from(target));
+        self
+    }
+
+    /// the CPU to compile for, like `cortex-a53`
+    pub fn cpu(mut self, cpu: &str) -> Self {
+        self.cpu = Some(String::from(cpu));
+        self
+    }
+
+    /// from 0 to 3
+    pub fn opt_level(mut self, level: u32) -> Self {
+        self.opt_level = Some(level);
+        self
+    }
+
+    /// from 0 to 3, the smaller, the larger the code model
+    pub fn size_level(mut self, level: u32) -> Self {
+        self.size_level = Some(level);
+        self
+    }
+
+    /// address the linear memory accesses of `flags` via a segment register, see
+    /// `SegueFlags`
+    pub fn segue(mut self, flags: SegueFlags) -> Self {
+        self.segue = flags;
+        self
+    }
+
+    /// check the bounds of every linear memory access in the code, instead of relying on
+    /// guard pages, by default only with the `no-hw-bound-check` feature
+    pub fn bounds_checks(mut self, enabled: bool) -> Self {
+        self.bounds_checks = Some(enabled);
+        self
+    }
+
+    /// a shared library exporting host functions via `get_native_lib()`, as for
+    /// `iwasm --native-lib`, so their calls are compiled into direct calls
+    pub fn native_lib(mut self, path: &Path) -> Self {
+        self.native_libs.push(path.to_path_buf());
+        self
+    }
+
+    /// check the imports of the .wasm against the host functions registered into `runtime`
+    /// before compiling it, so a mismatch fails now rather than when the .aot is loaded
+    pub fn host_functions(mut self, runtime: &Runtime) -> Self {
+        self.host_functions = runtime.host_symbols();
+        self
+    }
+
+    /// the arguments given to `wamrc`
+    pub fn args(&self, input: &Path, output: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        if let Some(target) = &self.target {
+            args.push(format!("--target={}", target).into());
+        }
+        if let Some(cpu) = &self.cpu {
+            args.push(format!("--cpu={}", cpu).into());
+        }
+        if let Some(level) = self.opt_level {
+            args.push(format!("--opt-level={}", level).into());
+        }
+        if let Some(level) = self.size_level {
+            args.push(format!("--size-level={}", level).into());
+        }
+        if self.segue != SegueFlags::empty() {
+            args.push(format!("--enable-segue={}", self.segue.to_wamrc()).into());
+        }
+        for lib in &self.native_libs {
+            let mut arg = OsString::from("--native-lib=");
+            arg.push(lib);
+            args.push(arg);
+        }
+
+        if !cfg!(feature = "simd") {
+            args.push("--disable-simd".into());
+        }
+        if cfg!(feature = "threads") {
+            args.push("--enable-multi-thread".into());
+        }
+        if cfg!(feature = "gc") {
+            args.push("--enable-gc".into());
+        }
+        if cfg!(feature = "dump-call-stack") {
+            args.push("--enable-dump-call-stack".into());
+        }
+        if cfg!(feature = "perf-profiling") {
+            args.push("--enable-perf-profiling".into());
+        }
+        match self.bounds_checks {
+            Some(enabled) => args.push(format!("--bounds-checks={}", enabled as u32).into()),
+            None if cfg!(feature = "no-hw-bound-check") => args.push("--bounds-checks=1".into()),
+            None => {}
+        }
+
+        args.push("-o".into());
+        args.push(output.into());
+        args.push(input.into());
+        args
+    }
+
+    /// check the function imports of `binary` against the registered host functions. An
+    /// import of none of them isn't an error, WAMR may provide it, like libc-wasi does
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if `binary` is malformed, or imports a host
+    /// function with another signature than the registered one.
+    pub fn check_imports(&self, binary: &[u8]) -> Result<(), RuntimeError> {
+        if self.host_functions.is_empty() {
+            return Ok(());
+        }
+        let malformed =
+            |e: ErrorMessage| RuntimeError::CompilationError(message!("malformed .wasm: {}", e));
+        let signatures = binary::type_signatures(binary).map_err(malformed)?;
+        let memory64 = binary::memory_limits(binary)
+            .map_err(malformed)?
+            .is_some_and(|limits| limits.memory64);
+        for import in binary::imports(binary).map_err(malformed)? {
+            let ImportKind::Func(type_index) = import.kind else {
+                continue;
+            };
+            let Some(symbol) = self
+                .host_functions
+                .iter()
+                .find(|symbol| symbol.module == import.module && symbol.name == import.name)
+            else {
+                continue;
+            };
+            let expected = signatures
+                .get(type_index as usize)
+                .ok_or_else(|| malformed(message!("unknown type {}", type_index)))?;
+            if !signature_matches(&symbol.signature, expected, memory64) {
+                return Err(RuntimeError::CompilationError(message!(
+                    "the import {}.{} has the signature {}, the host function {}",
+                    import.module,
+                    import.name,
+                    expected,
+                    symbol.signature
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// compile the .wasm at `input` into an .aot at `output`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::WasmFileFSError` if `input` can't be read or `wamrc` can't be
+    /// run, and `RuntimeError::CompilationError` if an import doesn't match its host
+    /// function, see `check_imports()`, or `wamrc` failed.
+    pub fn compile(&self, input: &Path, output: &Path) -> Result<(), RuntimeError> {
+        if !self.host_functions.is_empty() {
+            self.check_imports(&fs::read(input)?)?;
+        }
+
+        let wamrc = env::var_os("WAMRC").map_or_else(|| PathBuf::from("wamrc"), PathBuf::from);
+        let result = Command::new(wamrc)
+            .args(self.args(input, output))
+            .output()?;
+        if !result.status.success() {
+            // wamrc reports some errors on stdout
+            let output = [result.stderr, result.stdout].concat();
+            return Err(RuntimeError::CompilationError(message!(
+                "wamrc failed with {}: {}",
+                result.status,
+                String::from_utf8_lossy(&output).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// whether the signature of a host function, where pointers, buffers and strings are i32
+/// offsets, or i64 ones for a memory64, is the signature `wasm` of a wasm function type
+fn signature_matches(host: &str, wasm: &str, memory64: bool) -> bool {
+    let address = match memory64 {
+        true => 'I',
+        false => 'i',
+    };
+    let host: String = host
+        .chars()
+        .map(|c| match c {
+            '*' | '~' | '$' => address,
+            c => c,
+        })
+        .collect();
+    host == wasm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aot_compiler_args() {
+        let compiler = AotCompiler::new().target("aarch64").opt_level(3);
+        let args = compiler.args(Path::new("in.wasm"), Path::new("out.aot"));
+
+        assert_eq!(args[0], "--target=aarch64");
+        assert_eq!(args[1], "--opt-level=3");
+        assert_eq!(
+            args.contains(&"--disable-simd".into()),
+            !cfg!(feature = "simd")
+        );
+        assert_eq!(args[args.len() - 3..], ["-o", "out.aot", "in.wasm"]);
+
+        let segue = SegueFlags::I32_LOAD | SegueFlags::I64_STORE;
+        assert_eq!(segue.bits(), 0x201);
+        assert!(SegueFlags::all().contains(segue));
+        assert_eq!(SegueFlags::all().bits(), 0x1f1f);
+        let compiler = AotCompiler::new().segue(segue).bounds_checks(false);
+        let args = compiler.args(Path::new("in.wasm"), Path::new("out.aot"));
+        assert_eq!(args[0], "--enable-segue=i32.load,i64.store");
+        assert!(args.contains(&"--bounds-checks=0".into()));
+    }
+
+    #[test]
+    fn test_check_imports() {
+        // (module
+        //   (import "host" "add" (func (param i32 i64) (result i32)))
+        //   (import "host" "log" (func (param i32 i32)))
+        //   (import "wasi_snapshot_preview1" "proc_exit" (func (param i32))))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x10, 0x03, 0x60, 0x02, 0x7f,
+            0x7e, 0x01, 0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00, 0x60, 0x01, 0x7f, 0x00, 0x02, 0x3a,
+            0x03, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x03, 0x61, 0x64, 0x64, 0x00, 0x00, 0x04, 0x68,
+            0x6f, 0x73, 0x74, 0x03, 0x6c, 0x6f, 0x67, 0x00, 0x01, 0x16, 0x77, 0x61, 0x73, 0x69,
+            0x5f, 0x73, 0x6e, 0x61, 0x70, 0x73, 0x68, 0x6f, 0x74, 0x5f, 0x70, 0x72, 0x65, 0x76,
+            0x69, 0x65, 0x77, 0x31, 0x09, 0x70, 0x72, 0x6f, 0x63, 0x5f, 0x65, 0x78, 0x69, 0x74,
+            0x00, 0x02,
+        ];
+        let symbol = |name: &str, signature: &str| HostSymbol {
+            module: String::from("host"),
+            name: String::from(name),
+            signature: String::from(signature),
+        };
+
+        // nothing to check against
+        assert!(AotCompiler::new().check_imports(&binary).is_ok());
+
+        let compiler = AotCompiler {
+            host_functions: vec![symbol("add", "(iI)i"), symbol("log", "(*~)")],
+            ..AotCompiler::default()
+        };
+        assert!(compiler.check_imports(&binary).is_ok());
+        assert!(compiler.check_imports(&binary[..40]).is_err());
+
+        let compiler = AotCompiler {
+            host_functions: vec![symbol("add", "(ii)i")],
+            ..AotCompiler::default()
+        };
+        match compiler.check_imports(&binary) {
+            Err(RuntimeError::CompilationError(message)) => {
+                assert!(message.contains("host.add") && message.contains("(iI)i"))
+            }
+            _ => panic!("the signatures differ"),
+        }
+
+        // (module
+        //   (import "host" "log" (func (param i64 i64)))
+        //   (memory i64 1))
+        let memory64 = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x02, 0x7e,
+            0x7e, 0x00, 0x02, 0x0c, 0x01, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x03, 0x6c, 0x6f, 0x67,
+            0x00, 0x00, 0x05, 0x03, 0x01, 0x04, 0x01,
+        ];
+        let compiler = AotCompiler {
+            host_functions: vec![symbol("log", "(*~)")],
+            ..AotCompiler::default()
+        };
+        assert!(compiler.check_imports(&memory64).is_ok());
+        assert!(compiler.check_imports(&binary).is_ok());
+
+        let compiler = AotCompiler::new().native_lib(Path::new("libhost.so"));
+        let args = compiler.args(Path::new("in.wasm"), Path::new("out.aot"));
+        assert_eq!(args[0], "--native-lib=libhost.so");
+    }
+}
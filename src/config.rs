@@ -0,0 +1,277 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a runtime configuration read from TOML or JSON, so deployments tune the runtime
+//! without recompiling the host. build a runtime from one via `Runtime::from_config()`
+//!
+//! every field is optional, like
+//! ```toml
+//! running_mode = "interpreter"
+//! allocator = { pool = { size = 1048576 } }
+//! stack_size = 65536
+//! heap_size = 16384
+//! canonicalize_nans = true
+//!
+//! [features]
+//! simd = true
+//!
+//! [wasi]
+//! args = ["guest", "--verbose"]
+//! env = ["LANG=C"]
+//! preopens = [{ guest = "/data", host = "/srv/data" }]
+//! ```
+
+use serde::Deserialize;
+
+#[cfg(libc_wasi)]
+use crate::wasi_context::{WasiCtx, WasiCtxBuilder};
+use crate::{
+    features::WasmFeatures,
+    instance::DEFAULT_STACK_SIZE,
+    runtime::{Runtime, RuntimeBuilder},
+    RuntimeError,
+};
+
+/// how the runtime executes guests
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RunningModeConfig {
+    /// the default running mode of the WAMR build
+    #[default]
+    Default,
+    Interpreter,
+    LlvmJit {
+        opt_level: u32,
+        size_level: u32,
+    },
+}
+
+/// where WAMR allocates from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocatorConfig {
+    /// the allocator the builder starts with, a pool with the `tiny` feature
+    #[default]
+    Default,
+    System,
+    Instrumented,
+    Pool {
+        size: u32,
+    },
+}
+
+/// the proposals a deployment relies on, checked against `Runtime::features()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeaturesConfig {
+    pub simd: bool,
+    pub threads: bool,
+    pub tail_call: bool,
+    pub gc: bool,
+    pub memory64: bool,
+    pub exception_handling: bool,
+}
+
+impl FeaturesConfig {
+    /// the required features `supported` lacks
+    pub fn missing(&self, supported: &WasmFeatures) -> Vec<&'static str> {
+        [
+            ("simd", self.simd, supported.simd),
+            ("threads", self.threads, supported.threads),
+            ("tail_call", self.tail_call, supported.tail_call),
+            ("gc", self.gc, supported.gc),
+            ("memory64", self.memory64, supported.memory64),
+            (
+                "exception_handling",
+                self.exception_handling,
+                supported.exception_handling,
+            ),
+        ]
+        .into_iter()
+        .filter(|(_, required, supported)| *required && !*supported)
+        .map(|(name, _, _)| name)
+        .collect()
+    }
+}
+
+/// a directory of the host, seen by the guest at `guest`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreOpenConfig {
+    pub guest: String,
+    pub host: String,
+}
+
+/// what WASI grants guests
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WasiConfig {
+    pub args: Vec<String>,
+    /// `KEY=value` pairs
+    pub env: Vec<String>,
+    pub preopens: Vec<PreOpenConfig>,
+    pub allowed_addresses: Vec<String>,
+    pub allowed_dns: Vec<String>,
+}
+
+#[cfg(libc_wasi)]
+fn strs(strings: &[String]) -> Vec<&str> {
+    strings.iter().map(String::as_str).collect()
+}
+
+#[cfg(libc_wasi)]
+impl WasiConfig {
+    /// a context for `Module::set_wasi_context()`, one per module
+    pub fn wasi_context(&self) -> WasiCtx {
+        // a directory seen at another path is mapped as `<guest-path>::<host-path>`
+        let (dirs, maps): (Vec<_>, Vec<_>) = self
+            .preopens
+            .iter()
+            .partition(|preopen| preopen.guest == preopen.host);
+        let dirs = dirs
+            .iter()
+            .map(|preopen| preopen.host.clone())
+            .collect::<Vec<_>>();
+        let maps = maps
+            .iter()
+            .map(|preopen| format!("{}::{}", preopen.guest, preopen.host))
+            .collect::<Vec<_>>();
+        WasiCtxBuilder::new()
+            .set_arguments(strs(&self.args))
+            .set_env_vars(strs(&self.env))
+            .set_pre_open_path(strs(&dirs), strs(&maps))
+            .set_allowed_address(strs(&self.allowed_addresses))
+            .set_allowed_dns(strs(&self.allowed_dns))
+            .build()
+    }
+}
+
+/// the configuration of a runtime, and the defaults of its instances
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeConfig {
+    pub running_mode: RunningModeConfig,
+    pub allocator: AllocatorConfig,
+    /// the stack size of instances, `DEFAULT_STACK_SIZE` if unset
+    pub stack_size: Option<u32>,
+    /// the size of the app heap of instances
+    pub heap_size: u32,
+    pub features: FeaturesConfig,
+    pub canonicalize_nans: bool,
+    pub restore_signal_handlers: bool,
+    pub abort_on_host_panic: bool,
+    pub wasi: WasiConfig,
+}
+
+impl RuntimeConfig {
+    /// # Error
+    ///
+    /// Return `RuntimeError::ConfigError` if `content` isn't a valid configuration.
+    pub fn from_toml(content: &str) -> Result<Self, RuntimeError> {
+        toml::from_str(content)
+            .map_err(|e| RuntimeError::ConfigError(message!(code: "invalid TOML", e.to_string())))
+    }
+
+    /// # Error
+    ///
+    /// Return `RuntimeError::ConfigError` if `content` isn't a valid configuration.
+    pub fn from_json(content: &str) -> Result<Self, RuntimeError> {
+        serde_json::from_str(content)
+            .map_err(|e| RuntimeError::ConfigError(message!(code: "invalid JSON", e.to_string())))
+    }
+
+    /// the stack size to instantiate with
+    pub fn stack_size(&self) -> u32 {
+        self.stack_size.unwrap_or(DEFAULT_STACK_SIZE)
+    }
+
+    /// a builder configured like this, to register host functions on before building
+    pub fn builder(&self) -> RuntimeBuilder {
+        let mut builder = Runtime::builder();
+        builder = match self.allocator {
+            AllocatorConfig::Default => builder,
+            AllocatorConfig::System => builder.use_system_allocator(),
+            AllocatorConfig::Instrumented => builder.use_instrumented_allocator(),
+            AllocatorConfig::Pool { size } => {
+                builder.use_memory_pool(vec![0u8; size as usize], size)
+            }
+        };
+        builder = match self.running_mode {
+            RunningModeConfig::Default => builder,
+            RunningModeConfig::Interpreter => builder.run_as_interpreter(),
+            RunningModeConfig::LlvmJit {
+                opt_level,
+                size_level,
+            } => builder.run_as_llvm_jit(opt_level, size_level),
+        };
+        if self.canonicalize_nans {
+            builder = builder.canonicalize_nans();
+        }
+        if self.restore_signal_handlers {
+            builder = builder.restore_signal_handlers();
+        }
+        if self.abort_on_host_panic {
+            builder = builder.abort_on_host_panic();
+        }
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_config() {
+        let config = RuntimeConfig::from_toml(
+            r#"
+            running_mode = { llvm-jit = { opt_level = 3, size_level = 1 } }
+            allocator = { pool = { size = 1048576 } }
+            heap_size = 16384
+            canonicalize_nans = true
+
+            [features]
+            simd = true
+
+            [wasi]
+            args = ["guest"]
+            preopens = [{ guest = "/data", host = "/srv/data" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.running_mode,
+            RunningModeConfig::LlvmJit {
+                opt_level: 3,
+                size_level: 1
+            }
+        );
+        assert_eq!(config.allocator, AllocatorConfig::Pool { size: 1048576 });
+        assert_eq!(config.stack_size(), DEFAULT_STACK_SIZE);
+        assert_eq!(config.heap_size, 16384);
+        assert!(config.canonicalize_nans);
+        assert_eq!(config.wasi.args, vec![String::from("guest")]);
+        assert_eq!(config.wasi.preopens[0].host, "/srv/data");
+
+        let supported = WasmFeatures {
+            simd: false,
+            ..WasmFeatures::default()
+        };
+        assert_eq!(config.features.missing(&supported), vec!["simd"]);
+
+        let json = RuntimeConfig::from_json(
+            r#"{ "running_mode": "interpreter", "allocator": "system", "stack_size": 8192 }"#,
+        )
+        .unwrap();
+        assert_eq!(json.running_mode, RunningModeConfig::Interpreter);
+        assert_eq!(json.allocator, AllocatorConfig::System);
+        assert_eq!(json.stack_size(), 8192);
+
+        assert!(matches!(
+            RuntimeConfig::from_toml("stack_sise = 8192"),
+            Err(RuntimeError::ConfigError(_))
+        ));
+    }
+}
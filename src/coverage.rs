@@ -0,0 +1,367 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! which functions of a guest ran, and optionally which blocks, to measure how much of a
+//! plugin its tests cover. Enable it via `RuntimeBuilder::collect_coverage()`, and get a
+//! report via `Module::coverage()`, written as LCOV via `Coverage::to_lcov()`
+//!
+//! each .wasm is instrumented while it is loaded, see `instrument`: the entry of every
+//! function, and with `CoverageLevel::Blocks` the start of every block, calls a host
+//! function imported from `MODULE`. The hits add up over the instances of a module. The
+//! debug info of the guest isn't read, so the lines of a report are the offsets of the
+//! functions and blocks in the .wasm. An .aot isn't instrumented.
+
+use std::{
+    fmt::Write,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{wasm_module_t, wasm_runtime_get_module};
+
+use crate::{
+    binary::{self, write_i32_leb, write_u32_leb},
+    instrument::{self, Hook},
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+    ErrorMessage,
+};
+
+/// the module the instrumented code imports its hook from
+pub const MODULE: &str = "wamr_coverage";
+const HOOK: &str = "hit";
+
+/// the type of the hook, `(site: i32) -> ()`
+const HOOK_TYPE: [u8; 4] = [0x60, 0x01, 0x7f, 0x00];
+
+/// what is recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoverageLevel {
+    /// the functions called
+    Functions,
+    /// the functions called and the blocks run, in the interpreter
+    Blocks,
+}
+
+/// a function or a block which calls the hook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Site {
+    /// the index of the function
+    function: u32,
+    /// the offset in the .wasm
+    offset: usize,
+    /// whether it is the entry of the function
+    entry: bool,
+}
+
+/// the sites of an instrumented module, before it is loaded
+#[derive(Debug, Clone)]
+pub(crate) struct Sites {
+    level: CoverageLevel,
+    names: Vec<(u32, String)>,
+    sites: Vec<Site>,
+}
+
+struct Recorded {
+    name: String,
+    sites: Sites,
+    hits: Vec<u64>,
+}
+
+/// the instrumented modules, by the address of the module
+static MODULES: Mutex<Vec<(usize, Recorded)>> = Mutex::new(Vec::new());
+
+fn modules() -> MutexGuard<'static, Vec<(usize, Recorded)>> {
+    MODULES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// `binary` calling the hook at the sites of `level`, and the sites
+pub(crate) fn instrument(
+    binary: &[u8],
+    level: CoverageLevel,
+) -> Result<(Vec<u8>, Sites), ErrorMessage> {
+    let imported = binary::imported_function_count(binary)?;
+    let names = binary::function_names(binary)?
+        .into_iter()
+        .filter(|(index, _)| *index >= imported)
+        .map(|(index, name)| (index, String::from(name)))
+        .collect();
+    let hook = Hook {
+        module: MODULE,
+        name: HOOK,
+        func_type: &HOOK_TYPE,
+        locals: &[],
+    };
+
+    let mut sites = Vec::new();
+    // whether the instruction before ends a block, or branches out of it
+    let mut after_branch = false;
+    let instrumented = instrument::instrument(binary, &hook, |site, out| {
+        let entry = site.index == 0;
+        let starts_block = entry || (level == CoverageLevel::Blocks && after_branch);
+        after_branch = matches!(
+            site.opcode,
+            // block, loop, if, else, try, catch, end, br_if, delegate, catch_all, try_table
+            0x02..=0x07 | 0x0b | 0x0d | 0x18 | 0x19 | 0x1f
+            // br_on_null, br_on_non_null, br_on_cast and br_on_cast_fail
+            | 0xd5 | 0xd6 | 0xfb0018 | 0xfb0019
+        );
+        if !starts_block {
+            return Ok(());
+        }
+
+        // i32.const site, call hook
+        out.push(0x41);
+        write_i32_leb(out, sites.len() as i32);
+        out.push(0x10);
+        write_u32_leb(out, site.hook);
+        sites.push(Site {
+            function: imported + site.function,
+            offset: site.offset,
+            entry,
+        });
+        Ok(())
+    })?;
+
+    let sites = Sites {
+        level,
+        names,
+        sites,
+    };
+    Ok((instrumented, sites))
+}
+
+/// start counting the hits of `module`, named `name`, instrumented at `sites`
+pub(crate) fn record(module: wasm_module_t, name: &str, sites: Sites) {
+    let hits = vec![0; sites.sites.len()];
+    let recorded = Recorded {
+        name: String::from(name),
+        sites,
+        hits,
+    };
+    modules().push((module as usize, recorded));
+}
+
+/// forget an unloaded module
+pub(crate) fn remove(module: wasm_module_t) {
+    modules().retain(|(address, _)| *address != module as usize);
+}
+
+/// the hook the instrumented code calls
+pub(crate) struct CoverageCollector;
+
+impl NativeModule for CoverageCollector {
+    fn module_name(&self) -> &str {
+        MODULE
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(HOOK, hit as extern "C" fn(ExecEnv, i32));
+    }
+}
+
+extern "C" fn hit(env: ExecEnv, site: i32) {
+    let module = unsafe { wasm_runtime_get_module(env.module_inst()) };
+    let mut modules = modules();
+    let recorded = modules
+        .iter_mut()
+        .find(|(address, _)| *address == module as usize);
+    if let Some(hits) = recorded.and_then(|(_, recorded)| recorded.hits.get_mut(site as usize)) {
+        *hits += 1;
+    }
+}
+
+/// how often a function has been called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    /// from the `name` section, or the exports, else `func[index]`
+    pub name: String,
+    /// the offset of the first instruction of the function in the .wasm
+    pub offset: usize,
+    pub hits: u64,
+}
+
+/// how often a block has been entered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockCoverage {
+    /// the name of the function of the block
+    pub function: String,
+    /// the offset of the first instruction of the block in the .wasm
+    pub offset: usize,
+    pub hits: u64,
+}
+
+/// the coverage of a module, over all its instances
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    /// the name of the module
+    pub module: String,
+    /// in the order of the module
+    pub functions: Vec<FunctionCoverage>,
+    /// empty unless recorded with `CoverageLevel::Blocks`
+    pub blocks: Vec<BlockCoverage>,
+}
+
+impl Coverage {
+    fn new(recorded: &Recorded) -> Self {
+        let name = |function: u32| {
+            let names = &recorded.sites.names;
+            match names.iter().find(|(index, _)| *index == function) {
+                Some((_, name)) => name.clone(),
+                None => format!("func[{}]", function),
+            }
+        };
+
+        let mut functions = Vec::new();
+        let mut blocks = Vec::new();
+        for (site, hits) in recorded.sites.sites.iter().zip(&recorded.hits) {
+            if site.entry {
+                functions.push(FunctionCoverage {
+                    name: name(site.function),
+                    offset: site.offset,
+                    hits: *hits,
+                });
+            }
+            if recorded.sites.level == CoverageLevel::Blocks {
+                blocks.push(BlockCoverage {
+                    function: name(site.function),
+                    offset: site.offset,
+                    hits: *hits,
+                });
+            }
+        }
+        Coverage {
+            module: recorded.name.clone(),
+            functions,
+            blocks,
+        }
+    }
+
+    /// the number of functions called at least once
+    pub fn functions_hit(&self) -> usize {
+        self.functions.iter().filter(|f| f.hits > 0).count()
+    }
+
+    /// the number of blocks entered at least once
+    pub fn blocks_hit(&self) -> usize {
+        self.blocks.iter().filter(|b| b.hits > 0).count()
+    }
+
+    /// the report in the LCOV tracefile format, with the name of the module as the source
+    /// file and the offsets as the lines. The lines are the blocks, or the functions
+    /// without blocks recorded
+    pub fn to_lcov(&self) -> String {
+        let mut lcov = String::new();
+        let _ = writeln!(lcov, "TN:");
+        let _ = writeln!(lcov, "SF:{}", self.module);
+        for function in &self.functions {
+            let _ = writeln!(lcov, "FN:{},{}", function.offset, function.name);
+        }
+        for function in &self.functions {
+            let _ = writeln!(lcov, "FNDA:{},{}", function.hits, function.name);
+        }
+        let _ = writeln!(lcov, "FNF:{}", self.functions.len());
+        let _ = writeln!(lcov, "FNH:{}", self.functions_hit());
+
+        let lines: Vec<(usize, u64)> = match self.blocks.is_empty() {
+            true => self.functions.iter().map(|f| (f.offset, f.hits)).collect(),
+            false => self.blocks.iter().map(|b| (b.offset, b.hits)).collect(),
+        };
+        for (offset, hits) in &lines {
+            let _ = writeln!(lcov, "DA:{},{}", offset, hits);
+        }
+        let _ = writeln!(lcov, "LF:{}", lines.len());
+        let hit = lines.iter().filter(|(_, hits)| *hits > 0).count();
+        let _ = writeln!(lcov, "LH:{}", hit);
+        let _ = writeln!(lcov, "end_of_record");
+        lcov
+    }
+}
+
+/// the coverage of `module`, `None` unless it has been instrumented
+pub(crate) fn report(module: wasm_module_t) -> Option<Coverage> {
+    let modules = modules();
+    let recorded = modules
+        .iter()
+        .find(|(address, _)| *address == module as usize);
+    recorded.map(|(_, recorded)| Coverage::new(recorded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function, instance::Instance, instruction::decode_body, module::Module,
+        runtime::Runtime, value::WasmValue, RuntimeError,
+    };
+
+    // (module
+    //   (func $abs (export "abs") (param i32) (result i32)
+    //     (if (result i32) (i32.lt_s (local.get 0) (i32.const 0))
+    //       (then (i32.sub (i32.const 0) (local.get 0)))
+    //       (else (local.get 0))))
+    //   (func $unused (export "unused")))
+    const BINARY: [u8; 90] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x01, 0x7f, 0x01,
+        0x7f, 0x60, 0x00, 0x00, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x10, 0x02, 0x03, 0x61, 0x62,
+        0x73, 0x00, 0x00, 0x06, 0x75, 0x6e, 0x75, 0x73, 0x65, 0x64, 0x00, 0x01, 0x0a, 0x17, 0x02,
+        0x12, 0x00, 0x20, 0x00, 0x41, 0x00, 0x48, 0x04, 0x7f, 0x41, 0x00, 0x20, 0x00, 0x6b, 0x05,
+        0x20, 0x00, 0x0b, 0x0b, 0x02, 0x00, 0x0b, 0x00, 0x15, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x01,
+        0x0e, 0x02, 0x00, 0x03, 0x61, 0x62, 0x73, 0x01, 0x06, 0x75, 0x6e, 0x75, 0x73, 0x65, 0x64,
+    ];
+
+    #[test]
+    fn test_instrument() {
+        let (instrumented, sites) = instrument(&BINARY, CoverageLevel::Functions).unwrap();
+        let offsets: Vec<usize> = sites.sites.iter().map(|site| site.offset).collect();
+        assert_eq!(offsets, [47, 66]);
+        let bodies = binary::function_bodies(&instrumented).unwrap();
+        let instructions = decode_body(&instrumented, bodies[1].clone()).unwrap();
+        // i32.const 1, call hook, end
+        let bytes: Vec<&[u8]> = instructions.iter().map(|i| &i.bytes[..]).collect();
+        assert_eq!(bytes, [&[0x41, 0x01][..], &[0x10, 0x00], &[0x0b]]);
+
+        // the entry, then, else and after the if of $abs, the entry of $unused
+        let (_, sites) = instrument(&BINARY, CoverageLevel::Blocks).unwrap();
+        let offsets: Vec<usize> = sites.sites.iter().map(|site| site.offset).collect();
+        assert_eq!(offsets, [47, 54, 60, 63, 66]);
+
+        // any address, no module is involved
+        let module = 0x60 as wasm_module_t;
+        record(module, "plugin", sites);
+        for (address, recorded) in modules().iter_mut() {
+            if *address == module as usize {
+                recorded.hits.copy_from_slice(&[2, 1, 1, 2, 0]);
+            }
+        }
+        let coverage = report(module).unwrap();
+        assert_eq!((coverage.functions_hit(), coverage.blocks_hit()), (1, 4));
+        assert_eq!(coverage.blocks[1].function, "abs");
+        assert_eq!(
+            coverage.to_lcov(),
+            "TN:\nSF:plugin\nFN:47,abs\nFN:66,unused\nFNDA:2,abs\nFNDA:0,unused\nFNF:2\n\
+             FNH:1\nDA:47,2\nDA:54,1\nDA:60,1\nDA:63,2\nDA:66,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+        remove(module);
+        assert_eq!(report(module), None);
+    }
+
+    #[test]
+    fn test_coverage() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .collect_coverage(CoverageLevel::Blocks)
+            .build()?;
+        let module = Module::from_buf(&runtime, &BINARY, "coverage")?;
+        let instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+
+        let abs = Function::find_export_func(&instance, "abs")?;
+        abs.call(&instance, &vec![WasmValue::I32(-3)])?;
+        let coverage = module.coverage().unwrap();
+        assert_eq!(coverage.functions_hit(), 1);
+        let hits: Vec<u64> = coverage.blocks.iter().map(|block| block.hits).collect();
+        assert_eq!(hits, [1, 1, 0, 1, 0]);
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! pause, single-step and inspect a running instance, as the foundation of a custom debugger.
+//! get one via `DebugController::attach()`
+//!
+//! WAMR only steps guest code in its source debugging engine, available in the classic
+//! interpreter, which the `debug` feature builds it with. The engine serves each debugged
+//! instance over the GDB remote serial protocol, which `DebugController` speaks on the
+//! loopback interface. Enable the engine via `RuntimeBuilder::enable_debug_engine()`.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    ops::Range,
+};
+
+use wamr_sys::wasm_runtime_start_debug_instance;
+
+use crate::{binary, instance::Instance, module::Module, RuntimeError};
+
+/// why the debugged instance stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// stopped by a signal, like `SIGTRAP` after a step or `SIGINT` after a pause
+    Signal(u8),
+    /// the execution finished with an exit code
+    Exited(u8),
+}
+
+/// a client of the WAMR debugging engine for one instance.
+///
+/// the instance runs on the thread calling into it, and blocks whenever it is paused, so
+/// drive the controller from another thread.
+pub struct DebugController {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    thread: Option<String>,
+    imported_functions: u32,
+    function_bodies: Vec<Range<usize>>,
+}
+
+impl DebugController {
+    /// start debugging `instance`, which has to be instantiated from `module`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine isn't enabled or can't be reached.
+    pub fn attach<T>(instance: &Instance<T>, module: &Module) -> Result<Self, RuntimeError> {
+        let port = unsafe { wasm_runtime_start_debug_instance(instance.exec_env().as_raw()) };
+        if port == 0 {
+            return Err(RuntimeError::DebugError(message!(
+                "failed to start a debug instance",
+            )));
+        }
+
+        let binary = module.content();
+        let imported_functions =
+            binary::imported_function_count(binary).map_err(RuntimeError::DebugError)?;
+        let function_bodies = binary::function_bodies(binary).map_err(RuntimeError::DebugError)?;
+        Self::connect(port as u16, imported_functions, function_bodies)
+    }
+
+    fn connect(
+        port: u16,
+        imported_functions: u32,
+        function_bodies: Vec<Range<usize>>,
+    ) -> Result<Self, RuntimeError> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).map_err(debug_error)?;
+        stream.set_nodelay(true).map_err(debug_error)?;
+        Ok(DebugController {
+            reader: BufReader::new(stream.try_clone().map_err(debug_error)?),
+            writer: stream,
+            thread: None,
+            imported_functions,
+            function_bodies,
+        })
+    }
+
+    /// interrupt the running instance and wait until it stops
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn pause(&mut self) -> Result<StopReason, RuntimeError> {
+        self.writer.write_all(&[0x03]).map_err(debug_error)?;
+        self.wait()
+    }
+
+    /// let the instance run, until it hits `pause()` or finishes.
+    ///
+    /// call `wait()` to be notified when it stops.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn resume(&mut self) -> Result<(), RuntimeError> {
+        self.send_packet("c")
+    }
+
+    /// wait until the running instance stops
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn wait(&mut self) -> Result<StopReason, RuntimeError> {
+        let reply = self.recv_packet()?;
+        let (reason, thread) = parse_stop_reply(&reply)?;
+        if thread.is_some() {
+            self.thread = thread;
+        }
+        Ok(reason)
+    }
+
+    /// execute a single instruction
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn step(&mut self) -> Result<StopReason, RuntimeError> {
+        self.send_packet("s")?;
+        self.wait()
+    }
+
+    /// execute instructions until a function is entered or returns
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn step_call(&mut self) -> Result<StopReason, RuntimeError> {
+        let depth = self.call_stack()?.len();
+        loop {
+            let reason = self.step()?;
+            if matches!(reason, StopReason::Exited(_)) || self.call_stack()?.len() != depth {
+                return Ok(reason);
+            }
+        }
+    }
+
+    /// the code offsets, in the module binary, of all frames of the stopped instance. The
+    /// innermost frame comes first
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn call_stack(&mut self) -> Result<Vec<u32>, RuntimeError> {
+        let thread = self.thread()?;
+        let reply = self.query(&format!("qWasmCallStack:{}", thread))?;
+        let bytes = decode_hex(&reply)?;
+        Ok(bytes
+            .chunks_exact(8)
+            .map(|pc| u64::from_le_bytes(pc.try_into().unwrap()) as u32)
+            .collect())
+    }
+
+    /// the index of the function executed by the innermost frame
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer or the
+    /// instance isn't in a function.
+    pub fn current_function(&mut self) -> Result<u32, RuntimeError> {
+        let offset = match self.call_stack()?.first() {
+            Some(offset) => *offset as usize,
+            None => return Err(RuntimeError::DebugError(message!("no active frame"))),
+        };
+
+        match self
+            .function_bodies
+            .iter()
+            .position(|body| body.contains(&offset))
+        {
+            Some(defined) => Ok(self.imported_functions + defined as u32),
+            None => Err(RuntimeError::DebugError(message!(
+                "no function at offset {:#x}",
+                offset
+            ))),
+        }
+    }
+
+    /// the raw little-endian value of local `index` in `frame`, where frame 0 is the innermost
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer or the local
+    /// doesn't exist.
+    pub fn local(&mut self, frame: u32, index: u32) -> Result<Vec<u8>, RuntimeError> {
+        let reply = self.query(&format!("qWasmLocal:{};{}", frame, index))?;
+        decode_hex(&reply)
+    }
+
+    /// stop debugging and let the instance run freely
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::DebugError` if the debugging engine doesn't answer.
+    pub fn detach(mut self) -> Result<(), RuntimeError> {
+        self.query("D").map(|_| ())
+    }
+
+    fn thread(&mut self) -> Result<String, RuntimeError> {
+        if let Some(thread) = &self.thread {
+            return Ok(thread.clone());
+        }
+
+        let reply = self.query("qfThreadInfo")?;
+        let thread = reply
+            .strip_prefix('m')
+            .and_then(|threads| threads.split(',').next())
+            .filter(|thread| !thread.is_empty())
+            .ok_or_else(|| RuntimeError::DebugError(message!("no thread to debug")))?;
+        self.thread = Some(String::from(thread));
+        Ok(String::from(thread))
+    }
+
+    fn query(&mut self, packet: &str) -> Result<String, RuntimeError> {
+        self.send_packet(packet)?;
+        let reply = self.recv_packet()?;
+        match reply.strip_prefix('E') {
+            Some(code) if code.len() == 2 => Err(RuntimeError::DebugError(message!(
+                "{} failed with error {}",
+                packet,
+                code
+            ))),
+            _ => Ok(reply),
+        }
+    }
+
+    fn send_packet(&mut self, packet: &str) -> Result<(), RuntimeError> {
+        let framed = format!("${}#{:02x}", packet, checksum(packet.as_bytes()));
+        loop {
+            self.writer
+                .write_all(framed.as_bytes())
+                .map_err(debug_error)?;
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => continue,
+                byte => {
+                    return Err(RuntimeError::DebugError(message!(
+                        "unexpected acknowledgement {:#x}",
+                        byte
+                    )))
+                }
+            }
+        }
+    }
+
+    fn recv_packet(&mut self) -> Result<String, RuntimeError> {
+        loop {
+            while self.read_byte()? != b'$' {}
+
+            let mut packet = Vec::new();
+            self.reader
+                .read_until(b'#', &mut packet)
+                .map_err(debug_error)?;
+            packet.pop();
+            let mut sum = [0u8; 2];
+            self.reader.read_exact(&mut sum).map_err(debug_error)?;
+
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|sum| u8::from_str_radix(sum, 16).ok())
+                == Some(checksum(&packet));
+            if !valid {
+                self.writer.write_all(b"-").map_err(debug_error)?;
+                continue;
+            }
+            self.writer.write_all(b"+").map_err(debug_error)?;
+
+            // console output of the remote, not an answer
+            if packet.first() == Some(&b'O') && packet.len() > 1 {
+                continue;
+            }
+            return String::from_utf8(packet)
+                .map_err(|_| RuntimeError::DebugError(message!("invalid packet")));
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, RuntimeError> {
+        let mut byte = [0u8; 1];
+        self.reader.read_exact(&mut byte).map_err(debug_error)?;
+        Ok(byte[0])
+    }
+}
+
+fn debug_error(e: io::Error) -> RuntimeError {
+    RuntimeError::DebugError(message!(code: "debugger I/O error", e.to_string()))
+}
+
+fn checksum(packet: &[u8]) -> u8 {
+    packet.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, RuntimeError> {
+    if !hex.len().is_multiple_of(2) {
+        return Err(RuntimeError::DebugError(message!("invalid hex {}", hex)));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| RuntimeError::DebugError(message!("invalid hex {}", hex)))
+        })
+        .collect()
+}
+
+/// a stop reply, like `T05thread:1;` or `W00`, and the thread it names
+fn parse_stop_reply(reply: &str) -> Result<(StopReason, Option<String>), RuntimeError> {
+    let invalid = || RuntimeError::DebugError(message!("invalid stop reply {}", reply));
+    let code = reply
+        .get(1..3)
+        .and_then(|code| u8::from_str_radix(code, 16).ok())
+        .ok_or_else(invalid)?;
+
+    match reply.as_bytes()[0] {
+        b'T' | b'S' => {
+            let thread = reply[3..]
+                .split(';')
+                .find_map(|field| field.strip_prefix("thread:"))
+                .map(String::from);
+            Ok((StopReason::Signal(code), thread))
+        }
+        b'W' | b'X' => Ok((StopReason::Exited(code), None)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::TcpListener, thread};
+
+    #[test]
+    fn test_parse_stop_reply() {
+        assert_eq!(
+            parse_stop_reply("T05thread:1a;name:main;").unwrap(),
+            (StopReason::Signal(5), Some(String::from("1a")))
+        );
+        assert_eq!(
+            parse_stop_reply("S02").unwrap(),
+            (StopReason::Signal(2), None)
+        );
+        assert_eq!(
+            parse_stop_reply("W00").unwrap(),
+            (StopReason::Exited(0), None)
+        );
+        assert!(parse_stop_reply("OK").is_err());
+        assert!(parse_stop_reply("").is_err());
+    }
+
+    /// answer every packet of a single client with the scripted replies
+    fn serve(listener: TcpListener, script: Vec<(&'static str, &'static str)>) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+
+        for (expected, reply) in script {
+            let mut packet = Vec::new();
+            reader.read_until(b'$', &mut packet).unwrap();
+            packet.clear();
+            reader.read_until(b'#', &mut packet).unwrap();
+            packet.pop();
+            let mut sum = [0u8; 2];
+            reader.read_exact(&mut sum).unwrap();
+            assert_eq!(std::str::from_utf8(&packet).unwrap(), expected);
+
+            write!(writer, "+${}#{:02x}", reply, checksum(reply.as_bytes())).unwrap();
+            let mut ack = [0u8; 1];
+            reader.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+    }
+
+    #[test]
+    fn test_debug_controller() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            serve(
+                listener,
+                vec![
+                    ("s", "T05thread:7;"),
+                    // one frame at offset 0x24, tagged as a module address
+                    ("qWasmCallStack:7", "2400000000000040"),
+                    ("qWasmLocal:0;1", "2a000000"),
+                    ("qWasmLocal:0;9", "E01"),
+                ],
+            )
+        });
+
+        // one imported function, then two defined ones
+        let mut controller = DebugController::connect(port, 1, vec![20..30, 34..41]).unwrap();
+        assert_eq!(controller.step().unwrap(), StopReason::Signal(5));
+        assert_eq!(controller.current_function().unwrap(), 2);
+        assert_eq!(controller.local(0, 1).unwrap(), vec![42, 0, 0, 0]);
+        assert!(controller.local(0, 9).is_err());
+
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! which registered modules import from which, to debug the load order and missing
+//! sub-modules of multi-module setups, with the `multi-module` feature. Get one via
+//! `Runtime::dependency_graph()`
+//!
+//! the modules are the ones registered under a name for the imports of others, like the
+//! provider of an `InstanceGroup` or the bases of a side module. Their imports are taken
+//! when they are registered, along with whether WAMR linked them while loading, so an
+//! import of a module registered afterwards shows up as unresolved.
+
+use std::{
+    collections::HashSet,
+    ffi::CStr,
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{
+    wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC, wasm_import_t,
+    wasm_runtime_get_import_count, wasm_runtime_get_import_type,
+};
+
+use crate::module::Module;
+
+/// an import of a registered module
+#[derive(Debug, Clone)]
+struct Import {
+    module: String,
+    name: String,
+    /// whether WAMR found it among the host functions, only known for functions
+    linked: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+struct Registered {
+    name: String,
+    imports: Vec<Import>,
+}
+
+/// the modules registered since WAMR has been initialized, in order
+static REGISTERED: Mutex<Vec<Registered>> = Mutex::new(Vec::new());
+
+fn registered() -> MutexGuard<'static, Vec<Registered>> {
+    REGISTERED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// remember the imports of `module`, registered under `name`
+pub(crate) fn record(module: &Module, name: &str) {
+    let inner = module.get_inner_module();
+    let count = unsafe { wasm_runtime_get_import_count(inner) };
+    let imports = (0..count)
+        .filter_map(|index| {
+            let mut import: wasm_import_t = unsafe { mem::zeroed() };
+            unsafe { wasm_runtime_get_import_type(inner, index, &mut import) };
+            if import.module_name.is_null() || import.name.is_null() {
+                return None;
+            }
+            Some(Import {
+                module: unsafe { CStr::from_ptr(import.module_name) }
+                    .to_string_lossy()
+                    .into_owned(),
+                name: unsafe { CStr::from_ptr(import.name) }
+                    .to_string_lossy()
+                    .into_owned(),
+                linked: (import.kind == wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC)
+                    .then_some(import.linked),
+            })
+        })
+        .collect();
+
+    let mut registered = registered();
+    registered.retain(|module| module.name != name);
+    registered.push(Registered {
+        name: String::from(name),
+        imports,
+    });
+}
+
+/// forget every registered module, once WAMR is destroyed
+pub(crate) fn reset() {
+    registered().clear();
+}
+
+/// what a module imports from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exporter {
+    /// a registered module
+    Module,
+    /// host functions, of a native module or built into WAMR like libc-wasi
+    Native,
+    /// nothing WAMR knew about when the importer was loaded
+    Unknown,
+}
+
+/// the imports of a module from another one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub importer: String,
+    pub exporter: String,
+    pub kind: Exporter,
+    /// the names of the imports, in the order the importer declares them
+    pub imports: Vec<String>,
+    /// the imports WAMR couldn't link
+    pub unresolved: Vec<String>,
+}
+
+impl Dependency {
+    pub fn is_resolved(&self) -> bool {
+        self.unresolved.is_empty()
+    }
+}
+
+/// the registered modules and their dependencies
+#[derive(Debug, Clone, Default)]
+pub struct DependencyGraph {
+    modules: Vec<String>,
+    dependencies: Vec<Dependency>,
+}
+
+impl DependencyGraph {
+    /// the graph of the registered modules, given the names of the native modules
+    pub(crate) fn new(natives: &[String]) -> Self {
+        Self::from_registered(&registered(), natives)
+    }
+
+    fn from_registered(registered: &[Registered], natives: &[String]) -> Self {
+        let modules: Vec<String> = registered
+            .iter()
+            .map(|module| module.name.clone())
+            .collect();
+        let mut dependencies: Vec<Dependency> = Vec::new();
+        for module in registered {
+            for import in &module.imports {
+                let index = dependencies.iter().position(|dependency| {
+                    dependency.importer == module.name && dependency.exporter == import.module
+                });
+                let dependency = match index {
+                    Some(index) => &mut dependencies[index],
+                    None => {
+                        let kind = match modules.contains(&import.module) {
+                            true => Exporter::Module,
+                            false if natives.contains(&import.module) => Exporter::Native,
+                            false => Exporter::Unknown,
+                        };
+                        dependencies.push(Dependency {
+                            importer: module.name.clone(),
+                            exporter: import.module.clone(),
+                            kind,
+                            imports: Vec::new(),
+                            unresolved: Vec::new(),
+                        });
+                        dependencies.last_mut().unwrap()
+                    }
+                };
+                dependency.imports.push(import.name.clone());
+                match import.linked {
+                    // built into WAMR, since it linked it
+                    Some(true) if dependency.kind == Exporter::Unknown => {
+                        dependency.kind = Exporter::Native
+                    }
+                    Some(false) => dependency.unresolved.push(import.name.clone()),
+                    _ => {}
+                }
+            }
+        }
+
+        // only functions tell whether they are linked, and nothing else links the others
+        for dependency in &mut dependencies {
+            if dependency.kind == Exporter::Unknown {
+                dependency.unresolved = dependency.imports.clone();
+            }
+        }
+
+        DependencyGraph {
+            modules,
+            dependencies,
+        }
+    }
+
+    /// the names of the registered modules, in the order they have been registered
+    pub fn modules(&self) -> &[String] {
+        &self.modules
+    }
+
+    pub fn dependencies(&self) -> &[Dependency] {
+        &self.dependencies
+    }
+
+    /// the dependencies of the module registered as `importer`
+    pub fn dependencies_of<'a>(
+        &'a self,
+        importer: &'a str,
+    ) -> impl Iterator<Item = &'a Dependency> + 'a {
+        self.dependencies
+            .iter()
+            .filter(move |dependency| dependency.importer == importer)
+    }
+
+    /// the dependencies with imports WAMR couldn't link
+    pub fn unresolved(&self) -> impl Iterator<Item = &Dependency> + '_ {
+        self.dependencies
+            .iter()
+            .filter(|dependency| !dependency.is_resolved())
+    }
+
+    /// the registered modules, each after the ones it imports from, `None` if some import
+    /// from each other
+    pub fn load_order(&self) -> Option<Vec<&str>> {
+        let mut loaded: HashSet<&str> = HashSet::new();
+        let mut order = Vec::new();
+        while order.len() < self.modules.len() {
+            let next = self.modules.iter().find(|module| {
+                !loaded.contains(module.as_str())
+                    && self.dependencies_of(module).all(|dependency| {
+                        dependency.kind != Exporter::Module
+                            || dependency.exporter == **module
+                            || loaded.contains(dependency.exporter.as_str())
+                    })
+            })?;
+            loaded.insert(next);
+            order.push(next.as_str());
+        }
+        Some(order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered(name: &str, imports: &[(&str, &str, Option<bool>)]) -> Registered {
+        Registered {
+            name: String::from(name),
+            imports: imports
+                .iter()
+                .map(|(module, name, linked)| Import {
+                    module: String::from(*module),
+                    name: String::from(*name),
+                    linked: *linked,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_dependency_graph() {
+        let natives = vec![String::from("host")];
+        let graph = DependencyGraph::from_registered(
+            &[
+                registered(
+                    "app",
+                    &[("env", "memory", None), ("lib", "add", Some(true))],
+                ),
+                registered(
+                    "lib",
+                    &[
+                        ("env", "memory", None),
+                        ("host", "log", Some(true)),
+                        ("wasi_snapshot_preview1", "fd_write", Some(true)),
+                        ("missing", "f", Some(false)),
+                        ("missing", "table", None),
+                    ],
+                ),
+                registered("env", &[]),
+            ],
+            &natives,
+        );
+
+        assert_eq!(graph.modules(), ["app", "lib", "env"]);
+        let kinds: Vec<_> = graph
+            .dependencies_of("lib")
+            .map(|dependency| (dependency.exporter.as_str(), dependency.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ("env", Exporter::Module),
+                ("host", Exporter::Native),
+                ("wasi_snapshot_preview1", Exporter::Native),
+                ("missing", Exporter::Unknown),
+            ]
+        );
+
+        let unresolved: Vec<_> = graph.unresolved().collect();
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(unresolved[0].exporter, "missing");
+        assert_eq!(unresolved[0].unresolved, ["f", "table"]);
+
+        assert_eq!(graph.load_order(), Some(vec!["env", "lib", "app"]));
+        let cycle = DependencyGraph::from_registered(
+            &[
+                registered("a", &[("b", "f", Some(true))]),
+                registered("b", &[("a", "g", Some(true))]),
+            ],
+            &[],
+        );
+        assert_eq!(cycle.load_order(), None);
+    }
+}
@@ -0,0 +1,346 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! emscripten side modules, loaded next to a main module, with the `multi-module` feature.
+//! get a linker via `DynamicLinker::new()`
+//!
+//! a side module describes the memory and table space it needs in its `dylink.0` custom
+//! section, and imports where they start as the `env.__memory_base` and `env.__table_base`
+//! globals. The linker picks both, and gives them to each side module via a module of
+//! its own, registered as `<name>.bases`, before loading it.
+//!
+//! the side modules are members of an `InstanceGroup`, with the main module as its
+//! provider. See there for when they actually share its memory. Each of them gets its own
+//! copy of the table of the main module, so the table base only keeps their slots apart,
+//! and function pointers don't cross modules.
+
+use crate::{
+    binary::{self, Reader},
+    function::Function,
+    group::{self, InstanceGroup},
+    instance::Instance,
+    module::Module,
+    ErrorMessage, RuntimeError,
+};
+
+/// the `dylink.0` subsection of the memory and table space
+const WASM_DYLINK_MEM_INFO: u8 = 1;
+/// the `dylink.0` subsection of the needed libraries
+const WASM_DYLINK_NEEDED: u8 = 2;
+
+/// the content of the `dylink.0` custom section of a side module
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DylinkInfo {
+    /// the size of the static data, in bytes
+    pub memory_size: u32,
+    /// the alignment of the static data, as a power of 2
+    pub memory_align: u32,
+    /// the number of table slots
+    pub table_size: u32,
+    /// the alignment of the table slots, as a power of 2
+    pub table_align: u32,
+    /// the side modules this one depends on
+    pub needed: Vec<String>,
+}
+
+impl DylinkInfo {
+    /// parse the `dylink.0` custom section of a .wasm, `None` if there is none
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if the binary or the section is malformed.
+    pub fn parse(binary: &[u8]) -> Result<Option<Self>, RuntimeError> {
+        let section =
+            binary::custom_section(binary, "dylink.0").map_err(RuntimeError::CompilationError)?;
+        match section {
+            Some(section) => Self::parse_section(section)
+                .map(Some)
+                .map_err(RuntimeError::CompilationError),
+            None => Ok(None),
+        }
+    }
+
+    fn parse_section(section: &[u8]) -> Result<Self, ErrorMessage> {
+        let mut info = DylinkInfo::default();
+        let mut reader = Reader::new(section);
+        while !reader.is_empty() {
+            let kind = reader.read_u8()?;
+            let size = reader.read_u32_leb()? as usize;
+            let mut subsection = Reader::new(reader.read_bytes(size)?);
+            match kind {
+                WASM_DYLINK_MEM_INFO => {
+                    info.memory_size = subsection.read_u32_leb()?;
+                    info.memory_align = subsection.read_u32_leb()?;
+                    info.table_size = subsection.read_u32_leb()?;
+                    info.table_align = subsection.read_u32_leb()?;
+                }
+                WASM_DYLINK_NEEDED => {
+                    let count = subsection.read_u32_leb()?;
+                    for _ in 0..count {
+                        info.needed.push(subsection.read_name()?.to_string());
+                    }
+                }
+                // like the exported TLS symbols, which only matter with threads of emscripten
+                _ => {}
+            }
+        }
+
+        if info.memory_align >= 32 || info.table_align >= 32 {
+            return Err(message!("invalid alignment in dylink.0"));
+        }
+        Ok(info)
+    }
+}
+
+/// loads side modules one after the other, each right after the memory and table space of
+/// the previous one.
+///
+/// the space starts at the bases given to `DynamicLinker::new()`, usually the
+/// `__heap_base` of the main module and the end of its table. The memory and the table
+/// of the main module have to be large enough for all side modules, or their
+/// instantiation fails, even though the table isn't shared, see the module docs.
+pub struct DynamicLinker<'a, T> {
+    // dropped first, since its instances depend on the modules
+    group: InstanceGroup<'a, T>,
+    modules: Vec<Module>,
+    loaded: Vec<String>,
+    memory_base: u32,
+    table_base: u32,
+}
+
+impl<'a, T: 'static> DynamicLinker<'a, T> {
+    pub fn new(group: InstanceGroup<'a, T>, memory_base: u32, table_base: u32) -> Self {
+        DynamicLinker {
+            group,
+            modules: Vec::new(),
+            loaded: Vec::new(),
+            memory_base,
+            table_base,
+        }
+    }
+
+    /// the group of the main module and the side modules
+    pub fn group(&self) -> &InstanceGroup<'a, T> {
+        &self.group
+    }
+
+    /// where the next side module will be placed in the memory and the table
+    pub fn bases(&self) -> (u32, u32) {
+        (self.memory_base, self.table_base)
+    }
+
+    /// relocate, load and instantiate a side module, with stack size and host managed heap
+    /// size, then run its data relocations and constructors
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if the module isn't a side module, or fails to
+    /// load.
+    /// Return `RuntimeError::InstantiationFailure` if a library it needs hasn't been loaded
+    /// yet, the space it needs runs over 4GB, or its instantiation fails.
+    /// Return `RuntimeError::ExecutionError` if its constructors fail.
+    pub fn load(
+        &mut self,
+        buf: &[u8],
+        name: &str,
+        stack_size: u32,
+        heap_size: u32,
+        data: T,
+    ) -> Result<&Instance<T>, RuntimeError> {
+        let info = DylinkInfo::parse(buf)?.ok_or_else(|| {
+            RuntimeError::CompilationError(message!("{} has no dylink.0 section", name))
+        })?;
+        if let Some(missing) = info.needed.iter().find(|n| !self.loaded.contains(n)) {
+            return Err(RuntimeError::InstantiationFailure(message!(
+                "{} needs {}, load it first",
+                name,
+                missing
+            )));
+        }
+
+        let memory = place(self.memory_base, info.memory_size, info.memory_align);
+        let table = place(self.table_base, info.table_size, info.table_align);
+        let ((memory_base, memory_end), (table_base, table_end)) = match memory.zip(table) {
+            Some(space) => space,
+            None => {
+                return Err(RuntimeError::InstantiationFailure(message!(
+                    "no space left for {}",
+                    name
+                )))
+            }
+        };
+
+        let runtime = self.group.runtime();
+        let bases_name = format!("{}.bases", name);
+        let bases = bases_module(memory_base, table_base);
+        let bases = Module::from_buf(runtime, &bases, &bases_name)?;
+        group::register(&bases, &bases_name)?;
+        // kept loaded for as long as the side module, which WAMR links against it
+        self.modules.push(bases);
+
+        let relocated = relocate(buf, &bases_name).map_err(RuntimeError::CompilationError)?;
+        self.modules
+            .push(Module::from_buf(runtime, &relocated, name)?);
+        let module = self.modules.last().unwrap();
+        let instance = self.group.add(module, stack_size, heap_size, data)?;
+
+        for init in ["__wasm_apply_data_relocs", "__wasm_call_ctors"] {
+            if let Ok(function) = Function::find_export_func(instance, init) {
+                function.call_args(instance, &[])?;
+            }
+        }
+
+        self.loaded.push(name.to_string());
+        self.memory_base = memory_end;
+        self.table_base = table_end;
+        Ok(instance)
+    }
+}
+
+/// the start and the end of `size` units, from `base` aligned to `2^align`
+fn place(base: u32, size: u32, align: u32) -> Option<(u32, u32)> {
+    let mask = (1u32 << align) - 1;
+    let start = base.checked_add(mask)? & !mask;
+    Some((start, start.checked_add(size)?))
+}
+
+/// a module exporting the `__memory_base` and `__table_base` globals
+fn bases_module(memory_base: u32, table_base: u32) -> Vec<u8> {
+    let mut globals = Vec::new();
+    binary::write_u32_leb(&mut globals, 2);
+    for base in [memory_base, table_base] {
+        // an immutable i32, initialized by an i32.const
+        globals.extend_from_slice(&[0x7f, 0x00, 0x41]);
+        binary::write_i32_leb(&mut globals, base as i32);
+        globals.push(0x0b);
+    }
+
+    let mut exports = Vec::new();
+    binary::write_u32_leb(&mut exports, 2);
+    for (index, name) in ["__memory_base", "__table_base"].iter().enumerate() {
+        binary::write_name(&mut exports, name);
+        exports.extend_from_slice(&[0x03, index as u8]);
+    }
+
+    let mut module = binary::header();
+    for (id, payload) in [
+        (binary::SECTION_GLOBAL, globals),
+        (binary::SECTION_EXPORT, exports),
+    ] {
+        module.push(id);
+        binary::write_u32_leb(&mut module, payload.len() as u32);
+        module.extend_from_slice(&payload);
+    }
+    module
+}
+
+/// a copy of a side module, importing its bases from `bases_name` rather than `env`
+fn relocate(buf: &[u8], bases_name: &str) -> Result<Vec<u8>, ErrorMessage> {
+    binary::rename_imports(buf, |module, name| match (module, name) {
+        ("env", "__memory_base" | "__table_base") => Some(bases_name.to_string()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module
+    //   (@custom "dylink.0" (before first) "\01\04\08\02\00\00")
+    //   (import "env" "memory" (memory 1 1 shared))
+    //   (import "env" "__memory_base" (global i32))
+    //   (import "env" "__table_base" (global i32))
+    //   (data (global.get 0) "\2a\00\00\00")
+    //   (func (export "answer") (result i32) (i32.load (global.get 0)))
+    // )
+    const SIDE_MODULE: [u8; 130] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x08, 0x64, 0x79, 0x6c, 0x69,
+        0x6e, 0x6b, 0x2e, 0x30, 0x01, 0x04, 0x08, 0x02, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00,
+        0x01, 0x7f, 0x02, 0x39, 0x03, 0x03, 0x65, 0x6e, 0x76, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72,
+        0x79, 0x02, 0x03, 0x01, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x0d, 0x5f, 0x5f, 0x6d, 0x65, 0x6d,
+        0x6f, 0x72, 0x79, 0x5f, 0x62, 0x61, 0x73, 0x65, 0x03, 0x7f, 0x00, 0x03, 0x65, 0x6e, 0x76,
+        0x0c, 0x5f, 0x5f, 0x74, 0x61, 0x62, 0x6c, 0x65, 0x5f, 0x62, 0x61, 0x73, 0x65, 0x03, 0x7f,
+        0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x0a, 0x01, 0x06, 0x61, 0x6e, 0x73, 0x77, 0x65, 0x72,
+        0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x23, 0x00, 0x28, 0x02, 0x00, 0x0b, 0x0b, 0x0a,
+        0x01, 0x00, 0x23, 0x00, 0x0b, 0x04, 0x2a, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_dylink_info() {
+        let info = DylinkInfo::parse(&SIDE_MODULE).unwrap().unwrap();
+        assert_eq!(info.memory_size, 8);
+        assert_eq!(info.memory_align, 2);
+        assert!(info.needed.is_empty());
+
+        // (module
+        //   (@custom "dylink.0" "\01\04\08\02\00\00\02\06\01\04libm")
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x00, 0x17, 0x08, 0x64, 0x79, 0x6c,
+            0x69, 0x6e, 0x6b, 0x2e, 0x30, 0x01, 0x04, 0x08, 0x02, 0x00, 0x00, 0x02, 0x06, 0x01,
+            0x04, 0x6c, 0x69, 0x62, 0x6d,
+        ];
+        let info = DylinkInfo::parse(&binary).unwrap().unwrap();
+        assert_eq!(info.needed, vec![String::from("libm")]);
+
+        assert_eq!(DylinkInfo::parse(&binary::header()).unwrap(), None);
+        assert_eq!(place(13, 8, 3), Some((16, 24)));
+        assert_eq!(place(u32::MAX, 0, 2), None);
+        assert_eq!(place(u32::MAX - 3, 8, 2), None);
+    }
+
+    #[test]
+    fn test_relocate() {
+        let relocated = relocate(&SIDE_MODULE, "side.bases").unwrap();
+        let imports: Vec<(&str, &str)> = binary::imports(&relocated)
+            .unwrap()
+            .iter()
+            .map(|import| (import.module, import.name))
+            .collect();
+        assert_eq!(
+            imports,
+            vec![
+                ("env", "memory"),
+                ("side.bases", "__memory_base"),
+                ("side.bases", "__table_base")
+            ]
+        );
+        assert_eq!(
+            binary::function_bodies(&relocated).unwrap().len(),
+            binary::function_bodies(&SIDE_MODULE).unwrap().len()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "threads")]
+    fn test_dynamic_linker() -> Result<(), RuntimeError> {
+        use crate::{runtime::Runtime, value::WasmValue};
+
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1 1 shared)
+        // )
+        let main_module = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+            0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let main_module = Module::from_buf(&runtime, &main_module, "env")?;
+        let group = InstanceGroup::new(&runtime, &main_module, "env")?;
+        let mut linker = DynamicLinker::new(group, 1023, 0);
+
+        let side = linker.load(&SIDE_MODULE, "side", 1024, 0, ())?;
+        let answer = Function::find_export_func(side, "answer")?;
+        assert_eq!(answer.call_args(side, &[])?, WasmValue::I32(42));
+
+        let mut data = [0u8; 4];
+        side.memory().read(1024, &mut data)?;
+        assert_eq!(data, [0x2a, 0x00, 0x00, 0x00]);
+        assert_eq!(linker.bases(), (1032, 0));
+
+        Ok(())
+    }
+}
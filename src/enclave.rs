@@ -0,0 +1,130 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! run modules inside an Intel SGX enclave, with the `sgx` feature.
+//!
+//! an ecall hands over pointers into the untrusted memory of the host. The functions
+//! here check such buffers lie entirely outside the enclave, and copy them in before
+//! WAMR reads them, or copy results out, so the host can neither point WAMR at enclave
+//! secrets nor change a module while it is being loaded.
+
+use std::ffi::c_void;
+
+use crate::{
+    function::Function,
+    instance::Instance,
+    module::{LoadLimits, Module},
+    runtime::Runtime,
+    value::WasmValue,
+    RuntimeError,
+};
+
+extern "C" {
+    // from the trusted runtime library of the SGX SDK
+    fn sgx_is_outside_enclave(addr: *const c_void, size: usize) -> i32;
+}
+
+fn is_outside_enclave(ptr: *const u8, len: usize) -> bool {
+    len == 0 || unsafe { sgx_is_outside_enclave(ptr as *const c_void, len) == 1 }
+}
+
+/// copy `len` bytes from the untrusted memory into the enclave
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes.
+///
+/// # Error
+///
+/// Return `RuntimeError::MemoryAccessError` if the buffer isn't entirely outside the enclave.
+pub unsafe fn copy_from_untrusted(ptr: *const u8, len: usize) -> Result<Vec<u8>, RuntimeError> {
+    if !is_outside_enclave(ptr, len) {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "buffer isn't outside the enclave",
+        )));
+    }
+    let mut content = vec![0u8; len];
+    std::ptr::copy_nonoverlapping(ptr, content.as_mut_ptr(), len);
+    Ok(content)
+}
+
+/// copy `data` out of the enclave, to a buffer of `len` bytes of the untrusted memory
+///
+/// # Safety
+///
+/// `ptr` must be valid for writes of `len` bytes.
+///
+/// # Error
+///
+/// Return `RuntimeError::MemoryAccessError` if the buffer isn't entirely outside the enclave,
+/// or is smaller than `data`.
+pub unsafe fn copy_to_untrusted(ptr: *mut u8, len: usize, data: &[u8]) -> Result<(), RuntimeError> {
+    if !is_outside_enclave(ptr, len) {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "buffer isn't outside the enclave",
+        )));
+    }
+    if data.len() > len {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "{} bytes don't fit into a buffer of {} bytes",
+            data.len(),
+            len
+        )));
+    }
+    std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+    Ok(())
+}
+
+/// load a .wasm handed over by the untrusted host, once copied into the enclave and
+/// checked against `limits`
+///
+/// # Safety
+///
+/// `ptr` must be valid for reads of `len` bytes.
+///
+/// # Error
+///
+/// Return `RuntimeError::MemoryAccessError` if the buffer isn't entirely outside the enclave.
+/// Return `RuntimeError::LimitExceeded` if the module exceeds `limits`.
+/// Return `RuntimeError::CompilationError` if the module is invalid.
+pub unsafe fn module_from_untrusted(
+    runtime: &Runtime,
+    ptr: *const u8,
+    len: usize,
+    name: &str,
+    limits: &LoadLimits,
+) -> Result<Module, RuntimeError> {
+    let content = copy_from_untrusted(ptr, len)?;
+    Module::from_buf_untrusted(runtime, &content, name, limits)
+}
+
+/// execute an export function, and copy its result out to a buffer of `len` bytes of the
+/// untrusted memory, as the native-endian cells of `WasmValue::encode()`. Return the number
+/// of bytes written.
+///
+/// # Safety
+///
+/// `ptr` must be valid for writes of `len` bytes.
+///
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if the call failed.
+/// Return `RuntimeError::MemoryAccessError` if the result can't be copied out.
+pub unsafe fn call_to_untrusted<T>(
+    function: &Function,
+    instance: &Instance<T>,
+    params: &[WasmValue],
+    ptr: *mut u8,
+    len: usize,
+) -> Result<usize, RuntimeError> {
+    let result = function.call_args(instance, params)?;
+    let bytes: Vec<u8> = result
+        .encode()
+        .iter()
+        .flat_map(|cell| cell.to_ne_bytes())
+        .collect();
+    copy_to_untrusted(ptr, len, &bytes)?;
+    Ok(bytes.len())
+}
@@ -0,0 +1,85 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! modules encrypted with AES-256-GCM, for vendors distributing proprietary plugins to
+//! hosts they don't control. load one via `Module::from_encrypted_buf()`
+//!
+//! an encrypted module is `ENCRYPTION_MAGIC`, followed by the 12-byte nonce, and by the
+//! .wasm or .aot encrypted with its 16-byte tag. Encrypt one via `encryption::encrypt()`.
+//! It is decrypted in memory only, where it stays while the module is loaded, since WAMR
+//! refers to the content.
+
+use aes_gcm::{aead::Aead, Aes256Gcm, Key, KeyInit, Nonce};
+
+use crate::RuntimeError;
+
+/// the start of an encrypted module
+pub const ENCRYPTION_MAGIC: [u8; 8] = *b"wamrenc1";
+
+/// the size of a nonce, never to be used twice with the same key
+pub const NONCE_SIZE: usize = 12;
+
+/// `content` encrypted with `key` and `nonce`
+pub fn encrypt(content: &[u8], key: &[u8; 32], nonce: &[u8; NONCE_SIZE]) -> Vec<u8> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let encrypted = cipher
+        .encrypt(Nonce::from_slice(nonce), content)
+        .expect("the content is too large to encrypt");
+
+    let mut wrapped = Vec::with_capacity(ENCRYPTION_MAGIC.len() + NONCE_SIZE + encrypted.len());
+    wrapped.extend_from_slice(&ENCRYPTION_MAGIC);
+    wrapped.extend_from_slice(nonce);
+    wrapped.extend_from_slice(&encrypted);
+    wrapped
+}
+
+/// the content of an encrypted module
+///
+/// # Error
+///
+/// Return `RuntimeError::CompilationError` if `wrapped` isn't an encrypted module, or
+/// hasn't been encrypted with `key`, or has been tampered with.
+pub fn decrypt(wrapped: &[u8], key: &[u8; 32]) -> Result<Vec<u8>, RuntimeError> {
+    let encrypted = match wrapped.strip_prefix(&ENCRYPTION_MAGIC[..]) {
+        Some(encrypted) if encrypted.len() >= NONCE_SIZE => encrypted,
+        _ => {
+            return Err(RuntimeError::CompilationError(message!(
+                "the content isn't an encrypted module",
+            )))
+        }
+    };
+
+    let (nonce, encrypted) = encrypted.split_at(NONCE_SIZE);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), encrypted)
+        .map_err(|_| {
+            RuntimeError::CompilationError(message!(
+                "failed to decrypt the module, with a wrong key or tampered content",
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encryption() {
+        let key = [7u8; 32];
+        let wasm = b"\0asm\x01\0\0\0";
+
+        let wrapped = encrypt(wasm, &key, &[1; NONCE_SIZE]);
+        assert!(wrapped.starts_with(&ENCRYPTION_MAGIC));
+        assert!(!wrapped.windows(wasm.len()).any(|window| window == wasm));
+        assert_eq!(decrypt(&wrapped, &key).unwrap(), wasm);
+
+        assert!(decrypt(&wrapped, &[8u8; 32]).is_err());
+        let mut tampered = wrapped.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, &key).is_err());
+        assert!(decrypt(wasm, &key).is_err());
+    }
+}
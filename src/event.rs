@@ -0,0 +1,103 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! lifecycle events of all instances of a runtime, so a supervisor can react to them
+//! in one place. subscribe via `Runtime::subscribe()`
+
+use std::{fmt, sync::RwLock};
+
+use wamr_sys::wasm_module_inst_t;
+
+/// identifies an instance during its lifetime. Get one via `Instance::id()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceId(usize);
+
+impl InstanceId {
+    pub(crate) fn new(instance: wasm_module_inst_t) -> Self {
+        InstanceId(instance as usize)
+    }
+}
+
+/// an event emitted by a runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeEvent {
+    /// a module has been instantiated
+    Instantiated {
+        instance: InstanceId,
+        module: String,
+    },
+    /// a call into an instance failed with an exception
+    Trapped {
+        instance: InstanceId,
+        message: String,
+    },
+    /// a WASI guest called `proc_exit`
+    Exited { instance: InstanceId, code: u32 },
+    /// the linear memory has grown, via `Memory::grow()` or a `memory.grow` of the guest
+    MemoryGrown {
+        instance: InstanceId,
+        old_pages: u32,
+        new_pages: u32,
+    },
+    /// an instance has been dropped
+    Destroyed { instance: InstanceId },
+}
+
+type Subscriber = Box<dyn Fn(&RuntimeEvent) + Send + Sync>;
+
+/// the subscribers of a runtime, shared with its instances
+#[derive(Default)]
+pub(crate) struct EventBus {
+    subscribers: RwLock<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    pub(crate) fn subscribe(&self, subscriber: Subscriber) {
+        self.subscribers.write().unwrap().push(subscriber);
+    }
+
+    pub(crate) fn emit(&self, event: RuntimeEvent) {
+        for subscriber in self.subscribers.read().unwrap().iter() {
+            subscriber(&event);
+        }
+    }
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field("subscribers", &self.subscribers.read().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        for _ in 0..2 {
+            let seen = seen.clone();
+            bus.subscribe(Box::new(move |event| {
+                seen.lock().unwrap().push(event.clone());
+            }));
+        }
+
+        let instance = InstanceId(0x1000);
+        bus.emit(RuntimeEvent::Destroyed { instance });
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                RuntimeEvent::Destroyed { instance },
+                RuntimeEvent::Destroyed { instance }
+            ]
+        );
+    }
+}
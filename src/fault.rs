@@ -0,0 +1,515 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! failures injected into the host calls of guests, to test how they handle errors: the
+//! Nth call to a host function or a WASI function traps, returns an error without running,
+//! or runs late. Plan them via `FaultPlan`, and inject them via
+//! `RuntimeBuilder::inject_faults()`
+//!
+//! each .wasm is instrumented while it is loaded, see `instrument`: every call to an
+//! imported function with a fault planned first asks a host function imported from
+//! `MODULE` whether to make it, and skips it to return the planned value. The calls are
+//! counted by instance. An .aot isn't instrumented, and the calls through a table aren't
+//! counted.
+
+use std::{
+    ffi::CString,
+    sync::{Mutex, MutexGuard, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use wamr_sys::{
+    wasm_module_inst_t, wasm_module_t, wasm_runtime_get_module, wasm_runtime_set_exception,
+};
+
+use crate::{
+    binary::{self, write_i32_leb, write_u32_leb, ImportKind, Reader},
+    instrument::{self, Hook},
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+    value::WasmValue,
+    ErrorMessage,
+};
+
+/// the module the instrumented code imports its hook from
+pub const MODULE: &str = "wamr_fault";
+const HOOK: &str = "inject";
+
+/// the type of the hook, `(import: i32, query: i32) -> i64`
+const HOOK_TYPE: [u8; 6] = [0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7e];
+
+// what the hook is asked, whether to skip the call, and what to return instead
+const QUERY_FAULT: i32 = 0;
+const QUERY_RESULT: i32 = 1;
+
+// the value types the parameters of a call may have, in the order of their locals
+const PARAM_TYPES: [u8; 5] = [0x7f, 0x7e, 0x7d, 0x7c, 0x7b];
+
+/// what a fault does to a host call
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultAction {
+    /// trap the calling instance with the message, as a failing host function does
+    Trap(String),
+    /// return the value without calling the function, like an errno for a WASI function,
+    /// or `WasmValue::Void` for a function without results
+    Return(WasmValue),
+    /// call the function once the delay elapsed
+    Delay(Duration),
+}
+
+/// a fault of the calls to an imported function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fault {
+    /// the module of the import, like `env` or `wasi_snapshot_preview1`
+    pub module: String,
+    /// the name of the import
+    pub name: String,
+    /// the call the fault is injected into, counted from 1, by instance
+    pub nth: u32,
+    pub action: FaultAction,
+}
+
+/// the faults to inject. The second `fd_write` of each instance returns `EIO` (29) via
+/// `.fail("wasi_snapshot_preview1", "fd_write", 2, FaultAction::Return(WasmValue::I32(29)))`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultPlan {
+    faults: Vec<Fault>,
+}
+
+impl FaultPlan {
+    pub fn new() -> Self {
+        FaultPlan::default()
+    }
+
+    /// make the `nth` call to the import `module`.`name` of each instance fail as `action`
+    pub fn fail(mut self, module: &str, name: &str, nth: u32, action: FaultAction) -> Self {
+        self.faults.push(Fault {
+            module: String::from(module),
+            name: String::from(name),
+            nth,
+            action,
+        });
+        self
+    }
+
+    pub fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    pub(crate) fn into_faults(self) -> Vec<Fault> {
+        self.faults
+    }
+}
+
+/// an imported function with faults planned
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Target {
+    /// the index of the function
+    import: u32,
+    faults: Vec<Fault>,
+}
+
+/// the value type of `value`, `None` for `WasmValue::Void`
+fn valtype(value: &WasmValue) -> Option<u8> {
+    match value {
+        WasmValue::Void => None,
+        WasmValue::I32(_) => Some(0x7f),
+        WasmValue::I64(_) => Some(0x7e),
+        WasmValue::F32(_) => Some(0x7d),
+        WasmValue::F64(_) => Some(0x7c),
+        WasmValue::V128(_) => Some(0x7b),
+        #[cfg(feature = "stringref")]
+        WasmValue::StringRef(_) => Some(0x67),
+    }
+}
+
+/// the value types of a signature from `binary::type_signatures()`, `None` for a reference
+fn valtypes(signature: &str) -> Option<Vec<u8>> {
+    signature
+        .chars()
+        .map(|c| match c {
+            'i' => Some(0x7f),
+            'I' => Some(0x7e),
+            'f' => Some(0x7d),
+            'F' => Some(0x7c),
+            'V' => Some(0x7b),
+            _ => None,
+        })
+        .collect()
+}
+
+/// the parameter and result types of an import with faults planned
+struct Planned {
+    params: Vec<u8>,
+    result: Option<u8>,
+}
+
+/// `binary` asking the hook before each call to an import of `faults`, and the imports
+/// which are. `binary` as is if it imports none of them
+pub(crate) fn instrument(
+    binary: &[u8],
+    faults: &[Fault],
+) -> Result<(Vec<u8>, Vec<Target>), ErrorMessage> {
+    let signatures = binary::type_signatures(binary)?;
+    let mut targets = Vec::new();
+    let mut planned = Vec::new();
+    let functions = binary::imports(binary)?
+        .into_iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Func(type_index) => Some((import, type_index)),
+            _ => None,
+        });
+    for (index, (import, type_index)) in functions.enumerate() {
+        let matching: Vec<Fault> = faults
+            .iter()
+            .filter(|fault| fault.module == import.module && fault.name == import.name)
+            .cloned()
+            .collect();
+        if matching.is_empty() {
+            planned.push(None);
+            continue;
+        }
+
+        let unsupported = || {
+            message!(
+                "faults can't be injected into {}.{}",
+                import.module,
+                import.name
+            )
+        };
+        let signature = signatures
+            .get(type_index as usize)
+            .ok_or_else(unsupported)?;
+        let (params, results) = signature[1..].split_once(')').ok_or_else(unsupported)?;
+        let params = valtypes(params).ok_or_else(unsupported)?;
+        let results = valtypes(results).ok_or_else(unsupported)?;
+        if results.len() > 1 {
+            return Err(unsupported());
+        }
+        let result = results.first().copied();
+        for fault in &matching {
+            if let FaultAction::Return(value) = &fault.action {
+                if valtype(value) != result {
+                    return Err(message!(
+                        "a fault returns {} from {}.{}, which returns {}",
+                        value.ty(),
+                        import.module,
+                        import.name,
+                        signature
+                    ));
+                }
+            }
+        }
+
+        targets.push(Target {
+            import: index as u32,
+            faults: matching,
+        });
+        planned.push(Some(Planned { params, result }));
+    }
+    if targets.is_empty() {
+        return Ok((binary.to_vec(), targets));
+    }
+
+    // enough locals of each type to keep the parameters of any call in
+    let counts = PARAM_TYPES.map(|valtype| {
+        let count = |planned: &Planned| planned.params.iter().filter(|t| **t == valtype).count();
+        planned.iter().flatten().map(count).max().unwrap_or(0)
+    });
+    let locals: Vec<u8> = PARAM_TYPES
+        .iter()
+        .zip(counts)
+        .flat_map(|(valtype, count)| vec![*valtype; count])
+        .collect();
+    let hook = Hook {
+        module: MODULE,
+        name: HOOK,
+        func_type: &HOOK_TYPE,
+        locals: &locals,
+    };
+
+    // whether the instruction before is a call with a fault planned
+    let mut ending = false;
+    let instrumented = instrument::instrument(binary, &hook, |site, out| {
+        if ending {
+            out.push(0x0b);
+            ending = false;
+        }
+        if site.opcode != 0x10 {
+            return Ok(());
+        }
+        let callee = Reader::new(&site.bytes[1..]).read_u32_leb()?;
+        let Some(Some(planned)) = planned.get(callee as usize) else {
+            return Ok(());
+        };
+
+        // the local of each parameter
+        let mut next = [0; PARAM_TYPES.len()];
+        let params: Vec<u32> = planned
+            .params
+            .iter()
+            .map(|valtype| {
+                let kind = PARAM_TYPES.iter().position(|t| t == valtype).unwrap();
+                let local = site.locals + counts[..kind].iter().sum::<usize>() as u32 + next[kind];
+                next[kind] += 1;
+                local
+            })
+            .collect();
+        let call_hook = |out: &mut Vec<u8>, query: i32| {
+            // i32.const callee, i32.const query, call hook
+            out.push(0x41);
+            write_i32_leb(out, callee as i32);
+            out.push(0x41);
+            write_i32_leb(out, query);
+            out.push(0x10);
+            write_u32_leb(out, site.hook);
+        };
+
+        // local.set, the parameters off the stack
+        for local in params.iter().rev() {
+            out.push(0x21);
+            write_u32_leb(out, *local);
+        }
+        // i32.wrap_i64, if
+        call_hook(out, QUERY_FAULT);
+        out.extend_from_slice(&[0xa7, 0x04, planned.result.unwrap_or(0x40)]);
+        call_hook(out, QUERY_RESULT);
+        match planned.result {
+            // i32.wrap_i64
+            Some(0x7f) => out.push(0xa7),
+            Some(0x7e) => {}
+            // i32.wrap_i64, f32.reinterpret_i32
+            Some(0x7d) => out.extend_from_slice(&[0xa7, 0xbe]),
+            // f64.reinterpret_i64
+            Some(0x7c) => out.push(0xbf),
+            // drop, a v128 result is only ever void
+            _ => out.push(0x1a),
+        }
+        // else, local.get, the parameters back for the call
+        out.push(0x05);
+        for local in &params {
+            out.push(0x20);
+            write_u32_leb(out, *local);
+        }
+        ending = true;
+        Ok(())
+    })?;
+    Ok((instrumented, targets))
+}
+
+/// the imports with faults planned of the instrumented modules, by the address of the module
+static MODULES: Mutex<Vec<(usize, Vec<Target>)>> = Mutex::new(Vec::new());
+
+fn modules() -> MutexGuard<'static, Vec<(usize, Vec<Target>)>> {
+    MODULES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// remember the imports with faults planned of `module`
+pub(crate) fn loaded(module: wasm_module_t, targets: Vec<Target>) {
+    modules().push((module as usize, targets));
+}
+
+/// forget an unloaded module
+pub(crate) fn unloaded(module: wasm_module_t) {
+    modules().retain(|(address, _)| *address != module as usize);
+}
+
+/// the calls of an instance to the imports with faults planned
+#[derive(Default)]
+struct Calls {
+    /// the calls to each import so far, by the index of the import
+    counts: Vec<(u32, u32)>,
+    /// the value the call skipped returns
+    injected: Option<WasmValue>,
+}
+
+static INSTANCES: Mutex<Vec<(usize, Calls)>> = Mutex::new(Vec::new());
+
+fn instances() -> MutexGuard<'static, Vec<(usize, Calls)>> {
+    INSTANCES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    instances().retain(|(address, _)| *address != instance as usize);
+}
+
+/// count the call of `instance` to `import`, and return the action of its fault, if any
+fn count(instance: wasm_module_inst_t, module: wasm_module_t, import: u32) -> Option<FaultAction> {
+    let mut instances = instances();
+    let index = match instances
+        .iter()
+        .position(|(address, _)| *address == instance as usize)
+    {
+        Some(index) => index,
+        None => {
+            instances.push((instance as usize, Calls::default()));
+            instances.len() - 1
+        }
+    };
+    let calls = &mut instances[index].1;
+    let nth = match calls.counts.iter_mut().find(|(index, _)| *index == import) {
+        Some((_, count)) => {
+            *count += 1;
+            *count
+        }
+        None => {
+            calls.counts.push((import, 1));
+            1
+        }
+    };
+
+    let modules = modules();
+    let targets = modules
+        .iter()
+        .find(|(address, _)| *address == module as usize)
+        .map(|(_, targets)| targets.as_slice())
+        .unwrap_or_default();
+    let target = targets.iter().find(|target| target.import == import)?;
+    let fault = target.faults.iter().find(|fault| fault.nth == nth)?;
+    if let FaultAction::Return(value) = &fault.action {
+        calls.injected = Some(*value);
+    }
+    Some(fault.action.clone())
+}
+
+/// the host function the instrumented code asks
+pub(crate) struct FaultInjector;
+
+impl NativeModule for FaultInjector {
+    fn module_name(&self) -> &str {
+        MODULE
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(HOOK, inject as extern "C" fn(ExecEnv, i32, i32) -> i64);
+    }
+}
+
+extern "C" fn inject(env: ExecEnv, import: i32, query: i32) -> i64 {
+    let instance = env.module_inst();
+    if query == QUERY_RESULT {
+        let mut instances = instances();
+        let calls = instances
+            .iter_mut()
+            .find(|(address, _)| *address == instance as usize);
+        let injected = calls.and_then(|(_, calls)| calls.injected.take());
+        return match injected {
+            Some(WasmValue::I32(value)) => value as u32 as i64,
+            Some(WasmValue::I64(value)) => value,
+            Some(WasmValue::F32(value)) => value.to_bits() as i64,
+            Some(WasmValue::F64(value)) => value.to_bits() as i64,
+            _ => 0,
+        };
+    }
+
+    let module = unsafe { wasm_runtime_get_module(instance) };
+    match count(instance, module, import as u32) {
+        Some(FaultAction::Trap(message)) => {
+            let message = CString::new(message.replace('\0', " ")).unwrap();
+            unsafe { wasm_runtime_set_exception(instance, message.as_ptr()) };
+            0
+        }
+        Some(FaultAction::Return(_)) => 1,
+        Some(FaultAction::Delay(delay)) => {
+            thread::sleep(delay);
+            0
+        }
+        None => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function,
+        host_function::{ParamTy, ResultTy},
+        instance::Instance,
+        instruction::decode_body,
+        module::Module,
+        runtime::Runtime,
+        RuntimeError,
+    };
+    use std::ffi::c_void;
+
+    // (module
+    //   (import "host" "next" (func $next (param i32) (result i32)))
+    //   (func (export "run") (param i32) (result i32)
+    //     (call $next (local.get 0))))
+    const BINARY: [u8; 70] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01,
+        0x7f, 0x02, 0x0d, 0x01, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x04, 0x6e, 0x65, 0x78, 0x74, 0x00,
+        0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x72, 0x75, 0x6e, 0x00, 0x01, 0x0a,
+        0x08, 0x01, 0x06, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61, 0x6d,
+        0x65, 0x01, 0x07, 0x01, 0x00, 0x04, 0x6e, 0x65, 0x78, 0x74,
+    ];
+
+    #[test]
+    fn test_instrument() {
+        let plan =
+            FaultPlan::new().fail("host", "next", 2, FaultAction::Return(WasmValue::I32(-1)));
+        let (instrumented, targets) = instrument(&BINARY, plan.faults()).unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].import, 0);
+        let bodies = binary::function_bodies(&instrumented).unwrap();
+        let instructions = decode_body(&instrumented, bodies[0].clone()).unwrap();
+        let opcodes: Vec<u32> = instructions.iter().map(|i| i.opcode).collect();
+        // local.get 0, local.set, the hook, if, the hook, else, local.get, call $next, end
+        assert_eq!(
+            opcodes,
+            [
+                0x20, 0x21, 0x41, 0x41, 0x10, 0xa7, 0x04, 0x41, 0x41, 0x10, 0xa7, 0x05, 0x20, 0x10,
+                0x0b, 0x0b
+            ]
+        );
+
+        let unrelated = FaultPlan::new().fail("env", "next", 1, FaultAction::Trap(String::new()));
+        let (unchanged, targets) = instrument(&BINARY, unrelated.faults()).unwrap();
+        assert_eq!((&unchanged[..], targets.len()), (&BINARY[..], 0));
+
+        let mismatched =
+            FaultPlan::new().fail("host", "next", 1, FaultAction::Return(WasmValue::Void));
+        assert!(instrument(&BINARY, mismatched.faults()).is_err());
+    }
+
+    extern "C" fn next(_env: ExecEnv, value: i32) -> i32 {
+        value + 1
+    }
+
+    #[test]
+    fn test_inject_faults() -> Result<(), RuntimeError> {
+        let plan = FaultPlan::new()
+            .fail("host", "next", 2, FaultAction::Return(WasmValue::I32(-1)))
+            .fail(
+                "host",
+                "next",
+                3,
+                FaultAction::Trap(String::from("injected")),
+            );
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .inject_faults(plan)
+            .register_host_function("next", next as *mut c_void, &[ParamTy::I32], ResultTy::I32)
+            .build()?;
+        let module = Module::from_buf(&runtime, &BINARY, "fault")?;
+        let instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+        let run = Function::find_export_func(&instance, "run")?;
+        let params = vec![WasmValue::I32(1)];
+        assert_eq!(run.call(&instance, &params)?, WasmValue::I32(2));
+        assert_eq!(run.call(&instance, &params)?, WasmValue::I32(-1));
+        match run.call(&instance, &params) {
+            Err(RuntimeError::ExecutionError(message)) => assert!(message.contains("injected")),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(run.call(&instance, &params)?, WasmValue::I32(2));
+
+        // the calls are counted by instance
+        let other = Instance::new(&runtime, &module, 1024 * 64, ())?;
+        assert_eq!(run.call(&other, &params)?, WasmValue::I32(2));
+        assert_eq!(run.call(&other, &params)?, WasmValue::I32(-1));
+        Ok(())
+    }
+}
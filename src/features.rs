@@ -0,0 +1,124 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the WebAssembly proposals supported by the linked WAMR build.
+//! get them via `Runtime::features()`
+
+use std::{ffi::c_char, sync::OnceLock};
+
+use wamr_sys::{wasm_runtime_load, wasm_runtime_unload};
+
+use crate::helper::DEFAULT_ERROR_BUF_SIZE;
+
+// (module (func (drop (v128.const i64x2 0 0))))
+const SIMD_PROBE: [u8; 43] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02,
+    0x01, 0x00, 0x0a, 0x17, 0x01, 0x15, 0x00, 0xfd, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1a, 0x0b,
+];
+
+// (module (memory 1 1 shared))
+const THREADS_PROBE: [u8; 14] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+];
+
+// (module (func (return_call 0)))
+const TAIL_CALL_PROBE: [u8; 26] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x03, 0x02,
+    0x01, 0x00, 0x0a, 0x06, 0x01, 0x04, 0x00, 0x12, 0x00, 0x0b,
+];
+
+// (module (type (struct)))
+const GC_PROBE: [u8; 13] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x03, 0x01, 0x5f, 0x00,
+];
+
+// (module (memory i64 1))
+const MEMORY64_PROBE: [u8; 13] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x04, 0x01,
+];
+
+// (module (tag))
+const EXCEPTION_HANDLING_PROBE: [u8; 19] = [
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x0d, 0x03,
+    0x01, 0x00, 0x00,
+];
+
+/// which proposals the linked WAMR build supports, beside the MVP.
+///
+/// WAMR doesn't report how it has been built, so each proposal is probed by loading a
+/// tiny module using it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WasmFeatures {
+    /// 128-bit SIMD
+    pub simd: bool,
+    /// shared memories and atomic instructions
+    pub threads: bool,
+    /// `return_call` and `return_call_indirect`
+    pub tail_call: bool,
+    /// struct and array types
+    pub gc: bool,
+    /// memories indexed by an i64
+    pub memory64: bool,
+    /// tags, and throwing and catching exceptions
+    pub exception_handling: bool,
+}
+
+impl WasmFeatures {
+    /// probe once, since the features don't change while the process is running.
+    /// The runtime must be initialized
+    pub(crate) fn detect() -> Self {
+        static FEATURES: OnceLock<WasmFeatures> = OnceLock::new();
+        *FEATURES.get_or_init(|| WasmFeatures {
+            simd: loads(&SIMD_PROBE),
+            threads: loads(&THREADS_PROBE),
+            tail_call: loads(&TAIL_CALL_PROBE),
+            gc: loads(&GC_PROBE),
+            memory64: loads(&MEMORY64_PROBE),
+            exception_handling: loads(&EXCEPTION_HANDLING_PROBE),
+        })
+    }
+}
+
+fn loads(probe: &[u8]) -> bool {
+    // WAMR may patch the content while loading
+    let mut content = probe.to_vec();
+    let mut error_buf = [0 as c_char; DEFAULT_ERROR_BUF_SIZE];
+    let module = unsafe {
+        wasm_runtime_load(
+            content.as_mut_ptr(),
+            content.len() as u32,
+            error_buf.as_mut_ptr(),
+            error_buf.len() as u32,
+        )
+    };
+    if module.is_null() {
+        return false;
+    }
+    unsafe { wasm_runtime_unload(module) };
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{module::Module, runtime::Runtime};
+
+    #[test]
+    fn test_runtime_features() {
+        let runtime = Runtime::new().unwrap();
+        let features = runtime.features();
+
+        assert_eq!(
+            Module::from_buf(&runtime, &SIMD_PROBE, "simd").is_ok(),
+            features.simd
+        );
+        assert_eq!(
+            Module::from_buf(&runtime, &THREADS_PROBE, "threads").is_ok(),
+            features.threads
+        );
+        assert_eq!(runtime.features(), features);
+    }
+}
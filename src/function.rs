@@ -6,18 +6,110 @@
 //! an exported wasm function.
 //! get one via `Function::find_export_func()`
 
-use std::ffi::CString;
+#[cfg(feature = "threads")]
+use std::ffi::CStr;
+use std::{
+    cell::Cell,
+    ffi::CString,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "threads")]
+use wamr_sys::wasm_module_inst_t;
+#[cfg(feature = "gc")]
+use wamr_sys::wasm_valkind_enum_WASM_V128;
 use wamr_sys::{
-    wasm_exec_env_t, wasm_func_get_result_count, wasm_func_get_result_types, wasm_function_inst_t,
-    wasm_runtime_call_wasm, wasm_runtime_get_exception, wasm_runtime_get_exec_env_singleton,
-    wasm_runtime_lookup_function, wasm_valkind_enum_WASM_F32, wasm_valkind_enum_WASM_F64,
-    wasm_valkind_enum_WASM_I32, wasm_valkind_enum_WASM_I64, wasm_valkind_t,
+    wasm_exec_env_t, wasm_func_get_param_count, wasm_func_get_param_types,
+    wasm_func_get_result_count, wasm_func_get_result_types, wasm_function_inst_t,
+    wasm_runtime_call_wasm, wasm_runtime_create_exec_env, wasm_runtime_destroy_exec_env,
+    wasm_runtime_get_exception, wasm_runtime_lookup_function, wasm_valkind_enum_WASM_F32,
+    wasm_valkind_enum_WASM_F64, wasm_valkind_enum_WASM_I32, wasm_valkind_enum_WASM_I64,
+    wasm_valkind_t,
+};
+
+#[cfg(feature = "gc")]
+use crate::gc::GcRef;
+use crate::{
+    allocator,
+    event::RuntimeEvent,
+    helper::exception_to_string,
+    instance::Instance,
+    value::{cells_to_bytes, ValueType, WasmType, WasmValue},
+    RuntimeError,
 };
+#[cfg(feature = "threads")]
+use crate::{platform::CpuClock, sampler};
+
+/// the size of the argv kept on the stack by `Function::call_args()`, in 32-bit cells
+const STACK_ARGV_CELLS: usize = 16;
+
+/// a result takes up to 4 cells, as a v128
+const MAX_RESULT_CELLS: usize = 4;
+
+/// a snapshot of the statistics of the calls to a `Function`, see
+/// `Function::with_stats()`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallStats {
+    pub calls: u64,
+    /// the calls which trapped, WASI `proc_exit` included
+    pub traps: u64,
+    /// the wall-clock time spent in all calls
+    pub total_time: Duration,
+    /// the wall-clock time of the longest call
+    pub max_time: Duration,
+}
+
+impl CallStats {
+    /// the wall-clock time of a call on average, zero without calls
+    pub fn mean_time(&self) -> Duration {
+        match self.calls {
+            0 => Duration::ZERO,
+            calls => Duration::from_nanos((self.total_time.as_nanos() / calls as u128) as u64),
+        }
+    }
+}
+
+// a `Function` stays on the thread of its instance, so cells are enough
+#[derive(Debug, Default)]
+struct CallRecorder {
+    calls: Cell<u64>,
+    traps: Cell<u64>,
+    total_nanos: Cell<u64>,
+    max_nanos: Cell<u64>,
+}
+
+impl CallRecorder {
+    fn record(&self, elapsed: Duration, trapped: bool) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.calls.set(self.calls.get() + 1);
+        self.traps.set(self.traps.get() + trapped as u64);
+        self.total_nanos
+            .set(self.total_nanos.get().saturating_add(nanos));
+        self.max_nanos.set(self.max_nanos.get().max(nanos));
+    }
 
-use crate::{helper::exception_to_string, instance::Instance, value::WasmValue, RuntimeError};
+    fn snapshot(&self) -> CallStats {
+        CallStats {
+            calls: self.calls.get(),
+            traps: self.traps.get(),
+            total_time: Duration::from_nanos(self.total_nanos.get()),
+            max_time: Duration::from_nanos(self.max_nanos.get()),
+        }
+    }
+
+    fn take(&self) -> CallStats {
+        CallStats {
+            calls: self.calls.take(),
+            traps: self.traps.take(),
+            total_time: Duration::from_nanos(self.total_nanos.take()),
+            max_time: Duration::from_nanos(self.max_nanos.take()),
+        }
+    }
+}
 
 pub struct Function {
     function: wasm_function_inst_t,
+    recorder: Option<CallRecorder>,
 }
 
 impl Function {
@@ -32,20 +124,79 @@ impl Function {
             unsafe { wasm_runtime_lookup_function(instance.get_inner_instance(), name.as_ptr()) };
         match function.is_null() {
             true => Err(RuntimeError::FunctionNotFound),
-            false => Ok(Function { function }),
+            false => Ok(Function {
+                function,
+                recorder: None,
+            }),
         }
     }
 
-    #[allow(non_upper_case_globals)]
-    fn parse_result<T>(
-        &self,
+    /// record the statistics of the calls from now on, like to monitor an entry point in
+    /// production. It only takes a couple of counters per call, unlike profiling
+    pub fn with_stats(mut self) -> Self {
+        self.recorder.get_or_insert_with(CallRecorder::default);
+        self
+    }
+
+    /// the statistics of the calls so far, `None` unless recorded via `with_stats()`
+    pub fn stats(&self) -> Option<CallStats> {
+        self.recorder.as_ref().map(CallRecorder::snapshot)
+    }
+
+    /// return the statistics of the calls so far and start again from zero, `None` unless
+    /// recorded via `with_stats()`
+    pub fn reset_stats(&self) -> Option<CallStats> {
+        self.recorder.as_ref().map(CallRecorder::take)
+    }
+
+    /// the function with the Rust types `P`, a tuple of its parameters, and `R`, its
+    /// result or `()`, checked once against its wasm types, so a call can't get them wrong,
+    /// like `function.typed::<(i32, i32), i32>(&instance)?.call(&instance, (8, 8))`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if the types differ.
+    pub fn typed<P: WasmParams, R: WasmType, T>(
+        self,
         instance: &Instance<T>,
-        result: Vec<u32>,
-    ) -> Result<WasmValue, RuntimeError> {
+    ) -> Result<TypedFunction<P, R>, RuntimeError> {
+        let inner_instance = instance.get_inner_instance();
+        let param_count = unsafe { wasm_func_get_param_count(self.function, inner_instance) };
+        let mut param_kinds: Vec<wasm_valkind_t> = vec![0; param_count as usize];
+        unsafe {
+            wasm_func_get_param_types(self.function, inner_instance, param_kinds.as_mut_ptr())
+        };
+        let params = param_kinds
+            .into_iter()
+            .map(ValueType::from_kind)
+            .collect::<Option<Vec<_>>>();
+        let result = match self.result_kind(instance) {
+            Some(kind) => ValueType::from_kind(kind),
+            None => Some(ValueType::Void),
+        };
+
+        let expected = P::types();
+        if params.as_ref() != Some(&expected) || result != Some(R::TYPE) {
+            return Err(RuntimeError::TypeMismatch(message!(
+                "expect a function {:?} -> {}, got {:?} -> {:?}",
+                expected,
+                R::TYPE,
+                params,
+                result
+            )));
+        }
+        Ok(TypedFunction {
+            function: self,
+            _types: PhantomData,
+        })
+    }
+
+    /// the kind of the result, `None` for a function without one
+    fn result_kind<T>(&self, instance: &Instance<T>) -> Option<wasm_valkind_t> {
         let result_count =
             unsafe { wasm_func_get_result_count(self.function, instance.get_inner_instance()) };
         if result_count == 0 {
-            return Ok(WasmValue::Void);
+            return None;
         }
 
         let mut result_type: wasm_valkind_t = 0;
@@ -56,12 +207,32 @@ impl Function {
                 &mut result_type,
             );
         }
+        Some(result_type)
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn parse_result(
+        result_kind: Option<wasm_valkind_t>,
+        result: &[u32],
+    ) -> Result<WasmValue, RuntimeError> {
+        let result_type = match result_kind {
+            Some(result_type) => result_type,
+            None => return Ok(WasmValue::Void),
+        };
 
         match result_type as u32 {
-            wasm_valkind_enum_WASM_I32 => Ok(WasmValue::decode_to_i32(result)),
-            wasm_valkind_enum_WASM_I64 => Ok(WasmValue::decode_to_i64(result)),
-            wasm_valkind_enum_WASM_F32 => Ok(WasmValue::decode_to_f32(result)),
-            wasm_valkind_enum_WASM_F64 => Ok(WasmValue::decode_to_f64(result)),
+            wasm_valkind_enum_WASM_I32 => {
+                Ok(WasmValue::I32(i32::from_ne_bytes(cells_to_bytes(result))))
+            }
+            wasm_valkind_enum_WASM_I64 => {
+                Ok(WasmValue::I64(i64::from_ne_bytes(cells_to_bytes(result))))
+            }
+            wasm_valkind_enum_WASM_F32 => {
+                Ok(WasmValue::F32(f32::from_ne_bytes(cells_to_bytes(result))))
+            }
+            wasm_valkind_enum_WASM_F64 => {
+                Ok(WasmValue::F64(f64::from_ne_bytes(cells_to_bytes(result))))
+            }
             _ => Err(RuntimeError::NotImplemented),
         }
     }
@@ -72,44 +243,470 @@ impl Function {
     /// # Error
     ///
     /// Return `RuntimeError::ExecutionError` if failed.
+    #[allow(clippy::ptr_arg)]
     pub fn call<T>(
         &self,
         instance: &Instance<T>,
         params: &Vec<WasmValue>,
     ) -> Result<WasmValue, RuntimeError> {
-        // params -> Vec<u32>
-        let mut argv = Vec::new();
+        self.call_args(instance, params)
+    }
+
+    /// execute an export function without allocating on the heap, as long as the
+    /// parameters and the result fit in a small argv kept on the stack.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if failed.
+    pub fn call_args<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+    ) -> Result<WasmValue, RuntimeError> {
+        self.call_in(instance, instance.exec_env().as_raw(), params)
+    }
+
+    /// execute an export function with a stack of `stack_size` bytes, instead of the
+    /// stack size the instance has been instantiated with.
+    ///
+    /// the call runs in a temporary execution environment, so one instance can run deeply
+    /// recursive entry points without every call paying for a large stack.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if failed.
+    pub fn call_with_stack<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        stack_size: u32,
+    ) -> Result<WasmValue, RuntimeError> {
+        let inner_instance = instance.get_inner_instance();
+        let exec_env = unsafe { wasm_runtime_create_exec_env(inner_instance, stack_size) };
+        if exec_env.is_null() {
+            return Err(RuntimeError::ExecutionError(message!(
+                "failed to create an execution environment",
+            )));
+        }
+        // host functions find the user data through the execution environment
+        instance.exec_env().share_user_data(exec_env);
+
+        let result = self.call_in(instance, exec_env, params);
+        unsafe { wasm_runtime_destroy_exec_env(exec_env) };
+        result
+    }
+
+    /// execute an export function returning a reference of the GC proposal, like a struct
+    /// or an array, `None` for a null reference. See `gc` to inspect it. The reference
+    /// borrows the instance mutably, so the instance can't run again, and collect the
+    /// object, while it's alive
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if the function returns a number.
+    /// Return `RuntimeError::ExecutionError` if failed.
+    #[cfg(feature = "gc")]
+    pub fn call_gc<'a, T>(
+        &self,
+        instance: &'a mut Instance<T>,
+        params: &[WasmValue],
+    ) -> Result<Option<GcRef<'a>>, RuntimeError> {
+        #[allow(non_upper_case_globals)]
+        match self.result_kind(instance).map(|kind| kind as u32) {
+            None
+            | Some(
+                wasm_valkind_enum_WASM_I32
+                | wasm_valkind_enum_WASM_I64
+                | wasm_valkind_enum_WASM_F32
+                | wasm_valkind_enum_WASM_F64
+                | wasm_valkind_enum_WASM_V128,
+            ) => {
+                return Err(RuntimeError::TypeMismatch(message!(
+                    "expect a function returning a reference",
+                )))
+            }
+            Some(_) => {}
+        }
+
+        let exec_env = instance.exec_env().as_raw();
+        let param_cells: usize = params.iter().map(WasmValue::cell_count).sum();
+        let mut argv = vec![0u32; param_cells.max(MAX_RESULT_CELLS)];
+        // a reference takes the cells of a pointer
+        self.call_with_argv(instance, exec_env, None, params, &mut argv)?;
+        let object = usize::from_ne_bytes(cells_to_bytes(&argv));
+        Ok(GcRef::new(instance, object as _))
+    }
+
+    /// execute an export function with `state` as the host data its host functions see,
+    /// instead of the data of the instance, so per-call state doesn't have to live in `T`.
+    /// Host functions borrow it via a `Caller<S>`, and the data of the instance is
+    /// restored once the call returns.
+    ///
+    /// # Safety
+    ///
+    /// the call mustn't be made by a host function while a `Caller` borrows the data of
+    /// the instance. Host functions the call reaches that borrow a `Caller<T>` panic,
+    /// as the instance has no host data of type `T` during the call.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if failed.
+    pub unsafe fn call_with_state<T, S: 'static>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        state: &mut S,
+    ) -> Result<WasmValue, RuntimeError> {
+        let exec_env = instance.exec_env();
+        // lent for the call only, so the instance never drops it
+        let _lent = exec_env.lend_user_data(state as *mut S);
+        self.call_in(instance, exec_env.as_raw(), params)
+    }
+
+    /// execute an export function, and terminate it once `limit` elapsed.
+    ///
+    /// a sampler thread enforces the limit from outside, so it works in every running
+    /// mode. The limit is in wall-clock time, so time the thread spends descheduled or
+    /// blocked in host functions counts as well, see `call_with_cpu_time_limit()`
+    /// otherwise. WAMR only checks for termination while running guest code when its
+    /// thread manager is built, hence the `threads` feature.
+    ///
+    /// A call which returns before the sampler terminates it keeps its result, even
+    /// when the limit elapses in between.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TimeLimitExceeded` if the call has been terminated.
+    /// Return `RuntimeError::ExecutionError` if failed.
+    #[cfg(feature = "threads")]
+    pub fn call_with_time_limit<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        limit: Duration,
+    ) -> Result<WasmValue, RuntimeError> {
+        let deadline = sampler::arm(instance.get_inner_instance(), limit);
+        self.call_with_deadline(instance, params, deadline)
+            .unwrap_or(Err(RuntimeError::TimeLimitExceeded(limit)))
+    }
+
+    /// execute an export function, and terminate it once the calling thread used `limit`
+    /// of CPU time.
+    ///
+    /// like `call_with_time_limit()`, a sampler thread enforces the limit, so it works in
+    /// JIT and AOT modes, where instructions aren't counted. Only the time the thread runs
+    /// on a CPU counts, in the guest or in host functions, not the time it is descheduled
+    /// or blocked. The sampler reads the CPU clock of the thread, which only Linux gives.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CpuTimeExceeded` if the call has been terminated.
+    /// Return `RuntimeError::NotImplemented` if threads have no CPU clock on the host.
+    /// Return `RuntimeError::ExecutionError` if failed.
+    #[cfg(feature = "threads")]
+    pub fn call_with_cpu_time_limit<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        limit: Duration,
+    ) -> Result<WasmValue, RuntimeError> {
+        let clock = CpuClock::current().ok_or(RuntimeError::NotImplemented)?;
+        let deadline = sampler::arm_cpu_time(instance.get_inner_instance(), clock, limit);
+        self.call_with_deadline(instance, params, deadline)
+            .unwrap_or(Err(RuntimeError::CpuTimeExceeded(limit)))
+    }
+
+    /// a call the sampler terminates at `deadline`, `None` if it did. A call failing for
+    /// another reason keeps its error, even when the deadline expired in between
+    #[cfg(feature = "threads")]
+    fn call_with_deadline<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        deadline: u64,
+    ) -> Option<Result<WasmValue, RuntimeError>> {
+        let result = self.call_args(instance, params);
+        let stopped = result.is_err() && is_terminated(instance.get_inner_instance());
+        // stop the sampler first, it can't terminate the instance after `disarm()`
+        let terminated = sampler::disarm(deadline);
+        if terminated {
+            // the termination may land after the call returned, don't leave it pending
+            unsafe { wamr_sys::wasm_runtime_clear_exception(instance.get_inner_instance()) };
+        }
+        match stopped && terminated {
+            true => None,
+            false => Some(result),
+        }
+    }
+
+    /// execute an export function, and trap it once it executed `limit` instructions.
+    ///
+    /// the interpreter counts the instructions itself, so the limit is deterministic, unlike
+    /// a time limit, but AOT and JIT code isn't bounded.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::InstructionLimitExceeded` if the call has been trapped.
+    /// Return `RuntimeError::ExecutionError` if failed.
+    #[cfg(feature = "instruction-metering")]
+    pub fn call_with_instruction_limit<T>(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+        limit: u32,
+    ) -> Result<WasmValue, RuntimeError> {
+        let exec_env = instance.exec_env().as_raw();
+        let limit = limit.min(i32::MAX as u32);
+        unsafe { wamr_sys::wasm_runtime_set_instruction_count_limit(exec_env, limit as _) };
+        let result = self.call_in(instance, exec_env, params);
+        // unlimited again, for the other calls
+        unsafe { wamr_sys::wasm_runtime_set_instruction_count_limit(exec_env, -1) };
+        match result {
+            Err(RuntimeError::ExecutionError(exception))
+                if exception.contains("instruction limit exceeded") =>
+            {
+                unsafe { wamr_sys::wasm_runtime_clear_exception(instance.get_inner_instance()) };
+                Err(RuntimeError::InstructionLimitExceeded(limit))
+            }
+            result => result,
+        }
+    }
+
+    /// execute an export function once for each set of parameters, in order, and collect
+    /// the results.
+    ///
+    /// the execution environment, the result type and the argv are looked up or allocated
+    /// once for the whole batch, so the cost of a call comes down to the call itself. The
+    /// optional hooks of a call, like NaN canonicalization, watchpoints or a
+    /// `ResourceAccount`, are only run if the instance or the function sets any up. A
+    /// failed call doesn't stop the batch.
+    pub fn call_batch<T>(
+        &self,
+        instance: &Instance<T>,
+        batch: &[&[WasmValue]],
+    ) -> Vec<Result<WasmValue, RuntimeError>> {
+        let _call = instance.enter_call();
+        let exec_env = instance.exec_env().as_raw();
+        let result_kind = self.result_kind(instance);
+        let argv_cells = batch
+            .iter()
+            .map(|params| params.iter().map(WasmValue::cell_count).sum())
+            .fold(MAX_RESULT_CELLS, usize::max);
+        let instrumented = instance.is_instrumented() || self.recorder.is_some();
+
+        let mut argv = vec![0u32; argv_cells];
+        batch
+            .iter()
+            .map(|params| match instrumented {
+                true => self.call_with_argv(instance, exec_env, result_kind, params, &mut argv),
+                false => self.call_plain(instance, exec_env, result_kind, params, &mut argv),
+            })
+            .collect()
+    }
+
+    fn call_in<T>(
+        &self,
+        instance: &Instance<T>,
+        exec_env: wasm_exec_env_t,
+        params: &[WasmValue],
+    ) -> Result<WasmValue, RuntimeError> {
+        let param_cells: usize = params.iter().map(WasmValue::cell_count).sum();
+        let argv_cells = param_cells.max(MAX_RESULT_CELLS);
+        let result_kind = self.result_kind(instance);
+
+        let _call = instance.enter_call();
+        if argv_cells <= STACK_ARGV_CELLS {
+            let mut argv = [0u32; STACK_ARGV_CELLS];
+            self.call_with_argv(instance, exec_env, result_kind, params, &mut argv)
+        } else {
+            let mut argv = vec![0u32; argv_cells];
+            self.call_with_argv(instance, exec_env, result_kind, params, &mut argv)
+        }
+    }
+
+    /// a call with all of its hooks
+    fn call_with_argv<T>(
+        &self,
+        instance: &Instance<T>,
+        exec_env: wasm_exec_env_t,
+        result_kind: Option<wasm_valkind_t>,
+        params: &[WasmValue],
+        argv: &mut [u32],
+    ) -> Result<WasmValue, RuntimeError> {
+        let mut argc = 0;
         for p in params {
-            argv.append(&mut p.encode());
+            argc += match instance.canonicalize_nans() {
+                true => p.canonicalize_nan().encode_into(&mut argv[argc..]),
+                false => p.encode_into(&mut argv[argc..]),
+            };
         }
 
-        let argc = params.len();
-        let call_result: bool;
-        unsafe {
-            let exec_env: wasm_exec_env_t =
-                wasm_runtime_get_exec_env_singleton(instance.get_inner_instance());
-            call_result =
-                wasm_runtime_call_wasm(exec_env, self.function, argc as u32, argv.as_mut_ptr());
+        let _hints = instance.apply_scheduling_hints()?;
+        let started = Instant::now();
+        let call_result = unsafe {
+            wasm_runtime_call_wasm(exec_env, self.function, argc as u32, argv.as_mut_ptr())
         };
+        let elapsed = started.elapsed();
+        allocator::poll_pool_watermarks();
+        instance.check_watchpoints();
+        if let Some(account) = instance.resource_account() {
+            account
+                .counters()
+                .record_call(elapsed, !call_result, instance.memory().data_size());
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(elapsed, !call_result);
+        }
+
+        let result = Self::call_result(instance, call_result, result_kind, argv)?;
+        match instance.canonicalize_nans() {
+            true => Ok(result.canonicalize_nan()),
+            false => Ok(result),
+        }
+    }
 
+    /// a call without the optional hooks, for an instance which sets none up
+    fn call_plain<T>(
+        &self,
+        instance: &Instance<T>,
+        exec_env: wasm_exec_env_t,
+        result_kind: Option<wasm_valkind_t>,
+        params: &[WasmValue],
+        argv: &mut [u32],
+    ) -> Result<WasmValue, RuntimeError> {
+        let mut argc = 0;
+        for p in params {
+            argc += p.encode_into(&mut argv[argc..]);
+        }
+
+        let call_result = unsafe {
+            wasm_runtime_call_wasm(exec_env, self.function, argc as u32, argv.as_mut_ptr())
+        };
+        Self::call_result(instance, call_result, result_kind, argv)
+    }
+
+    /// the result of a call, or its exception, reported as an event
+    fn call_result<T>(
+        instance: &Instance<T>,
+        call_result: bool,
+        result_kind: Option<wasm_valkind_t>,
+        argv: &[u32],
+    ) -> Result<WasmValue, RuntimeError> {
         if !call_result {
-            unsafe {
-                let exception_c = wasm_runtime_get_exception(instance.get_inner_instance());
-                return Err(RuntimeError::ExecutionError(exception_to_string(
-                    exception_c,
-                )));
+            let exception = unsafe {
+                exception_to_string(wasm_runtime_get_exception(instance.get_inner_instance()))
+            };
+            match wasi_exit_code(instance, &exception) {
+                Some(code) => instance.emit(RuntimeEvent::Exited {
+                    instance: instance.id(),
+                    code,
+                }),
+                None => instance.emit(RuntimeEvent::Trapped {
+                    instance: instance.id(),
+                    message: exception.clone(),
+                }),
             }
+            return Err(RuntimeError::ExecutionError(
+                message!(code: "exception raised by WAMR", exception),
+            ));
         }
 
-        self.parse_result(instance, argv)
+        Self::parse_result(result_kind, argv)
     }
 }
 
+/// the parameters of a `TypedFunction`, a tuple of up to 8 `WasmType`s
+pub trait WasmParams {
+    fn types() -> Vec<ValueType>;
+    fn into_values(self) -> Vec<WasmValue>;
+}
+
+macro_rules! impl_wasm_params {
+    ($($param:ident),*) => {
+        impl<$($param: WasmType + Into<WasmValue>),*> WasmParams for ($($param,)*) {
+            fn types() -> Vec<ValueType> {
+                vec![$($param::TYPE),*]
+            }
+
+            #[allow(non_snake_case)]
+            fn into_values(self) -> Vec<WasmValue> {
+                let ($($param,)*) = self;
+                vec![$($param.into()),*]
+            }
+        }
+    };
+}
+
+impl_wasm_params!();
+impl_wasm_params!(A);
+impl_wasm_params!(A, B);
+impl_wasm_params!(A, B, C);
+impl_wasm_params!(A, B, C, D);
+impl_wasm_params!(A, B, C, D, E);
+impl_wasm_params!(A, B, C, D, E, F);
+impl_wasm_params!(A, B, C, D, E, F, G);
+impl_wasm_params!(A, B, C, D, E, F, G, H);
+
+/// an exported function whose types have been checked, see `Function::typed()`
+pub struct TypedFunction<P, R> {
+    function: Function,
+    _types: PhantomData<fn(P) -> R>,
+}
+
+impl<P: WasmParams, R: WasmType + TryFrom<WasmValue, Error = RuntimeError>> TypedFunction<P, R> {
+    /// execute the function
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if failed.
+    pub fn call<T>(&self, instance: &Instance<T>, params: P) -> Result<R, RuntimeError> {
+        let result = self.function.call_args(instance, &params.into_values())?;
+        R::try_from(result)
+    }
+
+    /// the function, to call it with `WasmValue`s
+    pub fn function(&self) -> &Function {
+        &self.function
+    }
+}
+
+/// the exit code, if the exception comes from a WASI `proc_exit`
+#[cfg(libc_wasi)]
+fn wasi_exit_code<T>(instance: &Instance<T>, exception: &str) -> Option<u32> {
+    match exception.contains("wasi proc exit") {
+        true => Some(unsafe {
+            wamr_sys::wasm_runtime_get_wasi_exit_code(instance.get_inner_instance())
+        }),
+        false => None,
+    }
+}
+
+#[cfg(not(libc_wasi))]
+fn wasi_exit_code<T>(_instance: &Instance<T>, _exception: &str) -> Option<u32> {
+    None
+}
+
+/// whether the exception pending in `instance` is the one of `wasm_runtime_terminate()`.
+/// It's read raw, exceptions aren't copied with the `tiny` feature
+#[cfg(feature = "threads")]
+fn is_terminated(instance: wasm_module_inst_t) -> bool {
+    let exception = unsafe { wasm_runtime_get_exception(instance) };
+    !exception.is_null()
+        && unsafe { CStr::from_ptr(exception) }
+            .to_bytes()
+            .ends_with(b"terminated by user")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{module::Module, runtime::Runtime, wasi_context::WasiCtxBuilder};
-    use std::path::PathBuf;
+    use crate::{
+        host_function::ResultTy,
+        module::Module,
+        runtime::Runtime,
+        user_data::{Caller, ExecEnv},
+    };
+    use std::ffi::c_void;
 
     #[test]
     fn test_func_in_wasm32_unknown() {
@@ -150,10 +747,114 @@ mod tests {
         let call_result = function.call(instance, &params);
         assert!(call_result.is_ok());
         assert_eq!(call_result.unwrap(), WasmValue::I32(384));
+
+        let call_result = function.call_args(instance, &[WasmValue::I32(1), WasmValue::I32(2)]);
+        assert_eq!(call_result.unwrap(), WasmValue::I32(3));
+
+        let params = [WasmValue::I32(4), WasmValue::I32(5)];
+        let call_result = function.call_with_stack(instance, &params, 64 * 1024);
+        assert_eq!(call_result.unwrap(), WasmValue::I32(9));
+
+        let batch: Vec<[WasmValue; 2]> = (0..4)
+            .map(|i| [WasmValue::I32(i), WasmValue::I32(i * 10)])
+            .collect();
+        let batch: Vec<&[WasmValue]> = batch.iter().map(|params| &params[..]).collect();
+        let results: Vec<WasmValue> = function
+            .call_batch(instance, &batch)
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                WasmValue::I32(0),
+                WasmValue::I32(11),
+                WasmValue::I32(22),
+                WasmValue::I32(33)
+            ]
+        );
     }
 
     #[test]
+    fn test_call_stats() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+        //   (func (export "trap") unreachable)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x60, 0x00, 0x00, 0x03, 0x03, 0x02, 0x00, 0x01, 0x07, 0x0e, 0x02,
+            0x03, 0x61, 0x64, 0x64, 0x00, 0x00, 0x04, 0x74, 0x72, 0x61, 0x70, 0x00, 0x01, 0x0a,
+            0x0d, 0x02, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b, 0x03, 0x00, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "stats")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let add = Function::find_export_func(&instance, "add")?;
+        add.call_args(&instance, &[WasmValue::I32(1), WasmValue::I32(2)])?;
+        assert_eq!(add.stats(), None);
+
+        let add = add.with_stats();
+        let params = [WasmValue::I32(1), WasmValue::I32(2)];
+        add.call_batch(&instance, &[&params, &params, &params]);
+        let stats = add.stats().unwrap();
+        assert_eq!((stats.calls, stats.traps), (3, 0));
+        assert!(stats.max_time <= stats.total_time);
+        assert!(stats.mean_time() <= stats.max_time);
+
+        let trap = Function::find_export_func(&instance, "trap")?.with_stats();
+        assert!(trap.call_args(&instance, &[]).is_err());
+        assert_eq!(trap.reset_stats().map(|stats| stats.traps), Some(1));
+        assert_eq!(trap.stats(), Some(CallStats::default()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_typed_function() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let add =
+            Function::find_export_func(&instance, "add")?.typed::<(i32, i32), i32, _>(&instance)?;
+        assert_eq!(add.call(&instance, (8, 8))?, 16);
+        assert_eq!(
+            add.function().call(&instance, &crate::params![1, 2])?,
+            WasmValue::I32(3)
+        );
+
+        let wrong_params =
+            Function::find_export_func(&instance, "add")?.typed::<(i64, i32), i32, _>(&instance);
+        assert!(matches!(wrong_params, Err(RuntimeError::TypeMismatch(_))));
+        let wrong_result =
+            Function::find_export_func(&instance, "add")?.typed::<(i32, i32), (), _>(&instance);
+        assert!(matches!(wrong_result, Err(RuntimeError::TypeMismatch(_))));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(libc_wasi)]
     fn test_func_in_wasm32_wasi() {
+        use crate::wasi_context::WasiCtxBuilder;
+        use std::path::PathBuf;
+
         let runtime = Runtime::new().unwrap();
 
         let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
@@ -184,4 +885,40 @@ mod tests {
         let result = function.call(instance, &params);
         assert_eq!(result.unwrap(), WasmValue::I32(27));
     }
+
+    extern "C" fn extra_logging(env: ExecEnv) -> i32 {
+        let mut caller: Caller<Vec<i32>> = Caller::from_env(&env);
+        caller.data_mut().push(1);
+        caller.data().len() as i32
+    }
+
+    #[test]
+    fn test_call_with_state() -> Result<(), RuntimeError> {
+        use std::path::PathBuf;
+
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("extra", extra_logging as *mut c_void, &[], ResultTy::I32)
+            .build()?;
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path())?;
+        let instance = Instance::new(&runtime, &module, 1024 * 64, vec![7; 10])?;
+        let function = Function::find_export_func(&instance, "add")?;
+        let params = [WasmValue::I32(8), WasmValue::I32(8)];
+
+        let mut state: Vec<i32> = Vec::new();
+        for expected in [17, 18] {
+            // `extra_logging` borrows a `Vec<i32>`, like `state`
+            let result = unsafe { function.call_with_state(&instance, &params, &mut state)? };
+            assert_eq!(result, WasmValue::I32(expected));
+        }
+        assert_eq!(state, [1, 1]);
+
+        assert_eq!(function.call_args(&instance, &params)?, WasmValue::I32(27));
+        assert_eq!(instance.data().len(), 11);
+        Ok(())
+    }
 }
@@ -0,0 +1,457 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the objects of the GC proposal, structs, arrays and i31s, like the exports of a module
+//! compiled from Kotlin, Java or Dart return them. get one via `Function::call_gc()`
+//!
+//! the objects are owned by the collector of the instance, which may collect them once
+//! the instance runs again, so a `GcRef` borrows the instance mutably, and the instance
+//! can't run until the `GcRef`s are dropped. Read what the host needs before the next call
+//!
+//! hosts collect between requests via `Instance::gc_collect()`, and watch the heap via
+//! `Instance::gc_stats()`
+
+use std::{ffi::c_void, marker::PhantomData, mem, ptr};
+
+use wamr_sys::{
+    mem_alloc_info_t, wasm_array_obj_get_elem, wasm_array_obj_length,
+    wasm_array_type_get_elem_type, wasm_i31_obj_get_value, wasm_module_inst_t, wasm_module_t,
+    wasm_obj_get_defined_type, wasm_obj_get_defined_type_idx, wasm_obj_is_array_obj,
+    wasm_obj_is_externref_obj, wasm_obj_is_func_obj, wasm_obj_is_i31_obj, wasm_obj_is_struct_obj,
+    wasm_obj_t, wasm_ref_type_t, wasm_runtime_get_module, wasm_struct_obj_get_field,
+    wasm_struct_type_get_field_count, wasm_struct_type_get_field_type, wasm_value_t,
+};
+
+#[cfg(feature = "stringref")]
+use crate::stringref::{self, StringRef};
+use crate::{instance::Instance, RuntimeError};
+
+/// the value types of the binary format, which WAMR reports the storage types with
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F32: u8 = 0x7d;
+const F64: u8 = 0x7c;
+const V128: u8 = 0x7b;
+const I8: u8 = 0x78;
+const I16: u8 = 0x77;
+
+/// what a `GcRef` refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcKind {
+    Struct,
+    Array,
+    /// an unboxed 31-bit integer
+    I31,
+    Func,
+    /// a host reference, converted to `anyref`
+    Extern,
+    /// a `stringref`, see `GcRef::as_string_ref()`
+    #[cfg(feature = "stringref")]
+    String,
+    Other,
+}
+
+/// the storage type of a field of a struct, or of the elements of an array
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcStorageType {
+    I8,
+    I16,
+    I32,
+    I64,
+    F32,
+    F64,
+    V128,
+    /// a reference, to the heap type `heap_type`: a type index if positive, an abstract
+    /// heap type like `any` otherwise
+    Ref {
+        nullable: bool,
+        heap_type: i32,
+    },
+}
+
+impl GcStorageType {
+    fn from_ref_type(ref_type: &wasm_ref_type_t) -> Self {
+        match ref_type.value_type {
+            I8 => GcStorageType::I8,
+            I16 => GcStorageType::I16,
+            I32 => GcStorageType::I32,
+            I64 => GcStorageType::I64,
+            F32 => GcStorageType::F32,
+            F64 => GcStorageType::F64,
+            V128 => GcStorageType::V128,
+            _ => GcStorageType::Ref {
+                nullable: ref_type.nullable,
+                heap_type: ref_type.heap_type,
+            },
+        }
+    }
+}
+
+/// a field of a struct type, or the elements of an array type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcField {
+    pub ty: GcStorageType,
+    pub mutable: bool,
+}
+
+/// a value read out of a struct or an array. `I8` and `I16` fields read as a zero-extended
+/// `I32`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GcValue<'a> {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    V128(i128),
+    /// a reference, `None` if null
+    Ref(Option<GcRef<'a>>),
+}
+
+/// an object of the collector of an instance, which borrows the instance mutably, so it
+/// isn't collected while the `GcRef` is alive
+#[derive(Clone, Copy)]
+pub struct GcRef<'a> {
+    object: wasm_obj_t,
+    module: wasm_module_t,
+    _instance: PhantomData<&'a ()>,
+}
+
+impl<'a> GcRef<'a> {
+    /// a reference to `object` of `instance`, `None` if it is null
+    pub(crate) fn new<T>(instance: &'a Instance<T>, object: wasm_obj_t) -> Option<Self> {
+        let module = unsafe { wasm_runtime_get_module(instance.get_inner_instance()) };
+        Self::in_module(module, object)
+    }
+
+    fn in_module(module: wasm_module_t, object: wasm_obj_t) -> Option<Self> {
+        (!object.is_null()).then_some(GcRef {
+            object,
+            module,
+            _instance: PhantomData,
+        })
+    }
+
+    pub fn kind(&self) -> GcKind {
+        unsafe {
+            // an i31 is a tagged pointer, to be told apart first
+            if wasm_obj_is_i31_obj(self.object) {
+                GcKind::I31
+            } else if wasm_obj_is_struct_obj(self.object) {
+                GcKind::Struct
+            } else if wasm_obj_is_array_obj(self.object) {
+                GcKind::Array
+            } else if wasm_obj_is_func_obj(self.object) {
+                GcKind::Func
+            } else if wasm_obj_is_externref_obj(self.object) {
+                GcKind::Extern
+            } else {
+                #[cfg(feature = "stringref")]
+                if stringref::is_stringref_obj(self.object) {
+                    return GcKind::String;
+                }
+                GcKind::Other
+            }
+        }
+    }
+
+    /// the index of the type of a struct or an array in the type section of its module,
+    /// `None` for other objects
+    pub fn type_index(&self) -> Option<u32> {
+        match self.kind() {
+            GcKind::Struct | GcKind::Array => {
+                let index = unsafe { wasm_obj_get_defined_type_idx(self.module, self.object) };
+                u32::try_from(index).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// the fields of a struct, in order
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't a struct.
+    pub fn fields(&self) -> Result<Vec<GcField>, RuntimeError> {
+        self.expect(GcKind::Struct)?;
+        let struct_type = unsafe { wasm_obj_get_defined_type(self.object) } as _;
+        let count = unsafe { wasm_struct_type_get_field_count(struct_type) };
+        Ok((0..count)
+            .map(|index| {
+                let mut mutable = false;
+                let ty =
+                    unsafe { wasm_struct_type_get_field_type(struct_type, index, &mut mutable) };
+                GcField {
+                    ty: GcStorageType::from_ref_type(&ty),
+                    mutable,
+                }
+            })
+            .collect())
+    }
+
+    /// read the field `index` of a struct
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't a struct, or has no such field.
+    pub fn field(&self, index: u32) -> Result<GcValue<'a>, RuntimeError> {
+        let field = self.fields()?.get(index as usize).copied().ok_or_else(|| {
+            RuntimeError::TypeMismatch(message!("a struct without a field {}", index))
+        })?;
+        let mut value: wasm_value_t = unsafe { mem::zeroed() };
+        unsafe { wasm_struct_obj_get_field(self.object as _, index, false, &mut value) };
+        Ok(self.value(field.ty, &value))
+    }
+
+    /// the type of the elements of an array
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't an array.
+    pub fn element_type(&self) -> Result<GcField, RuntimeError> {
+        self.expect(GcKind::Array)?;
+        let array_type = unsafe { wasm_obj_get_defined_type(self.object) } as _;
+        let mut mutable = false;
+        let ty = unsafe { wasm_array_type_get_elem_type(array_type, &mut mutable) };
+        Ok(GcField {
+            ty: GcStorageType::from_ref_type(&ty),
+            mutable,
+        })
+    }
+
+    /// the number of elements of an array
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't an array.
+    pub fn array_len(&self) -> Result<u32, RuntimeError> {
+        self.expect(GcKind::Array)?;
+        Ok(unsafe { wasm_array_obj_length(self.object as _) })
+    }
+
+    /// read the element `index` of an array
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't an array, or
+    /// `RuntimeError::MemoryAccessError` if `index` is out of bounds.
+    pub fn element(&self, index: u32) -> Result<GcValue<'a>, RuntimeError> {
+        let element = self.element_type()?;
+        let len = self.array_len()?;
+        if index >= len {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "out of bounds array access: index {} length {}",
+                index,
+                len
+            )));
+        }
+        let mut value: wasm_value_t = unsafe { mem::zeroed() };
+        unsafe { wasm_array_obj_get_elem(self.object as _, index, false, &mut value) };
+        Ok(self.value(element.ty, &value))
+    }
+
+    /// the value of an i31, sign-extended
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::TypeMismatch` if it isn't an i31.
+    pub fn i31(&self) -> Result<i32, RuntimeError> {
+        self.expect(GcKind::I31)?;
+        Ok(unsafe { wasm_i31_obj_get_value(self.object as _, true) } as i32)
+    }
+
+    /// the string this refers to, `None` if it isn't a `stringref`
+    #[cfg(feature = "stringref")]
+    pub fn as_string_ref(&self) -> Option<StringRef> {
+        (self.kind() == GcKind::String).then(|| StringRef::from_obj(self.object))
+    }
+
+    fn expect(&self, kind: GcKind) -> Result<(), RuntimeError> {
+        match self.kind() {
+            actual if actual == kind => Ok(()),
+            actual => Err(RuntimeError::TypeMismatch(message!(
+                "expect {:?}, got {:?}",
+                kind,
+                actual
+            ))),
+        }
+    }
+
+    fn value(&self, ty: GcStorageType, value: &wasm_value_t) -> GcValue<'a> {
+        unsafe {
+            match ty {
+                GcStorageType::I8 | GcStorageType::I16 | GcStorageType::I32 => {
+                    GcValue::I32(value.i32)
+                }
+                GcStorageType::I64 => GcValue::I64(value.i64),
+                GcStorageType::F32 => GcValue::F32(value.f32),
+                GcStorageType::F64 => GcValue::F64(value.f64),
+                GcStorageType::V128 => {
+                    GcValue::V128(ptr::read_unaligned(value as *const _ as *const i128))
+                }
+                GcStorageType::Ref { .. } => {
+                    GcValue::Ref(Self::in_module(self.module, value.gc_obj as _))
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for GcRef<'_> {
+    /// the same object, like `ref.eq`
+    fn eq(&self, other: &Self) -> bool {
+        self.object == other.object
+    }
+}
+
+impl std::fmt::Debug for GcRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GcRef")
+            .field("object", &self.object)
+            .field("kind", &self.kind())
+            .finish()
+    }
+}
+
+/// the occupancy of the GC heap of an instance, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub heap_size: u32,
+    pub free: u32,
+    /// the most bytes allocated at once
+    pub peak: u32,
+}
+
+impl GcStats {
+    /// the bytes allocated right now
+    pub fn used(&self) -> u32 {
+        self.heap_size - self.free
+    }
+}
+
+// the GC heap of an instance, and its collector, which aren't part of *gc_export.h*
+extern "C" {
+    fn wasm_runtime_get_gc_heap_handle(module_inst: wasm_module_inst_t) -> *mut c_void;
+    fn mem_allocator_get_alloc_info(allocator: *mut c_void, info: *mut c_void) -> bool;
+    fn gci_gc_heap(heap: *mut c_void) -> i32;
+}
+
+/// collect the garbage of the heap of `instance`, which mustn't be running
+///
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if the instance has no GC heap, or the collection
+/// failed.
+pub(crate) fn collect(instance: wasm_module_inst_t) -> Result<(), RuntimeError> {
+    let heap = unsafe { wasm_runtime_get_gc_heap_handle(instance) };
+    if heap.is_null() || unsafe { gci_gc_heap(heap) } != 0 {
+        return Err(RuntimeError::ExecutionError(message!(
+            "failed to collect the GC heap",
+        )));
+    }
+    Ok(())
+}
+
+/// the occupancy of the heap of `instance`, `None` if it has none
+pub(crate) fn stats(instance: wasm_module_inst_t) -> Option<GcStats> {
+    let heap = unsafe { wasm_runtime_get_gc_heap_handle(instance) };
+    let mut info = mem_alloc_info_t::default();
+    if heap.is_null()
+        || !unsafe { mem_allocator_get_alloc_info(heap, &mut info as *mut _ as *mut c_void) }
+    {
+        return None;
+    }
+    Some(GcStats {
+        heap_size: info.total_size,
+        free: info.total_free_size,
+        peak: info.highmark_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{function::Function, module::Module, runtime::Runtime, value::WasmValue};
+
+    #[test]
+    fn test_gc_objects() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (type $point (struct (field i32) (field (mut f64))))
+        //   (type $points (array (mut (ref null $point))))
+        //   (func (export "points") (param i32) (result (ref $points))
+        //     (array.new $points
+        //       (struct.new $point (local.get 0) (f64.const 1.5))
+        //       (i32.const 2)))
+        //   (func (export "small") (result i31ref) (ref.i31 (i32.const -3)))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x15, 0x04, 0x5f, 0x02, 0x7f,
+            0x00, 0x7c, 0x01, 0x5e, 0x63, 0x00, 0x01, 0x60, 0x01, 0x7f, 0x01, 0x64, 0x01, 0x60,
+            0x00, 0x01, 0x6c, 0x03, 0x03, 0x02, 0x02, 0x03, 0x07, 0x12, 0x02, 0x06, 0x70, 0x6f,
+            0x69, 0x6e, 0x74, 0x73, 0x00, 0x00, 0x05, 0x73, 0x6d, 0x61, 0x6c, 0x6c, 0x00, 0x01,
+            0x0a, 0x1e, 0x02, 0x15, 0x00, 0x20, 0x00, 0x44, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0xf8, 0x3f, 0xfb, 0x00, 0x00, 0x41, 0x02, 0xfb, 0x06, 0x01, 0x0b, 0x06, 0x00, 0x41,
+            0x7d, 0xfb, 0x1c, 0x0b, 0x00, 0x17, 0x04, 0x6e, 0x61, 0x6d, 0x65, 0x04, 0x10, 0x02,
+            0x00, 0x05, 0x70, 0x6f, 0x69, 0x6e, 0x74, 0x01, 0x06, 0x70, 0x6f, 0x69, 0x6e, 0x74,
+            0x73,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "gc")?;
+        let mut instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+
+        let points = Function::find_export_func(&instance, "points")?;
+        let points = points
+            .call_gc(&mut instance, &[WasmValue::I32(7)])?
+            .unwrap();
+        assert_eq!(points.kind(), GcKind::Array);
+        assert_eq!(points.type_index(), Some(1));
+        assert_eq!(points.array_len()?, 2);
+        assert!(points.element_type()?.mutable);
+
+        let GcValue::Ref(Some(point)) = points.element(1)? else {
+            panic!("expect a point");
+        };
+        assert_eq!(point.kind(), GcKind::Struct);
+        assert_eq!(points.element(0)?, GcValue::Ref(Some(point)));
+        assert_eq!(
+            point.fields()?,
+            [
+                GcField {
+                    ty: GcStorageType::I32,
+                    mutable: false
+                },
+                GcField {
+                    ty: GcStorageType::F64,
+                    mutable: true
+                }
+            ]
+        );
+        assert_eq!(point.field(0)?, GcValue::I32(7));
+        assert_eq!(point.field(1)?, GcValue::F64(1.5));
+        assert!(matches!(point.field(2), Err(RuntimeError::TypeMismatch(_))));
+        assert!(matches!(
+            points.element(2),
+            Err(RuntimeError::MemoryAccessError(_))
+        ));
+        assert!(matches!(
+            point.array_len(),
+            Err(RuntimeError::TypeMismatch(_))
+        ));
+
+        let small = Function::find_export_func(&instance, "small")?;
+        let small = small.call_gc(&mut instance, &[])?.unwrap();
+        assert_eq!(small.kind(), GcKind::I31);
+        assert_eq!(small.i31()?, -3);
+
+        // nothing roots the points once their references are dropped
+        let used = instance.gc_stats().unwrap().used();
+        assert!(used > 0);
+        instance.gc_collect()?;
+        let stats = instance.gc_stats().unwrap();
+        assert!(stats.used() < used);
+        assert!(stats.peak >= used);
+        Ok(())
+    }
+}
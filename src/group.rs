@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! several instances linked against one provider module, which defines the memory they
+//! import, like the main module of emscripten side modules.
+//! get one via `InstanceGroup::new()`, with the `multi-module` feature
+//!
+//! WAMR resolves the imports of each member against its own instance of the provider, so
+//! members only end up on the same memory when the provider declares it `shared`, with
+//! the `threads` feature. `InstanceGroup::add()` checks they do.
+//!
+//! tables can't be shared this way: each member gets a copy of the table of the provider,
+//! so a function one member puts in its table can't be called indirectly by another.
+
+use std::{
+    ffi::{c_char, CString},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::wasm_runtime_register_module;
+
+use crate::{
+    helper::{error_buf_to_string, DEFAULT_ERROR_BUF_SIZE},
+    instance::Instance,
+    module::Module,
+    runtime::Runtime,
+    RuntimeError,
+};
+
+/// instances sharing the memory of a provider module, each with its own table
+pub struct InstanceGroup<'a, T> {
+    runtime: &'a Runtime,
+    provider: &'a Module,
+    members: Vec<Instance<T>>,
+}
+
+impl<'a, T: 'static> InstanceGroup<'a, T> {
+    /// register `provider` under `name`, like `env`, for the imports of the members.
+    ///
+    /// WAMR links imports while loading a module, so load the members afterwards.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::InstantiationFailure` if the provider can't be registered.
+    pub fn new(
+        runtime: &'a Runtime,
+        provider: &'a Module,
+        name: &str,
+    ) -> Result<Self, RuntimeError> {
+        register(provider, name)?;
+
+        Ok(InstanceGroup {
+            runtime,
+            provider,
+            members: Vec::new(),
+        })
+    }
+
+    pub(crate) fn runtime(&self) -> &'a Runtime {
+        self.runtime
+    }
+
+    /// the provider module
+    pub fn provider(&self) -> &Module {
+        self.provider
+    }
+
+    /// instantiate a member, with stack size and host managed heap size
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::InstantiationFailure` if failed, or if the member isn't on the
+    /// memory of the other members.
+    pub fn add(
+        &mut self,
+        module: &Module,
+        stack_size: u32,
+        heap_size: u32,
+        data: T,
+    ) -> Result<&Instance<T>, RuntimeError> {
+        let instance = Instance::new_with_args(self.runtime, module, stack_size, heap_size, data)?;
+
+        let base = instance.memory().base_address();
+        let shared = self
+            .members
+            .iter()
+            .map(|member| member.memory().base_address())
+            .all(|member_base| base.is_null() || member_base.is_null() || member_base == base);
+        if !shared {
+            return Err(RuntimeError::InstantiationFailure(message!(
+                "module {:?} isn't on the memory of the group",
+                module.get_name()
+            )));
+        }
+
+        self.members.push(instance);
+        Ok(self.members.last().unwrap())
+    }
+
+    /// the members, in the order they have been added
+    pub fn members(&self) -> &[Instance<T>] {
+        &self.members
+    }
+}
+
+/// the names modules are registered under. WAMR keeps pointing at them until it's
+/// destroyed, and only unloads registered modules then
+static NAMES: Mutex<Vec<CString>> = Mutex::new(Vec::new());
+
+fn names() -> MutexGuard<'static, Vec<CString>> {
+    NAMES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// free the names of the registered modules, once WAMR is destroyed
+pub(crate) fn reset() {
+    names().clear();
+}
+
+/// make the exports of `module` available, under `name`, to the imports of the modules
+/// loaded afterwards
+pub(crate) fn register(module: &Module, name: &str) -> Result<(), RuntimeError> {
+    let c_name = CString::new(name).map_err(|_| {
+        RuntimeError::InstantiationFailure(message!("module name contains a nul byte"))
+    })?;
+    let mut error_buf = [0 as c_char; DEFAULT_ERROR_BUF_SIZE];
+    let registered = unsafe {
+        wasm_runtime_register_module(
+            c_name.as_ptr(),
+            module.get_inner_module(),
+            error_buf.as_mut_ptr(),
+            error_buf.len() as u32,
+        )
+    };
+    match registered {
+        true => {
+            // moving the `CString` keeps its buffer where it is
+            names().push(c_name);
+            crate::dependency::record(module, name);
+            Ok(())
+        }
+        false => Err(RuntimeError::InstantiationFailure(error_buf_to_string(
+            &error_buf,
+        ))),
+    }
+}
+
+#[cfg(all(test, feature = "threads"))]
+mod tests {
+    use super::*;
+    use crate::{function::Function, value::WasmValue};
+
+    #[test]
+    fn test_instance_group() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1 1 shared)
+        // )
+        let provider = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+            0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let provider = Module::from_buf(&runtime, &provider, "env")?;
+        let mut group = InstanceGroup::new(&runtime, &provider, "env")?;
+
+        // (module
+        //   (import "env" "memory" (memory 1 1 shared))
+        //   (func (export "store") (param i32) (i32.store (i32.const 0) (local.get 0)))
+        //   (func (export "load") (result i32) (i32.load (i32.const 0)))
+        // )
+        let member = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x01, 0x7f,
+            0x00, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x10, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x06, 0x6d,
+            0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x03, 0x01, 0x01, 0x03, 0x03, 0x02, 0x00, 0x01,
+            0x07, 0x10, 0x02, 0x05, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x00, 0x00, 0x04, 0x6c, 0x6f,
+            0x61, 0x64, 0x00, 0x01, 0x0a, 0x13, 0x02, 0x09, 0x00, 0x41, 0x00, 0x20, 0x00, 0x36,
+            0x02, 0x00, 0x0b, 0x07, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x0b,
+        ];
+        let member = Module::from_buf(&runtime, &member, "member")?;
+        group.add(&member, 1024, 0, ())?;
+        group.add(&member, 1024, 0, ())?;
+
+        let [writer, reader] = group.members() else {
+            unreachable!()
+        };
+        let store = Function::find_export_func(writer, "store")?;
+        store.call_args(writer, &[WasmValue::I32(42)])?;
+        let load = Function::find_export_func(reader, "load")?;
+        assert_eq!(load.call_args(reader, &[])?, WasmValue::I32(42));
+
+        drop(group);
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! what host code needs of an instance, its exports, its linear memory and its data,
+//! behind traits. Written against `Guest`, the host logic runs on an `Instance`, and on a
+//! `mock::MockInstance` in unit tests, see `mock`
+//!
+//! `WasmInstance` and `WasmFunc` abstract over the engine as well: code generic over them
+//! looks an export up once and calls it many times, whatever runs it
+//!
+//! the traits, like `mock`, build without WAMR, once the default features, which enable
+//! `wamr`, are left out
+
+#[cfg(feature = "wamr")]
+use crate::{function::Function, instance::Instance, memory::Memory};
+use crate::{value::WasmValue, RuntimeError};
+
+/// the size of a wasm page, in bytes
+pub const WASM_PAGE_SIZE: usize = 65536;
+
+/// the default linear memory of a guest
+pub trait GuestMemory {
+    /// the current size of the linear memory, in bytes
+    fn data_size(&self) -> usize;
+
+    /// the current size of the linear memory, in wasm pages
+    fn pages(&self) -> u32 {
+        (self.data_size() / WASM_PAGE_SIZE) as u32
+    }
+
+    /// copy `buf.len()` bytes starting at `offset` out of the linear memory
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the range is out of bounds.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), RuntimeError>;
+
+    /// copy `data` into the linear memory starting at `offset`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the range is out of bounds.
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), RuntimeError>;
+
+    /// grow the linear memory by `delta` pages and return the previous page count
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the memory can't be enlarged.
+    fn grow(&self, delta: u32) -> Result<u32, RuntimeError>;
+}
+
+impl<M: GuestMemory + ?Sized> GuestMemory for &M {
+    fn data_size(&self) -> usize {
+        (**self).data_size()
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), RuntimeError> {
+        (**self).read(offset, buf)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), RuntimeError> {
+        (**self).write(offset, data)
+    }
+
+    fn grow(&self, delta: u32) -> Result<u32, RuntimeError> {
+        (**self).grow(delta)
+    }
+}
+
+#[cfg(feature = "wamr")]
+impl GuestMemory for Memory<'_> {
+    fn data_size(&self) -> usize {
+        Memory::data_size(self)
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), RuntimeError> {
+        Memory::read(self, offset, buf)
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), RuntimeError> {
+        Memory::write(self, offset, data)
+    }
+
+    fn grow(&self, delta: u32) -> Result<u32, RuntimeError> {
+        Memory::grow(self, delta)
+    }
+}
+
+/// an instance, as host code calls it
+pub trait Guest {
+    /// the host data of the instance
+    type Data;
+
+    /// the linear memory of the instance
+    type Memory<'a>: GuestMemory
+    where
+        Self: 'a;
+
+    /// call the export `name`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export, or
+    /// `RuntimeError::ExecutionError` if the call fails.
+    fn call(&self, name: &str, params: &[WasmValue]) -> Result<WasmValue, RuntimeError>;
+
+    fn memory(&self) -> Self::Memory<'_>;
+
+    fn data(&self) -> &Self::Data;
+}
+
+#[cfg(feature = "wamr")]
+impl<T: 'static> Guest for Instance<T> {
+    type Data = T;
+    type Memory<'a>
+        = Memory<'a>
+    where
+        T: 'a;
+
+    fn call(&self, name: &str, params: &[WasmValue]) -> Result<WasmValue, RuntimeError> {
+        Instance::call(self, name, params)
+    }
+
+    fn memory(&self) -> Memory<'_> {
+        Instance::memory(self)
+    }
+
+    fn data(&self) -> &T {
+        Instance::data(self)
+    }
+}
+
+/// an export looked up in an instance of type `I`, see `WasmInstance::function()`
+pub trait WasmFunc<I: ?Sized> {
+    /// call the function in `instance`, the one it has been looked up in
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the call fails.
+    fn call(&self, instance: &I, params: &[WasmValue]) -> Result<WasmValue, RuntimeError>;
+}
+
+/// an instance of an engine, whose exports are looked up once to be called many times
+pub trait WasmInstance: Guest {
+    type Func: WasmFunc<Self>;
+
+    /// look the export `name` up
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export.
+    fn function(&self, name: &str) -> Result<Self::Func, RuntimeError>;
+}
+
+#[cfg(feature = "wamr")]
+impl<T> WasmFunc<Instance<T>> for Function {
+    fn call(
+        &self,
+        instance: &Instance<T>,
+        params: &[WasmValue],
+    ) -> Result<WasmValue, RuntimeError> {
+        self.call_args(instance, params)
+    }
+}
+
+#[cfg(feature = "wamr")]
+impl<T: 'static> WasmInstance for Instance<T> {
+    type Func = Function;
+
+    fn function(&self, name: &str) -> Result<Function, RuntimeError> {
+        Function::find_export_func(self, name)
+    }
+}
@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the exports of an instance behind a Rust trait, the inverse of a host function.
+//! declare one via `guest_interface!` and get one via `Instance::bind()`
+//!
+//! each method of the trait calls the export of the same name. Parameters are converted
+//! into `WasmValue` via `From`, and the result out of it via `TryFrom`, so `i32`, `u32`,
+//! `i64`, `u64`, `f32`, `f64` and `bool` are accepted, and `()` for no result. A method
+//! returns `Result<R, RuntimeError>`, for the call may trap.
+//!
+//! ```ignore
+//! guest_interface! {
+//!     pub trait Plugin {
+//!         fn add(&self, a: i32, b: i32) -> i32;
+//!         fn reset(&self);
+//!     }
+//! }
+//!
+//! let plugin = instance.bind::<dyn Plugin>()?;
+//! assert_eq!(plugin.add(1, 2)?, 3);
+//! ```
+
+use crate::{instance::Instance, RuntimeError};
+
+/// a trait object whose methods call the exports of an instance, implemented by
+/// `guest_interface!`
+pub trait GuestInterface<'a, T> {
+    /// look up every export of the trait in `instance`
+    fn bind(instance: &'a Instance<T>) -> Result<Box<Self>, RuntimeError>;
+}
+
+/// declare a trait whose methods call the exports of an instance, and implement
+/// `GuestInterface` for its trait objects. A method takes `&self` and parameters of
+/// wasm value types, and is declared with the result of the export, if any
+#[macro_export]
+macro_rules! guest_interface {
+    (
+        $(#[$meta:meta])*
+        $vis:vis trait $name:ident {
+            $(
+                $(#[$method_meta:meta])*
+                fn $method:ident(&self $(, $arg:ident: $arg_ty:ty)* $(,)?) $(-> $ret:ty)?;
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        $vis trait $name {
+            $(
+                $(#[$method_meta])*
+                fn $method(
+                    &self,
+                    $($arg: $arg_ty),*
+                ) -> ::core::result::Result<
+                    $crate::guest_interface!(@result $($ret)?),
+                    $crate::RuntimeError,
+                >;
+            )*
+        }
+
+        impl<'a, T: 'a> $crate::guest_interface::GuestInterface<'a, T> for dyn $name + 'a {
+            fn bind(
+                instance: &'a $crate::instance::Instance<T>,
+            ) -> ::core::result::Result<::std::boxed::Box<Self>, $crate::RuntimeError> {
+                // the methods of the trait may be left uncalled
+                #[allow(dead_code)]
+                struct Guest<'a, T> {
+                    instance: &'a $crate::instance::Instance<T>,
+                    $($method: $crate::function::Function,)*
+                }
+
+                impl<T> $name for Guest<'_, T> {
+                    $(
+                        fn $method(
+                            &self,
+                            $($arg: $arg_ty),*
+                        ) -> ::core::result::Result<
+                            $crate::guest_interface!(@result $($ret)?),
+                            $crate::RuntimeError,
+                        > {
+                            let result = self.$method.call_args(
+                                self.instance,
+                                &[$($crate::value::WasmValue::from($arg)),*],
+                            )?;
+                            ::core::convert::TryFrom::try_from(result)
+                        }
+                    )*
+                }
+
+                ::core::result::Result::Ok(::std::boxed::Box::new(Guest {
+                    instance,
+                    $(
+                        $method: $crate::function::Function::find_export_func(
+                            instance,
+                            ::core::stringify!($method),
+                        )?,
+                    )*
+                }))
+            }
+        }
+    };
+    (@result) => { () };
+    (@result $ret:ty) => { $ret };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{module::Module, runtime::Runtime};
+
+    guest_interface! {
+        /// a guest keeping one number
+        trait Calculator {
+            fn add(&self, a: i32, b: i32) -> i32;
+            fn store(&self, value: i64);
+            fn load(&self) -> i64;
+        }
+    }
+
+    guest_interface! {
+        #[allow(dead_code)]
+        trait Missing {
+            fn subtract(&self, a: i32, b: i32) -> i32;
+        }
+    }
+
+    #[test]
+    fn test_guest_interface() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (global (mut i64) (i64.const 0))
+        //   (func (export "add") (param i32 i32) (result i32) (i32.add (local.get 0) (local.get 1)))
+        //   (func (export "store") (param i64) (global.set 0 (local.get 0)))
+        //   (func (export "load") (result i64) (global.get 0))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0f, 0x03, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x60, 0x01, 0x7e, 0x00, 0x60, 0x00, 0x01, 0x7e, 0x03, 0x04, 0x03,
+            0x00, 0x01, 0x02, 0x06, 0x06, 0x01, 0x7e, 0x01, 0x42, 0x00, 0x0b, 0x07, 0x16, 0x03,
+            0x03, 0x61, 0x64, 0x64, 0x00, 0x00, 0x05, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x00, 0x01,
+            0x04, 0x6c, 0x6f, 0x61, 0x64, 0x00, 0x02, 0x0a, 0x15, 0x03, 0x07, 0x00, 0x20, 0x00,
+            0x20, 0x01, 0x6a, 0x0b, 0x06, 0x00, 0x20, 0x00, 0x24, 0x00, 0x0b, 0x04, 0x00, 0x23,
+            0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "calculator")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let calculator = instance.bind::<dyn Calculator>()?;
+        assert_eq!(calculator.add(40, 2)?, 42);
+        calculator.store(i64::MAX)?;
+        assert_eq!(calculator.load()?, i64::MAX);
+
+        assert!(matches!(
+            instance.bind::<dyn Missing>(),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+
+        Ok(())
+    }
+}
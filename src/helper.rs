@@ -6,22 +6,45 @@
 use std::ffi::{c_char, CStr};
 use std::string::String;
 
+use crate::ErrorMessage;
+
 pub const DEFAULT_ERROR_BUF_SIZE: usize = 128;
 
-pub fn error_buf_to_string(&error_buf: &[c_char; DEFAULT_ERROR_BUF_SIZE]) -> String {
+/// with the `tiny` feature, messages of WAMR are dropped instead of copied into errors,
+/// which get a static code
+#[cfg(feature = "tiny")]
+pub fn error_buf_to_string(_error_buf: &[c_char; DEFAULT_ERROR_BUF_SIZE]) -> ErrorMessage {
+    "error reported by WAMR"
+}
+
+#[cfg(not(feature = "tiny"))]
+pub fn error_buf_to_string(&error_buf: &[c_char; DEFAULT_ERROR_BUF_SIZE]) -> ErrorMessage {
     let error_content: Vec<u8> = error_buf
         .map(|c| c as u8)
         .into_iter()
         .filter(|c| *c > 0)
         .collect();
-    String::from_utf8(error_content).unwrap()
+    String::from_utf8_lossy(&error_content).to_string()
 }
 
+/// the static code `message!()` makes of an error with the `tiny` feature
+#[cfg(feature = "tiny")]
+pub fn error_code(code: &'static str) -> ErrorMessage {
+    code
+}
+
+#[cfg_attr(feature = "tiny", allow(dead_code))]
 pub fn cstr_to_string(raw_cstr: *const c_char) -> String {
     let cstr = unsafe { CStr::from_ptr(raw_cstr) };
     String::from_utf8_lossy(cstr.to_bytes()).to_string()
 }
 
+#[cfg(feature = "tiny")]
+pub fn exception_to_string(_raw_exception: *const c_char) -> String {
+    String::new()
+}
+
+#[cfg(not(feature = "tiny"))]
 pub fn exception_to_string(raw_exception: *const c_char) -> String {
     cstr_to_string(raw_exception)
 }
@@ -29,9 +52,24 @@ pub fn exception_to_string(raw_exception: *const c_char) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::ffi::CString;
 
     #[test]
+    fn test_message() {
+        let message: ErrorMessage = message!("{} pages", 3);
+        #[cfg(not(feature = "tiny"))]
+        assert_eq!(message, "3 pages");
+        #[cfg(feature = "tiny")]
+        assert_eq!(message, "{} pages");
+
+        let message: ErrorMessage = message!(code: "invalid TOML", String::from("line 1"));
+        #[cfg(not(feature = "tiny"))]
+        assert_eq!(message, "line 1");
+        #[cfg(feature = "tiny")]
+        assert_eq!(message, "invalid TOML");
+    }
+
+    #[test]
+    #[cfg(not(feature = "tiny"))]
     fn test_error_buf_empty() {
         let error_buf = [0 as c_char; DEFAULT_ERROR_BUF_SIZE];
         let error_str = error_buf_to_string(&error_buf);
@@ -40,6 +78,7 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "tiny"))]
     fn test_error_buf() {
         let mut error_buf = [0 as c_char; DEFAULT_ERROR_BUF_SIZE];
         error_buf[0] = 'a' as i8;
@@ -52,7 +91,10 @@ mod tests {
     }
 
     #[test]
+    #[cfg(not(feature = "tiny"))]
     fn test_exception_to_string() {
+        use std::ffi::CString;
+
         let exception = "it is an exception";
 
         let exception_cstr = CString::new(exception).expect("CString::new failed");
@@ -0,0 +1,244 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! an HTTP client for guests, limited to the domains allowed by the host.
+//!
+//! guests import, from the `http` module:
+//! - `http_request(method_ptr, method_len, url_ptr, url_len, body_ptr, body_len, resp_ptr,
+//!   resp_cap) -> i32`. It returns the length of the response body, of which at most
+//!   `resp_cap` bytes are copied, or one of the negative `HTTP_ERR_*` codes.
+//!
+//! redirects are never followed, so a guest can't escape the allowlist.
+
+use std::{ffi::c_void, io::Read, time::Duration};
+
+use url::Url;
+
+use crate::{
+    host_apis::{guest_bytes, guest_bytes_mut},
+    host_function::{catch_panic, ParamTy, ResultTy},
+    native_module::{NativeExports, NativeModule},
+    user_data::{Caller, ExecEnv},
+};
+
+/// the host of the URL isn't allowed
+pub const HTTP_ERR_DENIED: i32 = -1;
+/// the request failed or timed out
+pub const HTTP_ERR_REQUEST: i32 = -2;
+/// the response body is over the size cap
+pub const HTTP_ERR_TOO_LARGE: i32 = -3;
+/// the response status isn't a success
+pub const HTTP_ERR_STATUS: i32 = -4;
+/// the method or the URL isn't valid
+pub const HTTP_ERR_INVALID: i32 = -5;
+
+/// the `http` native module
+pub struct HttpClient {
+    allowed_domains: Vec<String>,
+    timeout: Duration,
+    max_response_size: usize,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient {
+            allowed_domains: Vec::new(),
+            timeout: Duration::from_secs(10),
+            max_response_size: 1024 * 1024,
+        }
+    }
+}
+
+impl HttpClient {
+    /// a client which allows no domain at all
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// allow requests to `domain` and all its subdomains
+    pub fn allow_domain(mut self, domain: &str) -> Self {
+        self.allowed_domains.push(domain.to_ascii_lowercase());
+        self
+    }
+
+    /// the timeout of a whole request, from connecting to reading the body
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// the maximum size of a response body, in bytes
+    pub fn max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// the parsed URL, if its host is allowed. It's the URL given to ureq, so the host
+    /// checked is the one connected to
+    fn allowed_url(&self, url: &str) -> Option<Url> {
+        let url = parse_url(url)?;
+        let host = url_host(&url)?.to_ascii_lowercase();
+
+        let allowed = self.allowed_domains.iter().any(|domain| {
+            host == *domain
+                || (host.ends_with(domain.as_str())
+                    && host.as_bytes()[host.len() - domain.len() - 1] == b'.')
+        });
+        allowed.then_some(url)
+    }
+
+    fn request(&self, method: &str, url: &str, body: &[u8]) -> Result<Vec<u8>, i32> {
+        let url = self.allowed_url(url).ok_or(HTTP_ERR_DENIED)?;
+
+        let agent = ureq::AgentBuilder::new()
+            .timeout(self.timeout)
+            .redirects(0)
+            .build();
+        let response = match agent.request_url(method, &url).send_bytes(body) {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, _)) => return Err(HTTP_ERR_STATUS),
+            Err(_) => return Err(HTTP_ERR_REQUEST),
+        };
+
+        let mut content = Vec::new();
+        response
+            .into_reader()
+            .take(self.max_response_size as u64 + 1)
+            .read_to_end(&mut content)
+            .map_err(|_| HTTP_ERR_REQUEST)?;
+        if content.len() > self.max_response_size {
+            return Err(HTTP_ERR_TOO_LARGE);
+        }
+        Ok(content)
+    }
+}
+
+/// parse an http(s) URL the way ureq does
+fn parse_url(url: &str) -> Option<Url> {
+    let url = Url::parse(url).ok()?;
+    match url.scheme() {
+        "http" | "https" => Some(url),
+        _ => None,
+    }
+}
+
+/// the host of an http(s) URL, without the port nor the brackets of an IPv6 address
+fn url_host(url: &Url) -> Option<&str> {
+    let host = url.host_str()?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|ipv6| ipv6.strip_suffix(']'))
+        .unwrap_or(host);
+    match host.is_empty() {
+        true => None,
+        false => Some(host),
+    }
+}
+
+impl NativeModule for HttpClient {
+    fn module_name(&self) -> &str {
+        "http"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.raw_function(
+            "http_request",
+            http_request as *mut c_void,
+            &[
+                ParamTy::Buffer,
+                ParamTy::Buffer,
+                ParamTy::Buffer,
+                ParamTy::Buffer,
+            ],
+            ResultTy::I32,
+        );
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn http_request(
+    env: ExecEnv,
+    method: *const u8,
+    method_len: u32,
+    url: *const u8,
+    url_len: u32,
+    body: *const u8,
+    body_len: u32,
+    response: *mut u8,
+    response_cap: u32,
+) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let client = caller.native_module::<HttpClient>().unwrap();
+
+        let (method, url, body, response) = unsafe {
+            (
+                guest_bytes(method, method_len),
+                guest_bytes(url, url_len),
+                guest_bytes(body, body_len),
+                guest_bytes_mut(response, response_cap),
+            )
+        };
+        let (method, url) = match (std::str::from_utf8(method), std::str::from_utf8(url)) {
+            (Ok(method), Ok(url)) => (method, url),
+            _ => return HTTP_ERR_INVALID,
+        };
+
+        match client.request(method, url, body) {
+            Ok(content) => {
+                let copied = content.len().min(response.len());
+                response[..copied].copy_from_slice(&content[..copied]);
+                content.len() as i32
+            }
+            Err(code) => code,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_host() {
+        let host = |url| parse_url(url).and_then(|url| url_host(&url).map(str::to_owned));
+        assert_eq!(host("https://example.com").as_deref(), Some("example.com"));
+        assert_eq!(
+            host("http://a.example.com:8080/x?y").as_deref(),
+            Some("a.example.com")
+        );
+        assert_eq!(
+            host("https://user@example.com@evil.com/").as_deref(),
+            Some("evil.com")
+        );
+        assert_eq!(
+            host("https://evil.com\\@example.com/").as_deref(),
+            Some("evil.com")
+        );
+        assert_eq!(host("http://[::1]:80/").as_deref(), Some("::1"));
+        assert_eq!(host("ftp://example.com"), None);
+        assert_eq!(host("https://"), None);
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let client = HttpClient::new().allow_domain("Example.com");
+        assert!(client.allowed_url("https://example.com/").is_some());
+        assert!(client.allowed_url("https://api.EXAMPLE.com/v1").is_some());
+        assert!(client.allowed_url("https://badexample.com/").is_none());
+        assert!(client
+            .allowed_url("https://example.com.evil.org/")
+            .is_none());
+        assert!(client
+            .allowed_url("https://evil.org\\@example.com/")
+            .is_none());
+        assert!(client.allowed_url("file:///etc/passwd").is_none());
+
+        assert_eq!(
+            client.request("GET", "https://evil.org/", &[]),
+            Err(HTTP_ERR_DENIED)
+        );
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a key-value store for guests, backed by a host provided `KvBackend`.
+//!
+//! guests import, from the `kv` module:
+//! - `kv_get(key_ptr, key_len, value_ptr, value_cap) -> i32`. It returns the length of the
+//!   value, or `-1` if the key is absent. The value is only copied when it fits in `value_cap`,
+//!   so a guest may call again with a larger buffer.
+//! - `kv_set(key_ptr, key_len, value_ptr, value_len) -> i32`. It returns `0`, or `-1` if the
+//!   backend refused the value.
+//! - `kv_delete(key_ptr, key_len) -> i32`. It returns `1` if the key existed, otherwise `0`.
+
+use std::{collections::HashMap, ffi::c_void, sync::Mutex};
+
+use crate::{
+    host_apis::{guest_bytes, guest_bytes_mut},
+    host_function::{catch_panic, ParamTy, ResultTy},
+    native_module::{NativeExports, NativeModule},
+    user_data::{Caller, ExecEnv},
+};
+
+/// the storage behind the `kv` host API
+pub trait KvBackend {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// return `false` to refuse the value
+    fn set(&self, key: &[u8], value: &[u8]) -> bool;
+
+    /// return whether the key existed
+    fn delete(&self, key: &[u8]) -> bool;
+}
+
+/// an in-memory `KvBackend`
+#[derive(Debug, Default)]
+pub struct MemoryKvBackend {
+    entries: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl KvBackend for MemoryKvBackend {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_vec(), value.to_vec());
+        true
+    }
+
+    fn delete(&self, key: &[u8]) -> bool {
+        self.entries.lock().unwrap().remove(key).is_some()
+    }
+}
+
+/// the `kv` native module
+pub struct KvStore<B> {
+    module_name: String,
+    backend: B,
+}
+
+impl<B: KvBackend + 'static> KvStore<B> {
+    pub fn new(backend: B) -> Self {
+        Self::with_module_name("kv", backend)
+    }
+
+    /// export the host functions under another module name
+    pub fn with_module_name(module_name: &str, backend: B) -> Self {
+        KvStore {
+            module_name: String::from(module_name),
+            backend,
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn get_into(&self, key: &[u8], value: &mut [u8]) -> i32 {
+        match self.backend.get(key) {
+            Some(found) => {
+                if found.len() <= value.len() {
+                    value[..found.len()].copy_from_slice(&found);
+                }
+                found.len() as i32
+            }
+            None => -1,
+        }
+    }
+}
+
+impl<B: KvBackend + 'static> NativeModule for KvStore<B> {
+    fn module_name(&self) -> &str {
+        &self.module_name
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports
+            .raw_function(
+                "kv_get",
+                kv_get::<B> as *mut c_void,
+                &[ParamTy::Buffer, ParamTy::Buffer],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "kv_set",
+                kv_set::<B> as *mut c_void,
+                &[ParamTy::Buffer, ParamTy::Buffer],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "kv_delete",
+                kv_delete::<B> as *mut c_void,
+                &[ParamTy::Buffer],
+                ResultTy::I32,
+            );
+    }
+}
+
+extern "C" fn kv_get<B: KvBackend + 'static>(
+    env: ExecEnv,
+    key: *const u8,
+    key_len: u32,
+    value: *mut u8,
+    value_cap: u32,
+) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let store = caller.native_module::<KvStore<B>>().unwrap();
+        let (key, value) =
+            unsafe { (guest_bytes(key, key_len), guest_bytes_mut(value, value_cap)) };
+        store.get_into(key, value)
+    })
+}
+
+extern "C" fn kv_set<B: KvBackend + 'static>(
+    env: ExecEnv,
+    key: *const u8,
+    key_len: u32,
+    value: *const u8,
+    value_len: u32,
+) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let store = caller.native_module::<KvStore<B>>().unwrap();
+        let (key, value) = unsafe { (guest_bytes(key, key_len), guest_bytes(value, value_len)) };
+        match store.backend.set(key, value) {
+            true => 0,
+            false => -1,
+        }
+    })
+}
+
+extern "C" fn kv_delete<B: KvBackend + 'static>(env: ExecEnv, key: *const u8, key_len: u32) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let store = caller.native_module::<KvStore<B>>().unwrap();
+        let key = unsafe { guest_bytes(key, key_len) };
+        store.backend.delete(key) as i32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryKvBackend::default();
+        assert_eq!(backend.get(b"a"), None);
+        assert!(backend.set(b"a", b"apple"));
+        assert_eq!(backend.get(b"a"), Some(b"apple".to_vec()));
+        assert!(backend.delete(b"a"));
+        assert!(!backend.delete(b"a"));
+    }
+
+    #[test]
+    fn test_get_into() {
+        let store = KvStore::new(MemoryKvBackend::default());
+        store.backend().set(b"key", b"value");
+
+        let mut small = [0u8; 2];
+        assert_eq!(store.get_into(b"key", &mut small), 5);
+        assert_eq!(small, [0, 0]);
+
+        let mut large = [0u8; 8];
+        assert_eq!(store.get_into(b"key", &mut large), 5);
+        assert_eq!(&large[..5], b"value");
+
+        assert_eq!(store.get_into(b"missing", &mut large), -1);
+    }
+}
@@ -0,0 +1,86 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! structured logging for guests, routed into the `log` crate.
+//!
+//! guests import, from the `log` module:
+//! - `log(level, msg_ptr, msg_len)`. `level` is 1 (error) to 5 (trace), like `log::Level`.
+//!
+//! records are logged with the name of the calling module as the target.
+//! register it via `RuntimeBuilder::with_guest_logging()`
+
+use std::ffi::c_void;
+
+use wamr_sys::{wasm_runtime_get_module, wasm_runtime_get_module_name};
+
+use crate::{
+    helper::cstr_to_string,
+    host_apis::guest_bytes,
+    host_function::{catch_panic, ParamTy, ResultTy},
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+};
+
+/// the `log` native module
+#[derive(Debug, Default)]
+pub struct GuestLogger;
+
+impl NativeModule for GuestLogger {
+    fn module_name(&self) -> &str {
+        "log"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.raw_function(
+            "log",
+            guest_log as *mut c_void,
+            &[ParamTy::I32, ParamTy::Buffer],
+            ResultTy::Void,
+        );
+    }
+}
+
+/// out of range levels are clamped
+fn to_level(level: i32) -> log::Level {
+    match level {
+        i32::MIN..=1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+    }
+}
+
+extern "C" fn guest_log(env: ExecEnv, level: i32, msg: *const u8, msg_len: u32) {
+    catch_panic(env, || {
+        let level = to_level(level);
+        if level > log::max_level() {
+            return;
+        }
+
+        let target = unsafe {
+            let module = wasm_runtime_get_module(env.module_inst());
+            cstr_to_string(wasm_runtime_get_module_name(module))
+        };
+        let msg = unsafe { guest_bytes(msg, msg_len) };
+        log::log!(target: &target, level, "{}", String::from_utf8_lossy(msg));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_level() {
+        assert_eq!(to_level(-7), log::Level::Error);
+        assert_eq!(to_level(1), log::Level::Error);
+        assert_eq!(to_level(2), log::Level::Warn);
+        assert_eq!(to_level(3), log::Level::Info);
+        assert_eq!(to_level(4), log::Level::Debug);
+        assert_eq!(to_level(5), log::Level::Trace);
+        assert_eq!(to_level(99), log::Level::Trace);
+    }
+}
@@ -0,0 +1,44 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! optional host API packages. Each one is a `NativeModule` behind its own feature,
+//! registered via `RuntimeBuilder::register_native_module()`
+
+#[cfg(feature = "host-http")]
+pub mod http;
+#[cfg(feature = "host-kv")]
+pub mod kv;
+#[cfg(feature = "host-log")]
+pub mod log;
+#[cfg(feature = "host-sched")]
+pub mod sched;
+#[cfg(feature = "host-timer")]
+pub mod timer;
+
+/// a guest buffer, already validated and translated by WAMR for a `ParamTy::Buffer`
+///
+/// # Safety
+///
+/// `ptr` must be valid for `len` bytes during `'a`
+#[cfg(any(feature = "host-kv", feature = "host-http", feature = "host-log"))]
+pub(crate) unsafe fn guest_bytes<'a>(ptr: *const u8, len: u32) -> &'a [u8] {
+    match ptr.is_null() {
+        true => &[],
+        false => std::slice::from_raw_parts(ptr, len as usize),
+    }
+}
+
+/// a writable guest buffer, already validated and translated by WAMR for a `ParamTy::Buffer`
+///
+/// # Safety
+///
+/// `ptr` must be valid for `len` bytes during `'a`
+#[cfg(any(feature = "host-kv", feature = "host-http"))]
+pub(crate) unsafe fn guest_bytes_mut<'a>(ptr: *mut u8, len: u32) -> &'a mut [u8] {
+    match ptr.is_null() {
+        true => &mut [],
+        false => std::slice::from_raw_parts_mut(ptr, len as usize),
+    }
+}
@@ -0,0 +1,211 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! cooperative scheduling for long-running guests, which yield to the host now and then.
+//!
+//! guests import, from the `sched` module:
+//! - `yield_now()`.
+//!
+//! a host call can't suspend the wasm stack, so a call always keeps its thread. A
+//! `YieldHook` decides what a yield does with it. `FairScheduler` runs a fixed number of
+//! calls at once, and hands the slot of a yielding call over to the longest waiting one.
+
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use crate::{
+    host_function::catch_panic,
+    native_module::{NativeExports, NativeModule},
+    user_data::{Caller, ExecEnv},
+};
+
+/// what happens when a guest yields
+pub trait YieldHook: Send + Sync {
+    fn yield_now(&self);
+}
+
+/// a `YieldHook` via `std::thread::yield_now()`
+#[derive(Debug, Default)]
+pub struct ThreadYield;
+
+impl YieldHook for ThreadYield {
+    fn yield_now(&self) {
+        thread::yield_now()
+    }
+}
+
+impl<H: YieldHook> YieldHook for Arc<H> {
+    fn yield_now(&self) {
+        self.as_ref().yield_now()
+    }
+}
+
+thread_local! {
+    /// the address of the `FairScheduler` whose slot the thread holds
+    static HELD_SLOT: Cell<usize> = const { Cell::new(0) };
+}
+
+#[derive(Debug, Default)]
+struct SchedulerState {
+    running: usize,
+    next_ticket: u64,
+    waiting: VecDeque<u64>,
+}
+
+/// runs at most a fixed number of calls at once, in the order they asked for a slot.
+///
+/// run each call via `FairScheduler::run()`, and register the scheduler, shared via an
+/// `Arc`, as the `YieldHook` of the `sched` module
+#[derive(Debug)]
+pub struct FairScheduler {
+    slots: usize,
+    state: Mutex<SchedulerState>,
+    turn: Condvar,
+}
+
+impl FairScheduler {
+    pub fn new(slots: usize) -> Self {
+        FairScheduler {
+            slots: slots.max(1),
+            state: Mutex::new(SchedulerState::default()),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// run `f` once a slot is free
+    pub fn run<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.acquire();
+        let _slot = HeldSlot::new(self);
+        f()
+    }
+
+    /// the number of calls waiting for a slot
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().waiting.len()
+    }
+
+    fn acquire(&self) {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.waiting.push_back(ticket);
+        while state.waiting.front() != Some(&ticket) || state.running >= self.slots {
+            state = self.turn.wait(state).unwrap();
+        }
+        state.waiting.pop_front();
+        state.running += 1;
+        self.turn.notify_all();
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().running -= 1;
+        self.turn.notify_all();
+    }
+}
+
+/// releases the slot of the thread, even if the call panics
+struct HeldSlot<'a>(&'a FairScheduler);
+
+impl<'a> HeldSlot<'a> {
+    fn new(scheduler: &'a FairScheduler) -> Self {
+        HELD_SLOT.with(|held| held.set(scheduler as *const FairScheduler as usize));
+        HeldSlot(scheduler)
+    }
+}
+
+impl Drop for HeldSlot<'_> {
+    fn drop(&mut self) {
+        HELD_SLOT.with(|held| held.set(0));
+        self.0.release();
+    }
+}
+
+/// give the slot to the longest waiting call, if any, and wait for another one. A call not
+/// run via `FairScheduler::run()` doesn't hold a slot, and keeps running
+impl YieldHook for FairScheduler {
+    fn yield_now(&self) {
+        let holds_slot = HELD_SLOT.with(|held| held.get()) == self as *const Self as usize;
+        if holds_slot && self.waiting() > 0 {
+            self.release();
+            self.acquire();
+        }
+    }
+}
+
+/// the `sched` native module
+pub struct Yielder<H> {
+    hook: H,
+}
+
+impl Default for Yielder<ThreadYield> {
+    fn default() -> Self {
+        Self::new(ThreadYield)
+    }
+}
+
+impl<H: YieldHook + 'static> Yielder<H> {
+    pub fn new(hook: H) -> Self {
+        Yielder { hook }
+    }
+}
+
+impl<H: YieldHook + 'static> NativeModule for Yielder<H> {
+    fn module_name(&self) -> &str {
+        "sched"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function("yield_now", yield_now::<H> as extern "C" fn(ExecEnv));
+    }
+}
+
+extern "C" fn yield_now<H: YieldHook + 'static>(env: ExecEnv) {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        caller
+            .native_module::<Yielder<H>>()
+            .unwrap()
+            .hook
+            .yield_now()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_fair_scheduler() {
+        let scheduler = Arc::new(FairScheduler::new(1));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        scheduler.run(|| {
+            order.lock().unwrap().push("first");
+
+            let (other_scheduler, other_order) = (scheduler.clone(), order.clone());
+            let other = thread::spawn(move || {
+                other_scheduler.run(|| other_order.lock().unwrap().push("other"));
+            });
+            while scheduler.waiting() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            scheduler.yield_now();
+            order.lock().unwrap().push("second");
+            other.join().unwrap();
+        });
+
+        assert_eq!(*order.lock().unwrap(), vec!["first", "other", "second"]);
+
+        // no slot held, nothing to hand over
+        scheduler.yield_now();
+        assert_eq!(scheduler.run(|| 7), 7);
+    }
+}
@@ -0,0 +1,230 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! sleeping and timers for guests, with the waiting delegated to a host provided `Sleeper`.
+//!
+//! guests import, from the `timer` module:
+//! - `sleep_ms(ms: i64)`.
+//! - `timer_set(delay_ms: i64) -> i32`. It returns the id of a new one-shot timer.
+//! - `timer_cancel(id: i32) -> i32`. It returns `1` if the timer was pending, otherwise `0`.
+//! - `timer_wait() -> i32`. It waits for the earliest pending timer of the instance and
+//!   returns its id, or `-1` if there is none. A guest runs the callback of that timer itself.
+//!
+//! a host call can't suspend the wasm stack, so a guest always keeps its thread while waiting.
+//! A `Sleeper` decides how that thread waits, like by handing it over to an executor.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    host_function::catch_panic,
+    native_module::{NativeExports, NativeModule},
+    user_data::{Caller, ExecEnv},
+};
+
+/// how the thread of a guest waits
+pub trait Sleeper: Send + Sync {
+    fn sleep(&self, duration: Duration);
+}
+
+/// a `Sleeper` blocking the thread via `std::thread::sleep()`
+#[derive(Debug, Default)]
+pub struct ThreadSleeper;
+
+impl Sleeper for ThreadSleeper {
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+#[derive(Default)]
+struct TimerState {
+    next_id: i32,
+    /// pending timers, by the address of the owning instance
+    pending: HashMap<usize, Vec<(i32, Instant)>>,
+}
+
+/// the `timer` native module
+pub struct Timers<S> {
+    sleeper: S,
+    state: Mutex<TimerState>,
+}
+
+impl Default for Timers<ThreadSleeper> {
+    fn default() -> Self {
+        Self::new(ThreadSleeper)
+    }
+}
+
+impl<S: Sleeper + 'static> Timers<S> {
+    pub fn new(sleeper: S) -> Self {
+        Timers {
+            sleeper,
+            state: Mutex::new(TimerState::default()),
+        }
+    }
+
+    fn sleep_ms(&self, ms: i64) {
+        if ms > 0 {
+            self.sleeper.sleep(Duration::from_millis(ms as u64));
+        }
+    }
+
+    fn set(&self, owner: usize, delay_ms: i64) -> i32 {
+        let deadline = Instant::now() + Duration::from_millis(delay_ms.max(0) as u64);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id = state.next_id.wrapping_add(1) & i32::MAX;
+        state.pending.entry(owner).or_default().push((id, deadline));
+        id
+    }
+
+    fn cancel(&self, owner: usize, id: i32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let timers = match state.pending.get_mut(&owner) {
+            Some(timers) => timers,
+            None => return false,
+        };
+        let before = timers.len();
+        timers.retain(|(pending, _)| *pending != id);
+        before != timers.len()
+    }
+
+    fn wait(&self, owner: usize) -> i32 {
+        let (id, deadline) = {
+            let mut state = self.state.lock().unwrap();
+            let timers = match state.pending.get_mut(&owner) {
+                Some(timers) if !timers.is_empty() => timers,
+                _ => return -1,
+            };
+            let earliest = (0..timers.len()).min_by_key(|i| timers[*i].1).unwrap();
+            let timer = timers.swap_remove(earliest);
+            if timers.is_empty() {
+                state.pending.remove(&owner);
+            }
+            timer
+        };
+
+        let now = Instant::now();
+        if deadline > now {
+            self.sleeper.sleep(deadline - now);
+        }
+        id
+    }
+}
+
+impl<S: Sleeper + 'static> NativeModule for Timers<S> {
+    fn module_name(&self) -> &str {
+        "timer"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports
+            .function("sleep_ms", sleep_ms::<S> as extern "C" fn(ExecEnv, i64))
+            .function(
+                "timer_set",
+                timer_set::<S> as extern "C" fn(ExecEnv, i64) -> i32,
+            )
+            .function(
+                "timer_cancel",
+                timer_cancel::<S> as extern "C" fn(ExecEnv, i32) -> i32,
+            )
+            .function(
+                "timer_wait",
+                timer_wait::<S> as extern "C" fn(ExecEnv) -> i32,
+            );
+    }
+}
+
+fn owner(env: ExecEnv) -> usize {
+    env.module_inst() as usize
+}
+
+extern "C" fn sleep_ms<S: Sleeper + 'static>(env: ExecEnv, ms: i64) {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        caller.native_module::<Timers<S>>().unwrap().sleep_ms(ms)
+    })
+}
+
+extern "C" fn timer_set<S: Sleeper + 'static>(env: ExecEnv, delay_ms: i64) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let timers = caller.native_module::<Timers<S>>().unwrap();
+        timers.set(owner(env), delay_ms)
+    })
+}
+
+extern "C" fn timer_cancel<S: Sleeper + 'static>(env: ExecEnv, id: i32) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let timers = caller.native_module::<Timers<S>>().unwrap();
+        timers.cancel(owner(env), id) as i32
+    })
+}
+
+extern "C" fn timer_wait<S: Sleeper + 'static>(env: ExecEnv) -> i32 {
+    catch_panic(env, || {
+        let caller: Caller<()> = Caller::from_env(&env);
+        let timers = caller.native_module::<Timers<S>>().unwrap();
+        timers.wait(owner(env))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RecordingSleeper {
+        slept: Mutex<Vec<Duration>>,
+    }
+
+    impl Sleeper for RecordingSleeper {
+        fn sleep(&self, duration: Duration) {
+            self.slept.lock().unwrap().push(duration);
+        }
+    }
+
+    #[test]
+    fn test_sleep_ms() {
+        let timers = Timers::new(RecordingSleeper::default());
+        timers.sleep_ms(0);
+        timers.sleep_ms(-5);
+        timers.sleep_ms(20);
+        assert_eq!(
+            *timers.sleeper.slept.lock().unwrap(),
+            vec![Duration::from_millis(20)]
+        );
+    }
+
+    #[test]
+    fn test_timers() {
+        let timers = Timers::new(RecordingSleeper::default());
+        assert_eq!(timers.wait(1), -1);
+
+        let late = timers.set(1, 60_000);
+        let early = timers.set(1, 0);
+        let cancelled = timers.set(1, 10);
+        let other = timers.set(2, 0);
+
+        assert!(timers.cancel(1, cancelled));
+        assert!(!timers.cancel(1, cancelled));
+        assert!(!timers.cancel(1, other));
+
+        assert_eq!(timers.wait(1), early);
+        assert_eq!(timers.wait(1), late);
+        assert_eq!(timers.wait(1), -1);
+        assert_eq!(timers.wait(2), other);
+
+        let slept = timers.sleeper.slept.lock().unwrap();
+        assert_eq!(slept.len(), 1);
+        assert!(slept[0] > Duration::from_secs(59));
+    }
+}
@@ -4,11 +4,25 @@
  */
 
 /// This is a wrapper of a host defined(Rust) function.
-use std::ffi::{c_void, CString};
+use std::any::Any;
+use std::ffi::{c_void, CStr, CString};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::ptr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
-use wamr_sys::NativeSymbol;
+/// an entry of a WAMR symbol table, see `RuntimeBuilder::register_native_symbols_raw()`
+pub use wamr_sys::NativeSymbol;
+use wamr_sys::{wasm_module_inst_t, wasm_runtime_get_function_attachment};
 
+use crate::{account, user_data::ExecEnv, ErrorMessage, RuntimeError};
+
+/// the type of a parameter of a host function. `Str`, `Pointer` and `Buffer` are
+/// addresses in the linear memory of the guest, i32 ones, or i64 ones when it's a
+/// memory64. WAMR translates them into host pointers either way, and passes the length
+/// of a `Buffer` as a `u32`, or a `u64` for memory64. See `Caller::app_to_native()` to
+/// translate other addresses
 pub enum ParamTy {
     I32,
     I64,
@@ -53,12 +67,295 @@ impl ResultTy {
     }
 }
 
+/// a Rust type which maps to a wasm value type in a host function signature
+pub trait HostValue {
+    fn param_ty() -> ParamTy;
+}
+
+/// a Rust type which may be returned by a host function
+pub trait HostResult {
+    fn result_ty() -> ResultTy;
+}
+
+macro_rules! impl_host_value {
+    ($native:ty, $variant:ident) => {
+        impl HostValue for $native {
+            fn param_ty() -> ParamTy {
+                ParamTy::$variant
+            }
+        }
+
+        impl HostResult for $native {
+            fn result_ty() -> ResultTy {
+                ResultTy::$variant
+            }
+        }
+    };
+}
+
+impl_host_value!(i32, I32);
+impl_host_value!(i64, I64);
+impl_host_value!(f32, F32);
+impl_host_value!(f64, F64);
+
+impl HostResult for () {
+    fn result_ty() -> ResultTy {
+        ResultTy::Void
+    }
+}
+
+/// a host function whose signature is derived from its Rust type.
+///
+/// implemented for `fn(ExecEnv, ...) -> R` and `extern "C" fn(ExecEnv, ...) -> R` with up
+/// to 6 parameters. Function items have to be cast to the pointer type, like
+/// `add as fn(ExecEnv, i32, i32) -> i32`.
+///
+/// WAMR calls the function through a trampoline generated for its type, which runs it in
+/// `catch_panic()`: a panic of a `fn` traps the calling instance, while one escaping an
+/// `extern "C" fn` aborts the process, unless it's declared with `host_function!`
+pub trait TypedHostFunction {
+    fn params() -> Vec<ParamTy>;
+    fn result() -> ResultTy;
+    fn function_ptr(self) -> *mut c_void;
+    /// the function WAMR calls instead, which finds `function_ptr()` in the `HostCall`
+    /// attached to the host function
+    fn trampoline() -> *mut c_void;
+}
+
+macro_rules! impl_typed_host_function {
+    ($($param:ident $arg:ident),*) => {
+        impl_typed_host_function!(@abi [] $($param $arg),*);
+        impl_typed_host_function!(@abi [extern "C"] $($param $arg),*);
+    };
+    (@abi [$($abi:tt)*] $($param:ident $arg:ident),*) => {
+        impl<R: HostResult + Default, $($param: HostValue),*> TypedHostFunction
+            for $($abi)* fn(ExecEnv, $($param),*) -> R
+        {
+            fn params() -> Vec<ParamTy> {
+                vec![$($param::param_ty()),*]
+            }
+
+            fn result() -> ResultTy {
+                R::result_ty()
+            }
+
+            fn function_ptr(self) -> *mut c_void {
+                self as *mut c_void
+            }
+
+            fn trampoline() -> *mut c_void {
+                extern "C" fn trampoline<R: HostResult + Default, $($param: HostValue),*>(
+                    env: ExecEnv,
+                    $($arg: $param),*
+                ) -> R {
+                    let call = unsafe { HostCall::of(env) };
+                    if call.counted {
+                        account::record_host_call(env.module_inst());
+                    }
+                    let function: $($abi)* fn(ExecEnv, $($param),*) -> R =
+                        unsafe { mem::transmute(call.function_ptr) };
+                    catch_panic(env, move || function(env, $($arg),*))
+                }
+
+                trampoline::<R, $($param),*> as *mut c_void
+            }
+        }
+    };
+}
+
+impl_typed_host_function!();
+impl_typed_host_function!(A1 a1);
+impl_typed_host_function!(A1 a1, A2 a2);
+impl_typed_host_function!(A1 a1, A2 a2, A3 a3);
+impl_typed_host_function!(A1 a1, A2 a2, A3 a3, A4 a4);
+impl_typed_host_function!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5);
+impl_typed_host_function!(A1 a1, A2 a2, A3 a3, A4 a4, A5 a5, A6 a6);
+
+/// the attachment WAMR passes to a host function of a `HostFunctionList`: the function a
+/// trampoline calls, and the attachment of the list, see `Caller::native_module()`. Boxed,
+/// so its address stays the same
+#[derive(Debug)]
+pub(crate) struct HostCall {
+    function_ptr: *mut c_void,
+    pub(crate) attachment: *mut c_void,
+    // whether the trampoline counts the call into the `ResourceAccount` of the instance,
+    // unlike the calls of the hooks of instrumented code
+    counted: bool,
+}
+
+/// the addresses of the live `HostCall`s, to tell them from the attachments of the symbols
+/// of a WAMR symbol table, which WAMR passes as they are
+static HOST_CALLS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn host_calls() -> MutexGuard<'static, Vec<usize>> {
+    HOST_CALLS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl HostCall {
+    fn new(function_ptr: *mut c_void, attachment: *mut c_void, counted: bool) -> Box<Self> {
+        let call = Box::new(HostCall {
+            function_ptr,
+            attachment,
+            counted,
+        });
+        host_calls().push(&*call as *const HostCall as usize);
+        call
+    }
+
+    /// the host call of the running host function
+    ///
+    /// # Safety
+    ///
+    /// the host function is registered through a `HostFunctionList`, other than by
+    /// `register_native_symbol()`
+    pub(crate) unsafe fn of<'a>(env: ExecEnv) -> &'a HostCall {
+        &*(wasm_runtime_get_function_attachment(env.as_raw()) as *const HostCall)
+    }
+
+    /// the host call of the running host function, `None` if it's registered by
+    /// `register_native_symbol()`, so its attachment is its own
+    pub(crate) fn find<'a>(env: ExecEnv) -> Option<&'a HostCall> {
+        let attachment = unsafe { wasm_runtime_get_function_attachment(env.as_raw()) };
+        let attachment = attachment as *const HostCall;
+        host_calls()
+            .contains(&(attachment as usize))
+            .then(|| unsafe { &*attachment })
+    }
+}
+
+impl Drop for HostCall {
+    fn drop(&mut self) {
+        let address = self as *const HostCall as usize;
+        let mut calls = host_calls();
+        if let Some(index) = calls.iter().position(|call| *call == address) {
+            calls.swap_remove(index);
+        }
+    }
+}
+
+/// the instances whose host functions abort the process on a panic, see
+/// `RuntimeBuilder::abort_on_host_panic()`
+static ABORTING: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn aborting() -> MutexGuard<'static, Vec<usize>> {
+    ABORTING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn set_abort_on_panic(instance: wasm_module_inst_t) {
+    aborting().push(instance as usize);
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    aborting().retain(|aborting| *aborting != instance as usize);
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map_or("Box<dyn Any>", String::as_str),
+    }
+}
+
+/// run the body of a host function, turning a panic into a trap of the calling instance,
+/// since a panic can't unwind through WAMR. The host function returns the default value,
+/// which the guest never sees.
+///
+/// for the instances of a runtime built with `RuntimeBuilder::abort_on_host_panic()`,
+/// abort the process instead, to debug it. Typed host functions are wrapped this way by
+/// their trampoline; see `host_function!` to declare a raw one wrapped this way, since a
+/// panic escaping an `extern "C" fn` aborts the process
+pub fn catch_panic<R: Default>(env: ExecEnv, body: impl FnOnce() -> R) -> R {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => return result,
+        Err(payload) => payload,
+    };
+    if aborting().contains(&(env.module_inst() as usize)) {
+        process::abort();
+    }
+
+    let message = format!(
+        "host function panicked: {}",
+        panic_message(payload.as_ref())
+    );
+    env.set_exception(&message);
+    R::default()
+}
+
+/// declare an `extern "C"` host function whose body runs in `catch_panic()`, like
+/// ```ignore
+/// host_function! {
+///     fn add(env: ExecEnv, a: i32, b: i32) -> i32 {
+///         a.checked_add(b).expect("overflow")
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! host_function {
+    (
+        $(#[$meta:meta])*
+        $vis:vis fn $name:ident(
+            $env:ident: $env_ty:ty $(, $arg:ident: $arg_ty:ty)* $(,)?
+        ) $(-> $ret:ty)? $body:block
+    ) => {
+        $(#[$meta])*
+        $vis extern "C" fn $name($env: $env_ty $(, $arg: $arg_ty)*) $(-> $ret)? {
+            $crate::host_function::catch_panic($env, || $body)
+        }
+    };
+}
+
+/// check a signature the way WAMR reads it, like `(i*~$)I`: parameters among `iIfFr*~$`,
+/// each `~`, the length of a buffer, right after the `*` of the buffer, and one result
+/// among `iIfFr` at most
+fn check_signature(signature: &[u8]) -> Result<(), ErrorMessage> {
+    let text = String::from_utf8_lossy(signature);
+    let (params, results) = signature
+        .strip_prefix(b"(")
+        .and_then(|rest| {
+            let end = rest.iter().position(|c| *c == b')')?;
+            Some((&rest[..end], &rest[end + 1..]))
+        })
+        .ok_or_else(|| message!("malformed signature {}", text))?;
+    for (i, param) in params.iter().enumerate() {
+        match param {
+            b'i' | b'I' | b'f' | b'F' | b'r' | b'*' | b'$' => {}
+            b'~' if i > 0 && params[i - 1] == b'*' => {}
+            b'~' => return Err(message!("a buffer length without a buffer in {}", text)),
+            _ => {
+                return Err(message!(
+                    "unsupported parameter {:?} in {}",
+                    *param as char,
+                    text
+                ))
+            }
+        }
+    }
+    match results {
+        [] | [b'i' | b'I' | b'f' | b'F' | b'r'] => Ok(()),
+        _ => Err(message!("unsupported results in {}", text)),
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 struct HostFunction {
     function_name: CString,
     function_ptr: *mut c_void,
     signature: CString,
+    // `None` for the symbols of a WAMR symbol table, which keep their own attachments
+    call: Option<Box<HostCall>>,
+}
+
+/// a registered host function, as given to the AOT compiler, see
+/// `AotCompiler::host_functions()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostSymbol {
+    pub module: String,
+    pub name: String,
+    /// as registered into WAMR, like `(i*~)I`
+    pub signature: String,
 }
 
 #[derive(Debug)]
@@ -67,6 +364,8 @@ pub struct HostFunctionList {
     // keep ownership of the content of `native_symbols`
     host_functions: Vec<HostFunction>,
     pub native_symbols: Vec<NativeSymbol>,
+    // passed to every host function of the list, see `Caller::native_module()`
+    attachment: *mut c_void,
 }
 
 impl HostFunctionList {
@@ -75,10 +374,33 @@ impl HostFunctionList {
             module_name: CString::new(module_name).unwrap(),
             host_functions: Vec::new(),
             native_symbols: Vec::new(),
+            attachment: ptr::null_mut(),
+        }
+    }
+
+    pub(crate) fn set_attachment(&mut self, attachment: *mut c_void) {
+        self.attachment = attachment;
+        for function in self.host_functions.iter_mut() {
+            if let Some(call) = &mut function.call {
+                call.attachment = attachment;
+            }
         }
     }
 
-    pub fn register_host_function(&mut self, function_name: &str, function_ptr: *mut c_void, params: &[ParamTy], result: ResultTy) {
+    /// register a host function, once its name and its signature are checked
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::HostRegistration` if the name is empty, holds a nul byte or
+    /// is registered already, if the function is null, or if WAMR wouldn't accept the
+    /// signature.
+    pub fn register_host_function(
+        &mut self,
+        function_name: &str,
+        function_ptr: *mut c_void,
+        params: &[ParamTy],
+        result: ResultTy,
+    ) -> Result<(), RuntimeError> {
         let mut signature = Vec::new();
         signature.push(b'(');
         for param in params {
@@ -86,17 +408,141 @@ impl HostFunctionList {
         }
         signature.push(b')');
         result.encode(&mut signature);
+        self.register(function_name, function_ptr, function_ptr, signature)
+    }
+
+    /// register a host function of a WAMR symbol table, with copies of its name and its
+    /// signature, and the attachment of the symbol
+    ///
+    /// # Safety
+    ///
+    /// the name and the signature of `symbol` are null, or nul-terminated strings
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::HostRegistration` if the name isn't UTF-8, or is invalid, see
+    /// `register_host_function()`.
+    pub(crate) unsafe fn register_native_symbol(
+        &mut self,
+        symbol: &NativeSymbol,
+    ) -> Result<(), RuntimeError> {
+        let name = match symbol.symbol.is_null() {
+            true => "",
+            false => CStr::from_ptr(symbol.symbol)
+                .to_str()
+                .map_err(|_| RuntimeError::HostRegistration(message!("a name isn't UTF-8")))?,
+        };
+        let signature = match symbol.signature.is_null() {
+            true => Vec::new(),
+            false => CStr::from_ptr(symbol.signature).to_bytes().to_vec(),
+        };
+        self.register(name, symbol.func_ptr, symbol.func_ptr, signature)?;
+        self.host_functions.last_mut().unwrap().call = None;
+        self.native_symbols.last_mut().unwrap().attachment = symbol.attachment;
+        Ok(())
+    }
+
+    /// register `function_ptr`, which WAMR calls through `trampoline`, or directly when
+    /// they are the same
+    fn register(
+        &mut self,
+        function_name: &str,
+        function_ptr: *mut c_void,
+        trampoline: *mut c_void,
+        signature: Vec<u8>,
+    ) -> Result<(), RuntimeError> {
+        let invalid = |reason: ErrorMessage| {
+            RuntimeError::HostRegistration(message!("{:?}: {}", function_name, reason))
+        };
+        let name = CString::new(function_name)
+            .map_err(|_| invalid(message!("the name holds a nul byte")))?;
+        if function_name.is_empty() {
+            return Err(invalid(message!("the name is empty")));
+        }
+        if self
+            .host_functions
+            .iter()
+            .any(|function| function.function_name == name)
+        {
+            return Err(invalid(message!("registered twice")));
+        }
+        if function_ptr.is_null() {
+            return Err(invalid(message!("the function is null")));
+        }
+        check_signature(&signature).map_err(invalid)?;
         let signature = CString::new(signature).unwrap();
 
+        let counted = !account::is_hook_module(&self.module_name.to_string_lossy());
+        let mut call = HostCall::new(function_ptr, self.attachment, counted);
+        let mut native_symbol = pack_host_function(&name, trampoline, &signature);
+        native_symbol.attachment = &mut *call as *mut HostCall as *mut c_void;
         self.host_functions.push(HostFunction {
-            function_name: CString::new(function_name).unwrap(),
+            function_name: name,
             function_ptr,
             signature,
+            call: Some(call),
         });
+        self.native_symbols.push(native_symbol);
+        Ok(())
+    }
 
-        let last = self.host_functions.last().unwrap();
-        self.native_symbols
-            .push(pack_host_function(&(last.function_name), function_ptr, &(last.signature)));
+    /// register a host function with the signature derived from its type, called through
+    /// its trampoline
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::HostRegistration` if the name is invalid, see
+    /// `register_host_function()`.
+    pub fn register_typed_host_function<F: TypedHostFunction>(
+        &mut self,
+        function_name: &str,
+        function: F,
+    ) -> Result<(), RuntimeError> {
+        let mut signature = vec![b'('];
+        for param in F::params() {
+            param.encode(&mut signature);
+        }
+        signature.push(b')');
+        F::result().encode(&mut signature);
+        self.register(
+            function_name,
+            function.function_ptr(),
+            F::trampoline(),
+            signature,
+        )
+    }
+
+    /// the host functions of the list
+    pub(crate) fn symbols(&self) -> impl Iterator<Item = HostSymbol> + '_ {
+        let module = self.module_name.to_string_lossy();
+        self.host_functions.iter().map(move |function| HostSymbol {
+            module: module.clone().into_owned(),
+            name: function.function_name.to_string_lossy().into_owned(),
+            signature: function.signature.to_string_lossy().into_owned(),
+        })
+    }
+
+    /// the name of a host function `other` registers under the same module name as well,
+    /// as another function or with another signature. The same function registered twice
+    /// is no collision
+    pub(crate) fn collision(&self, other: &HostFunctionList) -> Option<String> {
+        if self.module_name != other.module_name {
+            return None;
+        }
+        self.host_functions.iter().find_map(|function| {
+            let collides = other.host_functions.iter().any(|registered| {
+                registered.function_name == function.function_name
+                    && (registered.function_ptr != function.function_ptr
+                        || registered.signature != function.signature)
+            });
+            collides.then(|| {
+                format!(
+                    "{}.{} registered twice",
+                    self.module_name.to_string_lossy(),
+                    function.function_name.to_string_lossy()
+                )
+            })
+        })
     }
 
     pub fn get_native_symbols(&mut self) -> &mut Vec<NativeSymbol> {
@@ -122,7 +568,8 @@ mod tests {
     use super::*;
     use crate::user_data::{ExecEnv, Caller};
     use crate::{
-        function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
+        function::Function, instance::Instance, memory::WASM_PAGE_SIZE, module::Module,
+        runtime::Runtime, value::WasmValue, RuntimeError,
     };
     use std::env;
     use std::path::PathBuf;
@@ -164,7 +611,7 @@ mod tests {
     }
 
     extern "C" fn extra_with_side_effect(env: ExecEnv) -> i32 {
-        let mut user_data: Caller<Counter> = Caller::from_env(env);
+        let mut user_data: Caller<Counter> = Caller::from_env(&env);
         let count = user_data.data_mut();
         count.count += 1;
         count.count
@@ -201,4 +648,234 @@ mod tests {
         let result = function.call(instance, &params);
         assert_eq!(result.unwrap(), WasmValue::I32(18));
     }
+
+    host_function! {
+        fn extra_panicking(env: ExecEnv) -> i32 {
+            let caller: Caller<Counter> = Caller::from_env(&env);
+            panic!("count {}", caller.data().count)
+        }
+    }
+
+    #[test]
+    fn test_host_function_panic() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("extra", extra_panicking as *mut c_void, &[], ResultTy::I32)
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 7 }).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+        match function.call(&instance, &params) {
+            Err(RuntimeError::ExecutionError(e)) => {
+                assert!(e.contains("host function panicked: count 7"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    fn extra_typed_panicking(env: ExecEnv) -> i32 {
+        let caller: Caller<Counter> = Caller::from_env(&env);
+        panic!("count {}", caller.data().count)
+    }
+
+    #[test]
+    fn test_typed_host_function_panic() {
+        let function = extra_typed_panicking as fn(ExecEnv) -> i32;
+        let mut list = HostFunctionList::new("host");
+        assert!(list.register_typed_host_function("extra", function).is_ok());
+        assert_ne!(list.native_symbols[0].func_ptr, function as *mut c_void);
+        assert_eq!(list.symbols().next().unwrap().signature, "()i");
+
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_typed_host_function("extra", function)
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 3 }).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+        match function.call(&instance, &params) {
+            Err(RuntimeError::ExecutionError(e)) => {
+                assert!(e.contains("host function panicked: count 3"))
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    host_function! {
+        fn extra_aliasing(env: ExecEnv) -> i32 {
+            let mut first: Caller<Counter> = Caller::from_env(&env);
+            first.data_mut().count += 1;
+            let second: Caller<Counter> = Caller::from_env(&env);
+            second.data().count
+        }
+    }
+
+    #[test]
+    fn test_caller_borrows() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("extra", extra_aliasing as *mut c_void, &[], ResultTy::I32)
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 0 }).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+        for _ in 0..2 {
+            match function.call(&instance, &params) {
+                Err(RuntimeError::ExecutionError(e)) => {
+                    assert!(e.contains("already borrowed mutably"))
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+        assert_eq!(instance.data().count, 2);
+    }
+
+    extern "C" fn extra_limited(env: ExecEnv) -> i32 {
+        let count = env.user_data::<Counter>().unwrap().data().count;
+        if count > 5 {
+            env.set_exception("count over the limit");
+        }
+        count
+    }
+
+    fn extra_inspecting(env: ExecEnv) -> i32 {
+        let caller: Caller<Counter> = Caller::from_env(&env);
+        let instance = caller.instance();
+        assert_eq!(instance.id(), env.instance());
+        assert!(instance.has_export("add"));
+        assert!(!instance.has_export("sub"));
+
+        instance.set_exception("pending");
+        assert_eq!(instance.exception().as_deref(), Some("Exception: pending"));
+        instance.clear_exception();
+        assert_eq!(instance.exception(), None);
+        instance.memory().data_size() as i32 / WASM_PAGE_SIZE as i32
+    }
+
+    #[test]
+    fn test_caller_instance() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_typed_host_function("extra", extra_inspecting as fn(ExecEnv) -> i32)
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 0 }).unwrap();
+        let pages = instance.memory().pages() as i32;
+        let function = Function::find_export_func(&instance, "add").unwrap();
+
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+        let result = function.call(&instance, &params);
+        assert_eq!(result.unwrap(), WasmValue::I32(16 + pages));
+    }
+
+    #[test]
+    fn test_exec_env() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("extra", extra_limited as *mut c_void, &[], ResultTy::I32)
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 5 }).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+        assert_eq!(
+            function.call(&instance, &params).unwrap(),
+            WasmValue::I32(21)
+        );
+
+        let instance = Instance::new(&runtime, &module, 1024 * 64, Counter { count: 6 }).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+        match function.call(&instance, &params) {
+            Err(RuntimeError::ExecutionError(e)) => assert!(e.contains("count over the limit")),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_register_invalid_host_function() {
+        let mut list = HostFunctionList::new("host");
+        let extra = extra as *mut c_void;
+        let params = [ParamTy::I32, ParamTy::Buffer, ParamTy::Str];
+        assert!(list
+            .register_host_function("extra", extra, &params, ResultTy::I64)
+            .is_ok());
+        assert_eq!(list.symbols().next().unwrap().signature, "(i*~$)I");
+
+        for (name, function) in [("extra", extra), ("", extra), ("ex\0tra", extra)] {
+            assert!(matches!(
+                list.register_host_function(name, function, &[], ResultTy::I32),
+                Err(RuntimeError::HostRegistration(_))
+            ));
+        }
+        assert!(list
+            .register_host_function("null", ptr::null_mut(), &[], ResultTy::I32)
+            .is_err());
+        assert_eq!(list.native_symbols.len(), 1);
+
+        assert!(check_signature(b"(*~)").is_ok());
+        assert!(check_signature(b"(i~)i").is_err());
+        assert!(check_signature(b"(ii)ii").is_err());
+        assert!(check_signature(b"(i").is_err());
+
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("extra", extra, &[], ResultTy::I32)
+            .register_host_function("extra", extra, &[], ResultTy::I32)
+            .build();
+        assert!(matches!(runtime, Err(RuntimeError::HostRegistration(_))));
+    }
+
+    #[test]
+    fn test_native_symbol_attachment() {
+        let mut own = 0u32;
+        let (name, signature) = (CString::new("extra").unwrap(), CString::new("()i").unwrap());
+        let symbol = NativeSymbol {
+            symbol: name.as_ptr(),
+            func_ptr: extra as *mut c_void,
+            signature: signature.as_ptr(),
+            attachment: &mut own as *mut u32 as *mut c_void,
+        };
+        let mut list = HostFunctionList::new("host");
+        list.register_host_function("other", extra as *mut c_void, &[], ResultTy::I32)
+            .unwrap();
+        unsafe { list.register_native_symbol(&symbol) }.unwrap();
+
+        // the symbol keeps its own attachment, which isn't taken for a `HostCall`
+        let attachments: Vec<_> = list.native_symbols.iter().map(|s| s.attachment).collect();
+        assert_eq!(attachments[1], symbol.attachment);
+        assert!(host_calls().contains(&(attachments[0] as usize)));
+        assert!(!host_calls().contains(&(attachments[1] as usize)));
+    }
 }
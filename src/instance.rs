@@ -9,51 +9,122 @@
 #![allow(unused_variables)]
 
 use core::ffi::c_char;
-use std::marker::PhantomData;
+use std::{
+    cell::{Cell, RefCell},
+    ffi::{c_void, CString},
+    fmt,
+    marker::PhantomData,
+    ops::Range,
+    ptr,
+    rc::Rc,
+    sync::Arc,
+};
 
 use wamr_sys::{
-    wasm_module_inst_t, wasm_runtime_deinstantiate, wasm_runtime_destroy_thread_env,
-    wasm_runtime_init_thread_env, wasm_runtime_instantiate,
+    wasm_exec_env_t, wasm_module_inst_t, wasm_runtime_clear_exception, wasm_runtime_deinstantiate,
+    wasm_runtime_get_exception, wasm_runtime_get_exec_env_singleton, wasm_runtime_get_module_inst,
+    wasm_runtime_get_user_data, wasm_runtime_instantiate, wasm_runtime_lookup_function,
+    wasm_runtime_set_custom_data, wasm_runtime_set_exception,
+    wasm_runtime_set_native_stack_boundary, wasm_runtime_set_user_data,
 };
 
 use crate::{
-    helper::error_buf_to_string, helper::DEFAULT_ERROR_BUF_SIZE, module::Module, runtime::Runtime,
+    account::ResourceAccount,
+    allocator,
+    event::{EventBus, InstanceId, RuntimeEvent},
+    function::Function,
+    guest_interface::GuestInterface,
+    helper::error_buf_to_string,
+    helper::exception_to_string,
+    helper::DEFAULT_ERROR_BUF_SIZE,
+    host_function,
+    journal::{self, Checkpoint},
+    lifecycle::{Call, Calls, Dependent},
+    memory::{self, Memory, MemoryHints, SharedMemory, Watchpoint, WASM_PAGE_SIZE},
+    module::{Module, DEFERRED_INITIALIZE_EXPORT, DEFERRED_START_EXPORT},
+    oom::{self, GuestOom, OomAction},
+    platform,
+    registry::InstanceRegistry,
+    replay::{self, Recording},
+    runtime::Runtime,
+    scheduling::{AppliedHints, SchedulingHints},
+    user_data::{HostData, UserData},
+    value::WasmValue,
     RuntimeError,
 };
 
-#[derive(Debug)]
+/// a stack size to instantiate with, when a module doesn't need a specific one. Small with
+/// the `tiny` feature
+#[cfg(feature = "tiny")]
+pub const DEFAULT_STACK_SIZE: u32 = 8 * 1024;
+#[cfg(not(feature = "tiny"))]
+pub const DEFAULT_STACK_SIZE: u32 = 64 * 1024;
+
 pub struct Instance<T> {
     instance: wasm_module_inst_t,
+    shared_memory: bool,
+    watchpoints: RefCell<Vec<Option<Watchpoint>>>,
+    events: Arc<EventBus>,
+    calls: Calls,
+    account: Option<ResourceAccount>,
+    scheduling_hints: Option<SchedulingHints>,
+    canonicalize_nans: bool,
+    // whether calls go through optional hooks, see `Instance::is_instrumented()`
+    instrumented: bool,
+    memory_hints: MemoryHints,
+    // as the guest names them and where they are on the host, see `wasi_policy`
+    #[cfg(libc_wasi)]
+    preopens: Vec<(String, std::path::PathBuf)>,
+    started: Cell<bool>,
+    finalized: Cell<bool>,
+    // the exports looked up by `call()`, by name
+    functions: RefCell<Vec<(String, Rc<Function>)>>,
+    _module: Dependent,
     _data: PhantomData<T>
 }
 
-impl<T> Instance<T> {
-    /// instantiate a module with stack size
+impl<T: 'static> Instance<T> {
+    /// instantiate a module with stack size, and the app heap of the runtime, see
+    /// `RuntimeBuilder::default_app_heap()`
     ///
     /// # Error
     ///
     /// Return `RuntimeError::CompilationError` if failed.
+    /// Return `RuntimeError::AppHeapRequired` if the module imports an allocator, while the
+    /// runtime disables the app heap.
     pub fn new(runtime: &Runtime, module: &Module, stack_size: u32, data: T) -> Result<Self, RuntimeError> {
-        Self::new_with_args(runtime, module, stack_size, 0, data)
+        let heap_size = runtime.app_heap().unwrap_or(0);
+        Self::new_with_args(runtime, module, stack_size, heap_size, data)
     }
 
     /// instantiate a module with stack size and host managed heap size
     ///
-    /// heap_size is used for `-nostdlib` Wasm and wasm32-unknown
+    /// heap_size is used for `-nostdlib` Wasm and wasm32-unknown, `0` for no app heap,
+    /// see `RuntimeBuilder::default_app_heap()`
     ///
     /// # Error
     ///
     /// Return `RuntimeError::CompilationError` if failed.
+    /// Return `RuntimeError::AppHeapRequired` if `heap_size` is `0` and the module imports
+    /// an allocator, while the runtime disables the app heap.
     pub fn new_with_args(
-        _runtime: &Runtime,
+        runtime: &Runtime,
         module: &Module,
         stack_size: u32,
         heap_size: u32,
         data: T,
     ) -> Result<Self, RuntimeError> {
-        let init_thd_env = unsafe { wasm_runtime_init_thread_env() };
-        if !init_thd_env {
-            return Err(RuntimeError::InstantiationFailure(String::from(
+        if heap_size == 0 && runtime.app_heap().is_none() {
+            if let Some(import) = module.app_heap_import() {
+                return Err(RuntimeError::AppHeapRequired(message!(
+                    "{} imports {}",
+                    module.get_name(),
+                    import
+                )));
+            }
+        }
+        if !platform::enter_thread_env() {
+            return Err(RuntimeError::InstantiationFailure(message!(
                 "thread signal env initialized failed",
             )));
         }
@@ -70,9 +141,10 @@ impl<T> Instance<T> {
         };
 
         if instance.is_null() {
+            platform::leave_thread_env();
             match error_buf.len() {
                 0 => {
-                    return Err(RuntimeError::InstantiationFailure(String::from(
+                    return Err(RuntimeError::InstantiationFailure(message!(
                         "instantiation failed",
                     )))
                 }
@@ -84,53 +156,808 @@ impl<T> Instance<T> {
             }
         }
 
-        let boxed_data = Box::new(data);
-        let raw = Box::into_raw(boxed_data);
-        let exec_env = unsafe { wamr_sys::wasm_runtime_get_exec_env_singleton(instance) };
-        unsafe {
-            wamr_sys::wasm_runtime_set_user_data(exec_env, raw as *mut std::ffi::c_void);
+        InstanceRegistry::register(instance, module.get_name());
+        allocator::poll_pool_watermarks();
+        let events = runtime.events().clone();
+        memory::track(instance, events.clone());
+        if runtime.abort_on_host_panic() {
+            host_function::set_abort_on_panic(instance);
+        }
+        let memory_hints = runtime.memory_hints();
+        if memory_hints.huge_pages {
+            let memory = Memory::new(instance, &events);
+            platform::advise_huge_pages(
+                memory.base_address() as *mut u8,
+                memory_reservation(module, memory.data_size()),
+            );
         }
+        events.emit(RuntimeEvent::Instantiated {
+            instance: InstanceId::new(instance),
+            module: String::from(module.get_name()),
+        });
+
+        let instance = Instance {
+            instance,
+            shared_memory: module.memory_limits().is_some_and(|limits| limits.shared),
+            watchpoints: RefCell::new(Vec::new()),
+            events,
+            calls: Calls::default(),
+            account: None,
+            scheduling_hints: None,
+            canonicalize_nans: runtime.canonicalize_nans(),
+            instrumented: runtime.canonicalize_nans() || allocator::polls_pool_watermarks(),
+            memory_hints,
+            #[cfg(libc_wasi)]
+            preopens: module.wasi_ctx().preopen_dirs(),
+            started: Cell::new(false),
+            finalized: Cell::new(false),
+            functions: RefCell::new(Vec::new()),
+            _module: module.track_instance(),
+            _data: PhantomData,
+        };
+        let raw = Box::into_raw(Box::new(data));
+        instance.exec_env().init_user_data(raw);
+        Ok(instance)
+    }
+
+    /// the host data of the instance
+    ///
+    /// # Panics
+    ///
+    /// Panics if other host data is lent to a running call, see
+    /// `Function::call_with_state()`.
+    pub fn data(&self) -> &T {
+        let raw_user_data = self.exec_env().user_data::<T>();
+        unsafe { &*raw_user_data.expect("the host data is lent to a call") }
+    }
+
+    /// the host data of the instance, mutably
+    ///
+    /// # Panics
+    ///
+    /// Panics if other host data is lent to a running call, see
+    /// `Function::call_with_state()`.
+    pub fn data_mut(&mut self) -> &mut T {
+        let raw_user_data = self.exec_env().user_data::<T>();
+        unsafe { &mut *raw_user_data.expect("the host data is lent to a call") }
+    }
+}
+
+impl<T> Instance<T> {
+    /// identifies the instance in a `RuntimeEvent`
+    pub fn id(&self) -> InstanceId {
+        InstanceId::new(self.instance)
+    }
+
+    /// name the instance in `InstanceRegistry::list()`, like after the tenant it serves
+    pub fn set_label(&self, label: &str) {
+        InstanceRegistry::set_label(self.instance, label);
+    }
+
+    pub fn label(&self) -> Option<String> {
+        InstanceRegistry::label(self.instance)
+    }
+
+    pub(crate) fn canonicalize_nans(&self) -> bool {
+        self.canonicalize_nans
+    }
+
+    /// whether calls into the instance canonicalize NaNs, apply scheduling hints, poll
+    /// the watermarks of the pool, check watchpoints or count into a `ResourceAccount`.
+    /// Checked once by `Function::call_batch()`, which skips all of them otherwise
+    pub(crate) fn is_instrumented(&self) -> bool {
+        self.instrumented
+    }
+
+    pub(crate) fn memory_hints(&self) -> MemoryHints {
+        self.memory_hints
+    }
+
+    /// count a call into the instance as running, until the `Call` is dropped
+    pub(crate) fn enter_call(&self) -> Call<'_> {
+        self.calls.enter()
+    }
+
+    /// whether a call into the instance is running
+    pub(crate) fn is_running(&self) -> bool {
+        self.calls.is_running()
+    }
+
+    /// whether the instance was instantiated from `module`
+    pub(crate) fn is_of(&self, module: &Module) -> bool {
+        module.is_instance(&self._module)
+    }
 
-        Ok(Instance { instance, _data: PhantomData })
+    /// apply the scheduling hints, if any, to the thread of a call
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the thread can't be given the hints.
+    pub(crate) fn apply_scheduling_hints(&self) -> Result<Option<AppliedHints>, RuntimeError> {
+        self.scheduling_hints
+            .as_ref()
+            .map(SchedulingHints::apply)
+            .transpose()
+    }
+
+    pub(crate) fn emit(&self, event: RuntimeEvent) {
+        self.events.emit(event);
     }
 
     pub fn get_inner_instance(&self) -> wasm_module_inst_t {
         self.instance
     }
 
-    pub fn data(&self) -> &T {
-        let raw_user_data = unsafe {
-            let instance = self.get_inner_instance();
-            let exec_env = wamr_sys::wasm_runtime_get_exec_env_singleton(instance);
-            wamr_sys::wasm_runtime_get_user_data(exec_env)
+    /// the default linear memory of the instance
+    pub fn memory(&self) -> Memory<'_> {
+        Memory::new(self.instance, &self.events)
+    }
+
+    /// the default linear memory of the instance, if it is declared `shared`
+    pub fn shared_memory(&self) -> Option<SharedMemory<'_>> {
+        match self.shared_memory {
+            true => SharedMemory::new(&self.memory()).ok(),
+            false => None,
+        }
+    }
+
+    /// give the pages of the linear memory which only hold zeros back to the OS, like after
+    /// a burst, so a long-lived idle instance is cheap. The memory keeps its size, since
+    /// wasm memories can't shrink, and its content, since the OS maps the pages again,
+    /// zeroed, once the guest touches them. Return the bytes given back
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if a call into the instance, or a thread of
+    /// its guest, is running, or `RuntimeError::NotImplemented` where the platform can't
+    /// decommit pages.
+    /// Return `RuntimeError::MemoryAccessError` if the memory is shared, since other
+    /// instances may write to it meanwhile, or comes from a memory pool, which the host may
+    /// have mapped in a way decommitting doesn't keep the content of.
+    pub fn shrink_memory_to_fit(&self) -> Result<usize, RuntimeError> {
+        #[cfg(feature = "threads")]
+        let threads_running = self.threads().list().iter().any(|t| !t.is_finished());
+        #[cfg(not(feature = "threads"))]
+        let threads_running = false;
+        if self.is_running() || threads_running {
+            return Err(RuntimeError::ExecutionError(message!(
+                "can't shrink the memory of an instance during a call",
+            )));
+        }
+
+        if self.shared_memory {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "can't shrink a shared memory",
+            )));
+        }
+        if !allocator::private_linear_memories() {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "can't shrink a memory from a memory pool",
+            )));
+        }
+
+        let memory = self.memory();
+        let base = memory.base_address() as *mut u8;
+        if base.is_null() {
+            return Ok(0);
+        }
+        // private anonymous memory, which nothing writes to while no call is running
+        unsafe { platform::decommit_zero_pages(base, memory.data_size()) }
+            .ok_or(RuntimeError::NotImplemented)
+    }
+
+    /// set a callback consulted with `(old_pages, delta)` before the linear memory
+    /// grows, via `Memory::grow()` or a `memory.grow` of the guest. Returning `false`
+    /// denies the growth, which the guest sees as a failed `memory.grow`.
+    ///
+    /// The guest only reaches the callback with `RuntimeBuilder::hook_memory_grow()`,
+    /// see `memory` for which guests do.
+    pub fn set_memory_grow_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(u32, u32) -> bool + Send + Sync + 'static,
+    {
+        memory::set_grow_callback(self.instance, Arc::new(callback));
+    }
+
+    /// call `handler` whenever the linear memory fails to grow, to free memory on the host
+    /// or terminate the guest. See `oom`
+    pub fn set_oom_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&GuestOom) -> OomAction + Send + Sync + 'static,
+    {
+        oom::set_handler(self.instance, Arc::new(handler));
+    }
+
+    /// pin the calls of the instance to cores, or run them at a nice level, on the thread
+    /// calling, see `scheduling`
+    pub fn set_scheduling_hints(&mut self, hints: SchedulingHints) {
+        self.scheduling_hints = Some(hints);
+        self.instrumented = true;
+    }
+
+    /// watch `range` of the linear memory for writes, and invoke `on_access` with
+    /// `(offset, old, new)` whenever guest code changed it. Return the id of the
+    /// watchpoint.
+    ///
+    /// WAMR doesn't offer a hook into the loads and stores executed by guest code,
+    /// so a region is compared with its last snapshot whenever a call into the
+    /// instance returns. Only writes changing the region are detected, not reads,
+    /// and several writes during one call are reported as one change.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the range is empty or out of bounds.
+    pub fn add_watchpoint<F>(
+        &mut self,
+        range: Range<u64>,
+        on_access: F,
+    ) -> Result<usize, RuntimeError>
+    where
+        F: Fn(u64, &[u8], &[u8]) + 'static,
+    {
+        let watchpoint = Watchpoint::new(&self.memory(), range, Box::new(on_access))?;
+        self.instrumented = true;
+        let mut watchpoints = self.watchpoints.borrow_mut();
+        watchpoints.push(Some(watchpoint));
+        Ok(watchpoints.len() - 1)
+    }
+
+    /// stop watching a region. Return whether the watchpoint existed
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        match self.watchpoints.borrow_mut().get_mut(id) {
+            Some(watchpoint) => watchpoint.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// compare the watched regions with their snapshots
+    pub(crate) fn check_watchpoints(&self) {
+        let memory = self.memory();
+        for watchpoint in self.watchpoints.borrow_mut().iter_mut().flatten() {
+            watchpoint.check(&memory);
+        }
+    }
+
+    /// count the calls into the instance, and the host function calls it makes, into
+    /// `account`, which may be shared with other instances
+    pub fn set_resource_account(&mut self, account: ResourceAccount) {
+        let counters = Arc::as_ptr(account.counters()) as *mut std::ffi::c_void;
+        unsafe { wasm_runtime_set_custom_data(self.instance, counters) };
+        self.account = Some(account);
+        self.instrumented = true;
+    }
+
+    pub fn resource_account(&self) -> Option<&ResourceAccount> {
+        self.account.as_ref()
+    }
+
+    /// the reads and writes of the guest to each page of its linear memory so far, with
+    /// `RuntimeBuilder::profile_memory_accesses()`, see `memory_profile`
+    pub fn memory_heatmap(&self) -> crate::memory_profile::MemoryHeatmap {
+        crate::memory_profile::heatmap(self.instance)
+    }
+
+    /// collect the garbage of the GC heap of the instance now, like between requests,
+    /// instead of once the heap runs full. It invalidates the `GcRef`s read so far, hence
+    /// `&mut`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if a call into the instance is running, or
+    /// the collection failed.
+    #[cfg(feature = "gc")]
+    pub fn gc_collect(&mut self) -> Result<(), RuntimeError> {
+        if self.is_running() {
+            return Err(RuntimeError::ExecutionError(message!(
+                "can't collect the GC heap of an instance during a call",
+            )));
+        }
+        crate::gc::collect(self.instance)
+    }
+
+    /// the occupancy of the GC heap of the instance, `None` if it has none
+    #[cfg(feature = "gc")]
+    pub fn gc_stats(&self) -> Option<crate::gc::GcStats> {
+        crate::gc::stats(self.instance)
+    }
+
+    /// the threads spawned by the guest via wasi-threads, see `threads`
+    #[cfg(feature = "threads")]
+    pub fn threads(&self) -> crate::threads::GuestThreads<'_> {
+        crate::threads::GuestThreads::new(self.instance)
+    }
+
+    /// the time spent in each guest function so far, as a gzipped pprof profile, with the
+    /// `perf-profiling` feature, see `profile`
+    #[cfg(feature = "perf-profiling")]
+    pub fn perf_profile_pprof(&self) -> Vec<u8> {
+        crate::profile::pprof(self.instance)
+    }
+
+    /// the filesystem accesses the guest made via WASI since the last call, recorded with
+    /// `RuntimeBuilder::with_wasi_audit()`, see `wasi_audit`
+    #[cfg(libc_wasi)]
+    pub fn take_fs_audit(&self) -> Vec<crate::wasi_audit::FsAccess> {
+        crate::wasi_audit::take(self.instance)
+    }
+
+    /// deny WASI syscalls to the guest, replacing the policy set before, with
+    /// `RuntimeBuilder::with_wasi_policies()`, see `wasi_policy`
+    #[cfg(libc_wasi)]
+    pub fn set_wasi_policy(&self, policy: crate::wasi_policy::WasiPolicy) {
+        crate::wasi_policy::set(self.instance, policy, &self.preopens);
+    }
+
+    /// the syscalls denied to the guest since the last call
+    #[cfg(libc_wasi)]
+    pub fn take_wasi_denials(&self) -> Vec<crate::wasi_policy::WasiDenial> {
+        crate::wasi_policy::take_denials(self.instance)
+    }
+
+    /// mount `data` as a read-only file at `path`, as the guest sees it, replacing the
+    /// file mounted there before, with `RuntimeBuilder::with_wasi_mounts()`, see
+    /// `wasi_mount`
+    #[cfg(libc_wasi)]
+    pub fn mount_buffer(&self, path: &str, data: Arc<[u8]>) {
+        crate::wasi_mount::mount_buffer(self.instance, path, data);
+    }
+
+    /// mount `range` of the linear memory of `source` as a read-only file at `path`, like
+    /// `mount_buffer()`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `range` is out of bounds.
+    #[cfg(libc_wasi)]
+    pub fn mount_memory<U>(
+        &self,
+        path: &str,
+        source: &Instance<U>,
+        range: Range<u64>,
+    ) -> Result<(), RuntimeError> {
+        crate::wasi_mount::mount_memory(self.instance, path, source.instance, range)
+    }
+
+    /// whether a file was mounted at `path`. The guest may keep reading it if it opened it
+    #[cfg(libc_wasi)]
+    pub fn unmount(&self, path: &str) -> bool {
+        crate::wasi_mount::unmount(self.instance, path)
+    }
+
+    /// the linear memory and mutable exported globals of the instance, to restore into a
+    /// new instance of the same module via `restore_state()`, see `snapshot`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if a call into the instance is running, or
+    /// `RuntimeError::TypeMismatch` if a mutable exported global holds a reference.
+    pub fn serialize_state(&self) -> Result<Vec<u8>, RuntimeError> {
+        crate::snapshot::serialize(self)
+    }
+
+    /// replace the linear memory and mutable exported globals of the instance with a state
+    /// from `serialize_state()`. The linear memory grows to the size it had, but can't
+    /// shrink
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if a call into the instance is running or the
+    /// state is malformed, `RuntimeError::MemoryAccessError` if the linear memory is larger
+    /// than in the state or can't grow to its size, or `RuntimeError::TypeMismatch` if the
+    /// globals don't match.
+    pub fn restore_state(&self, state: &[u8]) -> Result<(), RuntimeError> {
+        crate::snapshot::restore(self, state)
+    }
+
+    /// journal the host calls into functions wrapped in `journaled()`, from the current state
+    /// of the instance on, and hand `on_checkpoint` the checkpoint after each one, to
+    /// persist it. Replace the journal started before, see `journal`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if a call into the instance is running, or
+    /// `RuntimeError::TypeMismatch` if a mutable exported global holds a reference.
+    pub fn begin_journal<F>(&self, on_checkpoint: F) -> Result<(), RuntimeError>
+    where
+        F: Fn(&Checkpoint) + Send + Sync + 'static,
+    {
+        let checkpoint = Checkpoint::new(self.serialize_state()?);
+        journal::begin(self.instance, checkpoint, Arc::new(on_checkpoint));
+        Ok(())
+    }
+
+    /// restore the state of `checkpoint`, and journal the host calls from there on, replaying
+    /// the ones of `checkpoint` first. Make the call the checkpoint was taken during again,
+    /// with the same arguments, to resume it
+    ///
+    /// # Error
+    ///
+    /// Return the errors of `restore_state()`.
+    pub fn resume_journal<F>(
+        &self,
+        checkpoint: &Checkpoint,
+        on_checkpoint: F,
+    ) -> Result<(), RuntimeError>
+    where
+        F: Fn(&Checkpoint) + Send + Sync + 'static,
+    {
+        self.restore_state(checkpoint.state())?;
+        journal::begin(self.instance, checkpoint.clone(), Arc::new(on_checkpoint));
+        Ok(())
+    }
+
+    /// stop journaling the host calls, and return the last checkpoint, if journaling
+    pub fn end_journal(&self) -> Option<Checkpoint> {
+        journal::end(self.instance)
+    }
+
+    /// call the export `function`, and record the state of the instance before, and the
+    /// host calls the call makes, to replay it via `replay()`. A call which fails is
+    /// recorded as well, with its error, see `replay`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the module hasn't been loaded by a runtime
+    /// built with `RuntimeBuilder::record_host_calls()`, `RuntimeError::FunctionNotFound` if
+    /// there is no such export, or the errors of `serialize_state()`.
+    pub fn record_call(
+        &self,
+        function: &str,
+        params: &[WasmValue],
+    ) -> Result<Recording, RuntimeError> {
+        replay::record(self, function, params)
+    }
+
+    /// restore the state of `recording`, and make its call again, with the host calls
+    /// returning what they did when recorded, and return what the call returns
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the module hasn't been loaded by a runtime
+    /// built with `RuntimeBuilder::replay_host_calls()`, or if the call fails, including when
+    /// the guest makes other host calls than the recorded ones, the errors of
+    /// `restore_state()`, or `RuntimeError::FunctionNotFound` if there is no such export.
+    pub fn replay(&self, recording: &Recording) -> Result<WasmValue, RuntimeError> {
+        replay::replay(self, recording)
+    }
+
+    /// call the export `name`, like `instance.call("add", &[WasmValue::I32(1),
+    /// WasmValue::I32(2)])`. The export is looked up on the first call only
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export, or
+    /// `RuntimeError::ExecutionError` if the call failed.
+    pub fn call(&self, name: &str, params: &[WasmValue]) -> Result<WasmValue, RuntimeError> {
+        let cached = self
+            .functions
+            .borrow()
+            .iter()
+            .find(|(function, _)| function == name)
+            .map(|(_, function)| function.clone());
+        let function = match cached {
+            Some(function) => function,
+            None => {
+                let function = Rc::new(Function::find_export_func(self, name)?);
+                let entry = (String::from(name), function.clone());
+                self.functions.borrow_mut().push(entry);
+                function
+            }
         };
-        unsafe { &*(raw_user_data as *const T) }
+        // not borrowed during the call, which may call the instance again
+        function.call_args(self, params)
     }
 
-    pub fn data_mut(&mut self) -> &mut T {
-        let raw_user_data = unsafe {
-            let instance = self.get_inner_instance();
-            let exec_env = wamr_sys::wasm_runtime_get_exec_env_singleton(instance);
-            wamr_sys::wasm_runtime_get_user_data(exec_env)
+    /// the exports of the instance behind a trait declared via `guest_interface!`, like
+    /// `instance.bind::<dyn Plugin>()`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if a method of the trait isn't exported.
+    pub fn bind<'a, I: GuestInterface<'a, T> + ?Sized>(&'a self) -> Result<Box<I>, RuntimeError> {
+        I::bind(self)
+    }
+
+    /// run the start function, then `_initialize`, of a module compiled by
+    /// `Module::from_buf_deferring_start()`. Only the first call runs them, and an instance
+    /// of any other module has nothing to run.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the initialization failed.
+    pub fn run_start(&self) -> Result<(), RuntimeError> {
+        if self.started.replace(true) {
+            return Ok(());
+        }
+
+        for export in [DEFERRED_START_EXPORT, DEFERRED_INITIALIZE_EXPORT] {
+            if let Ok(function) = Function::find_export_func(self, export) {
+                function.call_args(self, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// run the destructors of the guest, like its `atexit` handlers, via the
+    /// `__wasm_call_dtors` export, if any. Only the first call runs them, and a dropped
+    /// instance runs them unless it has been finalized already. The instance shouldn't be
+    /// called afterwards.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the destructors failed. A WASI `proc_exit`
+    /// isn't a failure.
+    pub fn finalize(&self) -> Result<(), RuntimeError> {
+        if self.finalized.replace(true) {
+            return Ok(());
+        }
+
+        let dtors = match Function::find_export_func(self, "__wasm_call_dtors") {
+            Ok(dtors) => dtors,
+            Err(_) => return Ok(()),
         };
-        unsafe { &mut *(raw_user_data as *mut T) }
+        match dtors.call_args(self, &[]) {
+            Err(RuntimeError::ExecutionError(e)) if e.contains("wasi proc exit") => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// the execution environment the calls of the instance run in, unless they ask for a
+    /// stack of their own
+    pub fn exec_env(&self) -> ExecEnvRef<'_> {
+        ExecEnvRef::singleton(self)
+    }
+}
+
+/// the bytes the linear memory of an instance of `module` may span. With hardware bound
+/// checks on 64-bit hosts, WAMR reserves the maximum of a 32-bit memory up front, so it
+/// never moves and the hints cover its growth as well
+fn memory_reservation(module: &Module, data_size: usize) -> usize {
+    if cfg!(any(
+        target_pointer_width = "32",
+        feature = "no-hw-bound-check"
+    )) {
+        return data_size;
+    }
+    match module.memory_limits() {
+        Some(limits) if !limits.memory64 => {
+            let pages = limits.maximum.unwrap_or(65536).min(65536) as usize;
+            (pages * WASM_PAGE_SIZE).max(data_size)
+        }
+        _ => data_size,
+    }
+}
+
+impl<T> fmt::Debug for Instance<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Instance")
+            .field("instance", &self.instance)
+            .finish_non_exhaustive()
     }
 }
 
 impl<T> Drop for Instance<T> {
     fn drop(&mut self) {
-        let raw_user_data = unsafe {
-            let instance = self.get_inner_instance();
-            let exec_env = wamr_sys::wasm_runtime_get_exec_env_singleton(instance);
-            wamr_sys::wasm_runtime_get_user_data(exec_env)
-        };
+        // host functions called by the destructors may still use the user data
+        let _ = self.finalize();
+        #[cfg(feature = "threads")]
+        self.threads().terminate();
+
+        let raw_user_data = self.exec_env().take_user_data();
         if !raw_user_data.is_null() {
             let _ = unsafe { Box::from_raw(raw_user_data as *mut T) };
         }
+        self.emit(RuntimeEvent::Destroyed {
+            instance: self.id(),
+        });
+        InstanceRegistry::unregister(self.instance);
+        oom::remove_handler(self.instance);
+        journal::remove(self.instance);
+        replay::remove(self.instance);
+        crate::fault::remove(self.instance);
+        crate::bridge::remove(self.instance);
+        crate::memory_profile::remove(self.instance);
+        memory::remove(self.instance);
+        host_function::remove(self.instance);
+        #[cfg(feature = "threads")]
+        crate::threads::remove(self.instance);
+        #[cfg(libc_wasi)]
+        crate::wasi_audit::remove(self.instance);
+        #[cfg(libc_wasi)]
+        crate::wasi_policy::remove(self.instance);
+        #[cfg(libc_wasi)]
+        crate::wasi_mount::remove(self.instance);
         unsafe {
-            wasm_runtime_destroy_thread_env();
             wasm_runtime_deinstantiate(self.instance);
         }
+        platform::leave_thread_env();
+    }
+}
+
+/// an instance borrowed by a host function, the one calling it, whichever `Instance` owns
+/// it. Get one via `Caller::instance()`
+pub struct InstanceRef<'a> {
+    instance: wasm_module_inst_t,
+    events: EventBus,
+    _env: PhantomData<&'a ()>,
+}
+
+impl InstanceRef<'_> {
+    pub(crate) fn new(instance: wasm_module_inst_t) -> Self {
+        InstanceRef {
+            instance,
+            events: EventBus::default(),
+            _env: PhantomData,
+        }
+    }
+
+    /// identifies the instance in a `RuntimeEvent`
+    pub fn id(&self) -> InstanceId {
+        InstanceId::new(self.instance)
+    }
+
+    pub fn get_inner_instance(&self) -> wasm_module_inst_t {
+        self.instance
+    }
+
+    /// the default linear memory of the instance. Growing it here consults the grow
+    /// callback of the `Instance` and emits a `RuntimeEvent`, like `Instance::memory()`
+    pub fn memory(&self) -> Memory<'_> {
+        Memory::new(self.instance, &self.events)
+    }
+
+    /// whether the instance exports a function `name`
+    pub fn has_export(&self, name: &str) -> bool {
+        let Ok(name) = CString::new(name) else {
+            return false;
+        };
+        !unsafe { wasm_runtime_lookup_function(self.instance, name.as_ptr()) }.is_null()
+    }
+
+    /// the exception pending in the instance, `None` if there is none
+    pub fn exception(&self) -> Option<String> {
+        let exception = unsafe { wasm_runtime_get_exception(self.instance) };
+        match exception.is_null() {
+            true => None,
+            false => Some(exception_to_string(exception)),
+        }
+    }
+
+    /// raise an exception in the instance, which traps once the host function returns
+    pub fn set_exception(&self, message: &str) {
+        let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        unsafe { wasm_runtime_set_exception(self.instance, message.as_ptr()) };
+    }
+
+    /// clear the exception pending in the instance, so the guest goes on once the host
+    /// function returns
+    pub fn clear_exception(&self) {
+        unsafe { wasm_runtime_clear_exception(self.instance) };
+    }
+}
+
+impl fmt::Debug for InstanceRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InstanceRef")
+            .field("instance", &self.instance)
+            .finish_non_exhaustive()
+    }
+}
+
+/// the execution environment of an instance, borrowed from it. Get one via
+/// `Instance::exec_env()`
+#[derive(Debug, Clone, Copy)]
+pub struct ExecEnvRef<'a> {
+    exec_env: wasm_exec_env_t,
+    _instance: PhantomData<&'a ()>,
+}
+
+impl<'a> ExecEnvRef<'a> {
+    /// the execution environment WAMR creates with `instance`
+    pub(crate) fn singleton<T>(instance: &'a Instance<T>) -> Self {
+        ExecEnvRef {
+            exec_env: unsafe { wasm_runtime_get_exec_env_singleton(instance.instance) },
+            _instance: PhantomData,
+        }
+    }
+
+    /// lend `data` to the calls as the host data, until the `LentUserData` is dropped,
+    /// which puts the data of the instance back. Unlike with `set_user_data()`, `data`
+    /// stays owned by the caller, and host functions borrow it as a `Caller<S>`
+    pub(crate) fn lend_user_data<S: 'static>(&self, data: *mut S) -> LentUserData<'a> {
+        LentUserData {
+            exec_env: *self,
+            previous: self.swap_user_data(HostData::new(data)),
+        }
+    }
+}
+
+impl ExecEnvRef<'_> {
+    pub fn as_raw(&self) -> wasm_exec_env_t {
+        self.exec_env
+    }
+
+    /// the instance, as `RuntimeEvent`s identify it
+    pub fn instance(&self) -> InstanceId {
+        InstanceId::new(unsafe { wasm_runtime_get_module_inst(self.exec_env) })
+    }
+
+    /// the user data WAMR keeps, null until the instance gives it
+    fn header(&self) -> *mut UserData {
+        unsafe { wasm_runtime_get_user_data(self.exec_env) as *mut UserData }
+    }
+
+    /// give WAMR the user data of a new instance, holding its host data `data`
+    pub(crate) fn init_user_data<T: 'static>(&self, data: *mut T) {
+        let user_data = Box::into_raw(Box::new(UserData::new(HostData::new(data))));
+        unsafe { wasm_runtime_set_user_data(self.exec_env, user_data as *mut c_void) };
+    }
+
+    /// let `exec_env`, another execution environment of the instance, find the user data,
+    /// sharing its borrows
+    pub(crate) fn share_user_data(&self, exec_env: wasm_exec_env_t) {
+        unsafe { wasm_runtime_set_user_data(exec_env, self.header() as *mut c_void) };
+    }
+
+    /// free the user data of a destroyed instance, and return its host data
+    pub(crate) fn take_user_data(&self) -> *mut c_void {
+        let user_data = self.header();
+        if user_data.is_null() {
+            return ptr::null_mut();
+        }
+        unsafe { wasm_runtime_set_user_data(self.exec_env, ptr::null_mut()) };
+        unsafe { Box::from_raw(user_data) }.data().ptr
+    }
+
+    /// the host data, which host functions borrow via a `Caller`, `None` unless it's a `T`
+    pub(crate) fn user_data<T: 'static>(&self) -> Option<*mut T> {
+        let user_data = self.header();
+        match user_data.is_null() {
+            true => None,
+            false => unsafe { (*user_data).data() }.of::<T>(),
+        }
+    }
+
+    /// replace the host data, and return the previous one. The borrows of the data by
+    /// `Caller`s are kept
+    ///
+    /// # Safety
+    ///
+    /// `data` must come from `Box::into_raw()` on a `Box<T>` of the data type `T` of the
+    /// `Instance`, which drops it. The previous data is then owned by the caller.
+    pub unsafe fn set_user_data(&self, data: *mut c_void) -> *mut c_void {
+        let user_data = self.header();
+        assert!(!user_data.is_null(), "the instance has no user data");
+        (*user_data).replace_ptr(data)
+    }
+
+    fn swap_user_data(&self, data: HostData) -> HostData {
+        let user_data = self.header();
+        assert!(!user_data.is_null(), "the instance has no user data");
+        unsafe { (*user_data).replace(data) }
+    }
+
+    /// set the lowest address of native stack the calls may use, instead of the one WAMR
+    /// derives from the thread, like for a host running them on a stack of its own
+    ///
+    /// # Safety
+    ///
+    /// `boundary` must be in the stack of the thread making the calls, leaving enough room
+    /// below it for WAMR to raise the overflow.
+    pub unsafe fn set_native_stack_boundary(&self, boundary: *mut u8) {
+        wasm_runtime_set_native_stack_boundary(self.exec_env, boundary);
+    }
+}
+
+/// host data lent to the calls of an instance, see `ExecEnvRef::lend_user_data()`
+pub(crate) struct LentUserData<'a> {
+    exec_env: ExecEnvRef<'a>,
+    previous: HostData,
+}
+
+impl Drop for LentUserData<'_> {
+    fn drop(&mut self) {
+        self.exec_env.swap_user_data(self.previous);
     }
 }
 
@@ -257,4 +1084,184 @@ mod tests {
             RunningMode_Mode_Interp
         );
     }
+
+    #[test]
+    fn test_instance_call() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (i32.add (local.get 0) (local.get 1))
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "add")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let params = [WasmValue::I32(1), WasmValue::I32(2)];
+        assert_eq!(instance.call("add", &params)?, WasmValue::I32(3));
+        assert_eq!(instance.call("add", &params)?, WasmValue::I32(3));
+        assert_eq!(instance.functions.borrow().len(), 1);
+        assert!(matches!(
+            instance.call("subtract", &params),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_finalize() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        //   (func (export "__wasm_call_dtors")
+        //     (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x1e, 0x02, 0x06, 0x6d,
+            0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x11, 0x5f, 0x5f, 0x77, 0x61, 0x73, 0x6d,
+            0x5f, 0x63, 0x61, 0x6c, 0x6c, 0x5f, 0x64, 0x74, 0x6f, 0x72, 0x73, 0x00, 0x00, 0x0a,
+            0x11, 0x01, 0x0f, 0x00, 0x41, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x41, 0x01, 0x6a,
+            0x36, 0x02, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "dtors")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        instance.finalize()?;
+        instance.finalize()?;
+        let mut runs = [0u8; 4];
+        instance.memory().read(0, &mut runs)?;
+        assert_eq!(u32::from_le_bytes(runs), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_run_start() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        //   (func
+        //     (i32.store (i32.const 0) (i32.add (i32.load (i32.const 0)) (i32.const 1)))
+        //   )
+        //   (start 0)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x0a, 0x01, 0x06, 0x6d,
+            0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x08, 0x01, 0x00, 0x0a, 0x11, 0x01, 0x0f,
+            0x00, 0x41, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x41, 0x01, 0x6a, 0x36, 0x02, 0x00,
+            0x0b,
+        ];
+        let starts = |instance: &Instance<()>| -> Result<u32, RuntimeError> {
+            let mut runs = [0u8; 4];
+            instance.memory().read(0, &mut runs)?;
+            Ok(u32::from_le_bytes(runs))
+        };
+
+        let module = Module::from_buf(&runtime, &binary, "start")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        assert_eq!(starts(&instance)?, 1);
+        instance.run_start()?;
+        assert_eq!(starts(&instance)?, 1);
+
+        let module = Module::from_buf_deferring_start(&runtime, &binary, "deferred")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        assert_eq!(starts(&instance)?, 0);
+        instance.run_start()?;
+        instance.run_start()?;
+        assert_eq!(starts(&instance)?, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_exec_env() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module)
+        let binary = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let module = Module::from_buf(&runtime, &binary, "empty")?;
+        let mut instance = Instance::new(&runtime, &module, 1024, 7u32)?;
+
+        let exec_env = instance.exec_env();
+        assert!(!exec_env.as_raw().is_null());
+        assert_eq!(exec_env.instance(), instance.id());
+        assert_eq!(instance.exec_env().as_raw(), exec_env.as_raw());
+
+        let replaced = Box::into_raw(Box::new(9u32));
+        let previous = unsafe { instance.exec_env().set_user_data(replaced as *mut c_void) };
+        assert_eq!(*instance.data(), 9);
+        *instance.data_mut() += 1;
+        let replaced = unsafe { instance.exec_env().set_user_data(previous) };
+        assert_eq!(*unsafe { Box::from_raw(replaced as *mut u32) }, 10);
+        assert_eq!(*instance.data(), 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instance_without_app_heap() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .default_app_heap(None)
+            .build()?;
+
+        // (module (import "env" "malloc" (func (param i32) (result i32))))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7f,
+            0x01, 0x7f, 0x02, 0x0e, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x06, 0x6d, 0x61, 0x6c, 0x6c,
+            0x6f, 0x63, 0x00, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "malloc")?;
+        let instance = Instance::new(&runtime, &module, 1024, ());
+        assert!(matches!(instance, Err(RuntimeError::AppHeapRequired(_))));
+        Instance::new_with_args(&runtime, &module, 1024, 1024, ())?;
+
+        // (module)
+        let binary = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        let module = Module::from_buf(&runtime, &binary, "empty")?;
+        Instance::new(&runtime, &module, 1024, ())?;
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_shrink_memory_to_fit() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module (memory (export "memory") 4))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x04, 0x07,
+            0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "memory")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        instance.memory().write(8, b"kept")?;
+        instance.memory().write(3 * 65536, &[0xff; 65536])?;
+        instance.memory().write(3 * 65536, &[0; 65536])?;
+        let released = instance.shrink_memory_to_fit()?;
+        assert!(released >= 2 * 65536);
+        assert_eq!(instance.memory().pages(), 4);
+
+        let mut kept = [0u8; 4];
+        instance.memory().read(8, &mut kept)?;
+        assert_eq!(&kept, b"kept");
+        let mut zeroed = [1u8; 16];
+        instance.memory().read(3 * 65536, &mut zeroed)?;
+        assert_eq!(zeroed, [0; 16]);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,267 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the instructions of a function body, as encoded in a .wasm.
+//! get them via `Module::function_body()`
+
+use std::ops::Range;
+
+use crate::binary::{skip_valtype, Reader};
+use crate::ErrorMessage;
+
+/// the prefix of the bulk memory, reference types and saturating truncation instructions
+pub const PREFIX_MISC: u8 = 0xfc;
+/// the prefix of the SIMD instructions
+pub const PREFIX_SIMD: u8 = 0xfd;
+/// the prefix of the atomic instructions
+pub const PREFIX_ATOMIC: u8 = 0xfe;
+/// the prefix of the GC instructions
+pub const PREFIX_GC: u8 = 0xfb;
+
+/// one decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    /// the offset of the instruction in the module binary
+    pub offset: usize,
+    /// the opcode. A prefixed opcode carries its prefix byte above the 16 bits of the
+    /// sub-opcode, like `0xfe0000` for `memory.atomic.notify`
+    pub opcode: u32,
+    /// the whole encoded instruction, opcode and immediates
+    pub bytes: Vec<u8>,
+}
+
+impl Instruction {
+    /// the prefix byte of a prefixed opcode
+    pub fn prefix(&self) -> Option<u8> {
+        match self.opcode > 0xff {
+            true => Some((self.opcode >> 16) as u8),
+            false => None,
+        }
+    }
+
+    pub fn is_simd(&self) -> bool {
+        self.prefix() == Some(PREFIX_SIMD)
+    }
+
+    pub fn is_atomic(&self) -> bool {
+        self.prefix() == Some(PREFIX_ATOMIC)
+    }
+}
+
+/// decode the function body spanning `body` in `binary`, skipping its local declarations
+pub(crate) fn decode_body(
+    binary: &[u8],
+    body: Range<usize>,
+) -> Result<Vec<Instruction>, ErrorMessage> {
+    let code = binary
+        .get(body.clone())
+        .ok_or_else(|| message!("function body {:?} out of bounds", body))?;
+    let mut reader = Reader::new(code);
+
+    let local_groups = reader.read_u32_leb()?;
+    for _ in 0..local_groups {
+        reader.read_u32_leb()?;
+        skip_valtype(&mut reader)?;
+    }
+
+    let mut instructions = Vec::new();
+    while !reader.is_empty() {
+        let start = reader.position();
+        let opcode = decode_instruction(&mut reader)?;
+        instructions.push(Instruction {
+            offset: body.start + start,
+            opcode,
+            bytes: code[start..reader.position()].to_vec(),
+        });
+    }
+    Ok(instructions)
+}
+
+fn skip_blocktype(reader: &mut Reader) -> Result<(), ErrorMessage> {
+    match reader.read_u8()? {
+        0x63 | 0x64 => reader.skip_leb(),
+        byte if byte & 0x80 != 0 => reader.skip_leb(),
+        _ => Ok(()),
+    }
+}
+
+fn skip_memarg(reader: &mut Reader) -> Result<(), ErrorMessage> {
+    let align = reader.read_u32_leb()?;
+    // a multi-memory memarg names its memory
+    if align & 0x40 != 0 {
+        reader.read_u32_leb()?;
+    }
+    reader.read_u64_leb()?;
+    Ok(())
+}
+
+fn skip_indices(reader: &mut Reader, count: usize) -> Result<(), ErrorMessage> {
+    for _ in 0..count {
+        reader.read_u32_leb()?;
+    }
+    Ok(())
+}
+
+/// consume one instruction and return its opcode
+pub(crate) fn decode_instruction(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    let offset = reader.position();
+    let opcode = reader.read_u8()?;
+    match opcode {
+        0x00 | 0x01 | 0x05 | 0x0a | 0x0b | 0x0f | 0x19 | 0x1a | 0x1b => {}
+        0x02..=0x04 | 0x06 => skip_blocktype(reader)?,
+        0x07..=0x09 | 0x0c | 0x0d | 0x10 | 0x12 | 0x14 | 0x15 | 0x18 => skip_indices(reader, 1)?,
+        0x0e => {
+            let targets = reader.read_u32_leb()? as usize;
+            skip_indices(reader, targets + 1)?;
+        }
+        0x11 | 0x13 => skip_indices(reader, 2)?,
+        0x1c => {
+            let types = reader.read_u32_leb()?;
+            for _ in 0..types {
+                skip_valtype(reader)?;
+            }
+        }
+        0x1f => {
+            skip_blocktype(reader)?;
+            let catches = reader.read_u32_leb()?;
+            for _ in 0..catches {
+                match reader.read_u8()? {
+                    0x00 | 0x01 => skip_indices(reader, 2)?,
+                    0x02 | 0x03 => skip_indices(reader, 1)?,
+                    kind => return Err(message!("invalid catch kind {:#x}", kind)),
+                }
+            }
+        }
+        0x20..=0x26 => skip_indices(reader, 1)?,
+        0x28..=0x3e => skip_memarg(reader)?,
+        0x3f | 0x40 => skip_indices(reader, 1)?,
+        0x41 | 0x42 => reader.skip_leb()?,
+        0x43 => {
+            reader.read_bytes(4)?;
+        }
+        0x44 => {
+            reader.read_bytes(8)?;
+        }
+        0x45..=0xc4 => {}
+        0xd0 => reader.skip_leb()?,
+        0xd1 | 0xd3 | 0xd4 => {}
+        0xd2 | 0xd5 | 0xd6 => skip_indices(reader, 1)?,
+        PREFIX_GC => return decode_gc(reader),
+        PREFIX_MISC => return decode_misc(reader),
+        PREFIX_SIMD => return decode_simd(reader),
+        PREFIX_ATOMIC => return decode_atomic(reader),
+        _ => {
+            return Err(message!(
+                "unknown opcode {:#x} at offset {}",
+                opcode,
+                offset
+            ))
+        }
+    }
+    Ok(opcode as u32)
+}
+
+fn prefixed(prefix: u8, sub_opcode: u32) -> u32 {
+    (prefix as u32) << 16 | sub_opcode
+}
+
+fn decode_gc(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    let sub_opcode = reader.read_u32_leb()?;
+    match sub_opcode {
+        15 | 26..=30 => {}
+        0 | 1 | 6 | 7 | 11..=14 | 16 => skip_indices(reader, 1)?,
+        2..=5 | 8..=10 | 17..=19 => skip_indices(reader, 2)?,
+        20..=23 => reader.skip_leb()?,
+        24 | 25 => {
+            reader.read_u8()?;
+            reader.read_u32_leb()?;
+            reader.skip_leb()?;
+            reader.skip_leb()?;
+        }
+        _ => return Err(message!("unknown opcode 0xfb {:#x}", sub_opcode)),
+    }
+    Ok(prefixed(PREFIX_GC, sub_opcode))
+}
+
+fn decode_misc(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    let sub_opcode = reader.read_u32_leb()?;
+    match sub_opcode {
+        0..=7 => {}
+        9 | 11 | 13 | 15..=17 => skip_indices(reader, 1)?,
+        8 | 10 | 12 | 14 => skip_indices(reader, 2)?,
+        _ => return Err(message!("unknown opcode 0xfc {:#x}", sub_opcode)),
+    }
+    Ok(prefixed(PREFIX_MISC, sub_opcode))
+}
+
+fn decode_simd(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    let sub_opcode = reader.read_u32_leb()?;
+    match sub_opcode {
+        0..=11 | 92 | 93 => skip_memarg(reader)?,
+        12 | 13 => {
+            reader.read_bytes(16)?;
+        }
+        21..=34 => {
+            reader.read_u8()?;
+        }
+        84..=91 => {
+            skip_memarg(reader)?;
+            reader.read_u8()?;
+        }
+        14..=20 | 35..=83 | 94..=0x113 => {}
+        _ => return Err(message!("unknown opcode 0xfd {:#x}", sub_opcode)),
+    }
+    Ok(prefixed(PREFIX_SIMD, sub_opcode))
+}
+
+fn decode_atomic(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    let sub_opcode = reader.read_u32_leb()?;
+    match sub_opcode {
+        0x00..=0x02 | 0x10..=0x4e => skip_memarg(reader)?,
+        0x03 => {
+            reader.read_u8()?;
+        }
+        _ => return Err(message!("unknown opcode 0xfe {:#x}", sub_opcode)),
+    }
+    Ok(prefixed(PREFIX_ATOMIC, sub_opcode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_body() {
+        // (func (param i32 i32) (result i32)
+        //   (local i64)
+        //   (i32.add (local.get 0) (i32.const -1))
+        //   (i32.atomic.load offset=4)
+        //   (drop (v128.const i64x2 0 0))
+        // )
+        let code = [
+            0x01, 0x01, 0x7e, 0x20, 0x00, 0x41, 0x7f, 0x6a, 0xfe, 0x10, 0x02, 0x04, 0xfd, 0x0c,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x1a, 0x0b,
+        ];
+        let mut binary = vec![0u8; 10];
+        binary.extend_from_slice(&code);
+
+        let instructions = decode_body(&binary, 10..binary.len()).unwrap();
+        let opcodes: Vec<u32> = instructions.iter().map(|i| i.opcode).collect();
+        assert_eq!(
+            opcodes,
+            vec![0x20, 0x41, 0x6a, 0xfe0010, 0xfd000c, 0x1a, 0x0b]
+        );
+        assert_eq!(instructions[0].offset, 13);
+        assert_eq!(instructions[1].bytes, vec![0x41, 0x7f]);
+        assert!(instructions[3].is_atomic());
+        assert!(instructions[4].is_simd());
+        assert_eq!(instructions[4].bytes.len(), 18);
+        assert_eq!(instructions[2].prefix(), None);
+
+        assert!(decode_body(&binary, 10..binary.len() - 10).is_err());
+        assert!(decode_body(&[0x00, 0xff], 0..2).is_err());
+    }
+}
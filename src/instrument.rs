@@ -0,0 +1,468 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! insert calls to a host function into the function bodies of a .wasm, before it is
+//! loaded, for the profiling and the coverage of guests, or replace instructions by them
+//!
+//! the host function is imported after the other imports, so the functions defined by the
+//! module are shifted by one, in the calls, the exports, the start, the elements, the
+//! globals and the `name` section.
+
+use crate::{
+    binary::{
+        self, skip_valtype, write_name, write_u32_leb, Reader, SECTION_CODE, SECTION_CUSTOM,
+        SECTION_ELEMENT, SECTION_EXPORT, SECTION_GLOBAL, SECTION_IMPORT, SECTION_START,
+        SECTION_TABLE, SECTION_TYPE,
+    },
+    instruction::decode_instruction,
+    ErrorMessage,
+};
+
+/// the host function a pass calls
+pub(crate) struct Hook<'a> {
+    pub module: &'a str,
+    pub name: &'a str,
+    /// the encoded function type, like `[0x60, 0x01, 0x7f, 0x00]` for `(i32) -> ()`
+    pub func_type: &'a [u8],
+    /// the value types of the locals added to each function, for the code inserted
+    pub locals: &'a [u8],
+}
+
+/// an instruction of a function body, before which code may be inserted
+pub(crate) struct Site<'a> {
+    /// the index of the function, among the defined ones
+    pub function: u32,
+    /// the index of the instruction in the body, `0` for the entry
+    pub index: usize,
+    /// the offset of the instruction in the binary
+    pub offset: usize,
+    pub opcode: u32,
+    /// the whole encoded instruction, opcode and immediates
+    pub bytes: &'a [u8],
+    /// the index of the hook
+    pub hook: u32,
+    /// the index of the first local of `Hook::locals`
+    pub locals: u32,
+}
+
+/// copy a payload, shifting the indices of the functions defined by the module
+struct Rewriter<'a> {
+    input: &'a [u8],
+    reader: Reader<'a>,
+    out: Vec<u8>,
+    /// the index of the hook, the first one shifted
+    hook: u32,
+}
+
+impl<'a> Rewriter<'a> {
+    fn new(input: &'a [u8], hook: u32) -> Self {
+        Rewriter {
+            input,
+            reader: Reader::new(input),
+            out: Vec::new(),
+            hook,
+        }
+    }
+
+    /// copy what `read` consumes
+    fn copy<T>(
+        &mut self,
+        read: impl FnOnce(&mut Reader<'a>) -> Result<T, ErrorMessage>,
+    ) -> Result<T, ErrorMessage> {
+        let start = self.reader.position();
+        let value = read(&mut self.reader)?;
+        let end = self.reader.position();
+        self.out.extend_from_slice(&self.input[start..end]);
+        Ok(value)
+    }
+
+    fn copy_rest(&mut self) {
+        let start = self.reader.position();
+        self.out.extend_from_slice(&self.input[start..]);
+    }
+
+    fn shifted(&self, index: u32) -> Result<u32, ErrorMessage> {
+        match index >= self.hook {
+            true => index
+                .checked_add(1)
+                .ok_or_else(|| message!("function index {} out of range", index)),
+            false => Ok(index),
+        }
+    }
+
+    fn function_index(&mut self) -> Result<(), ErrorMessage> {
+        let index = self.reader.read_u32_leb()?;
+        let index = self.shifted(index)?;
+        write_u32_leb(&mut self.out, index);
+        Ok(())
+    }
+
+    fn function_indices(&mut self) -> Result<(), ErrorMessage> {
+        let count = self.copy(|reader| reader.read_u32_leb())?;
+        for _ in 0..count {
+            self.function_index()?;
+        }
+        Ok(())
+    }
+
+    /// a constant expression, where `ref.func` may appear
+    fn expr(&mut self) -> Result<(), ErrorMessage> {
+        loop {
+            let start = self.reader.position();
+            let opcode = decode_instruction(&mut self.reader)?;
+            let bytes = &self.input[start..self.reader.position()];
+            match opcode {
+                // ref.func
+                0xd2 => {
+                    let index = self.shifted(Reader::new(&bytes[1..]).read_u32_leb()?)?;
+                    self.out.push(0xd2);
+                    write_u32_leb(&mut self.out, index);
+                }
+                _ => self.out.extend_from_slice(bytes),
+            }
+            if opcode == 0x0b {
+                return Ok(());
+            }
+        }
+    }
+
+    fn exprs(&mut self) -> Result<(), ErrorMessage> {
+        let count = self.copy(|reader| reader.read_u32_leb())?;
+        for _ in 0..count {
+            self.expr()?;
+        }
+        Ok(())
+    }
+}
+
+fn export_section(payload: &[u8], hook: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    let count = rewriter.copy(|reader| reader.read_u32_leb())?;
+    for _ in 0..count {
+        rewriter.copy(|reader| reader.read_name())?;
+        match rewriter.copy(|reader| reader.read_u8())? {
+            0x00 => rewriter.function_index()?,
+            _ => rewriter.copy(|reader| reader.read_u32_leb()).map(|_| ())?,
+        }
+    }
+    Ok(rewriter.out)
+}
+
+fn element_section(payload: &[u8], hook: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    let count = rewriter.copy(|reader| reader.read_u32_leb())?;
+    for _ in 0..count {
+        let flags = rewriter.copy(|reader| reader.read_u32_leb())?;
+        match flags {
+            0 => {
+                rewriter.expr()?;
+                rewriter.function_indices()?;
+            }
+            1 | 3 => {
+                rewriter.copy(|reader| reader.read_u8())?;
+                rewriter.function_indices()?;
+            }
+            2 => {
+                rewriter.copy(|reader| reader.read_u32_leb())?;
+                rewriter.expr()?;
+                rewriter.copy(|reader| reader.read_u8())?;
+                rewriter.function_indices()?;
+            }
+            4 => {
+                rewriter.expr()?;
+                rewriter.exprs()?;
+            }
+            5 | 7 => {
+                rewriter.copy(skip_valtype)?;
+                rewriter.exprs()?;
+            }
+            6 => {
+                rewriter.copy(|reader| reader.read_u32_leb())?;
+                rewriter.expr()?;
+                rewriter.copy(skip_valtype)?;
+                rewriter.exprs()?;
+            }
+            _ => return Err(message!("invalid element segment flags {}", flags)),
+        }
+    }
+    Ok(rewriter.out)
+}
+
+fn global_section(payload: &[u8], hook: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    let count = rewriter.copy(|reader| reader.read_u32_leb())?;
+    for _ in 0..count {
+        rewriter.copy(skip_valtype)?;
+        rewriter.copy(|reader| reader.read_u8())?;
+        rewriter.expr()?;
+    }
+    Ok(rewriter.out)
+}
+
+fn table_section(payload: &[u8], hook: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    let count = rewriter.copy(|reader| reader.read_u32_leb())?;
+    for _ in 0..count {
+        // a table with an initializer
+        let with_init = payload.get(rewriter.reader.position()) == Some(&0x40);
+        if with_init {
+            rewriter.copy(|reader| reader.read_bytes(2))?;
+        }
+        rewriter.copy(skip_valtype)?;
+        rewriter.copy(binary::read_limits)?;
+        if with_init {
+            rewriter.expr()?;
+        }
+    }
+    Ok(rewriter.out)
+}
+
+/// a name map by function index, or with `indirect` a map of name maps by function index,
+/// like the local names
+fn function_names(payload: &[u8], hook: u32, indirect: bool) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    let count = rewriter.copy(|reader| reader.read_u32_leb())?;
+    for _ in 0..count {
+        rewriter.function_index()?;
+        if !indirect {
+            rewriter.copy(|reader| reader.read_name())?;
+            continue;
+        }
+        let names = rewriter.copy(|reader| reader.read_u32_leb())?;
+        for _ in 0..names {
+            rewriter.copy(|reader| reader.read_u32_leb())?;
+            rewriter.copy(|reader| reader.read_name())?;
+        }
+    }
+    Ok(rewriter.out)
+}
+
+fn name_section(payload: &[u8], hook: u32) -> Result<Vec<u8>, ErrorMessage> {
+    let mut rewriter = Rewriter::new(payload, hook);
+    rewriter.copy(|reader| reader.read_name())?;
+    while !rewriter.reader.is_empty() {
+        let id = rewriter.reader.read_u8()?;
+        let size = rewriter.reader.read_u32_leb()?;
+        let subsection = rewriter.reader.read_bytes(size as usize)?;
+        let subsection = match id {
+            // the function names, and the local and label names of each function
+            1 => function_names(subsection, hook, false)?,
+            2 | 3 => function_names(subsection, hook, true)?,
+            _ => subsection.to_vec(),
+        };
+        rewriter.out.push(id);
+        write_u32_leb(&mut rewriter.out, subsection.len() as u32);
+        rewriter.out.extend_from_slice(&subsection);
+    }
+    Ok(rewriter.out)
+}
+
+/// instrument a function body, with `locals` being the number of parameters, where the
+/// locals of the body start, and `offset` where the body is in the binary
+fn body<F>(
+    body: &[u8],
+    offset: usize,
+    function: u32,
+    locals: u32,
+    hook: &Hook,
+    index: u32,
+    insert: &mut F,
+) -> Result<Vec<u8>, ErrorMessage>
+where
+    F: FnMut(&Site, &mut Vec<u8>) -> Result<bool, ErrorMessage>,
+{
+    let mut rewriter = Rewriter::new(body, index);
+    let groups = rewriter.reader.read_u32_leb()?;
+    let mut declared = Vec::new();
+    let mut locals = locals;
+    for _ in 0..groups {
+        let start = rewriter.reader.position();
+        locals = locals
+            .checked_add(rewriter.reader.read_u32_leb()?)
+            .ok_or_else(|| message!("function {} has too many locals", function))?;
+        skip_valtype(&mut rewriter.reader)?;
+        declared.extend_from_slice(&body[start..rewriter.reader.position()]);
+    }
+
+    let mut instruction = 0;
+    while !rewriter.reader.is_empty() {
+        let start = rewriter.reader.position();
+        let opcode = decode_instruction(&mut rewriter.reader)?;
+        let bytes = &body[start..rewriter.reader.position()];
+        let site = Site {
+            function,
+            index: instruction,
+            offset: offset + start,
+            opcode,
+            bytes,
+            hook: index,
+            locals,
+        };
+        let replaced = insert(&site, &mut rewriter.out)?;
+        instruction += 1;
+        if replaced {
+            continue;
+        }
+
+        match opcode {
+            // call, return_call and ref.func
+            0x10 | 0x12 | 0xd2 => {
+                let index = rewriter.shifted(Reader::new(&bytes[1..]).read_u32_leb()?)?;
+                rewriter.out.push(opcode as u8);
+                write_u32_leb(&mut rewriter.out, index);
+            }
+            _ => rewriter.out.extend_from_slice(bytes),
+        }
+    }
+
+    let mut instrumented = Vec::new();
+    write_u32_leb(&mut instrumented, groups + hook.locals.len() as u32);
+    instrumented.extend_from_slice(&declared);
+    for valtype in hook.locals {
+        instrumented.extend_from_slice(&[0x01, *valtype]);
+    }
+    instrumented.extend_from_slice(&rewriter.out);
+    Ok(instrumented)
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, payload: &[u8]) {
+    out.push(id);
+    write_u32_leb(out, payload.len() as u32);
+    out.extend_from_slice(payload);
+}
+
+/// `binary` importing `hook`, with the code `insert` writes before each instruction of the
+/// defined functions
+pub(crate) fn instrument<F>(
+    binary: &[u8],
+    hook: &Hook,
+    mut insert: F,
+) -> Result<Vec<u8>, ErrorMessage>
+where
+    F: FnMut(&Site, &mut Vec<u8>) -> Result<(), ErrorMessage>,
+{
+    rewrite(binary, hook, |site, out| insert(site, out).map(|()| false))
+}
+
+/// like `instrument()`, but `rewrite` returns whether the code it wrote replaces the
+/// instruction, rather than going before it
+pub(crate) fn rewrite<F>(
+    binary: &[u8],
+    hook: &Hook,
+    mut rewrite: F,
+) -> Result<Vec<u8>, ErrorMessage>
+where
+    F: FnMut(&Site, &mut Vec<u8>) -> Result<bool, ErrorMessage>,
+{
+    let types = binary::type_param_counts(binary)?;
+    let index = binary::imported_function_count(binary)?;
+    let params: Vec<u32> = binary::function_types(binary)?[index as usize..]
+        .iter()
+        .map(|type_index| types.get(*type_index as usize).copied())
+        .collect::<Option<_>>()
+        .ok_or_else(|| message!("a function has an unknown type"))?;
+
+    let type_section = |payload: &[u8]| -> Result<Vec<u8>, ErrorMessage> {
+        let mut reader = Reader::new(payload);
+        let count = match payload.is_empty() {
+            true => 0,
+            false => reader.read_u32_leb()?,
+        };
+        let mut out = Vec::new();
+        write_u32_leb(&mut out, count + 1);
+        out.extend_from_slice(&payload[reader.position()..]);
+        out.extend_from_slice(hook.func_type);
+        Ok(out)
+    };
+    let import_section = |payload: &[u8]| -> Result<Vec<u8>, ErrorMessage> {
+        let mut reader = Reader::new(payload);
+        let count = match payload.is_empty() {
+            true => 0,
+            false => reader.read_u32_leb()?,
+        };
+        let mut out = Vec::new();
+        write_u32_leb(&mut out, count + 1);
+        out.extend_from_slice(&payload[reader.position()..]);
+        write_name(&mut out, hook.module);
+        write_name(&mut out, hook.name);
+        out.push(0x00);
+        write_u32_leb(&mut out, types.len() as u32);
+        Ok(out)
+    };
+    let code_section =
+        |section: &binary::Section, rewrite: &mut F| -> Result<Vec<u8>, ErrorMessage> {
+            let mut reader = Reader::new(section.payload);
+            let count = reader.read_u32_leb()?;
+            let mut out = Vec::new();
+            write_u32_leb(&mut out, count);
+            for function in 0..count {
+                let size = reader.read_u32_leb()?;
+                let offset = section.offset + reader.position();
+                let code = reader.read_bytes(size as usize)?;
+                let locals = params
+                    .get(function as usize)
+                    .ok_or_else(|| message!("function {} has no type", index + function))?;
+                let code = body(code, offset, function, *locals, hook, index, rewrite)?;
+                write_u32_leb(&mut out, code.len() as u32);
+                out.extend_from_slice(&code);
+            }
+            Ok(out)
+        };
+
+    let mut out = binary::header();
+    let (mut typed, mut imported) = (false, false);
+    for section in binary::sections(binary)? {
+        // the type and import sections go before the others
+        if !matches!(section.id, SECTION_CUSTOM | SECTION_TYPE | SECTION_IMPORT) {
+            if !typed {
+                write_section(&mut out, SECTION_TYPE, &type_section(&[])?);
+                typed = true;
+            }
+            if !imported {
+                write_section(&mut out, SECTION_IMPORT, &import_section(&[])?);
+                imported = true;
+            }
+        }
+
+        let payload = section.payload;
+        let rewritten = match section.id {
+            SECTION_CUSTOM if Reader::new(payload).read_name()? == "name" => {
+                name_section(payload, index)?
+            }
+            SECTION_TYPE => {
+                typed = true;
+                type_section(payload)?
+            }
+            SECTION_IMPORT => {
+                if !typed {
+                    write_section(&mut out, SECTION_TYPE, &type_section(&[])?);
+                    typed = true;
+                }
+                imported = true;
+                import_section(payload)?
+            }
+            SECTION_TABLE => table_section(payload, index)?,
+            SECTION_GLOBAL => global_section(payload, index)?,
+            SECTION_EXPORT => export_section(payload, index)?,
+            SECTION_START => {
+                let mut rewriter = Rewriter::new(payload, index);
+                rewriter.function_index()?;
+                rewriter.copy_rest();
+                rewriter.out
+            }
+            SECTION_ELEMENT => element_section(payload, index)?,
+            SECTION_CODE => code_section(&section, &mut rewrite)?,
+            _ => payload.to_vec(),
+        };
+        write_section(&mut out, section.id, &rewritten);
+    }
+    if !typed {
+        write_section(&mut out, SECTION_TYPE, &type_section(&[])?);
+    }
+    if !imported {
+        write_section(&mut out, SECTION_IMPORT, &import_section(&[])?);
+    }
+    Ok(out)
+}
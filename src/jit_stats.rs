@@ -0,0 +1,192 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! how much compiling the loaded modules cost, to weigh the LLVM JIT, the Fast JIT and the
+//! interpreters against each other. Get them via `Runtime::jit_stats()`
+//!
+//! WAMR doesn't report what its JITs emit, so the bytes of machine code are only known for
+//! an .aot, from its text section. The compile time is how long loading took, which
+//! includes compiling every function for an eager JIT, but not for the `lazy-jit` feature.
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use wamr_sys::{
+    wasm_module_t, RunningMode, RunningMode_Mode_Fast_JIT, RunningMode_Mode_LLVM_JIT,
+    RunningMode_Mode_Multi_Tier_JIT,
+};
+
+use crate::{binary, target};
+
+/// what runs the code of a module
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compiler {
+    /// the fast or classic interpreter, which compiles nothing
+    Interpreter,
+    FastJit,
+    LlvmJit,
+    /// the Fast JIT first, then the LLVM JIT for the functions called most
+    MultiTierJit,
+    /// wamrc, ahead of time
+    Aot,
+}
+
+impl Compiler {
+    /// the compiler of a module loaded from `content` with the running mode of a runtime
+    #[allow(non_upper_case_globals)]
+    fn new(content: &[u8], running_mode: RunningMode) -> Self {
+        match running_mode {
+            _ if target::is_aot(content) => Compiler::Aot,
+            RunningMode_Mode_Fast_JIT => Compiler::FastJit,
+            RunningMode_Mode_LLVM_JIT => Compiler::LlvmJit,
+            RunningMode_Mode_Multi_Tier_JIT => Compiler::MultiTierJit,
+            _ => Compiler::Interpreter,
+        }
+    }
+}
+
+/// what compiling a module cost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleJitStats {
+    /// the name of the module
+    pub module: String,
+    pub compiler: Compiler,
+    /// the functions defined by the module, which a JIT compiles, `None` for an .aot
+    pub functions: Option<u32>,
+    /// the bytes of machine code, `None` unless the module is an .aot
+    pub code_size: Option<u64>,
+    /// how long loading the module took
+    pub compile_time: Duration,
+}
+
+/// the stats of the modules loaded, and not dropped yet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JitStats {
+    /// in the order the modules have been loaded
+    pub modules: Vec<ModuleJitStats>,
+}
+
+impl JitStats {
+    pub fn functions(&self) -> u32 {
+        self.modules
+            .iter()
+            .filter_map(|stats| stats.functions)
+            .sum()
+    }
+
+    /// the bytes of machine code of the modules it is known for
+    pub fn code_size(&self) -> u64 {
+        self.modules
+            .iter()
+            .filter_map(|stats| stats.code_size)
+            .sum()
+    }
+
+    pub fn compile_time(&self) -> Duration {
+        self.modules.iter().map(|stats| stats.compile_time).sum()
+    }
+}
+
+/// the stats of the loaded modules, by the address of the module
+static MODULES: Mutex<Vec<(usize, ModuleJitStats)>> = Mutex::new(Vec::new());
+
+fn modules() -> MutexGuard<'static, Vec<(usize, ModuleJitStats)>> {
+    MODULES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// record the stats of `module`, loaded from `content` in `compile_time`
+pub(crate) fn record(
+    module: wasm_module_t,
+    name: &str,
+    content: &[u8],
+    running_mode: RunningMode,
+    compile_time: Duration,
+) {
+    let compiler = Compiler::new(content, running_mode);
+    let stats = ModuleJitStats {
+        module: String::from(name),
+        compiler,
+        functions: match compiler {
+            Compiler::Aot => None,
+            _ => binary::function_bodies(content)
+                .ok()
+                .map(|bodies| bodies.len() as u32),
+        },
+        code_size: target::aot_text_size(content),
+        compile_time,
+    };
+    modules().push((module as usize, stats));
+}
+
+/// forget the stats of an unloaded module
+pub(crate) fn remove(module: wasm_module_t) {
+    modules().retain(|(address, _)| *address != module as usize);
+}
+
+pub(crate) fn snapshot() -> JitStats {
+    JitStats {
+        modules: modules().iter().map(|(_, stats)| stats.clone()).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jit_stats() {
+        // (module (func) (func))
+        let wasm = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00,
+            0x03, 0x03, 0x02, 0x00, 0x00, 0x0a, 0x07, 0x02, 0x02, 0x00, 0x0b, 0x02, 0x00, 0x0b,
+        ];
+        // the target info section, empty, and a text section of 8 bytes
+        let mut aot = b"\0aot".to_vec();
+        aot.extend_from_slice(&3u32.to_le_bytes());
+        aot.extend_from_slice(&[0; 8]);
+        aot.extend_from_slice(&2u32.to_le_bytes());
+        aot.extend_from_slice(&8u32.to_le_bytes());
+        aot.extend_from_slice(&[0x90; 8]);
+
+        // any addresses, no module is involved
+        let (first, second) = (0x40 as wasm_module_t, 0x50 as wasm_module_t);
+        record(
+            first,
+            "jit",
+            &wasm,
+            RunningMode_Mode_LLVM_JIT,
+            Duration::from_millis(3),
+        );
+        record(
+            second,
+            "aot",
+            &aot,
+            RunningMode_Mode_LLVM_JIT,
+            Duration::from_millis(1),
+        );
+
+        let stats = snapshot();
+        let [jit, aot] = ["jit", "aot"].map(|name| {
+            let stats = stats.modules.iter().find(|stats| stats.module == name);
+            stats.unwrap().clone()
+        });
+        assert_eq!(jit.compiler, Compiler::LlvmJit);
+        assert_eq!(jit.functions, Some(2));
+        assert_eq!(jit.code_size, None);
+        assert_eq!(aot.compiler, Compiler::Aot);
+        assert_eq!(aot.functions, None);
+        assert_eq!(aot.code_size, Some(8));
+        assert!(stats.compile_time() >= Duration::from_millis(4));
+
+        remove(first);
+        remove(second);
+        assert!(snapshot()
+            .modules
+            .iter()
+            .all(|stats| stats.module != "jit" && stats.module != "aot"));
+    }
+}
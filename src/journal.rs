@@ -0,0 +1,380 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! checkpoints of an instance at the boundaries of its host calls, to resume a guest
+//! deterministically after a restart of the host, like the steps of a workflow engine.
+//! Start one via `Instance::begin_journal()`, and resume from one via
+//! `Instance::resume_journal()`
+//!
+//! the frames of a guest blocked in a host function can't be persisted, see `snapshot`, so
+//! a checkpoint is the state of the instance before the call into it, and a journal of the
+//! results of the host calls made since. Each host function wrapped in `journaled()`
+//! appends to the journal, and hands the checkpoint to persist to the handler. Resuming
+//! restores the state, and the same call into the instance is then made again: the
+//! journaled host functions return their recorded results without running, until the
+//! journal is replayed, and run again from there on. The guest reaches the same boundary
+//! as long as it is deterministic, and a host call other than the recorded one traps.
+//!
+//! a journaled host function's effects on the linear memory are replayed only if they
+//! derive from its result, like bytes it returns and the host then copies into the guest.
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use wamr_sys::wasm_module_inst_t;
+
+use crate::{
+    binary::{write_name, write_u32_leb, Reader},
+    user_data::ExecEnv,
+    ErrorMessage, RuntimeError,
+};
+
+const CHECKPOINT_MAGIC: [u8; 4] = [0x00, 0x6a, 0x6e, 0x6c];
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// a result of a host function, which can be recorded in a journal
+pub trait JournalValue: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+    /// `None` if `bytes` weren't recorded from a `Self`
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_journal_value {
+    ($($native:ty),*) => {
+        $(
+            impl JournalValue for $native {
+                fn to_bytes(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                    Some(<$native>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_journal_value!(i32, i64, f32, f64);
+
+impl JournalValue for () {
+    fn to_bytes(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        bytes.is_empty().then_some(())
+    }
+}
+
+impl JournalValue for Vec<u8> {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+/// a host call recorded in a journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostCall {
+    /// the name `journaled()` was given
+    pub function: String,
+    pub result: Vec<u8>,
+}
+
+/// the state of an instance before a call into it, and the host calls made since
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    state: Arc<Vec<u8>>,
+    host_calls: Vec<HostCall>,
+}
+
+impl Checkpoint {
+    /// a checkpoint before any host call
+    pub(crate) fn new(state: Vec<u8>) -> Self {
+        Checkpoint {
+            state: Arc::new(state),
+            host_calls: Vec::new(),
+        }
+    }
+
+    pub(crate) fn state(&self) -> &[u8] {
+        &self.state
+    }
+
+    /// the host calls made since the state was taken, in order
+    pub fn host_calls(&self) -> &[HostCall] {
+        &self.host_calls
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = CHECKPOINT_MAGIC.to_vec();
+        write_u32_leb(&mut bytes, CHECKPOINT_VERSION);
+        // a state holds the whole linear memory, which may not fit in 32 bits
+        bytes.extend_from_slice(&(self.state.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.state);
+        write_u32_leb(&mut bytes, self.host_calls.len() as u32);
+        for call in &self.host_calls {
+            write_name(&mut bytes, &call.function);
+            write_u32_leb(&mut bytes, call.result.len() as u32);
+            bytes.extend_from_slice(&call.result);
+        }
+        bytes
+    }
+
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if `bytes` aren't from `Checkpoint::to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RuntimeError> {
+        let malformed =
+            |e: ErrorMessage| RuntimeError::ExecutionError(message!("malformed checkpoint: {}", e));
+
+        let mut reader = Reader::new(bytes);
+        if reader
+            .read_bytes(CHECKPOINT_MAGIC.len())
+            .map_err(malformed)?
+            != CHECKPOINT_MAGIC
+        {
+            return Err(malformed(message!("not a checkpoint")));
+        }
+        let version = reader.read_u32_leb().map_err(malformed)?;
+        if version != CHECKPOINT_VERSION {
+            return Err(malformed(message!("unsupported version {}", version)));
+        }
+
+        let len = reader.read_bytes(8).map_err(malformed)?;
+        let len = u64::from_le_bytes(len.try_into().unwrap());
+        let len = usize::try_from(len).map_err(|_| malformed(message!("state too large")))?;
+        let state = reader.read_bytes(len).map_err(malformed)?.to_vec();
+
+        let count = reader.read_u32_leb().map_err(malformed)?;
+        let mut host_calls = Vec::new();
+        for _ in 0..count {
+            let function = String::from(reader.read_name().map_err(malformed)?);
+            let len = reader.read_u32_leb().map_err(malformed)? as usize;
+            let result = reader.read_bytes(len).map_err(malformed)?.to_vec();
+            host_calls.push(HostCall { function, result });
+        }
+        if !reader.is_empty() {
+            return Err(malformed(message!(
+                "trailing bytes at offset {}",
+                reader.position()
+            )));
+        }
+
+        Ok(Checkpoint {
+            state: Arc::new(state),
+            host_calls,
+        })
+    }
+}
+
+/// a host callback given the checkpoint after each host call, to persist it
+pub type CheckpointHandler = Arc<dyn Fn(&Checkpoint) + Send + Sync>;
+
+struct Journal {
+    checkpoint: Checkpoint,
+    /// the host calls replayed so far, the ones after are still to
+    replayed: usize,
+    on_checkpoint: CheckpointHandler,
+}
+
+static JOURNALS: Mutex<Vec<(usize, Journal)>> = Mutex::new(Vec::new());
+
+fn journals() -> MutexGuard<'static, Vec<(usize, Journal)>> {
+    JOURNALS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// journal the host calls of `instance` after the state of `checkpoint`, replaying its host
+/// calls first. Replace the journal started before
+pub(crate) fn begin(
+    instance: wasm_module_inst_t,
+    checkpoint: Checkpoint,
+    on_checkpoint: CheckpointHandler,
+) {
+    let mut journals = journals();
+    journals.retain(|(address, _)| *address != instance as usize);
+    journals.push((
+        instance as usize,
+        Journal {
+            checkpoint,
+            replayed: 0,
+            on_checkpoint,
+        },
+    ));
+}
+
+/// stop journaling the host calls of `instance`, and return its last checkpoint
+pub(crate) fn end(instance: wasm_module_inst_t) -> Option<Checkpoint> {
+    let mut journals = journals();
+    let position = journals
+        .iter()
+        .position(|(address, _)| *address == instance as usize)?;
+    let (_, mut journal) = journals.swap_remove(position);
+    journal.checkpoint.host_calls.truncate(journal.replayed);
+    Some(journal.checkpoint)
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    journals().retain(|(address, _)| *address != instance as usize);
+}
+
+fn trap(env: ExecEnv, message: String) {
+    env.set_exception(&message);
+}
+
+/// run the body of the host function `function`, unless the instance of `env` journals its
+/// host calls and this one is still to be replayed, then return the recorded result.
+/// Otherwise record the result, and hand the checkpoint after it to the handler. Without a
+/// journal, just run `live`.
+///
+/// a replayed host call other than the recorded one traps the calling instance, and returns
+/// the default value, which the guest never sees. See `journal`
+pub fn journaled<R: JournalValue + Default>(
+    env: ExecEnv,
+    function: &str,
+    live: impl FnOnce() -> R,
+) -> R {
+    let instance = env.module_inst() as usize;
+    {
+        let mut journals = journals();
+        let Some((_, journal)) = journals
+            .iter_mut()
+            .find(|(address, _)| *address == instance)
+        else {
+            drop(journals);
+            return live();
+        };
+
+        if let Some(recorded) = journal.checkpoint.host_calls.get(journal.replayed) {
+            let index = journal.replayed;
+            let result = match recorded.function == function {
+                true => R::from_bytes(&recorded.result),
+                false => None,
+            };
+            let Some(result) = result else {
+                let message = format!(
+                    "replay diverged at host call {}: {} was recorded, not {}",
+                    index, recorded.function, function
+                );
+                drop(journals);
+                trap(env, message);
+                return R::default();
+            };
+            journal.replayed += 1;
+            return result;
+        }
+    }
+
+    // the host function may call into the journal of another instance
+    let result = live();
+
+    let mut journals = journals();
+    let Some((_, journal)) = journals
+        .iter_mut()
+        .find(|(address, _)| *address == instance)
+    else {
+        return result;
+    };
+    journal.checkpoint.host_calls.push(HostCall {
+        function: String::from(function),
+        result: result.to_bytes(),
+    });
+    journal.replayed += 1;
+    let checkpoint = journal.checkpoint.clone();
+    let on_checkpoint = journal.on_checkpoint.clone();
+    drop(journals);
+    on_checkpoint(&checkpoint);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function, host_function::ResultTy, instance::Instance, module::Module,
+        runtime::Runtime, value::WasmValue,
+    };
+    use std::{
+        ffi::c_void,
+        sync::atomic::{AtomicI32, Ordering},
+    };
+
+    static TICKS: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn tick(env: ExecEnv) -> i32 {
+        journaled(env, "tick", || TICKS.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+
+    #[test]
+    fn test_journal() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_host_function("tick", tick as *mut c_void, &[], ResultTy::I32)
+            .build()?;
+
+        // (module
+        //   (import "host" "tick" (func $tick (result i32)))
+        //   (func (export "run") (result i32) (i32.add (call $tick) (call $tick)))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+            0x7f, 0x02, 0x0d, 0x01, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x04, 0x74, 0x69, 0x63, 0x6b,
+            0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x72, 0x75, 0x6e, 0x00,
+            0x01, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x10, 0x00, 0x10, 0x00, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "journal")?;
+        let run = |instance: &Instance<()>| {
+            Function::find_export_func(instance, "run")?.call(instance, &vec![])
+        };
+
+        let checkpoints = Arc::new(Mutex::new(Vec::new()));
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        let persisted = checkpoints.clone();
+        instance.begin_journal(move |checkpoint| {
+            persisted.lock().unwrap().push(checkpoint.to_bytes());
+        })?;
+        assert_eq!(run(&instance)?, WasmValue::I32(1 + 2));
+        assert_eq!(instance.end_journal().unwrap().host_calls().len(), 2);
+        drop(instance);
+
+        // resume at the boundary after the first tick, as after a crash
+        let checkpoint = Checkpoint::from_bytes(&checkpoints.lock().unwrap()[0])?;
+        assert_eq!(
+            checkpoint.host_calls(),
+            [HostCall {
+                function: String::from("tick"),
+                result: 1i32.to_bytes(),
+            }]
+        );
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        instance.resume_journal(&checkpoint, |_| {})?;
+        assert_eq!(run(&instance)?, WasmValue::I32(1 + 3));
+        assert_eq!(TICKS.load(Ordering::SeqCst), 3);
+
+        // a guest diverging from the journal traps
+        let diverged = Checkpoint {
+            host_calls: vec![HostCall {
+                function: String::from("tock"),
+                result: Vec::new(),
+            }],
+            ..checkpoint
+        };
+        instance.resume_journal(&diverged, |_| {})?;
+        assert!(matches!(
+            run(&instance),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+
+        assert!(matches!(
+            Checkpoint::from_bytes(&[0x00, 0x6a]),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+
+        Ok(())
+    }
+}
@@ -145,14 +145,167 @@ use std::error;
 use std::fmt;
 use std::io;
 
+/// the message of a `RuntimeError`, or with the `tiny` feature, a static error code
+#[cfg(not(feature = "tiny"))]
+pub type ErrorMessage = String;
+/// the message of a `RuntimeError`, or with the `tiny` feature, a static error code
+#[cfg(feature = "tiny")]
+pub type ErrorMessage = &'static str;
+
+/// an `ErrorMessage` formatted like `format!()`. With the `tiny` feature, the format string
+/// itself is the code, the arguments are left out and nothing is allocated.
+/// `message!(code: "...", message)` takes a `String` built elsewhere, which `tiny` swaps
+/// for the code without evaluating it
+#[cfg(not(feature = "tiny"))]
+macro_rules! message {
+    (code: $code:literal, $message:expr) => {
+        $message
+    };
+    ($($arg:tt)*) => {
+        format!($($arg)*)
+    };
+}
+
+#[cfg(feature = "tiny")]
+macro_rules! message {
+    (code: $code:literal, $message:expr) => {{
+        let _ = || $message;
+        $crate::helper::error_code($code)
+    }};
+    ($format:literal $(, $arg:expr)* $(,)?) => {{
+        $(let _ = &$arg;)*
+        $crate::helper::error_code($format)
+    }};
+}
+
+#[cfg(feature = "wamr")]
+pub mod account;
+#[cfg(feature = "wamr")]
+pub mod allocator;
+#[cfg(feature = "wamr")]
+pub mod async_host;
+#[cfg(feature = "wamr")]
+mod binary;
+#[cfg(feature = "wamr")]
+pub mod bridge;
+#[cfg(feature = "wamr")]
+pub mod buffer;
+#[cfg(feature = "wamr")]
+pub mod cache;
+#[cfg(feature = "wamr")]
+pub mod channel;
+#[cfg(feature = "wamr")]
+pub mod compiler;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "wamr")]
+pub mod coverage;
+#[cfg(feature = "debug")]
+pub mod debugger;
+#[cfg(feature = "multi-module")]
+pub mod dependency;
+#[cfg(feature = "multi-module")]
+pub mod dylink;
+#[cfg(feature = "sgx")]
+pub mod enclave;
+#[cfg(feature = "encrypted")]
+pub mod encryption;
+#[cfg(feature = "wamr")]
+pub mod event;
+#[cfg(feature = "wamr")]
+pub mod fault;
+#[cfg(feature = "wamr")]
+pub mod features;
+#[cfg(feature = "wamr")]
 pub mod function;
+#[cfg(feature = "gc")]
+pub mod gc;
+#[cfg(feature = "multi-module")]
+pub mod group;
+pub mod guest;
+#[cfg(feature = "wamr")]
+pub mod guest_interface;
+#[cfg_attr(not(feature = "wamr"), allow(dead_code))]
 mod helper;
+#[cfg(feature = "wamr")]
+pub mod host_apis;
+#[cfg(feature = "wamr")]
 pub mod host_function;
+#[cfg(feature = "wamr")]
 pub mod instance;
+#[cfg(feature = "wamr")]
+pub mod instruction;
+#[cfg(feature = "wamr")]
+mod instrument;
+#[cfg(feature = "wamr")]
+pub mod jit_stats;
+#[cfg(feature = "wamr")]
+pub mod journal;
+#[cfg(feature = "wamr")]
+mod lifecycle;
+#[cfg(feature = "wamr")]
+pub mod mailbox;
+#[cfg(feature = "wamr")]
+pub mod memory;
+#[cfg(feature = "wamr")]
+pub mod memory_profile;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+#[cfg(feature = "wamr")]
 pub mod module;
+#[cfg(feature = "wamr")]
+pub mod native_module;
+#[cfg(feature = "wamr")]
+pub mod oom;
+#[cfg(feature = "wamr")]
+pub mod pipeline;
+#[cfg(feature = "wamr")]
+mod platform;
+#[cfg(feature = "wamr")]
+pub mod policy;
+#[cfg(feature = "perf-profiling")]
+pub mod profile;
+#[cfg(feature = "wamr")]
+pub mod registry;
+#[cfg(feature = "wamr")]
+pub mod replay;
+#[cfg(feature = "instruction-metering")]
+pub mod round_robin;
+#[cfg(feature = "wamr")]
 pub mod runtime;
+#[cfg(feature = "threads")]
+mod sampler;
+#[cfg(feature = "wamr")]
+pub mod scheduling;
+#[cfg(feature = "wamr")]
+pub mod signals;
+#[cfg(feature = "signed-aot")]
+pub mod signature;
+#[cfg(feature = "wamr")]
+mod snapshot;
+#[cfg(feature = "wamr")]
+pub mod source;
+#[cfg(feature = "wamr")]
+mod stack;
+#[cfg(feature = "stringref")]
+pub mod stringref;
+#[cfg(feature = "wamr")]
+pub mod supervisor;
+#[cfg(feature = "wamr")]
+pub mod target;
+#[cfg(feature = "threads")]
+pub mod threads;
+#[cfg_attr(not(feature = "wamr"), allow(dead_code))]
 pub mod value;
+#[cfg(libc_wasi)]
+pub mod wasi_audit;
+#[cfg(libc_wasi)]
 pub mod wasi_context;
+#[cfg(libc_wasi)]
+pub mod wasi_mount;
+#[cfg(libc_wasi)]
+pub mod wasi_policy;
+#[cfg(feature = "wamr")]
 pub mod user_data;
 
 /// all kinds of exceptions raised by WAMR
@@ -164,13 +317,37 @@ pub enum RuntimeError {
     /// file operation error. usually while loading(compilation) a .wasm
     WasmFileFSError(std::io::Error),
     /// A compilation error. usually means that the .wasm file is invalid
-    CompilationError(String),
+    CompilationError(ErrorMessage),
     /// instantiation failure
-    InstantiationFailure(String),
+    InstantiationFailure(ErrorMessage),
     /// Error during execute wasm functions
-    ExecutionError(String),
+    ExecutionError(ErrorMessage),
     /// usually returns by `find_export_func()`
     FunctionNotFound,
+    /// out of bounds or denied access to the linear memory
+    MemoryAccessError(ErrorMessage),
+    /// a module exceeds the ceilings of `LoadLimits`
+    LimitExceeded(ErrorMessage),
+    /// a `WasmValue` isn't of the expected type
+    TypeMismatch(ErrorMessage),
+    /// the debugging engine is unavailable or failed a request
+    DebugError(ErrorMessage),
+    /// a module breaks the rules of a `ModulePolicy`
+    PolicyViolation(ErrorMessage),
+    /// a call ran over the time limit given to `Function::call_with_time_limit()`
+    TimeLimitExceeded(std::time::Duration),
+    /// a `RuntimeConfig` is malformed, or asks for what the WAMR build lacks
+    ConfigError(ErrorMessage),
+    /// a call ran over the instructions given to `Function::call_with_instruction_limit()`
+    InstructionLimitExceeded(u32),
+    /// a host function can't be registered, like for a signature WAMR doesn't accept, or
+    /// a name registered twice under one module name
+    HostRegistration(ErrorMessage),
+    /// a module allocates from the app heap, which the `Runtime` disables, see
+    /// `RuntimeBuilder::default_app_heap()`
+    AppHeapRequired(ErrorMessage),
+    /// a call used more CPU time than given to `Function::call_with_cpu_time_limit()`
+    CpuTimeExceeded(std::time::Duration),
 }
 
 impl fmt::Display for RuntimeError {
@@ -183,6 +360,52 @@ impl fmt::Display for RuntimeError {
             RuntimeError::InstantiationFailure(e) => write!(f, "Wasm instantiation failure: {}", e),
             RuntimeError::ExecutionError(e) => write!(f, "Wasm execution error: {}", e),
             RuntimeError::FunctionNotFound => write!(f, "Function not found"),
+            RuntimeError::MemoryAccessError(e) => write!(f, "Wasm memory access error: {}", e),
+            RuntimeError::LimitExceeded(e) => write!(f, "Resource limit exceeded: {}", e),
+            RuntimeError::TypeMismatch(e) => write!(f, "Type mismatch: {}", e),
+            RuntimeError::DebugError(e) => write!(f, "Debugger error: {}", e),
+            RuntimeError::PolicyViolation(e) => write!(f, "Module policy violation: {}", e),
+            RuntimeError::TimeLimitExceeded(limit) => {
+                write!(f, "Time limit of {:?} exceeded", limit)
+            }
+            RuntimeError::ConfigError(e) => write!(f, "Runtime configuration error: {}", e),
+            RuntimeError::InstructionLimitExceeded(limit) => {
+                write!(f, "Instruction limit of {} exceeded", limit)
+            }
+            RuntimeError::HostRegistration(e) => {
+                write!(f, "Host function registration error: {}", e)
+            }
+            RuntimeError::AppHeapRequired(e) => write!(f, "App heap required: {}", e),
+            RuntimeError::CpuTimeExceeded(limit) => {
+                write!(f, "CPU time limit of {:?} exceeded", limit)
+            }
+        }
+    }
+}
+
+impl RuntimeError {
+    /// a stable number for the kind of error, to report it without its message, like with
+    /// the `tiny` feature, where messages are static codes
+    pub fn code(&self) -> u32 {
+        match self {
+            RuntimeError::NotImplemented => 1,
+            RuntimeError::InitializationFailure => 2,
+            RuntimeError::WasmFileFSError(_) => 3,
+            RuntimeError::CompilationError(_) => 4,
+            RuntimeError::InstantiationFailure(_) => 5,
+            RuntimeError::ExecutionError(_) => 6,
+            RuntimeError::FunctionNotFound => 7,
+            RuntimeError::MemoryAccessError(_) => 8,
+            RuntimeError::LimitExceeded(_) => 9,
+            RuntimeError::TypeMismatch(_) => 10,
+            RuntimeError::DebugError(_) => 11,
+            RuntimeError::PolicyViolation(_) => 12,
+            RuntimeError::TimeLimitExceeded(_) => 13,
+            RuntimeError::ConfigError(_) => 14,
+            RuntimeError::InstructionLimitExceeded(_) => 15,
+            RuntimeError::HostRegistration(_) => 16,
+            RuntimeError::AppHeapRequired(_) => 17,
+            RuntimeError::CpuTimeExceeded(_) => 18,
         }
     }
 }
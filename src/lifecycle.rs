@@ -0,0 +1,136 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! counts of the live objects depending on another one, like the modules loaded by a
+//! runtime, so dropping them in the wrong order panics instead of crashing inside WAMR.
+//! And the calls running into an instance
+
+use std::{
+    cell::Cell,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// the objects depending on an owner, kept by the owner
+#[derive(Debug, Default)]
+pub(crate) struct Dependents {
+    count: Arc<AtomicUsize>,
+}
+
+/// kept by a dependent while it is alive
+#[derive(Debug)]
+pub(crate) struct Dependent {
+    count: Arc<AtomicUsize>,
+}
+
+impl Dependents {
+    pub(crate) fn track(&self) -> Dependent {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        Dependent {
+            count: self.count.clone(),
+        }
+    }
+
+    /// the number of dependents alive
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    /// return whether the owner may release its WAMR resources, and panic if dependents
+    /// are still alive.
+    ///
+    /// while the thread is already panicking, a second panic would abort, so return
+    /// `false` instead and let the owner leak.
+    pub(crate) fn release(&self, owner: &str, dependent: &str) -> bool {
+        let count = self.count.load(Ordering::SeqCst);
+        if count == 0 {
+            return true;
+        }
+        if thread::panicking() {
+            return false;
+        }
+        panic!(
+            "{} dropped while {} {}(s) still depend on it. Drop every {} before",
+            owner, count, dependent, dependent
+        );
+    }
+}
+
+impl Dependent {
+    /// whether `owner` is the one the dependent depends on
+    pub(crate) fn is_of(&self, owner: &Dependents) -> bool {
+        Arc::ptr_eq(&self.count, &owner.count)
+    }
+}
+
+impl Drop for Dependent {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// the calls running into an instance, host functions calling back into it included
+#[derive(Debug, Default)]
+pub(crate) struct Calls {
+    running: Cell<usize>,
+}
+
+/// kept while a call is running
+#[derive(Debug)]
+pub(crate) struct Call<'a> {
+    calls: &'a Calls,
+}
+
+impl Calls {
+    pub(crate) fn enter(&self) -> Call<'_> {
+        self.running.set(self.running.get() + 1);
+        Call { calls: self }
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.get() > 0
+    }
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        self.calls.running.set(self.calls.running.get() - 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dependents() {
+        let dependents = Dependents::default();
+        let dependent = dependents.track();
+        let result = std::panic::catch_unwind(|| dependents.release("Runtime", "Module"));
+        let message = result.unwrap_err();
+        assert_eq!(
+            message.downcast_ref::<String>().unwrap(),
+            "Runtime dropped while 1 Module(s) still depend on it. Drop every Module before"
+        );
+
+        drop(dependent);
+        assert!(dependents.release("Runtime", "Module"));
+    }
+
+    #[test]
+    fn test_calls() {
+        let calls = Calls::default();
+        let call = calls.enter();
+        let reentered = calls.enter();
+        drop(call);
+        assert!(calls.is_running());
+
+        drop(reentered);
+        assert!(!calls.is_running());
+    }
+}
@@ -0,0 +1,219 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a fixed region of the linear memory, for the host and the guest to exchange values
+//! without allocating on every call.
+//! get one via `Mailbox::exported()` or `Mailbox::reserve()`
+//!
+//! the region is located once, right after instantiation, either at the address a guest
+//! exports as a global, like a `static` buffer of a Rust or C guest, or allocated by the
+//! host from the app heap of the instance, and handed to the guest as a parameter.
+
+use std::{ffi::CString, marker::PhantomData, mem, ptr};
+
+use wamr_sys::{
+    wasm_global_inst_t, wasm_module_inst_t, wasm_runtime_addr_app_to_native,
+    wasm_runtime_get_export_global_inst, wasm_runtime_module_free, wasm_runtime_module_malloc,
+    wasm_runtime_validate_app_addr, wasm_valkind_enum_WASM_I32, wasm_valkind_enum_WASM_I64,
+};
+
+use crate::{instance::Instance, RuntimeError};
+
+/// a type which may be copied in and out of the linear memory as plain bytes
+///
+/// # Safety
+///
+/// the type has no padding, and every bit pattern of its size is a valid value, like for
+/// integers, floats and arrays of them. A `#[repr(C)]` struct of such fields qualifies
+/// once laid out without padding.
+pub unsafe trait MailboxValue: Copy {}
+
+macro_rules! impl_mailbox_value {
+    ($($native:ty),*) => {
+        $(unsafe impl MailboxValue for $native {})*
+    };
+}
+
+impl_mailbox_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+unsafe impl<T: MailboxValue, const N: usize> MailboxValue for [T; N] {}
+
+/// a `T` at a fixed offset of the linear memory of an instance
+pub struct Mailbox<'a, T> {
+    instance: wasm_module_inst_t,
+    offset: u64,
+    // allocated from the app heap by `Mailbox::reserve()`
+    owned: bool,
+    _instance: PhantomData<&'a ()>,
+    _value: PhantomData<T>,
+}
+
+impl<'a, T: MailboxValue> Mailbox<'a, T> {
+    /// the mailbox at the address held by the exported global `symbol`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if there is no such global holding an
+    /// address, or a `T` at that address is out of bounds.
+    pub fn exported<U>(instance: &'a Instance<U>, symbol: &str) -> Result<Self, RuntimeError> {
+        let offset = exported_address(instance, symbol)?;
+        Self::at(instance.get_inner_instance(), offset, false)
+    }
+
+    /// a mailbox allocated from the app heap of the instance, which has to be instantiated
+    /// with a heap size, and freed once dropped
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the app heap is exhausted.
+    pub fn reserve<U>(instance: &'a Instance<U>) -> Result<Self, RuntimeError> {
+        let inner_instance = instance.get_inner_instance();
+        let offset = unsafe {
+            wasm_runtime_module_malloc(inner_instance, mem::size_of::<T>() as _, ptr::null_mut())
+        };
+        if offset == 0 {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "failed to allocate {} bytes from the app heap",
+                mem::size_of::<T>()
+            )));
+        }
+        Self::at(inner_instance, offset as u64, true)
+    }
+
+    fn at(instance: wasm_module_inst_t, offset: u64, owned: bool) -> Result<Self, RuntimeError> {
+        let valid = unsafe {
+            wasm_runtime_validate_app_addr(instance, offset as _, mem::size_of::<T>() as _)
+        };
+        if !valid {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "out of bounds mailbox: offset {} length {}",
+                offset,
+                mem::size_of::<T>()
+            )));
+        }
+
+        Ok(Mailbox {
+            instance,
+            offset,
+            owned,
+            _instance: PhantomData,
+            _value: PhantomData,
+        })
+    }
+
+    /// the offset of the mailbox in the linear memory, to hand over to the guest
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // the memory only grows, so the mailbox stays in bounds, but it may move
+    fn native_ptr(&self) -> *mut T {
+        unsafe { wasm_runtime_addr_app_to_native(self.instance, self.offset as _) as *mut T }
+    }
+
+    /// the value in the mailbox
+    pub fn read(&self) -> T {
+        unsafe { ptr::read_unaligned(self.native_ptr()) }
+    }
+
+    /// replace the value in the mailbox
+    pub fn write(&self, value: T) {
+        unsafe { ptr::write_unaligned(self.native_ptr(), value) }
+    }
+}
+
+/// the address held by the exported global `symbol`, like the one of a `static` buffer
+///
+/// # Error
+///
+/// Return `RuntimeError::MemoryAccessError` if there is no such global holding an address.
+pub(crate) fn exported_address<T>(
+    instance: &Instance<T>,
+    symbol: &str,
+) -> Result<u64, RuntimeError> {
+    let name = CString::new(symbol)
+        .map_err(|_| RuntimeError::MemoryAccessError(message!("symbol contains a nul byte")))?;
+    let mut global = wasm_global_inst_t {
+        kind: 0,
+        is_mutable: false,
+        global_data: ptr::null_mut(),
+    };
+    let found = unsafe {
+        wasm_runtime_get_export_global_inst(
+            instance.get_inner_instance(),
+            name.as_ptr(),
+            &mut global,
+        )
+    };
+    if !found {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "no exported global {}",
+            symbol
+        )));
+    }
+
+    #[allow(non_upper_case_globals)]
+    let offset = match global.kind as u32 {
+        wasm_valkind_enum_WASM_I32 => unsafe { *(global.global_data as *const u32) as u64 },
+        // with memory64
+        wasm_valkind_enum_WASM_I64 => unsafe { *(global.global_data as *const u64) },
+        _ => {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "exported global {} isn't an address",
+                symbol
+            )))
+        }
+    };
+    Ok(offset)
+}
+
+impl<T> Drop for Mailbox<'_, T> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { wasm_runtime_module_free(self.instance, self.offset as _) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{function::Function, module::Module, runtime::Runtime, value::WasmValue};
+
+    #[test]
+    fn test_mailbox() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        //   (global (export "mailbox") i32 (i32.const 1024))
+        //   (func (export "double") (result i32) (i32.mul (i32.load (i32.const 1024)) (i32.const 2)))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+            0x7f, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x06, 0x07, 0x01, 0x7f,
+            0x00, 0x41, 0x80, 0x08, 0x0b, 0x07, 0x1d, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72,
+            0x79, 0x02, 0x00, 0x07, 0x6d, 0x61, 0x69, 0x6c, 0x62, 0x6f, 0x78, 0x03, 0x00, 0x06,
+            0x64, 0x6f, 0x75, 0x62, 0x6c, 0x65, 0x00, 0x00, 0x0a, 0x0d, 0x01, 0x0b, 0x00, 0x41,
+            0x80, 0x08, 0x28, 0x02, 0x00, 0x41, 0x02, 0x6c, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "mailbox")?;
+        let instance = Instance::new_with_args(&runtime, &module, 1024, 4096, ())?;
+
+        let mailbox = Mailbox::<u32>::exported(&instance, "mailbox")?;
+        assert_eq!(mailbox.offset(), 1024);
+        mailbox.write(21);
+        let double = Function::find_export_func(&instance, "double")?;
+        assert_eq!(double.call_args(&instance, &[])?, WasmValue::I32(42));
+        assert!(Mailbox::<u32>::exported(&instance, "double").is_err());
+
+        let reserved = Mailbox::<[f64; 4]>::reserve(&instance)?;
+        reserved.write([1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(reserved.read(), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(mailbox.read(), 21);
+
+        Ok(())
+    }
+}
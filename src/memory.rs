@@ -0,0 +1,949 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the default linear memory of an instance.
+//! get one via `Instance::memory()`
+//!
+//! WAMR has no hook on the `memory.grow` of guests, so with
+//! `RuntimeBuilder::hook_memory_grow()` each .wasm growing its first memory is rewritten
+//! while it is loaded, see `instrument`: the `memory.grow` calls a host function imported
+//! from `GROW_MODULE` instead, which grows the memory like `Memory::grow()`. The growth of
+//! an .aot, and of a memory64, isn't rewritten.
+
+#[cfg(feature = "threads")]
+use std::time::Duration;
+use std::{
+    ffi::{c_void, CString},
+    marker::PhantomData,
+    mem,
+    ops::Range,
+    ptr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+};
+
+use wamr_sys::{
+    wasm_module_inst_t, wasm_runtime_addr_app_to_native, wasm_runtime_enlarge_memory,
+    wasm_runtime_get_app_addr_range, wasm_runtime_register_natives, NativeSymbol,
+};
+
+#[cfg(feature = "threads")]
+use wamr_sys::{wasm_runtime_clear_exception, wasm_runtime_get_exception};
+
+#[cfg(feature = "threads")]
+use crate::helper::exception_to_string;
+use crate::{
+    allocator,
+    binary::{self, write_u32_leb, Reader},
+    event::{EventBus, InstanceId, RuntimeEvent},
+    instrument::{self, Hook},
+    platform,
+    user_data::ExecEnv,
+    ErrorMessage, RuntimeError,
+};
+
+pub use crate::guest::WASM_PAGE_SIZE;
+
+/// a host callback consulted before the linear memory grows.
+///
+/// it receives the current page count and the requested delta, and returns
+/// `false` to deny the growth.
+pub type MemoryGrowCallback = Arc<dyn Fn(u32, u32) -> bool + Send + Sync>;
+
+/// the module the rewritten `memory.grow` of guests imports its hook from
+pub const GROW_MODULE: &str = "wamr_memory_grow";
+const GROW_HOOK: &str = "grow";
+
+/// the type of the hook, `(delta: i32) -> i32`, like `memory.grow`
+const GROW_HOOK_TYPE: [u8; 5] = [0x60, 0x01, 0x7f, 0x01, 0x7f];
+
+/// the grow callback and the events of each instance, by address, for the growth of its
+/// guest
+type Growths = Vec<(usize, Option<MemoryGrowCallback>, Arc<EventBus>)>;
+
+static GROWTHS: Mutex<Growths> = Mutex::new(Vec::new());
+
+fn growths() -> MutexGuard<'static, Growths> {
+    GROWTHS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn track(instance: wasm_module_inst_t, events: Arc<EventBus>) {
+    growths().push((instance as usize, None, events));
+}
+
+pub(crate) fn set_grow_callback(instance: wasm_module_inst_t, callback: MemoryGrowCallback) {
+    let mut growths = growths();
+    if let Some(growth) = growths
+        .iter_mut()
+        .find(|(owner, ..)| *owner == instance as usize)
+    {
+        growth.1 = Some(callback);
+    }
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    growths().retain(|(owner, ..)| *owner != instance as usize);
+}
+
+/// grow the linear memory of `instance` by `delta` pages, once its grow callback agreed,
+/// and return the previous page count
+fn grow(
+    instance: wasm_module_inst_t,
+    delta: u32,
+    events: Option<&EventBus>,
+) -> Result<u32, RuntimeError> {
+    let old_pages = (data_size(instance) / WASM_PAGE_SIZE) as u32;
+    // released before the callback runs, which may set another one
+    let on_grow = growths()
+        .iter()
+        .find(|(owner, ..)| *owner == instance as usize)
+        .and_then(|(_, on_grow, _)| on_grow.clone());
+    if let Some(on_grow) = on_grow {
+        if !on_grow(old_pages, delta) {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "memory growth by {} pages denied",
+                delta
+            )));
+        }
+    }
+
+    match unsafe { wasm_runtime_enlarge_memory(instance, delta as _) } {
+        true => {
+            if let Some(events) = events {
+                events.emit(RuntimeEvent::MemoryGrown {
+                    instance: InstanceId::new(instance),
+                    old_pages,
+                    new_pages: (data_size(instance) / WASM_PAGE_SIZE) as u32,
+                });
+            }
+            Ok(old_pages)
+        }
+        false => Err(RuntimeError::MemoryAccessError(message!(
+            "failed to grow memory by {} pages",
+            delta
+        ))),
+    }
+}
+
+/// the `memory.grow` of a guest, `-1` if it failed or was denied
+extern "C" fn grow_hook(env: ExecEnv, delta: i32) -> i32 {
+    let instance = env.module_inst();
+    // an instance isn't tracked while it is instantiated
+    let events = growths()
+        .iter()
+        .find(|(owner, ..)| *owner == instance as usize)
+        .map(|(.., events)| events.clone());
+    match grow(instance, delta as u32, events.as_deref()) {
+        Ok(old_pages) => old_pages as i32,
+        Err(_) => -1,
+    }
+}
+
+/// the hook registered into WAMR, kept until it is destroyed
+struct GrowHook {
+    // WAMR keeps the addresses of the names
+    _module: CString,
+    _name: CString,
+    _signature: CString,
+    _symbols: Box<[NativeSymbol]>,
+}
+
+// the symbols only point to the names next to them
+unsafe impl Send for GrowHook {}
+
+static GROW_HOOK_REGISTERED: Mutex<Option<GrowHook>> = Mutex::new(None);
+
+/// register the hook rewritten guests import, once for the WAMR runtime
+pub(crate) fn register_grow_hook() {
+    let mut registered = GROW_HOOK_REGISTERED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if registered.is_some() {
+        return;
+    }
+
+    let module = CString::new(GROW_MODULE).unwrap();
+    let name = CString::new(GROW_HOOK).unwrap();
+    let signature = CString::new("(i)i").unwrap();
+    let mut symbols = Box::new([NativeSymbol {
+        symbol: name.as_ptr(),
+        func_ptr: grow_hook as *mut c_void,
+        signature: signature.as_ptr(),
+        attachment: ptr::null_mut(),
+    }]);
+    let done = unsafe { wasm_runtime_register_natives(module.as_ptr(), symbols.as_mut_ptr(), 1) };
+    if done {
+        *registered = Some(GrowHook {
+            _module: module,
+            _name: name,
+            _signature: signature,
+            _symbols: symbols,
+        });
+    }
+}
+
+/// forget the hook, once WAMR is destroyed
+pub(crate) fn reset() {
+    *GROW_HOOK_REGISTERED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// `binary`, with each `memory.grow` of its first memory calling the hook instead, `None`
+/// if it has none
+pub(crate) fn instrument_grow(binary: &[u8]) -> Result<Option<Vec<u8>>, ErrorMessage> {
+    if binary::memory_limits(binary)?.is_some_and(|limits| limits.memory64) {
+        return Ok(None);
+    }
+    let hook = Hook {
+        module: GROW_MODULE,
+        name: GROW_HOOK,
+        func_type: &GROW_HOOK_TYPE,
+        locals: &[],
+    };
+    let mut grows = false;
+    let rewritten = instrument::rewrite(binary, &hook, |site, out| {
+        // memory.grow 0
+        if site.opcode != 0x40 || Reader::new(&site.bytes[1..]).read_u32_leb()? != 0 {
+            return Ok(false);
+        }
+        grows = true;
+        // call hook
+        out.push(0x10);
+        write_u32_leb(out, site.hook);
+        Ok(true)
+    })?;
+    Ok(grows.then_some(rewritten))
+}
+
+/// how the OS backs the linear memories of a runtime, see
+/// `RuntimeBuilder::memory_hints()`. The hints are applied on Linux, and ignored on other
+/// platforms
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryHints {
+    /// back linear memories with transparent huge pages, which cuts TLB misses of guests
+    /// touching much memory, at the cost of a larger RSS for small ones
+    pub huge_pages: bool,
+    /// give the pages zeroed by a reset, like `Instance::restore_state()`, back to the OS
+    /// instead of writing them, which cuts the RSS of reused instances. Memories from a
+    /// memory pool are written anyway, the OS may not zero them
+    pub decommit_on_reset: bool,
+}
+
+/// a host callback invoked with `(offset, old, new)` when a write changed a watched region
+pub type WatchpointCallback = Box<dyn Fn(u64, &[u8], &[u8])>;
+
+/// the size of the default linear memory of `instance`, in bytes, 0 without one
+pub(crate) fn data_size(instance: wasm_module_inst_t) -> usize {
+    let mut end = 0;
+    let found = unsafe { wasm_runtime_get_app_addr_range(instance, 0, ptr::null_mut(), &mut end) };
+    match found {
+        true => end as usize,
+        false => 0,
+    }
+}
+
+/// a borrowed view of the default linear memory of an instance
+pub struct Memory<'a> {
+    instance: wasm_module_inst_t,
+    events: &'a EventBus,
+    _instance: PhantomData<&'a ()>,
+}
+
+impl<'a> Memory<'a> {
+    pub(crate) fn new(instance: wasm_module_inst_t, events: &'a EventBus) -> Self {
+        Memory {
+            instance,
+            events,
+            _instance: PhantomData,
+        }
+    }
+
+    /// the current size of the linear memory, in bytes
+    pub fn data_size(&self) -> usize {
+        data_size(self.instance)
+    }
+
+    /// the current size of the linear memory, in wasm pages
+    pub fn pages(&self) -> u32 {
+        (self.data_size() / WASM_PAGE_SIZE) as u32
+    }
+
+    /// the host address of the start of the linear memory, or null without one
+    pub(crate) fn base_address(&self) -> *const u8 {
+        unsafe { wasm_runtime_addr_app_to_native(self.instance, 0) as *const u8 }
+    }
+
+    /// the host address of `offset` in the linear memory, after checking that
+    /// `[offset, offset + len)` is in bounds
+    fn native_ptr(&self, offset: u64, len: usize) -> Result<*mut u8, RuntimeError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.data_size() as u64 => {}
+            _ => {
+                return Err(RuntimeError::MemoryAccessError(message!(
+                    "out of bounds memory access: offset {} length {}",
+                    offset,
+                    len
+                )))
+            }
+        }
+
+        let native = unsafe { wasm_runtime_addr_app_to_native(self.instance, offset as _) };
+        match native.is_null() {
+            true => Err(RuntimeError::MemoryAccessError(message!(
+                "invalid memory offset {}",
+                offset
+            ))),
+            false => Ok(native as *mut u8),
+        }
+    }
+
+    /// copy `buf.len()` bytes starting at `offset` out of the linear memory
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the range is out of bounds.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), RuntimeError> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        let src = self.native_ptr(offset, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    /// copy `data` into the linear memory starting at `offset`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the range is out of bounds.
+    pub fn write(&self, offset: u64, data: &[u8]) -> Result<(), RuntimeError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let dst = self.native_ptr(offset, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Ok(())
+    }
+
+    /// copy `data` into the start of the linear memory like `write()`, but give the wasm
+    /// pages of `data` which only hold zeros back to the OS, where it zeroes them
+    pub(crate) fn reset(&self, data: &[u8]) -> Result<(), RuntimeError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let decommit = allocator::private_linear_memories();
+        let base = self.native_ptr(0, data.len())?;
+        let memory = unsafe { std::slice::from_raw_parts_mut(base, data.len()) };
+        for (dst, src) in memory
+            .chunks_mut(WASM_PAGE_SIZE)
+            .zip(data.chunks(WASM_PAGE_SIZE))
+        {
+            match src.iter().all(|byte| *byte == 0) {
+                // checked to be private anonymous memory
+                true if decommit => unsafe { platform::zero_and_decommit(dst) },
+                true => dst.fill(0),
+                false => dst.copy_from_slice(src),
+            }
+        }
+        Ok(())
+    }
+
+    /// grow the linear memory by `delta` pages and return the previous page count.
+    ///
+    /// the memory grow callback of the instance, if any, is consulted first, like for
+    /// the `memory.grow` of its guest.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the callback denies the growth
+    /// or the memory can't be enlarged.
+    pub fn grow(&self, delta: u32) -> Result<u32, RuntimeError> {
+        grow(self.instance, delta, Some(self.events))
+    }
+}
+
+/// a watched region of the linear memory, compared with its last snapshot
+pub(crate) struct Watchpoint {
+    range: Range<u64>,
+    snapshot: Vec<u8>,
+    on_access: WatchpointCallback,
+}
+
+impl Watchpoint {
+    pub(crate) fn new(
+        memory: &Memory,
+        range: Range<u64>,
+        on_access: WatchpointCallback,
+    ) -> Result<Self, RuntimeError> {
+        if range.is_empty() || range.end > memory.data_size() as u64 {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "watched range {:?} is out of {} bytes",
+                range,
+                memory.data_size()
+            )));
+        }
+
+        let mut snapshot = vec![0u8; (range.end - range.start) as usize];
+        memory.read(range.start, &mut snapshot)?;
+        Ok(Watchpoint {
+            range,
+            snapshot,
+            on_access,
+        })
+    }
+
+    /// invoke the callback if the region changed since the last check
+    pub(crate) fn check(&mut self, memory: &Memory) {
+        let mut current = vec![0u8; self.snapshot.len()];
+        if memory.read(self.range.start, &mut current).is_err() || current == self.snapshot {
+            return;
+        }
+
+        (self.on_access)(self.range.start, &self.snapshot, &current);
+        self.snapshot = current;
+    }
+}
+
+/// a view of a `shared` linear memory which may be accessed from several threads
+/// at once. get one via `Instance::shared_memory()`, or `HostSharedMemory::memory()`
+/// for one the host created for guests to import.
+///
+/// Threads spawned by the instance through wasi-threads or lib-pthread run on the
+/// same memory, and host threads may join them via this view.
+#[derive(Clone, Copy)]
+pub struct SharedMemory<'a> {
+    #[cfg_attr(not(feature = "threads"), allow(dead_code))]
+    instance: wasm_module_inst_t,
+    base: *mut u8,
+    size: usize,
+    _instance: PhantomData<&'a ()>,
+}
+
+// WAMR allocates a shared memory with its maximum size up front, so `base`
+// never moves and every access goes through atomics or explicit copies
+unsafe impl Send for SharedMemory<'_> {}
+unsafe impl Sync for SharedMemory<'_> {}
+
+impl<'a> SharedMemory<'a> {
+    pub(crate) fn new(memory: &Memory<'a>) -> Result<Self, RuntimeError> {
+        let size = memory.data_size();
+        Ok(SharedMemory {
+            instance: memory.instance,
+            base: memory.native_ptr(0, size)?,
+            size,
+            _instance: PhantomData,
+        })
+    }
+
+    /// the size of the shared memory, in bytes
+    pub fn data_size(&self) -> usize {
+        self.size
+    }
+
+    fn atomic_ptr<A>(&self, offset: u64) -> Result<*mut u8, RuntimeError> {
+        let width = mem::size_of::<A>() as u64;
+        if !offset.is_multiple_of(width) {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "unaligned atomic access: offset {}",
+                offset
+            )));
+        }
+
+        match offset.checked_add(width) {
+            Some(end) if end <= self.size as u64 => Ok(unsafe { self.base.add(offset as usize) }),
+            _ => Err(RuntimeError::MemoryAccessError(message!(
+                "out of bounds memory access: offset {} length {}",
+                offset,
+                width
+            ))),
+        }
+    }
+
+    /// the 32-bit atomic cell at `offset`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `offset` is unaligned or out of bounds.
+    pub fn atomic_u32(&self, offset: u64) -> Result<&'a AtomicU32, RuntimeError> {
+        let ptr = self.atomic_ptr::<AtomicU32>(offset)?;
+        Ok(unsafe { &*(ptr as *const AtomicU32) })
+    }
+
+    /// the 64-bit atomic cell at `offset`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `offset` is unaligned or out of bounds.
+    pub fn atomic_u64(&self, offset: u64) -> Result<&'a AtomicU64, RuntimeError> {
+        let ptr = self.atomic_ptr::<AtomicU64>(offset)?;
+        Ok(unsafe { &*(ptr as *const AtomicU64) })
+    }
+
+    /// block the current thread while the 32-bit cell at `offset` holds `expected`, until a
+    /// `notify()`, from the host or a guest, or until `timeout`, like `memory.atomic.wait32`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `offset` is unaligned or out of bounds.
+    #[cfg(feature = "threads")]
+    pub fn wait(
+        &self,
+        offset: u64,
+        expected: u32,
+        timeout: Option<Duration>,
+    ) -> Result<WaitResult, RuntimeError> {
+        let ptr = self.atomic_ptr::<AtomicU32>(offset)?;
+        self.wait_at(ptr, expected as u64, timeout, false)
+    }
+
+    /// `wait()` on the 64-bit cell at `offset`, like `memory.atomic.wait64`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `offset` is unaligned or out of bounds.
+    #[cfg(feature = "threads")]
+    pub fn wait64(
+        &self,
+        offset: u64,
+        expected: u64,
+        timeout: Option<Duration>,
+    ) -> Result<WaitResult, RuntimeError> {
+        let ptr = self.atomic_ptr::<AtomicU64>(offset)?;
+        self.wait_at(ptr, expected, timeout, true)
+    }
+
+    #[cfg(feature = "threads")]
+    fn wait_at(
+        &self,
+        ptr: *mut u8,
+        expected: u64,
+        timeout: Option<Duration>,
+        wait64: bool,
+    ) -> Result<WaitResult, RuntimeError> {
+        // a negative timeout waits forever
+        let timeout = timeout.map_or(-1, |timeout| {
+            timeout.as_nanos().min(i64::MAX as u128) as i64
+        });
+        let result = unsafe {
+            wasm_runtime_atomic_wait(self.instance, ptr as *mut c_void, expected, timeout, wait64)
+        };
+        match result {
+            0 => Ok(WaitResult::Woken),
+            1 => Ok(WaitResult::NotEqual),
+            2 => Ok(WaitResult::TimedOut),
+            _ => Err(self.take_exception()),
+        }
+    }
+
+    /// wake up to `count` threads waiting on the cell at `offset`, host and guest ones, like
+    /// `memory.atomic.notify`, and return how many were woken
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if `offset` is unaligned or out of bounds.
+    #[cfg(feature = "threads")]
+    pub fn notify(&self, offset: u64, count: u32) -> Result<u32, RuntimeError> {
+        let ptr = self.atomic_ptr::<AtomicU32>(offset)?;
+        let woken = unsafe { wasm_runtime_atomic_notify(self.instance, ptr as *mut c_void, count) };
+        match woken {
+            u32::MAX => Err(self.take_exception()),
+            woken => Ok(woken),
+        }
+    }
+
+    /// WAMR reports a failed wait or notify as an exception of the instance
+    #[cfg(feature = "threads")]
+    fn take_exception(&self) -> RuntimeError {
+        let exception = unsafe { exception_to_string(wasm_runtime_get_exception(self.instance)) };
+        unsafe { wasm_runtime_clear_exception(self.instance) };
+        RuntimeError::MemoryAccessError(message!(code: "exception raised by WAMR", exception))
+    }
+}
+
+/// how `SharedMemory::wait()` returned
+#[cfg(feature = "threads")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// by a notify
+    Woken,
+    /// at once, since the cell didn't hold the expected value
+    NotEqual,
+    TimedOut,
+}
+
+/// a shared memory created by the host, which guests import as
+/// `(import "<name>" "memory" (memory <min> <max> shared))`, to share data with the host
+/// and each other without copies. Create one via `HostSharedMemory::new()`, with the
+/// `multi-module` and `threads` features
+///
+/// WAMR doesn't accept memories created by the host as imports, so this is a module only
+/// defining the memory, registered under `name` like the provider of an `InstanceGroup`,
+/// and instantiated by the host to reach the memory. WAMR links imports while loading a
+/// module, so load the guests afterwards.
+#[cfg(all(feature = "multi-module", feature = "threads"))]
+pub struct HostSharedMemory {
+    // declared before the module, to be dropped first
+    instance: crate::instance::Instance<()>,
+    _module: crate::module::Module,
+}
+
+#[cfg(all(feature = "multi-module", feature = "threads"))]
+impl HostSharedMemory {
+    /// a shared memory of `min_pages` pages, which guests may grow up to `max_pages`,
+    /// imported from the module `name`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if `min_pages` is larger than `max_pages`, or
+    /// `max_pages` than 65536, or `RuntimeError::InstantiationFailure` if `name` can't be
+    /// registered or the memory can't be allocated.
+    pub fn new(
+        runtime: &crate::runtime::Runtime,
+        name: &str,
+        min_pages: u32,
+        max_pages: u32,
+    ) -> Result<Self, RuntimeError> {
+        use crate::{
+            instance::{Instance, DEFAULT_STACK_SIZE},
+            module::Module,
+        };
+
+        if min_pages > max_pages || max_pages > 65536 {
+            return Err(RuntimeError::CompilationError(message!(
+                "invalid shared memory limits {}..{}",
+                min_pages,
+                max_pages
+            )));
+        }
+        let module = Module::from_buf(runtime, &shared_memory_module(min_pages, max_pages), name)?;
+        crate::group::register(&module, name)?;
+        let instance = Instance::new(runtime, &module, DEFAULT_STACK_SIZE, ())?;
+        if instance.shared_memory().is_none() {
+            return Err(RuntimeError::InstantiationFailure(message!(
+                "the shared memory can't be allocated",
+            )));
+        }
+        Ok(HostSharedMemory {
+            instance,
+            _module: module,
+        })
+    }
+
+    pub fn memory(&self) -> SharedMemory<'_> {
+        self.instance.shared_memory().expect("checked by new()")
+    }
+}
+
+/// a module exporting a shared memory as `memory`
+#[cfg(all(feature = "multi-module", feature = "threads"))]
+fn shared_memory_module(min_pages: u32, max_pages: u32) -> Vec<u8> {
+    let mut memories = Vec::new();
+    // one memory, shared and with a maximum
+    memories.extend_from_slice(&[0x01, 0x03]);
+    write_u32_leb(&mut memories, min_pages);
+    write_u32_leb(&mut memories, max_pages);
+
+    let mut exports = Vec::new();
+    write_u32_leb(&mut exports, 1);
+    binary::write_name(&mut exports, "memory");
+    exports.extend_from_slice(&[0x02, 0x00]);
+
+    let mut module = binary::header();
+    for (id, payload) in [
+        (binary::SECTION_MEMORY, memories),
+        (binary::SECTION_EXPORT, exports),
+    ] {
+        module.push(id);
+        write_u32_leb(&mut module, payload.len() as u32);
+        module.extend_from_slice(&payload);
+    }
+    module
+}
+
+#[cfg(feature = "threads")]
+extern "C" {
+    // from the shared memory support of WAMR, which guests wait and notify through, but
+    // missing from *wasm_export.h*
+    fn wasm_runtime_atomic_wait(
+        module: wasm_module_inst_t,
+        address: *mut c_void,
+        expect: u64,
+        timeout: i64,
+        wait64: bool,
+    ) -> u32;
+    fn wasm_runtime_atomic_notify(
+        module: wasm_module_inst_t,
+        address: *mut c_void,
+        count: u32,
+    ) -> u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        event::RuntimeEvent, function::Function, instance::Instance, instruction::decode_body,
+        module::Module, runtime::Runtime, value::WasmValue, RuntimeError,
+    };
+    use std::sync::atomic::Ordering;
+
+    // (module
+    //   (memory (export "memory") 1)
+    // )
+    const MEMORY_MODULE: [u8; 25] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x0a,
+        0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+    ];
+
+    // (module
+    //   (memory (export "memory") 1 3)
+    //   (func (export "grow") (param i32) (result i32) (memory.grow (local.get 0)))
+    // )
+    const GROW_MODULE_BINARY: [u8; 55] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7f, 0x01,
+        0x7f, 0x03, 0x02, 0x01, 0x00, 0x05, 0x04, 0x01, 0x01, 0x01, 0x03, 0x07, 0x11, 0x02, 0x06,
+        0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x04, 0x67, 0x72, 0x6f, 0x77, 0x00, 0x00,
+        0x0a, 0x08, 0x01, 0x06, 0x00, 0x20, 0x00, 0x40, 0x00, 0x0b,
+    ];
+
+    #[test]
+    fn test_memory_read_write() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+        let module = Module::from_buf(&runtime, &MEMORY_MODULE, "memory")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let memory = instance.memory();
+        assert_eq!(memory.pages(), 1);
+
+        memory.write(16, b"hello")?;
+        let mut buf = [0u8; 5];
+        memory.read(16, &mut buf)?;
+        assert_eq!(&buf, b"hello");
+
+        assert!(memory.read(65534, &mut buf).is_err());
+        assert!(memory.write(u64::MAX, b"x").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_grow_callback() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+        let module = Module::from_buf(&runtime, &MEMORY_MODULE, "memory")?;
+        let mut instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let requests = Arc::new(AtomicU32::new(0));
+        let seen = requests.clone();
+        instance.set_memory_grow_callback(move |old_pages, delta| {
+            seen.fetch_add(1, Ordering::Relaxed);
+            old_pages + delta <= 2
+        });
+
+        assert_eq!(instance.memory().grow(1)?, 1);
+        assert_eq!(instance.memory().pages(), 2);
+
+        assert!(instance.memory().grow(1).is_err());
+        assert_eq!(instance.memory().pages(), 2);
+        assert_eq!(requests.load(Ordering::Relaxed), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instrument_grow() {
+        assert_eq!(instrument_grow(&MEMORY_MODULE), Ok(None));
+
+        let rewritten = instrument_grow(&GROW_MODULE_BINARY).unwrap().unwrap();
+        let imports = binary::imports(&rewritten).unwrap();
+        assert_eq!(
+            imports
+                .iter()
+                .map(|i| (i.module, i.name))
+                .collect::<Vec<_>>(),
+            [(GROW_MODULE, GROW_HOOK)]
+        );
+        let bodies = binary::function_bodies(&rewritten).unwrap();
+        let opcodes: Vec<u32> = decode_body(&rewritten, bodies[0].clone())
+            .unwrap()
+            .iter()
+            .map(|i| i.opcode)
+            .collect();
+        // local.get, call hook, end
+        assert_eq!(opcodes, [0x20, 0x10, 0x0b]);
+    }
+
+    #[test]
+    fn test_guest_memory_grow() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .hook_memory_grow()
+            .build()?;
+        let grown = Arc::new(Mutex::new(Vec::new()));
+        let recorded = grown.clone();
+        runtime.subscribe(move |event| {
+            if let RuntimeEvent::MemoryGrown { new_pages, .. } = event {
+                recorded.lock().unwrap().push(*new_pages);
+            }
+        });
+        let module = Module::from_buf(&runtime, &GROW_MODULE_BINARY, "grow")?;
+        let mut instance = Instance::new(&runtime, &module, 1024, ())?;
+        instance.set_memory_grow_callback(|old_pages, delta| old_pages + delta <= 2);
+
+        let grow = Function::find_export_func(&instance, "grow")?;
+        assert_eq!(
+            grow.call_args(&instance, &[WasmValue::I32(1)])?,
+            WasmValue::I32(1)
+        );
+        // denied by the callback, though the maximum is 3
+        assert_eq!(
+            grow.call_args(&instance, &[WasmValue::I32(1)])?,
+            WasmValue::I32(-1)
+        );
+        assert_eq!(instance.memory().pages(), 2);
+        assert_eq!(*grown.lock().unwrap(), [2]);
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "wat")]
+    fn test_watchpoint() -> Result<(), RuntimeError> {
+        use std::{cell::RefCell, rc::Rc};
+
+        let runtime = Runtime::new()?;
+        let module = Module::from_wat(
+            &runtime,
+            r#"(module
+                (memory (export "memory") 1)
+                (func (export "store") (param i32 i32)
+                    (i32.store8 (local.get 0) (local.get 1))
+                )
+            )"#,
+        )?;
+        let mut instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let seen = changes.clone();
+        let id = instance.add_watchpoint(8..10, move |offset, old, new| {
+            seen.borrow_mut().push((offset, old.to_vec(), new.to_vec()));
+        })?;
+        assert!(instance.add_watchpoint(65535..65537, |_, _, _| {}).is_err());
+        assert!(instance.add_watchpoint(0..u64::MAX, |_, _, _| {}).is_err());
+
+        let store = Function::find_export_func(&instance, "store")?;
+        store.call_args(&instance, &[WasmValue::I32(100), WasmValue::I32(1)])?;
+        store.call_args(&instance, &[WasmValue::I32(9), WasmValue::I32(7)])?;
+        assert_eq!(*changes.borrow(), vec![(8, vec![0, 0], vec![0, 7])]);
+
+        assert!(instance.remove_watchpoint(id));
+        assert!(!instance.remove_watchpoint(id));
+        store.call_args(&instance, &[WasmValue::I32(8), WasmValue::I32(7)])?;
+        assert_eq!(changes.borrow().len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "threads")]
+    fn test_shared_memory_wait_notify() -> Result<(), RuntimeError> {
+        use super::WaitResult;
+        use std::{thread, time::Duration};
+
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1 1 shared)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+            0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "shared")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        let memory = instance.shared_memory().unwrap();
+
+        assert_eq!(memory.wait(0, 1, None)?, WaitResult::NotEqual);
+        let timeout = Some(Duration::from_millis(1));
+        assert_eq!(memory.wait64(8, 0, timeout)?, WaitResult::TimedOut);
+        assert_eq!(memory.notify(0, 1)?, 0);
+        assert!(memory.wait(2, 0, timeout).is_err());
+
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| memory.wait(0, 0, Some(Duration::from_secs(5))));
+            while memory.notify(0, 1).unwrap() == 0 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(waiter.join().unwrap().unwrap(), WaitResult::Woken);
+        });
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "multi-module", feature = "threads"))]
+    fn test_host_shared_memory() -> Result<(), RuntimeError> {
+        use super::HostSharedMemory;
+
+        let runtime = Runtime::new()?;
+        let shared = HostSharedMemory::new(&runtime, "env", 1, 1)?;
+
+        // (module
+        //   (import "env" "memory" (memory 1 1 shared))
+        //   (func (export "store") (param i32) (i32.store (i32.const 0) (local.get 0)))
+        //   (func (export "load") (result i32) (i32.load (i32.const 0)))
+        // )
+        let guest = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x09, 0x02, 0x60, 0x01, 0x7f,
+            0x00, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x10, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x06, 0x6d,
+            0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x03, 0x01, 0x01, 0x03, 0x03, 0x02, 0x00, 0x01,
+            0x07, 0x10, 0x02, 0x05, 0x73, 0x74, 0x6f, 0x72, 0x65, 0x00, 0x00, 0x04, 0x6c, 0x6f,
+            0x61, 0x64, 0x00, 0x01, 0x0a, 0x13, 0x02, 0x09, 0x00, 0x41, 0x00, 0x20, 0x00, 0x36,
+            0x02, 0x00, 0x0b, 0x07, 0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x0b,
+        ];
+        let guest = Module::from_buf(&runtime, &guest, "guest")?;
+        let writer = Instance::new(&runtime, &guest, 1024, ())?;
+        let reader = Instance::new(&runtime, &guest, 1024, ())?;
+
+        shared.memory().atomic_u32(0)?.store(7, Ordering::SeqCst);
+        let load = Function::find_export_func(&reader, "load")?;
+        assert_eq!(load.call_args(&reader, &[])?, WasmValue::I32(7));
+
+        let store = Function::find_export_func(&writer, "store")?;
+        store.call_args(&writer, &[WasmValue::I32(42)])?;
+        assert_eq!(shared.memory().atomic_u32(0)?.load(Ordering::SeqCst), 42);
+        assert_eq!(load.call_args(&reader, &[])?, WasmValue::I32(42));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "multi-module", feature = "threads"))]
+    fn test_shared_memory_module() {
+        // (module
+        //   (memory (export "memory") 1 1 shared)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01,
+            0x07, 0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        assert_eq!(super::shared_memory_module(1, 1), binary);
+    }
+
+    #[test]
+    fn test_unshared_memory() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+        let module = Module::from_buf(&runtime, &MEMORY_MODULE, "memory")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        assert!(instance.shared_memory().is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! how often guests read and write each 64 KiB page of their linear memory, to find data
+//! layouts which are unfriendly to the caches. Enable it via
+//! `RuntimeBuilder::profile_memory_accesses()`, and get the counts via
+//! `Instance::memory_heatmap()`
+//!
+//! WAMR has no hook on memory accesses, so each .wasm is instrumented while it is loaded,
+//! see `instrument`: every plain load and store first calls a host function imported from
+//! `MODULE`, with the address it accesses. Atomic, SIMD and bulk memory accesses aren't
+//! counted, nor the accesses of an .aot, and memory64 isn't supported.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use wamr_sys::wasm_module_inst_t;
+
+use crate::{
+    binary::{self, write_i32_leb, write_u32_leb, Reader},
+    instrument::{self, Hook},
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+    ErrorMessage,
+};
+
+/// the module the instrumented code imports its hook from
+pub const MODULE: &str = "wamr_memory_profile";
+const HOOK: &str = "access";
+
+/// the granularity of the counts, a wasm page
+pub const PAGE_SIZE: u64 = 65536;
+
+/// the accesses of a page
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PageAccesses {
+    /// the index of the page, its address divided by `PAGE_SIZE`
+    pub page: u64,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl PageAccesses {
+    pub fn accesses(&self) -> u64 {
+        self.reads + self.writes
+    }
+}
+
+/// the accesses of an instance to its linear memory, by page. An access spanning two
+/// pages is counted on the first one
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryHeatmap {
+    /// indexed by page, up to the last page accessed
+    pages: Vec<PageAccesses>,
+}
+
+impl MemoryHeatmap {
+    /// the pages accessed at least once, in order
+    pub fn histogram(&self) -> Vec<PageAccesses> {
+        self.pages
+            .iter()
+            .filter(|page| page.accesses() > 0)
+            .copied()
+            .collect()
+    }
+
+    pub fn page(&self, page: u64) -> PageAccesses {
+        let accesses = self.pages.get(page as usize).copied();
+        accesses.unwrap_or(PageAccesses {
+            page,
+            ..PageAccesses::default()
+        })
+    }
+
+    /// the `count` pages accessed the most, the most accessed first
+    pub fn hottest(&self, count: usize) -> Vec<PageAccesses> {
+        let mut pages = self.histogram();
+        pages.sort_by(|a, b| b.accesses().cmp(&a.accesses()).then(a.page.cmp(&b.page)));
+        pages.truncate(count);
+        pages
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.pages.iter().map(|page| page.reads).sum()
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.pages.iter().map(|page| page.writes).sum()
+    }
+}
+
+/// the `[reads, writes]` of each page, by the address of the instance
+type Counts = Vec<(usize, Vec<[u64; 2]>)>;
+
+static COUNTS: Mutex<Counts> = Mutex::new(Vec::new());
+
+fn counts() -> MutexGuard<'static, Counts> {
+    COUNTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn record(instance: usize, page: u64, write: bool) {
+    let mut counts = counts();
+    let index = match counts.iter().position(|(address, _)| *address == instance) {
+        Some(index) => index,
+        None => {
+            counts.push((instance, Vec::new()));
+            counts.len() - 1
+        }
+    };
+    let pages = &mut counts[index].1;
+    if pages.len() <= page as usize {
+        pages.resize(page as usize + 1, [0; 2]);
+    }
+    pages[page as usize][write as usize] += 1;
+}
+
+pub(crate) fn heatmap(instance: wasm_module_inst_t) -> MemoryHeatmap {
+    let counts = counts();
+    let pages = counts
+        .iter()
+        .find(|(address, _)| *address == instance as usize)
+        .map(|(_, pages)| pages.as_slice())
+        .unwrap_or_default();
+    MemoryHeatmap {
+        pages: pages
+            .iter()
+            .enumerate()
+            .map(|(page, [reads, writes])| PageAccesses {
+                page: page as u64,
+                reads: *reads,
+                writes: *writes,
+            })
+            .collect(),
+    }
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    counts().retain(|(address, _)| *address != instance as usize);
+}
+
+/// the host function the instrumented code calls, which returns the address
+pub(crate) struct MemoryProfiler;
+
+impl NativeModule for MemoryProfiler {
+    fn module_name(&self) -> &str {
+        MODULE
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(HOOK, access as extern "C" fn(ExecEnv, i32, i32, i32) -> i32);
+    }
+}
+
+extern "C" fn access(env: ExecEnv, address: i32, offset: i32, write: i32) -> i32 {
+    let instance = env.module_inst() as usize;
+    let effective = address as u32 as u64 + offset as u32 as u64;
+    record(instance, effective / PAGE_SIZE, write != 0);
+    address
+}
+
+/// the type of the hook, `(address: i32, offset: i32, write: i32) -> i32`
+const HOOK_TYPE: [u8; 7] = [0x60, 0x03, 0x7f, 0x7f, 0x7f, 0x01, 0x7f];
+
+// the value types of the locals the stored values are kept in, while the hook runs
+const STORED_TYPES: [u8; 4] = [0x7f, 0x7e, 0x7d, 0x7c];
+
+/// the offset of the memarg of a load or store, `None` if it accesses another memory
+/// than the first one
+fn memarg_offset(immediates: &[u8]) -> Result<Option<u64>, ErrorMessage> {
+    let mut reader = Reader::new(immediates);
+    let align = reader.read_u32_leb()?;
+    if align & 0x40 != 0 && reader.read_u32_leb()? != 0 {
+        return Ok(None);
+    }
+    Ok(Some(reader.read_u64_leb()?))
+}
+
+/// `binary`, with a call to the hook before each load and store of the first memory
+pub(crate) fn instrument(binary: &[u8]) -> Result<Vec<u8>, ErrorMessage> {
+    if binary::memory_limits(binary)?.is_some_and(|limits| limits.memory64) {
+        return Err(message!("memory64 isn't supported"));
+    }
+    let hook = Hook {
+        module: MODULE,
+        name: HOOK,
+        func_type: &HOOK_TYPE,
+        locals: &STORED_TYPES,
+    };
+    instrument::instrument(binary, &hook, |site, out| {
+        let (write, valtype) = match site.opcode {
+            0x28..=0x35 => (false, None),
+            0x36 | 0x3a | 0x3b => (true, Some(0x7f)),
+            0x37 | 0x3c..=0x3e => (true, Some(0x7e)),
+            0x38 => (true, Some(0x7d)),
+            0x39 => (true, Some(0x7c)),
+            _ => return Ok(()),
+        };
+        let Some(offset) = memarg_offset(&site.bytes[1..])? else {
+            return Ok(());
+        };
+        // the local keeping the stored value
+        let stored = |valtype: u8| {
+            let index = STORED_TYPES.iter().position(|t| *t == valtype).unwrap();
+            site.locals + index as u32
+        };
+
+        if let Some(valtype) = valtype {
+            // local.set
+            out.push(0x21);
+            write_u32_leb(out, stored(valtype));
+        }
+        // i32.const offset, i32.const write, call hook
+        out.push(0x41);
+        write_i32_leb(out, offset as u32 as i32);
+        out.push(0x41);
+        write_i32_leb(out, write as i32);
+        out.push(0x10);
+        write_u32_leb(out, site.hook);
+        if let Some(valtype) = valtype {
+            // local.get
+            out.push(0x20);
+            write_u32_leb(out, stored(valtype));
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function, instance::Instance, instruction::decode_body, module::Module,
+        runtime::Runtime, value::WasmValue, RuntimeError,
+    };
+
+    // (module
+    //   (memory (export "memory") 2)
+    //   (func $touch (export "touch") (param $p i32)
+    //     (i32.store (local.get $p) (i32.const 1))
+    //     (drop (i32.load offset=4 (local.get $p))))
+    //   (func (export "twice") (param $p i32)
+    //     (call $touch (local.get $p))
+    //     (call $touch (local.get $p))))
+    const BINARY: [u8; 113] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x01, 0x7f, 0x00,
+        0x03, 0x03, 0x02, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x02, 0x07, 0x1a, 0x03, 0x06, 0x6d,
+        0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x05, 0x74, 0x6f, 0x75, 0x63, 0x68, 0x00, 0x00,
+        0x05, 0x74, 0x77, 0x69, 0x63, 0x65, 0x00, 0x01, 0x0a, 0x1c, 0x02, 0x0f, 0x00, 0x20, 0x00,
+        0x41, 0x01, 0x36, 0x02, 0x00, 0x20, 0x00, 0x28, 0x02, 0x04, 0x1a, 0x0b, 0x0a, 0x00, 0x20,
+        0x00, 0x10, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b, 0x00, 0x1c, 0x04, 0x6e, 0x61, 0x6d, 0x65,
+        0x01, 0x08, 0x01, 0x00, 0x05, 0x74, 0x6f, 0x75, 0x63, 0x68, 0x02, 0x0b, 0x02, 0x00, 0x01,
+        0x00, 0x01, 0x70, 0x01, 0x01, 0x00, 0x01, 0x70,
+    ];
+
+    #[test]
+    fn test_instrument() {
+        let instrumented = instrument(&BINARY).unwrap();
+        assert_eq!(
+            binary::function_names(&instrumented),
+            Ok(vec![(0, HOOK), (1, "touch"), (2, "twice")])
+        );
+        // the function name of $touch, and the local names of both functions, shifted
+        assert_eq!(
+            binary::custom_section(&instrumented, "name"),
+            Ok(Some(
+                &[
+                    0x01, 0x08, 0x01, 0x01, 0x05, 0x74, 0x6f, 0x75, 0x63, 0x68, 0x02, 0x0b, 0x02,
+                    0x01, 0x01, 0x00, 0x01, 0x70, 0x02, 0x01, 0x00, 0x01, 0x70,
+                ][..]
+            ))
+        );
+
+        let bodies = binary::function_bodies(&instrumented).unwrap();
+        let calls = |body| {
+            let instructions = decode_body(&instrumented, body).unwrap();
+            let calls = instructions.iter().filter(|i| i.opcode == 0x10);
+            calls.map(|i| i.bytes.clone()).collect::<Vec<_>>()
+        };
+        // the hook before the store and the load, and the calls of $touch shifted
+        assert_eq!(calls(bodies[0].clone()), [[0x10, 0x00], [0x10, 0x00]]);
+        assert_eq!(calls(bodies[1].clone()), [[0x10, 0x01], [0x10, 0x01]]);
+
+        // any address, no instance is involved
+        let instance = 0x30 as wasm_module_inst_t;
+        record(instance as usize, 0, true);
+        record(instance as usize, 2, false);
+        record(instance as usize, 2, false);
+        let heatmap = heatmap(instance);
+        assert_eq!(heatmap.histogram().len(), 2);
+        assert_eq!(heatmap.page(1).accesses(), 0);
+        assert_eq!(heatmap.hottest(1)[0].page, 2);
+        assert_eq!((heatmap.reads(), heatmap.writes()), (2, 1));
+        remove(instance);
+        assert_eq!(super::heatmap(instance), MemoryHeatmap::default());
+    }
+
+    #[test]
+    fn test_memory_heatmap() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .profile_memory_accesses()
+            .build()?;
+        let module = Module::from_buf(&runtime, &BINARY, "heatmap")?;
+        let instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+
+        let twice = Function::find_export_func(&instance, "twice")?;
+        twice.call(&instance, &vec![WasmValue::I32(65540)])?;
+        let heatmap = instance.memory_heatmap();
+        assert_eq!(
+            heatmap.histogram(),
+            [PageAccesses {
+                page: 1,
+                reads: 2,
+                writes: 2,
+            }]
+        );
+        Ok(())
+    }
+}
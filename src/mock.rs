@@ -0,0 +1,288 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! instances of pure Rust, to unit-test host logic written against `guest::Guest` or
+//! `guest::WasmInstance` without loading a module. A `MockInstance` never calls into
+//! WAMR: its exports are closures, and its linear memory a `Vec`. Built with the `mock`
+//! feature, and in the tests of the SDK. Without the default features, `mock` builds
+//! without WAMR at all:
+//!
+//! ```toml
+//! wamr-rust-sdk = { version = "1", default-features = false, features = ["mock"] }
+//! ```
+//!
+//! ```ignore
+//! let guest = MockInstance::new(1, ())
+//!     .export("alloc", |_, _| Ok(WasmValue::I32(1024)));
+//! assert_eq!(write_string(&guest, "hello")?, 1024);
+//! assert_eq!(guest.calls()[0].0, "alloc");
+//! ```
+
+use std::cell::RefCell;
+
+use crate::{
+    guest::{Guest, GuestMemory, WasmFunc, WasmInstance, WASM_PAGE_SIZE},
+    value::WasmValue,
+    RuntimeError,
+};
+
+/// an export of a `MockInstance`, called with the instance and the parameters
+pub type MockExport<T> =
+    Box<dyn Fn(&MockInstance<T>, &[WasmValue]) -> Result<WasmValue, RuntimeError>>;
+
+/// a linear memory on the heap of the host
+#[derive(Debug, Default)]
+pub struct MockMemory {
+    bytes: RefCell<Vec<u8>>,
+    max_pages: Option<u32>,
+}
+
+impl MockMemory {
+    /// a zeroed memory of `pages` wasm pages
+    pub fn new(pages: u32) -> Self {
+        MockMemory {
+            bytes: RefCell::new(vec![0; pages as usize * WASM_PAGE_SIZE]),
+            max_pages: None,
+        }
+    }
+
+    /// a copy of the whole memory
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+
+    /// check that `[offset, offset + len)` is in bounds
+    fn range(&self, offset: u64, len: usize) -> Result<usize, RuntimeError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.data_size() as u64 => Ok(offset as usize),
+            _ => Err(RuntimeError::MemoryAccessError(message!(
+                "out of bounds memory access: offset {} length {}",
+                offset,
+                len
+            ))),
+        }
+    }
+}
+
+impl GuestMemory for MockMemory {
+    fn data_size(&self) -> usize {
+        self.bytes.borrow().len()
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), RuntimeError> {
+        let start = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.bytes.borrow()[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&self, offset: u64, data: &[u8]) -> Result<(), RuntimeError> {
+        let start = self.range(offset, data.len())?;
+        self.bytes.borrow_mut()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn grow(&self, delta: u32) -> Result<u32, RuntimeError> {
+        let old_pages = self.pages();
+        let new_pages = old_pages.checked_add(delta).filter(|pages| {
+            *pages <= self.max_pages.unwrap_or(u32::MAX) && *pages as usize <= 65536
+        });
+        match new_pages {
+            Some(new_pages) => {
+                self.bytes
+                    .borrow_mut()
+                    .resize(new_pages as usize * WASM_PAGE_SIZE, 0);
+                Ok(old_pages)
+            }
+            None => Err(RuntimeError::MemoryAccessError(message!(
+                "failed to grow memory by {} pages",
+                delta
+            ))),
+        }
+    }
+}
+
+/// an instance whose exports are closures, see `mock`
+pub struct MockInstance<T = ()> {
+    exports: Vec<(String, MockExport<T>)>,
+    memory: MockMemory,
+    data: T,
+    calls: RefCell<Vec<(String, Vec<WasmValue>)>>,
+}
+
+impl<T> MockInstance<T> {
+    /// an instance without exports, with a zeroed memory of `pages` wasm pages
+    pub fn new(pages: u32, data: T) -> Self {
+        MockInstance {
+            exports: Vec::new(),
+            memory: MockMemory::new(pages),
+            data,
+            calls: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// bound the growth of the memory to `max_pages` wasm pages
+    pub fn set_max_pages(mut self, max_pages: u32) -> Self {
+        self.memory.max_pages = Some(max_pages);
+        self
+    }
+
+    /// export `name`, which runs `function`. It replaces an export of the same name
+    pub fn export<F>(mut self, name: &str, function: F) -> Self
+    where
+        F: Fn(&MockInstance<T>, &[WasmValue]) -> Result<WasmValue, RuntimeError> + 'static,
+    {
+        self.exports.retain(|(export, _)| export != name);
+        self.exports.push((String::from(name), Box::new(function)));
+        self
+    }
+
+    pub fn data_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    /// the exports called so far, in order, and their parameters
+    pub fn calls(&self) -> Vec<(String, Vec<WasmValue>)> {
+        self.calls.borrow().clone()
+    }
+}
+
+impl<T> Guest for MockInstance<T> {
+    type Data = T;
+    type Memory<'a>
+        = &'a MockMemory
+    where
+        T: 'a;
+
+    fn call(&self, name: &str, params: &[WasmValue]) -> Result<WasmValue, RuntimeError> {
+        let (_, function) = self
+            .exports
+            .iter()
+            .find(|(export, _)| export == name)
+            .ok_or(RuntimeError::FunctionNotFound)?;
+        self.calls
+            .borrow_mut()
+            .push((String::from(name), params.to_vec()));
+        function(self, params)
+    }
+
+    fn memory(&self) -> &MockMemory {
+        &self.memory
+    }
+
+    fn data(&self) -> &T {
+        &self.data
+    }
+}
+
+/// an export of a `MockInstance`, looked up by name on each call, so an export replaced
+/// after the lookup is the one called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockFunction {
+    name: String,
+}
+
+impl<T> WasmFunc<MockInstance<T>> for MockFunction {
+    fn call(
+        &self,
+        instance: &MockInstance<T>,
+        params: &[WasmValue],
+    ) -> Result<WasmValue, RuntimeError> {
+        Guest::call(instance, &self.name, params)
+    }
+}
+
+impl<T> WasmInstance for MockInstance<T> {
+    type Func = MockFunction;
+
+    fn function(&self, name: &str) -> Result<MockFunction, RuntimeError> {
+        match self.exports.iter().any(|(export, _)| export == name) {
+            true => Ok(MockFunction {
+                name: String::from(name),
+            }),
+            false => Err(RuntimeError::FunctionNotFound),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// host logic passing a string to a guest, via its `alloc` export
+    fn write_string<G: Guest>(guest: &G, string: &str) -> Result<i32, RuntimeError> {
+        let params = [WasmValue::I32(string.len() as i32)];
+        let WasmValue::I32(offset) = guest.call("alloc", &params)? else {
+            return Err(RuntimeError::TypeMismatch(message!("expect an i32")));
+        };
+        guest.memory().write(offset as u64, string.as_bytes())?;
+        Ok(offset)
+    }
+
+    #[test]
+    fn test_mock_instance() -> Result<(), RuntimeError> {
+        let mut guest = MockInstance::new(1, 0u32).set_max_pages(2).export(
+            "alloc",
+            |guest, params| match params {
+                [WasmValue::I32(len)] => {
+                    let offset = guest.memory().data_size() as i32 - len;
+                    Ok(WasmValue::I32(offset))
+                }
+                _ => Err(RuntimeError::ExecutionError(message!("bad params"))),
+            },
+        );
+        assert_eq!(write_string(&guest, "hello")?, 65531);
+        let mut buf = [0; 5];
+        guest.memory().read(65531, &mut buf)?;
+        assert_eq!(&buf, b"hello");
+        assert_eq!(
+            guest.calls(),
+            [(String::from("alloc"), vec![WasmValue::I32(5)])]
+        );
+
+        assert!(matches!(
+            guest.memory().write(65535, b"hello"),
+            Err(RuntimeError::MemoryAccessError(_))
+        ));
+        assert_eq!(guest.memory().grow(1)?, 1);
+        assert_eq!(guest.memory().pages(), 2);
+        assert!(guest.memory().grow(1).is_err());
+        assert!(matches!(
+            guest.call("free", &[]),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+
+        *guest.data_mut() += 1;
+        assert_eq!(*guest.data(), 1);
+        Ok(())
+    }
+
+    /// host logic generic over the engine, summing the results of an export
+    fn sum<I: WasmInstance>(instance: &I, export: &str, count: i32) -> Result<i64, RuntimeError> {
+        let function = instance.function(export)?;
+        let mut sum = 0;
+        for i in 0..count {
+            match function.call(instance, &[WasmValue::I32(i)])? {
+                WasmValue::I32(value) => sum += value as i64,
+                result => return Err(RuntimeError::TypeMismatch(message!("got {}", result))),
+            }
+        }
+        Ok(sum)
+    }
+
+    #[test]
+    fn test_wasm_instance() -> Result<(), RuntimeError> {
+        let guest = MockInstance::new(0, ()).export("double", |_, params| match params {
+            [WasmValue::I32(value)] => Ok(WasmValue::I32(value * 2)),
+            _ => Err(RuntimeError::ExecutionError(message!("bad params"))),
+        });
+        assert_eq!(sum(&guest, "double", 4)?, 12);
+        assert_eq!(guest.calls().len(), 4);
+        assert!(matches!(
+            sum(&guest, "triple", 4),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+        Ok(())
+    }
+}
@@ -6,18 +6,93 @@
 //! .wasm compiled, in-memory representation
 //! get one via `Module::from_file()` or `Module::from_buf()`
 
+#[cfg(feature = "encrypted")]
+use crate::encryption;
+#[cfg(libc_wasi)]
+use crate::wasi_context::WasiCtx;
 use crate::{
-    helper::error_buf_to_string, helper::DEFAULT_ERROR_BUF_SIZE, runtime::Runtime,
-    wasi_context::WasiCtx, RuntimeError,
+    account, binary,
+    binary::Limits,
+    bridge,
+    coverage::{self, Coverage},
+    fault,
+    helper::error_buf_to_string,
+    helper::DEFAULT_ERROR_BUF_SIZE,
+    instruction,
+    instruction::Instruction,
+    jit_stats,
+    lifecycle::{Dependent, Dependents},
+    memory, memory_profile,
+    policy::ModulePolicy,
+    replay,
+    runtime::Runtime,
+    source::ModuleSource,
+    stack,
+    target::{self, ModuleKind, TargetInfo},
+    RuntimeError,
 };
 use std::{
-    ffi::c_char, ffi::CString, fs::File, io::Read, path::Path, ptr, string::String, vec::Vec,
+    ffi::{c_char, CStr, CString},
+    mem,
+    path::Path,
+    string::String,
+    thread,
+    time::{Duration, Instant},
+    vec::Vec,
 };
 use wamr_sys::{
-    wasm_module_t, wasm_runtime_load, wasm_runtime_set_module_name,
-    wasm_runtime_set_wasi_addr_pool, wasm_runtime_set_wasi_args,
-    wasm_runtime_set_wasi_ns_lookup_pool, wasm_runtime_unload,
+    wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC, wasm_import_t, wasm_module_t,
+    wasm_runtime_get_import_count, wasm_runtime_get_import_type, wasm_runtime_load,
+    wasm_runtime_set_module_name, wasm_runtime_unload,
 };
+#[cfg(libc_wasi)]
+use {
+    std::ptr,
+    wamr_sys::{
+        wasm_runtime_set_wasi_addr_pool, wasm_runtime_set_wasi_args,
+        wasm_runtime_set_wasi_ns_lookup_pool,
+    },
+};
+
+/// the functions of the builtin libc of WAMR which allocate from the app heap
+const LIBC_ALLOCATORS: [&str; 6] = ["malloc", "calloc", "realloc", "free", "strdup", "_strdup"];
+
+/// resource ceilings enforced by `Module::from_buf_untrusted()`
+#[derive(Debug, Clone)]
+pub struct LoadLimits {
+    /// the maximum size of the module content, in bytes
+    pub max_module_size: usize,
+    /// the maximum number of functions, imported and defined
+    pub max_functions: u64,
+    /// the maximum number of pages the memory may declare, as its minimum or maximum. A
+    /// memory without a maximum may grow up to 65536 pages, so it's over any limit
+    pub max_memory_pages: u64,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        LoadLimits {
+            max_module_size: 16 * 1024 * 1024,
+            max_functions: 10000,
+            max_memory_pages: 16384,
+        }
+    }
+}
+
+/// a tool which took part in producing a module, as listed by its `producers` section
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Producer {
+    /// `language`, `processed-by` or `sdk`
+    pub field: String,
+    pub name: String,
+    /// may be empty
+    pub version: String,
+}
+
+/// the export of a start function deferred by `Module::from_buf_deferring_start()`
+pub(crate) const DEFERRED_START_EXPORT: &str = "__wamr_rust_sdk_start";
+/// the export `_initialize` is renamed to by `Module::from_buf_deferring_start()`
+pub(crate) const DEFERRED_INITIALIZE_EXPORT: &str = "__wamr_rust_sdk_initialize";
 
 #[allow(dead_code)]
 #[derive(Debug)]
@@ -26,7 +101,12 @@ pub struct Module {
     module: wasm_module_t,
     // to keep the module content in memory
     content: Vec<u8>,
+    // the content before it was instrumented, if it was, see `Module::source()`
+    source: Option<Vec<u8>>,
+    #[cfg(libc_wasi)]
     wasi_ctx: WasiCtx,
+    instances: Dependents,
+    _runtime: Dependent,
 }
 
 impl Module {
@@ -37,13 +117,22 @@ impl Module {
     /// If the file does not exist or the file cannot be read, an `RuntimeError::WasmFileFSError` will be returned.
     /// If the wasm file is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
     pub fn from_file(runtime: &Runtime, wasm_file: &Path) -> Result<Self, RuntimeError> {
-        let name = wasm_file.file_name().unwrap().to_str().unwrap();
-        let mut wasm_file = File::open(wasm_file)?;
-
-        let mut binary: Vec<u8> = Vec::new();
-        wasm_file.read_to_end(&mut binary)?;
+        Self::from_source(runtime, wasm_file)
+    }
 
-        Self::from_buf(runtime, &binary, name)
+    /// compile a module with the content fetched from `source`, use the name of the source
+    /// as the module name
+    ///
+    /// # Error
+    ///
+    /// If the content cannot be fetched, an `RuntimeError::WasmFileFSError` will be returned.
+    /// If the content is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
+    pub fn from_source<S: ModuleSource + ?Sized>(
+        runtime: &Runtime,
+        source: &S,
+    ) -> Result<Self, RuntimeError> {
+        let content = source.load()?;
+        Self::from_vec(runtime, content, &source.name())
     }
 
     /// compile a module int the given buffer,
@@ -52,9 +141,197 @@ impl Module {
     ///
     /// If the file does not exist or the file cannot be read, an `RuntimeError::WasmFileFSError` will be returned.
     /// If the wasm file is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
-    pub fn from_buf(_runtime: &Runtime, buf: &[u8], name: &str) -> Result<Self, RuntimeError> {
-        let mut content = buf.to_vec();
+    pub fn from_buf(runtime: &Runtime, buf: &[u8], name: &str) -> Result<Self, RuntimeError> {
+        Self::from_vec(runtime, buf.to_vec(), name)
+    }
+
+    /// compile a module from an untrusted buffer, like a user upload or a fuzzer input.
+    ///
+    /// Only .wasm is accepted, since an .aot can't be validated before loading. The
+    /// content is checked against `limits` before WAMR parses it, and malformed input
+    /// is reported as an error rather than a panic.
+    ///
+    /// # Error
+    ///
+    /// If the content exceeds `limits`, an `RuntimeError::LimitExceeded` will be returned.
+    /// If the content is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
+    pub fn from_buf_untrusted(
+        runtime: &Runtime,
+        buf: &[u8],
+        name: &str,
+        limits: &LoadLimits,
+    ) -> Result<Self, RuntimeError> {
+        if buf.len() > limits.max_module_size {
+            return Err(RuntimeError::LimitExceeded(message!(
+                "module size {} is over {}",
+                buf.len(),
+                limits.max_module_size
+            )));
+        }
+
+        let functions = binary::function_count(buf).map_err(RuntimeError::CompilationError)?;
+        if functions > limits.max_functions {
+            return Err(RuntimeError::LimitExceeded(message!(
+                "{} functions are over {}",
+                functions,
+                limits.max_functions
+            )));
+        }
+
+        let memories = binary::all_memory_limits(buf).map_err(RuntimeError::CompilationError)?;
+        for memory in memories {
+            match memory.maximum.map(|maximum| maximum.max(memory.minimum)) {
+                Some(pages) if pages <= limits.max_memory_pages => {}
+                Some(pages) => {
+                    return Err(RuntimeError::LimitExceeded(message!(
+                        "{} memory pages are over {}",
+                        pages,
+                        limits.max_memory_pages
+                    )))
+                }
+                None => {
+                    return Err(RuntimeError::LimitExceeded(message!(
+                        "a memory without a maximum may grow over {} pages",
+                        limits.max_memory_pages
+                    )))
+                }
+            }
+        }
+
+        Self::from_vec(runtime, buf.to_vec(), name)
+    }
+
+    /// compile a module from a buffer encrypted with `key`, see `encryption`. The content
+    /// is decrypted in memory only
+    ///
+    /// # Error
+    ///
+    /// If the buffer can't be decrypted with `key`, or the content is not a valid wasm
+    /// file, an `RuntimeError::CompilationError` will be returned.
+    #[cfg(feature = "encrypted")]
+    pub fn from_encrypted_buf(
+        runtime: &Runtime,
+        buf: &[u8],
+        key: &[u8; 32],
+        name: &str,
+    ) -> Result<Self, RuntimeError> {
+        Self::from_vec(runtime, encryption::decrypt(buf, key)?, name)
+    }
+
+    /// compile a module from a buffer, after checking it complies with `policy`.
+    ///
+    /// Only .wasm is accepted, since an .aot can't be inspected.
+    ///
+    /// # Error
+    ///
+    /// If the content breaks the policy, an `RuntimeError::PolicyViolation` listing all
+    /// violations will be returned.
+    /// If the content is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
+    pub fn from_buf_with_policy(
+        runtime: &Runtime,
+        buf: &[u8],
+        name: &str,
+        policy: &ModulePolicy,
+    ) -> Result<Self, RuntimeError> {
+        let violations = policy.check(buf);
+        if !violations.is_empty() {
+            let violations: Vec<String> = violations.iter().map(|v| v.to_string()).collect();
+            return Err(RuntimeError::PolicyViolation(
+                message!(code: "module policy violated", violations.join("; ")),
+            ));
+        }
+
+        Self::from_vec(runtime, buf.to_vec(), name)
+    }
+
+    /// compile a module from a buffer, which doesn't run its start function nor
+    /// `_initialize` at instantiation, until `Instance::run_start()` is called. Like to
+    /// time, instrument or sandbox the initialization apart from the instantiation.
+    ///
+    /// Only .wasm is accepted, since an .aot can't be rewritten.
+    ///
+    /// # Error
+    ///
+    /// If the content is not a valid wasm file, an `RuntimeError::CompilationError` will be returned.
+    pub fn from_buf_deferring_start(
+        runtime: &Runtime,
+        buf: &[u8],
+        name: &str,
+    ) -> Result<Self, RuntimeError> {
+        let content = binary::defer_start(buf, DEFERRED_START_EXPORT, DEFERRED_INITIALIZE_EXPORT)
+            .map_err(RuntimeError::CompilationError)?;
+        Self::from_vec(runtime, content, name)
+    }
+
+    /// compile a module written in the WebAssembly text format
+    ///
+    /// # Error
+    ///
+    /// If the text can't be parsed or the result is not a valid wasm module, an
+    /// `RuntimeError::CompilationError` will be returned.
+    #[cfg(feature = "wat")]
+    pub fn from_wat(runtime: &Runtime, wat: &str) -> Result<Self, RuntimeError> {
+        let content = wat::parse_str(wat).map_err(|e| {
+            RuntimeError::CompilationError(message!(code: "invalid wat", e.to_string()))
+        })?;
+        Self::from_vec(runtime, content, "")
+    }
+
+    fn from_vec(runtime: &Runtime, mut content: Vec<u8>, name: &str) -> Result<Self, RuntimeError> {
+        #[cfg(feature = "signed-aot")]
+        {
+            content = runtime.verify_aot(content)?;
+        }
+        let source = content.clone();
+
+        // instrumented first, so the sites are at their offsets in the original .wasm
+        let mut sites = None;
+        match runtime.coverage_level() {
+            Some(level) if !target::is_aot(&content) => {
+                let (instrumented, instrumented_sites) = coverage::instrument(&content, level)
+                    .map_err(RuntimeError::CompilationError)?;
+                content = instrumented;
+                sites = Some(instrumented_sites);
+            }
+            _ => (),
+        }
+        if runtime.profiles_memory() && !target::is_aot(&content) {
+            content =
+                memory_profile::instrument(&content).map_err(RuntimeError::CompilationError)?;
+        }
+        if runtime.meters_fuel() && !target::is_aot(&content) {
+            content = account::instrument(&content).map_err(RuntimeError::CompilationError)?;
+        }
+        if runtime.hooks_memory_grow() && !target::is_aot(&content) {
+            let rewritten =
+                memory::instrument_grow(&content).map_err(RuntimeError::CompilationError)?;
+            if let Some(rewritten) = rewritten {
+                memory::register_grow_hook();
+                content = rewritten;
+            }
+        }
+        // prepared after coverage, memory profiling and growth, so their hooks aren't
+        // recorded
+        let mut replayed = None;
+        match runtime.replay_mode() {
+            Some(mode) if !target::is_aot(&content) => {
+                let (prepared, imports) =
+                    replay::prepare(&content, mode).map_err(RuntimeError::CompilationError)?;
+                content = prepared;
+                replayed = Some((mode, imports));
+            }
+            _ => (),
+        }
+        // instrumented last, so the results the faults inject are recorded
+        let mut targets = Vec::new();
+        if !runtime.faults().is_empty() && !target::is_aot(&content) {
+            (content, targets) = fault::instrument(&content, runtime.faults())
+                .map_err(RuntimeError::CompilationError)?;
+        }
+
+        bridge::register_imports(&content);
         let mut error_buf: [c_char; DEFAULT_ERROR_BUF_SIZE] = [0; DEFAULT_ERROR_BUF_SIZE];
+        let loading = Instant::now();
         let module = unsafe {
             wasm_runtime_load(
                 content.as_mut_ptr(),
@@ -67,7 +344,7 @@ impl Module {
         if module.is_null() {
             match error_buf.len() {
                 0 => {
-                    return Err(RuntimeError::CompilationError(String::from(
+                    return Err(RuntimeError::CompilationError(message!(
                         "load module failed",
                     )))
                 }
@@ -79,31 +356,64 @@ impl Module {
             }
         }
 
+        let name_c = match CString::new(name.as_bytes()) {
+            Ok(name_c) => name_c,
+            Err(_) => {
+                unsafe { wasm_runtime_unload(module) };
+                return Err(RuntimeError::CompilationError(message!(
+                    "module name contains a nul byte",
+                )));
+            }
+        };
+
         unsafe {
-            let name_c = CString::new(name.as_bytes()).unwrap();
             if !wasm_runtime_set_module_name(
                 module,
                 name_c.as_ptr() as *mut c_char,
                 error_buf.as_mut_ptr(),
                 error_buf.len() as u32,
             ) {
+                wasm_runtime_unload(module);
                 return Err(RuntimeError::CompilationError(error_buf_to_string(
                     &error_buf,
                 )));
             }
         }
 
+        jit_stats::record(
+            module,
+            name,
+            &content,
+            runtime.running_mode(),
+            loading.elapsed(),
+        );
+        #[cfg(feature = "perf-profiling")]
+        crate::profile::record(module, &content);
+        if let Some(sites) = sites {
+            coverage::record(module, name, sites);
+        }
+        if let Some((mode, imports)) = replayed {
+            replay::loaded(module, mode, imports);
+        }
+        if !targets.is_empty() {
+            fault::loaded(module, targets);
+        }
         Ok(Module {
             name: String::from(name),
             module,
+            source: (source != content).then_some(source),
             content,
+            #[cfg(libc_wasi)]
             wasi_ctx: WasiCtx::default(),
+            instances: Dependents::default(),
+            _runtime: runtime.track_module(),
         })
     }
 
     /// set Wasi context for a module
     ///
     /// This function should be called before `Instance::new`
+    #[cfg(libc_wasi)]
     pub fn set_wasi_context(&mut self, wasi_ctx: WasiCtx) {
         self.wasi_ctx = wasi_ctx;
 
@@ -176,12 +486,209 @@ impl Module {
     pub fn get_name(&self) -> &str {
         &self.name
     }
+
+    /// the instructions of the function at `index` in the function index space, which
+    /// counts imported functions first
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if the function is imported or doesn't exist.
+    /// Return `RuntimeError::CompilationError` if the module isn't a .wasm or the body can't
+    /// be decoded.
+    pub fn function_body(&self, index: u32) -> Result<Vec<Instruction>, RuntimeError> {
+        let imported = binary::imported_function_count(self.source())
+            .map_err(RuntimeError::CompilationError)?;
+        let bodies =
+            binary::function_bodies(self.source()).map_err(RuntimeError::CompilationError)?;
+
+        let body = match index.checked_sub(imported) {
+            Some(defined) => bodies.get(defined as usize),
+            None => None,
+        };
+        match body {
+            Some(body) => instruction::decode_body(self.source(), body.clone())
+                .map_err(RuntimeError::CompilationError),
+            None => Err(RuntimeError::FunctionNotFound),
+        }
+    }
+
+    /// a stack size to instantiate the module with, estimated from the frames of its deepest
+    /// chain of calls. Recursion is assumed to go 100 calls deep, with the largest frame of
+    /// the module, and the estimate is capped at 1MB
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if the module isn't a .wasm or can't be
+    /// analyzed, like with the composite types of the GC proposal.
+    pub fn suggested_stack_size(&self) -> Result<u32, RuntimeError> {
+        stack::suggested_stack_size(self.source()).map_err(RuntimeError::CompilationError)
+    }
+
+    /// the languages, compilers and SDKs which produced the module, from its `producers`
+    /// section, like to log which toolchain built a plugin, or refuse some. Empty without
+    /// the section, which tools may leave out or strip
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if the module isn't a .wasm or the section
+    /// is malformed.
+    pub fn producers(&self) -> Result<Vec<Producer>, RuntimeError> {
+        let producers = binary::producers(self.source()).map_err(RuntimeError::CompilationError)?;
+        Ok(producers
+            .into_iter()
+            .map(|(field, name, version)| Producer {
+                field: String::from(field),
+                name: String::from(name),
+                version: String::from(version),
+            })
+            .collect())
+    }
+
+    /// wait until every function of the module is compiled, so no call pays for compiling
+    /// one, like at deploy time in a latency-sensitive service
+    ///
+    /// an .aot is precompiled, the interpreters compile nothing, and the LLVM JIT compiles
+    /// every function while loading, unless built with the `lazy-jit` feature. It compiles
+    /// them on background threads then, which this waits for, up to `timeout`.
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if some functions aren't compiled within
+    /// `timeout`, like when the JIT failed to compile one.
+    pub fn precompile_all(&self, timeout: Duration) -> Result<(), RuntimeError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let pending = self.pending_functions();
+            if pending == 0 {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(RuntimeError::CompilationError(message!(
+                    "{} functions not compiled after {:?}",
+                    pending,
+                    timeout
+                )));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// whether every function of the module is compiled already, see `precompile_all()`
+    pub fn is_precompiled(&self) -> bool {
+        self.pending_functions() == 0
+    }
+
+    /// the number of functions the lazy LLVM JIT has yet to compile
+    fn pending_functions(&self) -> u32 {
+        #[cfg(feature = "lazy-jit")]
+        return unsafe { wamr_sys::wamr_sys_pending_jit_functions(self.module) };
+        #[cfg(not(feature = "lazy-jit"))]
+        0
+    }
+
+    /// whether the module has been loaded from a .wasm or an .aot, see `target`
+    pub fn kind(&self) -> ModuleKind {
+        // WAMR only loads one or the other
+        ModuleKind::of(self.source()).unwrap_or(ModuleKind::Wasm)
+    }
+
+    /// the target the module has been compiled for, `None` for a .wasm
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::CompilationError` if the target info of the .aot is malformed.
+    pub fn target_info(&self) -> Result<Option<TargetInfo>, RuntimeError> {
+        match self.kind() {
+            ModuleKind::Wasm => Ok(None),
+            ModuleKind::Aot | ModuleKind::Xip => TargetInfo::parse(self.source())
+                .map(Some)
+                .map_err(RuntimeError::CompilationError),
+        }
+    }
+
+    /// which functions and blocks of the module ran, over all its instances, `None` unless
+    /// the runtime has been built with `RuntimeBuilder::collect_coverage()`, or for an .aot
+    pub fn coverage(&self) -> Option<Coverage> {
+        coverage::report(self.module)
+    }
+
+    /// the .wasm or .aot content the module was loaded from
+    #[allow(dead_code)]
+    pub(crate) fn content(&self) -> &[u8] {
+        &self.content
+    }
+
+    /// the content as given, before the runtime instrumented it, so function indices and
+    /// bodies are the ones of the guest
+    fn source(&self) -> &[u8] {
+        self.source.as_deref().unwrap_or(&self.content)
+    }
+
+    /// the limits of the default memory, `None` if there is no memory or the
+    /// content isn't a .wasm
+    pub(crate) fn memory_limits(&self) -> Option<Limits> {
+        binary::memory_limits(self.source()).ok().flatten()
+    }
+
+    #[cfg(libc_wasi)]
+    pub(crate) fn wasi_ctx(&self) -> &WasiCtx {
+        &self.wasi_ctx
+    }
+
+    /// the first allocator the module imports from the builtin libc of WAMR, which
+    /// allocates from the app heap, like `env.malloc`
+    pub(crate) fn app_heap_import(&self) -> Option<String> {
+        let count = unsafe { wasm_runtime_get_import_count(self.module) };
+        (0..count).find_map(|index| {
+            let mut import: wasm_import_t = unsafe { mem::zeroed() };
+            unsafe { wasm_runtime_get_import_type(self.module, index, &mut import) };
+            if import.kind != wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC
+                || import.module_name.is_null()
+                || import.name.is_null()
+            {
+                return None;
+            }
+            let module = unsafe { CStr::from_ptr(import.module_name) }.to_string_lossy();
+            let name = unsafe { CStr::from_ptr(import.name) }.to_string_lossy();
+            (module == "env" && LIBC_ALLOCATORS.contains(&name.as_ref()))
+                .then(|| format!("{}.{}", module, name))
+        })
+    }
+
+    pub(crate) fn track_instance(&self) -> Dependent {
+        self.instances.track()
+    }
+
+    pub(crate) fn is_instance(&self, instance: &Dependent) -> bool {
+        instance.is_of(&self.instances)
+    }
+
+    /// the number of instances of the module alive
+    pub(crate) fn instance_count(&self) -> usize {
+        self.instances.count()
+    }
+
+    pub(crate) fn is_of(&self, runtime: &Runtime) -> bool {
+        runtime.is_module(&self._runtime)
+    }
 }
 
 impl Drop for Module {
+    /// # Panics
+    ///
+    /// if an `Instance` of the module is still alive
     fn drop(&mut self) {
-        unsafe {
-            wasm_runtime_unload(self.module);
+        let owner = format!("Module {:?}", self.name);
+        if self.instances.release(&owner, "Instance") {
+            jit_stats::remove(self.module);
+            coverage::remove(self.module);
+            replay::unloaded(self.module);
+            fault::unloaded(self.module);
+            #[cfg(feature = "perf-profiling")]
+            crate::profile::remove(self.module);
+            unsafe {
+                wasm_runtime_unload(self.module);
+            }
         }
     }
 }
@@ -189,7 +696,7 @@ impl Drop for Module {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{helper::cstr_to_string, runtime::Runtime, wasi_context::WasiCtxBuilder};
+    use crate::{helper::cstr_to_string, runtime::Runtime};
     use std::path::PathBuf;
     use wamr_sys::wasm_runtime_get_module_name;
 
@@ -226,6 +733,208 @@ mod tests {
         assert!(module.is_ok());
     }
 
+    #[test]
+    fn test_module_function_body() {
+        let runtime = Runtime::new().unwrap();
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "add").unwrap();
+
+        let body = module.function_body(0).unwrap();
+        let opcodes: Vec<u32> = body.iter().map(|i| i.opcode).collect();
+        assert_eq!(opcodes, vec![0x20, 0x20, 0x6a, 0x0b]);
+        assert_eq!(body[1].offset, 37);
+        assert_eq!(body[1].bytes, vec![0x20, 0x01]);
+
+        assert!(matches!(
+            module.function_body(1),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_module_function_body_instrumented() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .hook_memory_grow()
+            .build()
+            .unwrap();
+
+        // (module
+        //   (memory 1)
+        //   (func (param i32) (result i32) (memory.grow (local.get 0)))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7f,
+            0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x0a, 0x08, 0x01,
+            0x06, 0x00, 0x20, 0x00, 0x40, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "grow").unwrap();
+        assert_ne!(module.content(), binary);
+
+        // the function of the guest, not shifted by the imported hook
+        let body = module.function_body(0).unwrap();
+        let opcodes: Vec<u32> = body.iter().map(|i| i.opcode).collect();
+        assert_eq!(opcodes, vec![0x20, 0x40, 0x0b]);
+    }
+
+    #[test]
+    fn test_module_from_buf_with_policy() {
+        let runtime = Runtime::new().unwrap();
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+
+        let module = Module::from_buf(&runtime, &binary, "add").unwrap();
+        assert_eq!(module.kind(), ModuleKind::Wasm);
+        assert!(module.target_info().unwrap().is_none());
+        assert!(module.precompile_all(Duration::from_secs(10)).is_ok());
+        assert!(module.is_precompiled());
+
+        let policy = ModulePolicy::new().allow_import_module("env").ban_simd();
+        assert!(Module::from_buf_with_policy(&runtime, &binary, "add", &policy).is_ok());
+
+        let policy = ModulePolicy::new().ban_opcode(0x6a);
+        let module = Module::from_buf_with_policy(&runtime, &binary, "add", &policy);
+        assert!(matches!(module, Err(RuntimeError::PolicyViolation(_))));
+    }
+
+    #[test]
+    #[cfg(feature = "wat")]
+    fn test_module_from_wat() {
+        let runtime = Runtime::new().unwrap();
+
+        let module = Module::from_wat(
+            &runtime,
+            r#"(module
+                (func (export "add") (param i32 i32) (result i32)
+                  (local.get 0)
+                  (local.get 1)
+                  (i32.add)
+                )
+              )"#,
+        );
+        assert!(module.is_ok());
+
+        let module = Module::from_wat(&runtime, "(module (func (export \"add\"))");
+        assert!(matches!(module, Err(RuntimeError::CompilationError(_))));
+    }
+
+    #[test]
+    fn test_module_from_buf_untrusted() {
+        let runtime = Runtime::new().unwrap();
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = vec![
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let binary = binary.into_iter().map(|c| c as u8).collect::<Vec<u8>>();
+
+        let limits = LoadLimits::default();
+        let module = Module::from_buf_untrusted(&runtime, &binary, "add", &limits);
+        assert!(module.is_ok());
+
+        let limits = LoadLimits {
+            max_functions: 0,
+            ..LoadLimits::default()
+        };
+        let module = Module::from_buf_untrusted(&runtime, &binary, "add", &limits);
+        assert!(matches!(module, Err(RuntimeError::LimitExceeded(_))));
+
+        let limits = LoadLimits {
+            max_module_size: 16,
+            ..LoadLimits::default()
+        };
+        let module = Module::from_buf_untrusted(&runtime, &binary, "add", &limits);
+        assert!(matches!(module, Err(RuntimeError::LimitExceeded(_))));
+
+        // truncated anywhere but on a section boundary
+        let limits = LoadLimits::default();
+        for len in (0..binary.len()).filter(|len| *len != 8 && *len != 17) {
+            let module = Module::from_buf_untrusted(&runtime, &binary[..len], "add", &limits);
+            assert!(matches!(module, Err(RuntimeError::CompilationError(_))));
+        }
+
+        let module = Module::from_buf_untrusted(&runtime, &binary, "a\0dd", &limits);
+        assert!(matches!(module, Err(RuntimeError::CompilationError(_))));
+    }
+
+    #[test]
+    fn test_module_from_buf_untrusted_memory() {
+        let runtime = Runtime::new().unwrap();
+        let limits = LoadLimits {
+            max_memory_pages: 2,
+            ..LoadLimits::default()
+        };
+
+        // (module
+        //   (memory 1 2)
+        // )
+        let bounded = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x04, 0x01, 0x01, 0x01, 0x02,
+        ];
+        let module = Module::from_buf_untrusted(&runtime, &bounded, "bounded", &limits);
+        assert!(module.is_ok());
+
+        let limits = LoadLimits {
+            max_memory_pages: 1,
+            ..LoadLimits::default()
+        };
+        let module = Module::from_buf_untrusted(&runtime, &bounded, "bounded", &limits);
+        assert!(matches!(module, Err(RuntimeError::LimitExceeded(_))));
+
+        // (module
+        //   (memory 1)
+        // )
+        let unbounded = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01,
+        ];
+        let limits = LoadLimits::default();
+        let module = Module::from_buf_untrusted(&runtime, &unbounded, "unbounded", &limits);
+        assert!(matches!(module, Err(RuntimeError::LimitExceeded(_))));
+
+        // (module
+        //   (import "env" "memory" (memory 1 1))
+        //   (memory 1)
+        // )
+        let second = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x02, 0x10, 0x01, 0x03, 0x65, 0x6e,
+            0x76, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x01, 0x01, 0x01, 0x05, 0x03,
+            0x01, 0x00, 0x01,
+        ];
+        let module = Module::from_buf_untrusted(&runtime, &second, "second", &limits);
+        assert!(matches!(module, Err(RuntimeError::LimitExceeded(_))));
+    }
+
     #[test]
     fn test_module_from_file() {
         let runtime = Runtime::new().unwrap();
@@ -238,7 +947,10 @@ mod tests {
     }
 
     #[test]
+    #[cfg(libc_wasi)]
     fn test_module_with_wasi_args() {
+        use crate::wasi_context::WasiCtxBuilder;
+
         let runtime = Runtime::new().unwrap();
 
         // (module
@@ -297,4 +1009,27 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    #[should_panic(expected = "Module \"add\" dropped while 1 Instance(s) still depend on it")]
+    fn test_module_dropped_before_instance() {
+        let runtime = Runtime::new().unwrap();
+
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "add").unwrap();
+        let _instance = crate::instance::Instance::new(&runtime, &module, 1024, ()).unwrap();
+
+        drop(module);
+    }
 }
@@ -0,0 +1,307 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a package of host functions exported under one module name, like a reusable
+//! host API (a key-value store, an HTTP client, logging ...).
+//! register one via `RuntimeBuilder::register_native_module()`
+
+use std::{any::Any, ffi::c_void, fmt};
+
+use wamr_sys::{wasm_runtime_register_natives, wasm_runtime_unregister_natives};
+
+use crate::{
+    host_function::{HostFunctionList, NativeSymbol, ParamTy, ResultTy, TypedHostFunction},
+    RuntimeError,
+};
+
+/// a native module implemented by a Rust type.
+///
+/// the module object is owned by the `Runtime`, and its host functions reach it
+/// via `Caller::native_module()`.
+pub trait NativeModule: Any {
+    /// the module name guests import the host functions from
+    fn module_name(&self) -> &str;
+
+    /// declare the host functions of the module
+    fn exports(&self, exports: &mut NativeExports);
+}
+
+/// the host functions exported by a `NativeModule`
+pub struct NativeExports {
+    host_functions: HostFunctionList,
+    // the first export which failed, returned by `RuntimeBuilder::build()`
+    error: Option<RuntimeError>,
+}
+
+impl NativeExports {
+    /// export a host function with the signature derived from its type
+    pub fn function<F: TypedHostFunction>(&mut self, name: &str, function: F) -> &mut Self {
+        let registered = self
+            .host_functions
+            .register_typed_host_function(name, function);
+        self.record(registered)
+    }
+
+    /// export a host function with an explicit signature, for `Str`, `Pointer`
+    /// and `Buffer` parameters
+    pub fn raw_function(
+        &mut self,
+        name: &str,
+        function_ptr: *mut c_void,
+        params: &[ParamTy],
+        result: ResultTy,
+    ) -> &mut Self {
+        let registered =
+            self.host_functions
+                .register_host_function(name, function_ptr, params, result);
+        self.record(registered)
+    }
+
+    fn record(&mut self, registered: Result<(), RuntimeError>) -> &mut Self {
+        if let Err(error) = registered {
+            self.error.get_or_insert(error);
+        }
+        self
+    }
+}
+
+/// a registered native module, boxed so its address, passed to WAMR as the
+/// attachment of every host function, stays the same
+pub(crate) struct NativeModuleEntry {
+    module: Box<dyn Any>,
+    host_functions: HostFunctionList,
+}
+
+impl NativeModuleEntry {
+    /// # Error
+    ///
+    /// Return `RuntimeError::HostRegistration` if an export of the module is invalid.
+    pub(crate) fn new<M: NativeModule>(module: M) -> Result<Box<Self>, RuntimeError> {
+        let mut exports = NativeExports {
+            host_functions: HostFunctionList::new(module.module_name()),
+            error: None,
+        };
+        module.exports(&mut exports);
+        if let Some(error) = exports.error {
+            return Err(error);
+        }
+
+        let mut entry = Box::new(NativeModuleEntry {
+            module: Box::new(module),
+            host_functions: exports.host_functions,
+        });
+        let attachment = &*entry as *const NativeModuleEntry as *mut c_void;
+        entry.host_functions.set_attachment(attachment);
+        Ok(entry)
+    }
+
+    /// an entry of the host functions of a WAMR symbol table, under `module_name`. They
+    /// keep their own attachments
+    ///
+    /// # Safety
+    ///
+    /// see `HostFunctionList::register_native_symbol()`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::HostRegistration` if the module name or a symbol is invalid.
+    pub(crate) unsafe fn raw(
+        module_name: &str,
+        symbols: &[NativeSymbol],
+    ) -> Result<Box<Self>, RuntimeError> {
+        if module_name.contains('\0') {
+            return Err(RuntimeError::HostRegistration(message!(
+                "{:?}: the module name holds a nul byte",
+                module_name
+            )));
+        }
+        let mut host_functions = HostFunctionList::new(module_name);
+        for symbol in symbols {
+            host_functions.register_native_symbol(symbol)?;
+        }
+        Ok(Box::new(NativeModuleEntry {
+            module: Box::new(()),
+            host_functions,
+        }))
+    }
+
+    pub(crate) fn host_functions(&self) -> &HostFunctionList {
+        &self.host_functions
+    }
+
+    /// register the host functions into the runtime. It has to be initialized
+    pub(crate) fn register(&mut self) -> bool {
+        let module_name = self.host_functions.module_name.as_ptr();
+        let native_symbols = self.host_functions.get_native_symbols();
+        unsafe {
+            wasm_runtime_register_natives(
+                module_name,
+                native_symbols.as_mut_ptr(),
+                native_symbols.len() as u32,
+            )
+        }
+    }
+
+    /// the counterpart of a successful `register()`
+    pub(crate) fn unregister(&mut self) -> bool {
+        let module_name = self.host_functions.module_name.as_ptr();
+        let native_symbols = self.host_functions.get_native_symbols();
+        unsafe { wasm_runtime_unregister_natives(module_name, native_symbols.as_mut_ptr()) }
+    }
+
+    #[cfg_attr(not(feature = "multi-module"), allow(dead_code))]
+    pub(crate) fn module_name(&self) -> String {
+        self.host_functions
+            .module_name
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    pub(crate) fn module(&self) -> &dyn Any {
+        self.module.as_ref()
+    }
+}
+
+impl fmt::Debug for NativeModuleEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativeModuleEntry")
+            .field("host_functions", &self.host_functions)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function,
+        host_function::{HostCall, HostSymbol},
+        instance::Instance,
+        module::Module,
+        runtime::Runtime,
+        user_data::{Caller, ExecEnv},
+        value::WasmValue,
+    };
+    use std::{ffi::CString, path::PathBuf, ptr};
+
+    struct Extra {
+        value: i32,
+    }
+
+    extern "C" fn extra(env: ExecEnv) -> i32 {
+        let caller: Caller<()> = Caller::from_env(&env);
+        caller.native_module::<Extra>().unwrap().value
+    }
+
+    impl NativeModule for Extra {
+        fn module_name(&self) -> &str {
+            "host"
+        }
+
+        fn exports(&self, exports: &mut NativeExports) {
+            exports.function("extra", extra as extern "C" fn(ExecEnv) -> i32);
+        }
+    }
+
+    #[test]
+    fn test_native_module_signature() {
+        let entry = NativeModuleEntry::new(Extra { value: 1 }).unwrap();
+        let symbols = &entry.host_functions.native_symbols;
+        assert_eq!(symbols.len(), 1);
+        let call = unsafe { &*(symbols[0].attachment as *const HostCall) };
+        assert_eq!(
+            call.attachment as *const NativeModuleEntry,
+            &*entry as *const NativeModuleEntry
+        );
+        assert!(entry.module().downcast_ref::<Extra>().is_some());
+    }
+
+    #[test]
+    fn test_native_module_collision() {
+        extern "C" fn other(_env: ExecEnv) -> i32 {
+            0
+        }
+
+        // another function under the same names collides, the same one registered twice not
+        let twice = Runtime::builder()
+            .use_system_allocator()
+            .register_native_module(Extra { value: 1 })
+            .register_host_function("extra", other as *mut c_void, &[], ResultTy::I32)
+            .build();
+        match twice {
+            Err(RuntimeError::HostRegistration(e)) => assert_eq!(e, "host.extra registered twice"),
+            result => panic!("unexpected result {:?}", result.map(|_| ())),
+        }
+
+        let entry = NativeModuleEntry::new(Extra { value: 1 }).unwrap();
+        let same = NativeModuleEntry::new(Extra { value: 2 }).unwrap();
+        assert_eq!(
+            entry.host_functions().collision(same.host_functions()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_native_symbols_raw() {
+        let name = CString::new("extra").unwrap();
+        let signature = CString::new("(i*~)I").unwrap();
+        let mut attachment = 7;
+        let symbols = [NativeSymbol {
+            symbol: name.as_ptr(),
+            func_ptr: extra as *mut c_void,
+            signature: signature.as_ptr(),
+            attachment: &mut attachment as *mut i32 as *mut c_void,
+        }];
+        let entry = unsafe { NativeModuleEntry::raw("c", &symbols) }.unwrap();
+        drop((name, signature));
+        let symbols: Vec<HostSymbol> = entry.host_functions().symbols().collect();
+        assert_eq!(
+            (symbols[0].module.as_str(), symbols[0].name.as_str()),
+            ("c", "extra")
+        );
+        assert_eq!(symbols[0].signature, "(i*~)I");
+        assert_eq!(
+            entry.host_functions.native_symbols[0].attachment,
+            &mut attachment as *mut i32 as *mut c_void
+        );
+
+        let name = CString::new("extra").unwrap();
+        let signature = CString::new("(~i)").unwrap();
+        let invalid = NativeSymbol {
+            symbol: name.as_ptr(),
+            func_ptr: extra as *mut c_void,
+            signature: signature.as_ptr(),
+            attachment: ptr::null_mut(),
+        };
+        let runtime = unsafe {
+            Runtime::builder()
+                .use_system_allocator()
+                .register_native_symbols_raw("c", &[invalid])
+        }
+        .build();
+        assert!(matches!(runtime, Err(RuntimeError::HostRegistration(_))));
+    }
+
+    #[test]
+    fn test_native_module() {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .register_native_module(Extra { value: 1000 })
+            .build()
+            .unwrap();
+
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("add_extra_wasm32_wasi.wasm");
+        let module = Module::from_file(&runtime, d.as_path()).unwrap();
+
+        let instance = Instance::new(&runtime, &module, 1024 * 64, ()).unwrap();
+        let function = Function::find_export_func(&instance, "add").unwrap();
+
+        let params: Vec<WasmValue> = vec![WasmValue::I32(8), WasmValue::I32(8)];
+        let result = function.call(&instance, &params);
+        assert_eq!(result.unwrap(), WasmValue::I32(1016));
+    }
+}
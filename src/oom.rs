@@ -0,0 +1,161 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a host handler for guests running out of memory, to free caches, raise a limit or
+//! terminate the guest, rather than the guest only seeing `malloc` return 0.
+//! set one via `Instance::set_oom_handler()`
+//!
+//! the handler runs whenever the linear memory fails to grow, via a `memory.grow` of the
+//! guest, which its allocator executes once its heap is exhausted, or via
+//! `Memory::grow()`. WAMR reports the failure after the fact, so the growth itself stays
+//! failed, and the handler only decides what happens next.
+
+use std::{
+    ffi::c_void,
+    panic::{self, AssertUnwindSafe},
+    ptr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{
+    enlarge_memory_error_reason_t, enlarge_memory_error_reason_t_MAX_SIZE_REACHED, wasm_exec_env_t,
+    wasm_module_inst_t, wasm_runtime_set_enlarge_mem_error_callback, wasm_runtime_terminate,
+};
+
+use crate::{event::InstanceId, memory::WASM_PAGE_SIZE};
+
+/// a failed growth of the linear memory of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestOom {
+    pub instance: InstanceId,
+    /// the bytes requested on top of the current size
+    pub requested: u64,
+    /// the current size of the linear memory, in bytes
+    pub current: u64,
+    /// whether the growth went over the maximum of the memory, rather than the host
+    /// running out of memory
+    pub max_size_reached: bool,
+}
+
+/// what happens after a guest ran out of memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OomAction {
+    /// let the guest handle the failure, like with a smaller allocation
+    Fail,
+    /// terminate the call running in the instance, like by a trap
+    Terminate,
+}
+
+/// consulted whenever the linear memory of an instance fails to grow. A panicking
+/// handler terminates the instance
+pub type OomHandler = Arc<dyn Fn(&GuestOom) -> OomAction + Send + Sync>;
+
+/// the handler of each instance, by address. WAMR has a single callback for the process
+static HANDLERS: Mutex<Vec<(usize, OomHandler)>> = Mutex::new(Vec::new());
+
+fn handlers() -> MutexGuard<'static, Vec<(usize, OomHandler)>> {
+    HANDLERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn set_handler(instance: wasm_module_inst_t, handler: OomHandler) {
+    let mut handlers = handlers();
+    handlers.retain(|(owner, _)| *owner != instance as usize);
+    handlers.push((instance as usize, handler));
+    // set again each time, since another runtime may have been destroyed meanwhile
+    unsafe { wasm_runtime_set_enlarge_mem_error_callback(Some(on_enlarge_error), ptr::null_mut()) };
+}
+
+pub(crate) fn remove_handler(instance: wasm_module_inst_t) {
+    handlers().retain(|(owner, _)| *owner != instance as usize);
+}
+
+unsafe extern "C" fn on_enlarge_error(
+    inc_page_count: u32,
+    current_memory_size: u64,
+    _memory_index: u32,
+    failure_reason: enlarge_memory_error_reason_t,
+    instance: wasm_module_inst_t,
+    _exec_env: wasm_exec_env_t,
+    _user_data: *mut c_void,
+) {
+    // released before the handler runs, which may set another handler
+    let handler = handlers()
+        .iter()
+        .find(|(owner, _)| *owner == instance as usize)
+        .map(|(_, handler)| handler.clone());
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return,
+    };
+
+    let oom = GuestOom {
+        instance: InstanceId::new(instance),
+        requested: inc_page_count as u64 * WASM_PAGE_SIZE as u64,
+        current: current_memory_size,
+        max_size_reached: failure_reason == enlarge_memory_error_reason_t_MAX_SIZE_REACHED,
+    };
+    let action =
+        panic::catch_unwind(AssertUnwindSafe(|| handler(&oom))).unwrap_or(OomAction::Terminate);
+    if action == OomAction::Terminate {
+        wasm_runtime_terminate(instance);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
+        RuntimeError,
+    };
+
+    #[test]
+    fn test_oom_handler() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory 1 1)
+        //   (func (export "grow") (param i32) (result i32) (memory.grow (local.get 0)))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7f,
+            0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x05, 0x04, 0x01, 0x01, 0x01, 0x01, 0x07, 0x08,
+            0x01, 0x04, 0x67, 0x72, 0x6f, 0x77, 0x00, 0x00, 0x0a, 0x08, 0x01, 0x06, 0x00, 0x20,
+            0x00, 0x40, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "oom")?;
+        let mut instance = Instance::new(&runtime, &module, 1024, ())?;
+
+        let ooms = Arc::new(Mutex::new(Vec::new()));
+        let recorded = ooms.clone();
+        instance.set_oom_handler(move |oom| {
+            recorded.lock().unwrap().push(*oom);
+            OomAction::Fail
+        });
+
+        let grow = Function::find_export_func(&instance, "grow")?;
+        assert_eq!(
+            grow.call_args(&instance, &[WasmValue::I32(2)])?,
+            WasmValue::I32(-1)
+        );
+        assert_eq!(
+            *ooms.lock().unwrap(),
+            vec![GuestOom {
+                instance: instance.id(),
+                requested: 2 * WASM_PAGE_SIZE as u64,
+                current: WASM_PAGE_SIZE as u64,
+                max_size_reached: true,
+            }]
+        );
+
+        assert_eq!(
+            grow.call_args(&instance, &[WasmValue::I32(0)])?,
+            WasmValue::I32(1)
+        );
+        assert_eq!(ooms.lock().unwrap().len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! chains of guest transformations, like the plugins of a data-processing pipeline, where
+//! the buffer a stage returns is the input of the next one. Build one via
+//! `Pipeline::new()`
+//!
+//! a stage is an export `(ptr: i32, len: i32) -> i64` of an instance, given its input in
+//! the linear memory, and returning its output as a packed buffer, see `buffer`. The input
+//! is copied into scratch memory allocated once per stage from the app heap of the
+//! instance, which has to be instantiated with a heap size, and reallocated only for a
+//! larger input. The output belongs to the guest, like a transformation done in place.
+
+use std::ptr;
+
+use wamr_sys::{wasm_module_inst_t, wasm_runtime_module_free, wasm_runtime_module_malloc};
+
+use crate::{
+    buffer::ReturnedBuffer, function::Function, instance::Instance, value::WasmValue, RuntimeError,
+};
+
+/// a region of the app heap of an instance, freed once dropped
+struct Scratch {
+    instance: wasm_module_inst_t,
+    offset: u64,
+    capacity: usize,
+}
+
+impl Scratch {
+    /// make room for `len` bytes, keeping the region unless it is too small
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::MemoryAccessError` if the app heap is exhausted.
+    fn reserve(&mut self, len: usize) -> Result<(), RuntimeError> {
+        if len <= self.capacity {
+            return Ok(());
+        }
+        self.free();
+
+        let offset =
+            unsafe { wasm_runtime_module_malloc(self.instance, len as _, ptr::null_mut()) };
+        if offset == 0 {
+            return Err(RuntimeError::MemoryAccessError(message!(
+                "failed to allocate {} bytes from the app heap",
+                len
+            )));
+        }
+        self.offset = offset as u64;
+        self.capacity = len;
+        Ok(())
+    }
+
+    fn free(&mut self) {
+        if self.capacity > 0 {
+            unsafe { wasm_runtime_module_free(self.instance, self.offset as _) };
+            self.offset = 0;
+            self.capacity = 0;
+        }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        self.free();
+    }
+}
+
+/// copy the input to an offset of the linear memory, call the export, and copy its output
+type StageFn<'a> = Box<dyn Fn(u64, &[u8]) -> Result<Vec<u8>, RuntimeError> + 'a>;
+
+struct Stage<'a> {
+    export: String,
+    scratch: Scratch,
+    run: StageFn<'a>,
+}
+
+/// stages run in order, each given the output of the one before
+#[derive(Default)]
+pub struct Pipeline<'a> {
+    stages: Vec<Stage<'a>>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add the stage `export` of `instance`, after the stages added before
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export.
+    pub fn stage<T>(
+        mut self,
+        instance: &'a Instance<T>,
+        export: &str,
+    ) -> Result<Self, RuntimeError> {
+        let function = Function::find_export_func(instance, export)?;
+        self.stages.push(Stage {
+            export: String::from(export),
+            scratch: Scratch {
+                instance: instance.get_inner_instance(),
+                offset: 0,
+                capacity: 0,
+            },
+            run: Box::new(move |offset, input| {
+                instance.memory().write(offset, input)?;
+                let params = vec![
+                    WasmValue::I32(offset as i32),
+                    WasmValue::I32(input.len() as i32),
+                ];
+                let result = function.call(instance, &params)?;
+                Ok(ReturnedBuffer::from_result(instance, result)?.into_vec())
+            }),
+        });
+        Ok(self)
+    }
+
+    /// the number of stages
+    pub fn len(&self) -> usize {
+        self.stages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    /// run `input` through every stage, and return the output of the last one, or `input`
+    /// without stages
+    ///
+    /// # Error
+    ///
+    /// Return the error of the first stage which failed, like a `RuntimeError::ExecutionError`
+    /// naming the stage if it trapped, or `RuntimeError::MemoryAccessError` if an app heap is
+    /// exhausted or a stage returned a buffer out of bounds.
+    pub fn run(&mut self, input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+        let mut buffer = input.to_vec();
+        for stage in &mut self.stages {
+            stage.scratch.reserve(buffer.len())?;
+            buffer =
+                (stage.run)(stage.scratch.offset, &buffer).map_err(|e| match e {
+                    RuntimeError::ExecutionError(message) => RuntimeError::ExecutionError(
+                        message!("stage {} failed: {}", stage.export, message),
+                    ),
+                    e => e,
+                })?;
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{module::Module, runtime::Runtime};
+
+    #[test]
+    fn test_pipeline() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        //   (func (export "upper") (param $p i32) (param $n i32) (result i64)
+        //     ;; uppercase the ASCII letters of the input in place, and return it
+        //   )
+        //   (func (export "tail") (param $p i32) (param $n i32) (result i64)
+        //     ;; return the input but its first byte
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7e, 0x03, 0x03, 0x02, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+            0x19, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x05, 0x75, 0x70,
+            0x70, 0x65, 0x72, 0x00, 0x00, 0x04, 0x74, 0x61, 0x69, 0x6c, 0x00, 0x01, 0x0a, 0x60,
+            0x02, 0x4b, 0x01, 0x02, 0x7f, 0x02, 0x40, 0x03, 0x40, 0x20, 0x02, 0x20, 0x01, 0x4f,
+            0x0d, 0x01, 0x20, 0x00, 0x20, 0x02, 0x6a, 0x2d, 0x00, 0x00, 0x21, 0x03, 0x20, 0x03,
+            0x41, 0xe1, 0x00, 0x4f, 0x20, 0x03, 0x41, 0xfa, 0x00, 0x4d, 0x71, 0x04, 0x40, 0x20,
+            0x00, 0x20, 0x02, 0x6a, 0x20, 0x03, 0x41, 0x20, 0x6b, 0x3a, 0x00, 0x00, 0x0b, 0x20,
+            0x02, 0x41, 0x01, 0x6a, 0x21, 0x02, 0x0c, 0x00, 0x0b, 0x0b, 0x20, 0x01, 0xad, 0x42,
+            0x20, 0x86, 0x20, 0x00, 0xad, 0x84, 0x0b, 0x12, 0x00, 0x20, 0x01, 0x41, 0x01, 0x6b,
+            0xad, 0x42, 0x20, 0x86, 0x20, 0x00, 0x41, 0x01, 0x6a, 0xad, 0x84, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "pipeline")?;
+        let first = Instance::new_with_args(&runtime, &module, 1024, 4096, ())?;
+        let second = Instance::new_with_args(&runtime, &module, 1024, 4096, ())?;
+
+        let mut pipeline = Pipeline::new()
+            .stage(&first, "upper")?
+            .stage(&second, "tail")?
+            .stage(&second, "tail")?;
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(pipeline.run(b"hello")?, b"LLO");
+        assert_eq!(pipeline.run(b"a longer input")?, b"LONGER INPUT");
+
+        assert!(matches!(
+            Pipeline::new().stage(&first, "missing"),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+        let mut single = Pipeline::new().stage(&first, "upper")?;
+        assert_eq!(single.run(b"")?, b"");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,340 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the few places where POSIX and Windows hosts differ
+
+use std::{cell::Cell, ffi::CString, path::Path, time::Duration};
+
+use wamr_sys::{wasm_runtime_destroy_thread_env, wasm_runtime_init_thread_env};
+
+use crate::RuntimeError;
+
+thread_local! {
+    static THREAD_ENV_USERS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// set up the thread env of WAMR on the current thread, for one more instance.
+///
+/// the thread env catches out of bounds accesses of guest code, via a signal handler
+/// and an alternate stack on POSIX, or a vectored exception handler on Windows. It is
+/// per thread and not reference counted by WAMR, so it is only set up for the first
+/// instance, and torn down with the last one, of a thread.
+pub(crate) fn enter_thread_env() -> bool {
+    THREAD_ENV_USERS.with(|users| {
+        if users.get() == 0 && !unsafe { wasm_runtime_init_thread_env() } {
+            return false;
+        }
+        users.set(users.get() + 1);
+        true
+    })
+}
+
+/// the counterpart of a successful `enter_thread_env()`, on the same thread
+pub(crate) fn leave_thread_env() {
+    THREAD_ENV_USERS.with(|users| {
+        users.set(users.get() - 1);
+        if users.get() == 0 {
+            unsafe { wasm_runtime_destroy_thread_env() };
+        }
+    })
+}
+
+/// a path as WAMR expects it: the raw bytes on POSIX, and UTF-8 on Windows, which WAMR
+/// converts to UTF-16 itself
+///
+/// # Error
+///
+/// Return `RuntimeError::WasmFileFSError` if the path contains a nul byte, or isn't valid
+/// Unicode on Windows.
+#[cfg_attr(not(libc_wasi), allow(dead_code))]
+pub(crate) fn path_to_cstring(path: &Path) -> Result<CString, RuntimeError> {
+    #[cfg(unix)]
+    let bytes = {
+        use std::os::unix::ffi::OsStrExt;
+        path.as_os_str().as_bytes()
+    };
+    #[cfg(not(unix))]
+    let bytes = path
+        .to_str()
+        .ok_or_else(|| invalid_path("isn't valid Unicode"))?
+        .as_bytes();
+
+    CString::new(bytes).map_err(|_| invalid_path("contains a nul byte"))
+}
+
+/// the path `path_to_cstring()` gave `path` of
+#[cfg(libc_wasi)]
+pub(crate) fn cstr_to_path(path: &std::ffi::CStr) -> std::path::PathBuf {
+    #[cfg(unix)]
+    let path = {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(path.to_bytes())
+    };
+    #[cfg(not(unix))]
+    let path = path.to_string_lossy().into_owned();
+
+    std::path::PathBuf::from(path)
+}
+
+#[cfg_attr(not(libc_wasi), allow(dead_code))]
+fn invalid_path(reason: &str) -> RuntimeError {
+    RuntimeError::WasmFileFSError(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("path {}", reason),
+    ))
+}
+
+/// the bytes of native stack left to the current thread, below the caller, `None` where
+/// the bounds of the stack of a thread aren't known
+pub(crate) fn stack_remaining() -> Option<usize> {
+    let here = 0u8;
+    let here = std::ptr::addr_of!(here) as usize;
+    stack_low_end().map(|low| here.saturating_sub(low))
+}
+
+/// the lowest address of the stack of the current thread, stacks grow downwards
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn stack_low_end() -> Option<usize> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let ret = libc::pthread_attr_getstack(&attr, &mut addr, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        (ret == 0).then_some(addr as usize)
+    }
+}
+
+/// the lowest address of the stack of the current thread, stacks grow downwards
+#[cfg(target_os = "macos")]
+fn stack_low_end() -> Option<usize> {
+    unsafe {
+        let thread = libc::pthread_self();
+        let high = libc::pthread_get_stackaddr_np(thread) as usize;
+        Some(high - libc::pthread_get_stacksize_np(thread))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+fn stack_low_end() -> Option<usize> {
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// give the whole pages of `[start, start + len)` which only hold zeros back to the OS,
+/// which maps them again, zeroed, once touched, so the content stays the same. Return the
+/// bytes given back, `None` where pages can't be decommitted
+///
+/// # Safety
+///
+/// the range must be private anonymous memory, which nothing writes to meanwhile.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+pub(crate) unsafe fn decommit_zero_pages(start: *mut u8, len: usize) -> Option<usize> {
+    // the pages of macOS are given back lazily, but stay zeroed until then
+    #[cfg(target_os = "macos")]
+    const ADVICE: libc::c_int = libc::MADV_FREE;
+    #[cfg(not(target_os = "macos"))]
+    const ADVICE: libc::c_int = libc::MADV_DONTNEED;
+
+    let page = page_size();
+    let skip = start.align_offset(page).min(len);
+    let pages = (len - skip) / page;
+    let mut released = 0;
+    // a run of zeroed pages, decommitted at once
+    let mut run: Option<(*mut u8, usize)> = None;
+    for index in (0..pages).map(Some).chain([None]) {
+        let zeroed = index
+            .map(|index| start.add(skip + index * page))
+            .filter(|chunk| {
+                let chunk = std::slice::from_raw_parts(*chunk, page);
+                chunk.iter().all(|byte| *byte == 0)
+            });
+        match zeroed {
+            Some(chunk) => match &mut run {
+                Some((_, len)) => *len += page,
+                None => run = Some((chunk, page)),
+            },
+            None => {
+                if let Some((start, len)) = run.take() {
+                    if libc::madvise(start as *mut libc::c_void, len, ADVICE) == 0 {
+                        released += len;
+                    }
+                }
+            }
+        }
+    }
+    Some(released)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) unsafe fn decommit_zero_pages(_start: *mut u8, _len: usize) -> Option<usize> {
+    None
+}
+
+/// fill `memory` with zeros. On Linux, its whole pages are given back to the OS instead,
+/// which maps them again, zeroed, once touched
+///
+/// # Safety
+///
+/// `memory` must be private anonymous memory. The OS keeps the content of the pages of a
+/// shared or a file-backed mapping given back.
+pub(crate) unsafe fn zero_and_decommit(memory: &mut [u8]) {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let page = page_size();
+        let skip = memory.as_ptr().align_offset(page).min(memory.len());
+        let whole = (memory.len() - skip) / page * page;
+        let start = memory[skip..].as_mut_ptr() as *mut libc::c_void;
+        if whole > 0 && libc::madvise(start, whole, libc::MADV_DONTNEED) == 0 {
+            memory[..skip].fill(0);
+            memory[skip + whole..].fill(0);
+            return;
+        }
+    }
+    memory.fill(0);
+}
+
+/// the CPU-time clock of a thread, which only runs while the thread is on a CPU
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(not(feature = "threads"), allow(dead_code))]
+pub(crate) struct CpuClock(libc::clockid_t);
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[cfg_attr(not(feature = "threads"), allow(dead_code))]
+pub(crate) struct CpuClock(std::convert::Infallible);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[cfg_attr(not(feature = "threads"), allow(dead_code))]
+impl CpuClock {
+    /// the clock of the current thread. It must not be read once the thread exited
+    pub(crate) fn current() -> Option<CpuClock> {
+        let mut clock = 0;
+        match unsafe { libc::pthread_getcpuclockid(libc::pthread_self(), &mut clock) } {
+            0 => Some(CpuClock(clock)),
+            _ => None,
+        }
+    }
+
+    /// the CPU time the thread used so far
+    pub(crate) fn now(&self) -> Duration {
+        let mut time: libc::timespec = unsafe { std::mem::zeroed() };
+        match unsafe { libc::clock_gettime(self.0, &mut time) } {
+            0 => Duration::new(time.tv_sec as u64, time.tv_nsec as u32),
+            _ => Duration::MAX,
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+#[cfg_attr(not(feature = "threads"), allow(dead_code))]
+impl CpuClock {
+    /// the clock of the current thread, there is none off Linux
+    pub(crate) fn current() -> Option<CpuClock> {
+        None
+    }
+
+    /// the CPU time the thread used so far
+    pub(crate) fn now(&self) -> Duration {
+        match self.0 {}
+    }
+}
+
+/// advise the OS to back `[start, start + len)` with transparent huge pages. Return
+/// whether it took the advice, never off Linux
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn advise_huge_pages(start: *mut u8, len: usize) -> bool {
+    let skip = start.align_offset(page_size());
+    if skip >= len {
+        return false;
+    }
+    let start = start.wrapping_add(skip) as *mut libc::c_void;
+    unsafe { libc::madvise(start, len - skip, libc::MADV_HUGEPAGE) == 0 }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn advise_huge_pages(_start: *mut u8, _len: usize) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_decommit_zero_pages() {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut memory = vec![1u8; 16 * page];
+        let start = memory.as_ptr().align_offset(page);
+        memory[start + page..start + 4 * page].fill(0);
+        memory[start + 6 * page..start + 7 * page].fill(0);
+        memory[start + 8 * page..start + 9 * page - 1].fill(0);
+        let before = memory.clone();
+
+        let released = unsafe { decommit_zero_pages(memory.as_mut_ptr(), memory.len()) };
+        assert_eq!(released, Some(4 * page));
+        assert_eq!(memory, before);
+        assert_eq!(
+            unsafe { decommit_zero_pages(memory.as_mut_ptr(), 0) },
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_zero_and_decommit() {
+        let mut memory = vec![0xaau8; 5 * 4096 + 17];
+        unsafe { zero_and_decommit(&mut memory[3..]) };
+        assert_eq!(&memory[..3], [0xaa; 3]);
+        assert!(memory[3..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_cpu_clock() {
+        let clock = CpuClock::current().unwrap();
+        let start = clock.now();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(clock.now() - start < Duration::from_millis(25));
+
+        let spinning = std::time::Instant::now();
+        while clock.now() - start < Duration::from_millis(30) {
+            assert!(spinning.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_stack_remaining() {
+        let remaining = stack_remaining().unwrap();
+        let nested = std::hint::black_box([0u8; 4096]);
+        assert!(remaining > nested.len());
+        assert!(stack_remaining().unwrap() <= remaining);
+    }
+
+    #[test]
+    fn test_path_to_cstring() {
+        let path = Path::new("resources").join("test");
+        let expected = format!("resources{}test", std::path::MAIN_SEPARATOR);
+        assert_eq!(path_to_cstring(&path).unwrap().to_str().unwrap(), expected);
+
+        assert!(path_to_cstring(Path::new("a\0b")).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_path_to_cstring_not_utf8() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(path_to_cstring(path).unwrap().as_bytes(), b"caf\xe9");
+    }
+}
@@ -0,0 +1,289 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! rules a module has to follow before it is loaded, like on a multi-tenant platform.
+//! enforce one via `Module::from_buf_with_policy()`
+
+use std::fmt;
+
+use crate::{
+    binary,
+    instruction::{self, PREFIX_ATOMIC, PREFIX_SIMD},
+    ErrorMessage,
+};
+
+/// one rule of a `ModulePolicy` broken by a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    /// a function, table, memory or global is imported from a module name not allowed
+    ImportNotAllowed { module: String, name: String },
+    /// a function uses a banned instruction
+    OpcodeBanned {
+        function: u32,
+        offset: usize,
+        opcode: u32,
+    },
+    /// the memory may grow over the maximum number of pages. `pages` is `None` for a memory
+    /// without a maximum, which is over any limit
+    MemoryTooLarge { pages: Option<u64>, max_pages: u64 },
+    /// a table may grow over the maximum number of elements. `size` is `None` for a table
+    /// without a maximum, which is over any limit
+    TableTooLarge { size: Option<u64>, max_size: u64 },
+    /// the module can't be inspected, like an .aot or a malformed .wasm
+    Malformed(String),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyViolation::ImportNotAllowed { module, name } => write!(
+                f,
+                "import {}.{} is from a module name not allowed",
+                module, name
+            ),
+            PolicyViolation::OpcodeBanned {
+                function,
+                offset,
+                opcode,
+            } => write!(
+                f,
+                "function {} uses banned opcode {:#x} at offset {:#x}",
+                function, opcode, offset
+            ),
+            PolicyViolation::MemoryTooLarge {
+                pages: Some(pages),
+                max_pages,
+            } => write!(f, "memory of {} pages is over {} pages", pages, max_pages),
+            PolicyViolation::MemoryTooLarge {
+                pages: None,
+                max_pages,
+            } => write!(f, "memory without a maximum is over {} pages", max_pages),
+            PolicyViolation::TableTooLarge {
+                size: Some(size),
+                max_size,
+            } => write!(
+                f,
+                "table of {} elements is over {} elements",
+                size, max_size
+            ),
+            PolicyViolation::TableTooLarge {
+                size: None,
+                max_size,
+            } => write!(f, "table without a maximum is over {} elements", max_size),
+            PolicyViolation::Malformed(e) => write!(f, "module can't be inspected: {}", e),
+        }
+    }
+}
+
+/// the rules checked by `ModulePolicy::check()`. Everything is allowed by default
+#[derive(Debug, Clone, Default)]
+pub struct ModulePolicy {
+    allowed_import_modules: Option<Vec<String>>,
+    banned_prefixes: Vec<u8>,
+    banned_opcodes: Vec<u32>,
+    max_memory_pages: Option<u64>,
+    max_table_size: Option<u64>,
+}
+
+impl ModulePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// allow imports from `module_name`. Once one is allowed, imports from any other module
+    /// name are violations
+    pub fn allow_import_module(mut self, module_name: &str) -> Self {
+        self.allowed_import_modules
+            .get_or_insert_with(Vec::new)
+            .push(String::from(module_name));
+        self
+    }
+
+    /// ban all SIMD instructions
+    pub fn ban_simd(mut self) -> Self {
+        self.banned_prefixes.push(PREFIX_SIMD);
+        self
+    }
+
+    /// ban all atomic instructions
+    pub fn ban_atomics(mut self) -> Self {
+        self.banned_prefixes.push(PREFIX_ATOMIC);
+        self
+    }
+
+    /// ban one opcode, encoded like `Instruction::opcode`
+    pub fn ban_opcode(mut self, opcode: u32) -> Self {
+        self.banned_opcodes.push(opcode);
+        self
+    }
+
+    /// the maximum number of pages the memory may declare
+    pub fn max_memory_pages(mut self, pages: u64) -> Self {
+        self.max_memory_pages = Some(pages);
+        self
+    }
+
+    /// the maximum number of elements a table may declare
+    pub fn max_table_size(mut self, size: u64) -> Self {
+        self.max_table_size = Some(size);
+        self
+    }
+
+    /// all violations of the policy by a .wasm, empty if it complies
+    pub fn check(&self, binary: &[u8]) -> Vec<PolicyViolation> {
+        let mut violations = Vec::new();
+        if let Err(e) = self.check_into(binary, &mut violations) {
+            violations.push(PolicyViolation::Malformed(e.to_string()));
+        }
+        violations
+    }
+
+    fn check_into(
+        &self,
+        binary: &[u8],
+        violations: &mut Vec<PolicyViolation>,
+    ) -> Result<(), ErrorMessage> {
+        if let Some(allowed) = &self.allowed_import_modules {
+            for import in binary::imports(binary)? {
+                if !allowed.iter().any(|module| module == import.module) {
+                    violations.push(PolicyViolation::ImportNotAllowed {
+                        module: String::from(import.module),
+                        name: String::from(import.name),
+                    });
+                }
+            }
+        }
+
+        if let Some(max_pages) = self.max_memory_pages {
+            if let Some(memory) = binary::memory_limits(binary)? {
+                let pages = memory.maximum.map(|maximum| maximum.max(memory.minimum));
+                if !matches!(pages, Some(pages) if pages <= max_pages) {
+                    violations.push(PolicyViolation::MemoryTooLarge { pages, max_pages });
+                }
+            }
+        }
+
+        if let Some(max_size) = self.max_table_size {
+            for table in binary::table_limits(binary)? {
+                let size = table.maximum.map(|maximum| maximum.max(table.minimum));
+                if !matches!(size, Some(size) if size <= max_size) {
+                    violations.push(PolicyViolation::TableTooLarge { size, max_size });
+                }
+            }
+        }
+
+        if !self.banned_prefixes.is_empty() || !self.banned_opcodes.is_empty() {
+            let imported = binary::imported_function_count(binary)?;
+            for (defined, body) in binary::function_bodies(binary)?.into_iter().enumerate() {
+                for instruction in instruction::decode_body(binary, body)? {
+                    let banned = self.banned_opcodes.contains(&instruction.opcode)
+                        || instruction
+                            .prefix()
+                            .is_some_and(|prefix| self.banned_prefixes.contains(&prefix));
+                    if banned {
+                        violations.push(PolicyViolation::OpcodeBanned {
+                            function: imported + defined as u32,
+                            offset: instruction.offset,
+                            opcode: instruction.opcode,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module
+    //   (import "env" "f" (func))
+    //   (import "wasi" "g" (func))
+    //   (memory 2 10)
+    //   (table 4 funcref)
+    //   (func
+    //     (drop (memory.atomic.notify (i32.const 0) (i32.const 1)))
+    //     (drop (memory.size))
+    //   )
+    // )
+    const POLICY_MODULE: [u8; 68] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x60, 0x00, 0x00, 0x02,
+        0x12, 0x02, 0x03, 0x65, 0x6e, 0x76, 0x01, 0x66, 0x00, 0x00, 0x04, 0x77, 0x61, 0x73, 0x69,
+        0x01, 0x67, 0x00, 0x00, 0x03, 0x02, 0x01, 0x00, 0x04, 0x04, 0x01, 0x70, 0x00, 0x04, 0x05,
+        0x04, 0x01, 0x01, 0x02, 0x0a, 0x0a, 0x10, 0x01, 0x0e, 0x00, 0x41, 0x00, 0x41, 0x01, 0xfe,
+        0x00, 0x02, 0x00, 0x1a, 0x3f, 0x00, 0x1a, 0x0b,
+    ];
+
+    #[test]
+    fn test_default_policy() {
+        assert!(ModulePolicy::new().check(&POLICY_MODULE).is_empty());
+    }
+
+    #[test]
+    fn test_policy_violations() {
+        let policy = ModulePolicy::new()
+            .allow_import_module("env")
+            .ban_atomics()
+            .ban_opcode(0x3f)
+            .max_memory_pages(4)
+            .max_table_size(2);
+
+        let violations = policy.check(&POLICY_MODULE);
+        assert_eq!(
+            violations,
+            vec![
+                PolicyViolation::ImportNotAllowed {
+                    module: String::from("wasi"),
+                    name: String::from("g"),
+                },
+                PolicyViolation::MemoryTooLarge {
+                    pages: Some(10),
+                    max_pages: 4,
+                },
+                PolicyViolation::TableTooLarge {
+                    size: None,
+                    max_size: 2,
+                },
+                PolicyViolation::OpcodeBanned {
+                    function: 2,
+                    offset: 59,
+                    opcode: 0xfe0000,
+                },
+                PolicyViolation::OpcodeBanned {
+                    function: 2,
+                    offset: 64,
+                    opcode: 0x3f,
+                },
+            ]
+        );
+        assert_eq!(
+            violations[3].to_string(),
+            "function 2 uses banned opcode 0xfe0000 at offset 0x3b"
+        );
+
+        assert_eq!(
+            violations[2].to_string(),
+            "table without a maximum is over 2 elements"
+        );
+
+        // (module
+        //   (memory 1)
+        // )
+        let unbounded = b"\0asm\x01\0\0\0\x05\x03\x01\x00\x01";
+        let violations = ModulePolicy::new().max_memory_pages(65536).check(unbounded);
+        assert_eq!(
+            violations,
+            vec![PolicyViolation::MemoryTooLarge {
+                pages: None,
+                max_pages: 65536,
+            }]
+        );
+
+        let violations = ModulePolicy::new().ban_simd().check(b"\0asm\x01\0\0\0\x0a");
+        assert!(matches!(violations[..], [PolicyViolation::Malformed(_)]));
+    }
+}
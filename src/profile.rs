@@ -0,0 +1,252 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the time spent in each guest function, measured by WAMR with the `perf-profiling`
+//! feature, exported as a gzipped pprof profile, so `go tool pprof`, speedscope or the
+//! flamegraph tools read it. Get one via `Instance::perf_profile_pprof()`
+//!
+//! WAMR measures how long each function ran itself, its callees excluded, but not who
+//! called it, so a profile has one sample per function which ran, with a stack of that
+//! function only. The functions are named as WAMR does, from the name section of a .wasm,
+//! else from its imports and exports. An .aot isn't parsed, so only its exports are named,
+//! and it has to be compiled with `wamrc --enable-perf-profiling`.
+
+use std::{
+    ffi::{CStr, CString},
+    mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use wamr_sys::{
+    wasm_export_t, wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC, wasm_module_inst_t,
+    wasm_module_t, wasm_runtime_get_export_count, wasm_runtime_get_export_type,
+    wasm_runtime_get_module, wasm_runtime_get_wasm_func_exec_time,
+};
+
+use crate::binary;
+
+/// the names of the functions of the loaded .wasm modules, by the address of the module
+static NAMES: Mutex<Vec<(usize, Vec<String>)>> = Mutex::new(Vec::new());
+
+fn names() -> MutexGuard<'static, Vec<(usize, Vec<String>)>> {
+    NAMES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// remember the names of the functions of `module`, loaded from `content`
+pub(crate) fn record(module: wasm_module_t, content: &[u8]) {
+    let Ok(functions) = binary::function_names(content) else {
+        return;
+    };
+    // WAMR looks a function up by name, so only the first one of a name is measured
+    let mut unique: Vec<String> = Vec::new();
+    for (_, name) in functions {
+        if !unique.iter().any(|known| known == name) {
+            unique.push(String::from(name));
+        }
+    }
+    names().push((module as usize, unique));
+}
+
+/// forget the names of an unloaded module
+pub(crate) fn remove(module: wasm_module_t) {
+    names().retain(|(address, _)| *address != module as usize);
+}
+
+/// the names of the functions `module` exports, for an .aot
+fn exported_functions(module: wasm_module_t) -> Vec<String> {
+    let count = unsafe { wasm_runtime_get_export_count(module) };
+    (0..count)
+        .filter_map(|index| {
+            let mut export: wasm_export_t = unsafe { mem::zeroed() };
+            unsafe { wasm_runtime_get_export_type(module, index, &mut export) };
+            match export.kind == wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_FUNC {
+                true if !export.name.is_null() => Some(
+                    unsafe { CStr::from_ptr(export.name) }
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// the time each function of `instance` ran so far, in the order of the module
+fn exec_times(instance: wasm_module_inst_t) -> Vec<(String, Duration)> {
+    let module = unsafe { wasm_runtime_get_module(instance) };
+    let recorded = names()
+        .iter()
+        .find(|(address, _)| *address == module as usize)
+        .map(|(_, names)| names.clone());
+    let functions = recorded.unwrap_or_else(|| exported_functions(module));
+
+    functions
+        .into_iter()
+        .filter_map(|name| {
+            let c_name = CString::new(name.as_bytes()).ok()?;
+            // in milliseconds, negative if WAMR doesn't know the function
+            let time = unsafe { wasm_runtime_get_wasm_func_exec_time(instance, c_name.as_ptr()) };
+            (time > 0.0).then(|| (name, Duration::from_secs_f64(time / 1000.0)))
+        })
+        .collect()
+}
+
+/// a protobuf message, with the fields written in order
+#[derive(Default)]
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn varint(&mut self, field: u32, value: u64) -> &mut Self {
+        write_varint(&mut self.buf, (field as u64) << 3);
+        write_varint(&mut self.buf, value);
+        self
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) -> &mut Self {
+        write_varint(&mut self.buf, (field as u64) << 3 | 2);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    fn message(&mut self, field: u32, message: &Message) -> &mut Self {
+        self.bytes(field, &message.buf)
+    }
+
+    fn packed(&mut self, field: u32, values: &[u64]) -> &mut Self {
+        let mut packed = Vec::new();
+        for value in values {
+            write_varint(&mut packed, *value);
+        }
+        self.bytes(field, &packed)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// the pprof `Profile` of the functions which ran for `times`, not compressed
+fn encode(times: &[(String, Duration)]) -> Vec<u8> {
+    // 0 is the empty string, as pprof requires
+    let mut strings: Vec<&str> = vec!["", "cpu", "nanoseconds"];
+    let mut profile = Message::default();
+
+    let mut value_type = Message::default();
+    value_type.varint(1, 1).varint(2, 2);
+    profile.message(1, &value_type);
+
+    for (index, (name, time)) in times.iter().enumerate() {
+        let id = index as u64 + 1;
+        let mut sample = Message::default();
+        sample.packed(1, &[id]).packed(2, &[time.as_nanos() as u64]);
+        profile.message(2, &sample);
+
+        let mut line = Message::default();
+        line.varint(1, id);
+        let mut location = Message::default();
+        location.varint(1, id).message(4, &line);
+        profile.message(4, &location);
+
+        strings.push(name);
+        let name = strings.len() as u64 - 1;
+        let mut function = Message::default();
+        function.varint(1, id).varint(2, name).varint(3, name);
+        profile.message(5, &function);
+    }
+
+    for string in strings {
+        profile.bytes(6, string.as_bytes());
+    }
+    profile.message(11, &value_type).varint(12, 1);
+    profile.buf
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+/// `data` in the gzip format, in stored deflate blocks, since a profile is small and the
+/// SDK has no compressor
+fn gzip(data: &[u8]) -> Vec<u8> {
+    // no mtime, no flags, an unknown OS
+    let mut gzip = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff];
+    let mut blocks = data.chunks(u16::MAX as usize).peekable();
+    if blocks.peek().is_none() {
+        gzip.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        gzip.push(last as u8);
+        gzip.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        gzip.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        gzip.extend_from_slice(block);
+    }
+    gzip.extend_from_slice(&crc32(data).to_le_bytes());
+    gzip.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    gzip
+}
+
+/// the gzipped pprof profile of `instance`
+pub(crate) fn pprof(instance: wasm_module_inst_t) -> Vec<u8> {
+    gzip(&encode(&exec_times(instance)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pprof() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let profile = encode(&[
+            (String::from("main"), Duration::from_nanos(300)),
+            (String::from("fib"), Duration::from_micros(2)),
+        ]);
+        // the sample type, cpu in nanoseconds
+        assert_eq!(profile[..6], [0x0a, 0x04, 0x08, 0x01, 0x10, 0x02]);
+        // the first sample, of location 1, 300 ns
+        assert_eq!(
+            profile[6..15],
+            [0x12, 0x07, 0x0a, 0x01, 0x01, 0x12, 0x02, 0xac, 0x02]
+        );
+        let strings: Vec<u8> = [&b"\x32\x00"[..], b"\x32\x03cpu", b"\x32\x0bnanoseconds"].concat();
+        assert!(profile
+            .windows(strings.len())
+            .any(|window| window == strings));
+        assert!(profile.windows(5).any(|window| window == b"\x32\x03fib"));
+
+        let compressed = gzip(&profile);
+        assert_eq!(compressed[..3], [0x1f, 0x8b, 0x08]);
+        // a single stored block, the last one
+        assert_eq!(compressed[10], 0x01);
+        assert_eq!(compressed[15..15 + profile.len()], profile[..]);
+        let trailer = &compressed[compressed.len() - 8..];
+        assert_eq!(trailer[..4], crc32(&profile).to_le_bytes());
+        assert_eq!(trailer[4..], (profile.len() as u32).to_le_bytes());
+
+        let large = gzip(&vec![0; 70000]);
+        assert_eq!(large[10], 0x00);
+        assert_eq!(large[15 + u16::MAX as usize], 0x01);
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the live instances of the process, for the admin and debug endpoints of long-running
+//! hosts. list them via `InstanceRegistry::list()`, and tell them apart via
+//! `Instance::set_label()`
+
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use wamr_sys::wasm_module_inst_t;
+
+use crate::{event::InstanceId, memory};
+
+struct Entry {
+    instance: usize,
+    module: String,
+    label: Option<String>,
+    created: Instant,
+}
+
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+fn entries() -> MutexGuard<'static, Vec<Entry>> {
+    ENTRIES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// a snapshot of a live instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceInfo {
+    pub id: InstanceId,
+    /// the name of the module it instantiates
+    pub module: String,
+    pub label: Option<String>,
+    /// the size of its linear memory, in bytes
+    pub memory_size: usize,
+    pub uptime: Duration,
+}
+
+/// every live instance of the process, across runtimes and threads
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstanceRegistry;
+
+impl InstanceRegistry {
+    /// the live instances, oldest first
+    pub fn list() -> Vec<InstanceInfo> {
+        let now = Instant::now();
+        // an instance is removed before it is deinstantiated, so its memory stays valid
+        // while the registry is locked
+        entries()
+            .iter()
+            .map(|entry| InstanceInfo {
+                id: InstanceId::new(entry.instance as wasm_module_inst_t),
+                module: entry.module.clone(),
+                label: entry.label.clone(),
+                memory_size: memory::data_size(entry.instance as wasm_module_inst_t),
+                uptime: now - entry.created,
+            })
+            .collect()
+    }
+
+    /// the live instances labeled `label`
+    pub fn find(label: &str) -> Vec<InstanceInfo> {
+        Self::list()
+            .into_iter()
+            .filter(|info| info.label.as_deref() == Some(label))
+            .collect()
+    }
+
+    pub(crate) fn register(instance: wasm_module_inst_t, module: &str) {
+        entries().push(Entry {
+            instance: instance as usize,
+            module: String::from(module),
+            label: None,
+            created: Instant::now(),
+        });
+    }
+
+    pub(crate) fn unregister(instance: wasm_module_inst_t) {
+        entries().retain(|entry| entry.instance != instance as usize);
+    }
+
+    pub(crate) fn set_label(instance: wasm_module_inst_t, label: &str) {
+        if let Some(entry) = entries()
+            .iter_mut()
+            .find(|entry| entry.instance == instance as usize)
+        {
+            entry.label = Some(String::from(label));
+        }
+    }
+
+    pub(crate) fn label(instance: wasm_module_inst_t) -> Option<String> {
+        entries()
+            .iter()
+            .find(|entry| entry.instance == instance as usize)
+            .and_then(|entry| entry.label.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{instance::Instance, module::Module, runtime::Runtime, RuntimeError};
+
+    #[test]
+    fn test_instance_registry() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module
+        //   (memory (export "memory") 1)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+            0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "registry")?;
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        let id = instance.id();
+        assert_eq!(instance.label(), None);
+
+        instance.set_label("tenant-42");
+        assert_eq!(instance.label().as_deref(), Some("tenant-42"));
+        let found = InstanceRegistry::find("tenant-42");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, id);
+        assert_eq!(found[0].module, "registry");
+        assert_eq!(found[0].memory_size, memory::WASM_PAGE_SIZE);
+
+        drop(instance);
+        assert!(InstanceRegistry::list().iter().all(|info| info.id != id));
+
+        Ok(())
+    }
+}
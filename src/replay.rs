@@ -0,0 +1,985 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a record of every host call made during a call into an instance, WASI included, to
+//! replay it later with the same module and reproduce a bug deterministically, without the
+//! host functions, files or services it ran against. Record one via
+//! `Instance::record_call()` with a runtime built with `RuntimeBuilder::record_host_calls()`,
+//! and replay it via `Instance::replay()` with one built with
+//! `RuntimeBuilder::replay_host_calls()`, in another process if need be
+//!
+//! a recording starts with the state of the instance, see `snapshot`, and holds the result
+//! of each host call, along with the bytes it changed in the linear memory. To record, each
+//! .wasm is instrumented while it is loaded, see `instrument`: every call to an imported
+//! function calls a host function imported from `MODULE` before and after it, which
+//! compares the linear memory before and after. To replay, the imported functions are
+//! linked to trampolines instead, which write the recorded bytes and return the recorded
+//! results, and a guest making another host call than the recorded one traps.
+//!
+//! the imported functions called through a table, or tail called, aren't recorded, nor are
+//! the host calls of an .aot. A host function calling back into the instance isn't
+//! supported.
+
+use std::{
+    ffi::{c_void, CString},
+    ptr, slice,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{
+    wasm_exec_env_t, wasm_module_inst_t, wasm_module_t, wasm_runtime_addr_app_to_native,
+    wasm_runtime_enlarge_memory, wasm_runtime_get_function_attachment, wasm_runtime_get_module,
+    wasm_runtime_get_module_inst, wasm_runtime_register_natives_raw, wasm_runtime_set_exception,
+    NativeSymbol,
+};
+
+use crate::{
+    account,
+    binary::{self, write_i32_leb, write_name, write_u32_leb, ImportKind, Reader},
+    coverage,
+    function::Function,
+    instance::Instance,
+    instrument::{self, Hook},
+    memory::{self, WASM_PAGE_SIZE},
+    memory_profile,
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+    value::{ValueType, WasmValue},
+    ErrorMessage, RuntimeError,
+};
+
+/// the module the instrumented code imports its hook from
+pub const MODULE: &str = "wamr_replay";
+const HOOK: &str = "host_call";
+
+/// the type of the hook, `(import: i32, event: i32, result: i64) -> ()`
+const HOOK_TYPE: [u8; 6] = [0x60, 0x03, 0x7f, 0x7f, 0x7e, 0x00];
+
+// the value types of the locals the results are kept in, while the hook runs
+const RESULT_TYPES: [u8; 4] = [0x7f, 0x7e, 0x7d, 0x7c];
+
+// the events the hook is called with
+const EVENT_CALL: i32 = 0;
+const EVENT_RETURN: i32 = 1;
+
+/// what replaying the imported functions are linked to is imported from, followed by the
+/// module they were imported from, like `wamr_replay:env`
+const REPLAYED_PREFIX: &str = "wamr_replay:";
+
+const RECORDING_MAGIC: [u8; 4] = [0x00, 0x72, 0x70, 0x6c];
+const RECORDING_VERSION: u32 = 1;
+
+/// what the host calls of the modules are instrumented for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayMode {
+    /// the host functions run, and their results are recorded
+    Record,
+    /// the host functions don't run, their recorded results are returned
+    Replay,
+}
+
+/// a host call made during a recorded call
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedCall {
+    /// the module of the import
+    pub module: String,
+    /// the name of the import
+    pub name: String,
+    /// `WasmValue::Void` for a function without results, or one which trapped
+    pub result: WasmValue,
+    /// whether the host function trapped, which ended the recorded call
+    pub trapped: bool,
+    /// the size of the linear memory after the call, in bytes
+    pub memory_size: u64,
+    /// the offset and the bytes of each range of the linear memory the call changed
+    pub writes: Vec<(u64, Vec<u8>)>,
+}
+
+/// a call into an instance, and the host calls it made
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    function: String,
+    params: Vec<WasmValue>,
+    state: Vec<u8>,
+    host_calls: Vec<RecordedCall>,
+    outcome: Result<WasmValue, String>,
+}
+
+impl Recording {
+    /// the export called
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+
+    pub fn params(&self) -> &[WasmValue] {
+        &self.params
+    }
+
+    /// the host calls made, in order
+    pub fn host_calls(&self) -> &[RecordedCall] {
+        &self.host_calls
+    }
+
+    /// what the call returned, or the error it failed with
+    pub fn outcome(&self) -> Result<&WasmValue, &str> {
+        self.outcome.as_ref().map_err(String::as_str)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = RECORDING_MAGIC.to_vec();
+        write_u32_leb(&mut bytes, RECORDING_VERSION);
+        write_name(&mut bytes, &self.function);
+        write_u32_leb(&mut bytes, self.params.len() as u32);
+        for param in &self.params {
+            write_value(&mut bytes, param);
+        }
+        // a state holds the whole linear memory, which may not fit in 32 bits
+        bytes.extend_from_slice(&(self.state.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.state);
+
+        write_u32_leb(&mut bytes, self.host_calls.len() as u32);
+        for call in &self.host_calls {
+            write_name(&mut bytes, &call.module);
+            write_name(&mut bytes, &call.name);
+            write_value(&mut bytes, &call.result);
+            bytes.push(call.trapped as u8);
+            bytes.extend_from_slice(&call.memory_size.to_le_bytes());
+            write_u32_leb(&mut bytes, call.writes.len() as u32);
+            for (offset, written) in &call.writes {
+                bytes.extend_from_slice(&offset.to_le_bytes());
+                write_u32_leb(&mut bytes, written.len() as u32);
+                bytes.extend_from_slice(written);
+            }
+        }
+
+        match &self.outcome {
+            Ok(result) => {
+                bytes.push(0);
+                write_value(&mut bytes, result);
+            }
+            Err(error) => {
+                bytes.push(1);
+                write_name(&mut bytes, error);
+            }
+        }
+        bytes
+    }
+
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if `bytes` aren't from `Recording::to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RuntimeError> {
+        let malformed =
+            |e: ErrorMessage| RuntimeError::ExecutionError(message!("malformed recording: {}", e));
+        Self::read(bytes).map_err(malformed)
+    }
+
+    fn read(bytes: &[u8]) -> Result<Self, ErrorMessage> {
+        let mut reader = Reader::new(bytes);
+        if reader.read_bytes(RECORDING_MAGIC.len())? != RECORDING_MAGIC {
+            return Err(message!("not a recording"));
+        }
+        let version = reader.read_u32_leb()?;
+        if version != RECORDING_VERSION {
+            return Err(message!("unsupported version {}", version));
+        }
+
+        let function = String::from(reader.read_name()?);
+        let count = reader.read_u32_leb()?;
+        let params = (0..count)
+            .map(|_| read_value(&mut reader))
+            .collect::<Result<_, _>>()?;
+        let len = read_u64(&mut reader)?;
+        let len = usize::try_from(len).map_err(|_| message!("state too large"))?;
+        let state = reader.read_bytes(len)?.to_vec();
+
+        let count = reader.read_u32_leb()?;
+        let mut host_calls = Vec::new();
+        for _ in 0..count {
+            let module = String::from(reader.read_name()?);
+            let name = String::from(reader.read_name()?);
+            let result = read_value(&mut reader)?;
+            let trapped = reader.read_u8()? != 0;
+            let memory_size = read_u64(&mut reader)?;
+            let writes = (0..reader.read_u32_leb()?)
+                .map(|_| {
+                    let offset = read_u64(&mut reader)?;
+                    let len = reader.read_u32_leb()?;
+                    Ok((offset, reader.read_bytes(len as usize)?.to_vec()))
+                })
+                .collect::<Result<_, ErrorMessage>>()?;
+            host_calls.push(RecordedCall {
+                module,
+                name,
+                result,
+                trapped,
+                memory_size,
+                writes,
+            });
+        }
+
+        let outcome = match reader.read_u8()? {
+            0 => Ok(read_value(&mut reader)?),
+            _ => Err(String::from(reader.read_name()?)),
+        };
+        if !reader.is_empty() {
+            return Err(message!("trailing bytes at offset {}", reader.position()));
+        }
+
+        Ok(Recording {
+            function,
+            params,
+            state,
+            host_calls,
+            outcome,
+        })
+    }
+}
+
+fn write_value(bytes: &mut Vec<u8>, value: &WasmValue) {
+    match *value {
+        WasmValue::Void => bytes.push(0),
+        WasmValue::I32(value) => {
+            bytes.push(1);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        WasmValue::I64(value) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        WasmValue::F32(value) => {
+            bytes.push(3);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        WasmValue::F64(value) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        WasmValue::V128(value) => {
+            bytes.push(5);
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        #[cfg(feature = "stringref")]
+        WasmValue::StringRef(_) => unreachable!("record() rejects stringrefs"),
+    }
+}
+
+fn read_value(reader: &mut Reader) -> Result<WasmValue, ErrorMessage> {
+    let value = match reader.read_u8()? {
+        0 => WasmValue::Void,
+        1 => WasmValue::I32(i32::from_le_bytes(
+            reader.read_bytes(4)?.try_into().unwrap(),
+        )),
+        2 => WasmValue::I64(i64::from_le_bytes(
+            reader.read_bytes(8)?.try_into().unwrap(),
+        )),
+        3 => WasmValue::F32(f32::from_le_bytes(
+            reader.read_bytes(4)?.try_into().unwrap(),
+        )),
+        4 => WasmValue::F64(f64::from_le_bytes(
+            reader.read_bytes(8)?.try_into().unwrap(),
+        )),
+        5 => WasmValue::V128(i128::from_le_bytes(
+            reader.read_bytes(16)?.try_into().unwrap(),
+        )),
+        tag => return Err(message!("invalid value tag {}", tag)),
+    };
+    Ok(value)
+}
+
+fn read_u64(reader: &mut Reader) -> Result<u64, ErrorMessage> {
+    Ok(u64::from_le_bytes(
+        reader.read_bytes(8)?.try_into().unwrap(),
+    ))
+}
+
+/// an imported function of a module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ImportedFunction {
+    module: String,
+    name: String,
+    result: ValueType,
+    /// whether its calls are recorded or replayed, which the hooks of the SDK aren't
+    replayed: bool,
+}
+
+/// the imported functions of `binary`, in the order of their indices
+fn imported_functions(binary: &[u8]) -> Result<Vec<ImportedFunction>, ErrorMessage> {
+    let signatures = binary::type_signatures(binary)?;
+    let mut functions = Vec::new();
+    for import in binary::imports(binary)? {
+        let ImportKind::Func(type_index) = import.kind else {
+            continue;
+        };
+        let hooks = [
+            MODULE,
+            coverage::MODULE,
+            memory_profile::MODULE,
+            memory::GROW_MODULE,
+            account::FUEL_MODULE,
+        ];
+        let replayed = !hooks.contains(&import.module);
+        let signature = signatures
+            .get(type_index as usize)
+            .ok_or_else(|| message!("the import {}.{} has no type", import.module, import.name))?;
+        let result = match signature.rsplit(')').next().unwrap_or_default() {
+            "" => ValueType::Void,
+            "i" => ValueType::I32,
+            "I" => ValueType::I64,
+            "f" => ValueType::F32,
+            "F" => ValueType::F64,
+            _ if !replayed => ValueType::Void,
+            results => {
+                return Err(message!(
+                    "the results {} of the import {}.{} can't be recorded",
+                    results,
+                    import.module,
+                    import.name
+                ))
+            }
+        };
+        functions.push(ImportedFunction {
+            module: String::from(import.module),
+            name: String::from(import.name),
+            result,
+            replayed,
+        });
+    }
+    Ok(functions)
+}
+
+/// `binary` calling the hook around each call to an imported function
+fn instrument(binary: &[u8], imports: &[ImportedFunction]) -> Result<Vec<u8>, ErrorMessage> {
+    let hook = Hook {
+        module: MODULE,
+        name: HOOK,
+        func_type: &HOOK_TYPE,
+        locals: &RESULT_TYPES,
+    };
+    // the import called by the instruction before, and the type of its result
+    let mut returning: Option<(u32, ValueType)> = None;
+    instrument::instrument(binary, &hook, |site, out| {
+        let call_hook = |out: &mut Vec<u8>, import: u32, event: i32| {
+            // i32.const import, i32.const event
+            out.push(0x41);
+            write_i32_leb(out, import as i32);
+            out.push(0x41);
+            write_i32_leb(out, event);
+        };
+
+        if let Some((import, result)) = returning.take() {
+            let valtype = match result {
+                ValueType::I32 => Some(0x7f),
+                ValueType::I64 => Some(0x7e),
+                ValueType::F32 => Some(0x7d),
+                ValueType::F64 => Some(0x7c),
+                _ => None,
+            };
+            match valtype {
+                Some(valtype) => {
+                    let index = RESULT_TYPES.iter().position(|t| *t == valtype).unwrap();
+                    let local = site.locals + index as u32;
+                    // local.set, then the bits of the result as an i64
+                    out.push(0x21);
+                    write_u32_leb(out, local);
+                    call_hook(out, import, EVENT_RETURN);
+                    out.push(0x20);
+                    write_u32_leb(out, local);
+                    match valtype {
+                        // i64.extend_i32_u
+                        0x7f => out.push(0xad),
+                        // i32.reinterpret_f32, i64.extend_i32_u
+                        0x7d => out.extend_from_slice(&[0xbc, 0xad]),
+                        // i64.reinterpret_f64
+                        0x7c => out.push(0xbd),
+                        _ => {}
+                    }
+                }
+                None => {
+                    call_hook(out, import, EVENT_RETURN);
+                    // i64.const 0
+                    out.extend_from_slice(&[0x42, 0x00]);
+                }
+            }
+            // call hook
+            out.push(0x10);
+            write_u32_leb(out, site.hook);
+            if let Some(valtype) = valtype {
+                // local.get, the result back on the stack
+                let index = RESULT_TYPES.iter().position(|t| *t == valtype).unwrap();
+                out.push(0x20);
+                write_u32_leb(out, site.locals + index as u32);
+            }
+        }
+
+        // call and return_call
+        if !matches!(site.opcode, 0x10 | 0x12) {
+            return Ok(());
+        }
+        let callee = Reader::new(&site.bytes[1..]).read_u32_leb()?;
+        let Some(import) = imports
+            .get(callee as usize)
+            .filter(|import| import.replayed)
+        else {
+            return Ok(());
+        };
+        if site.opcode == 0x12 {
+            return Err(message!(
+                "the import {}.{} is tail called, which can't be recorded",
+                import.module,
+                import.name
+            ));
+        }
+        call_hook(out, callee, EVENT_CALL);
+        // i64.const 0, call hook
+        out.extend_from_slice(&[0x42, 0x00, 0x10]);
+        write_u32_leb(out, site.hook);
+        returning = Some((callee, import.result));
+        Ok(())
+    })
+}
+
+/// a trampoline an imported function is linked to, kept until WAMR is destroyed
+struct Trampoline {
+    // WAMR keeps the addresses of both names, and of the function as the attachment
+    _module: CString,
+    _name: CString,
+    function: Box<ImportedFunction>,
+    _symbols: Box<[NativeSymbol]>,
+}
+
+// the symbols only point to the names and the function next to them
+unsafe impl Send for Trampoline {}
+
+static TRAMPOLINES: Mutex<Vec<Trampoline>> = Mutex::new(Vec::new());
+
+fn trampolines() -> MutexGuard<'static, Vec<Trampoline>> {
+    TRAMPOLINES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// link the imported functions to trampolines, under the modules they are renamed to
+fn register_trampolines(imports: &[ImportedFunction]) {
+    let mut trampolines = trampolines();
+    for import in imports.iter().filter(|import| import.replayed) {
+        if trampolines
+            .iter()
+            .any(|trampoline| *trampoline.function == *import)
+        {
+            continue;
+        }
+        let module = format!("{}{}", REPLAYED_PREFIX, import.module);
+        let (Ok(module), Ok(name)) = (CString::new(module), CString::new(import.name.as_str()))
+        else {
+            continue;
+        };
+
+        let function = Box::new(import.clone());
+        let mut symbols = Box::new([NativeSymbol {
+            symbol: name.as_ptr(),
+            func_ptr: replayed as *mut c_void,
+            // raw, typed by the import
+            signature: ptr::null(),
+            attachment: &*function as *const ImportedFunction as *mut c_void,
+        }]);
+        let registered =
+            unsafe { wasm_runtime_register_natives_raw(module.as_ptr(), symbols.as_mut_ptr(), 1) };
+        if registered {
+            trampolines.push(Trampoline {
+                _module: module,
+                _name: name,
+                function,
+                _symbols: symbols,
+            });
+        }
+    }
+}
+
+/// forget the trampolines, once WAMR is destroyed
+pub(crate) fn reset() {
+    trampolines().clear();
+}
+
+/// `binary` importing the functions of `imports` from the trampolines
+fn rename(binary: &[u8], imports: &[ImportedFunction]) -> Result<Vec<u8>, ErrorMessage> {
+    binary::rename_imports(binary, |module, name| {
+        let replayed = imports
+            .iter()
+            .any(|import| import.replayed && import.module == module && import.name == name);
+        replayed.then(|| format!("{}{}", REPLAYED_PREFIX, module))
+    })
+}
+
+/// `binary` prepared for `mode`, and its imported functions
+pub(crate) fn prepare(
+    binary: &[u8],
+    mode: ReplayMode,
+) -> Result<(Vec<u8>, Vec<ImportedFunction>), ErrorMessage> {
+    let imports = imported_functions(binary)?;
+    let prepared = match mode {
+        ReplayMode::Record => instrument(binary, &imports)?,
+        ReplayMode::Replay => {
+            register_trampolines(&imports);
+            rename(binary, &imports)?
+        }
+    };
+    Ok((prepared, imports))
+}
+
+/// the modules prepared for recording or replaying, by the address of the module
+static MODULES: Mutex<Vec<(usize, ReplayMode, Vec<ImportedFunction>)>> = Mutex::new(Vec::new());
+
+fn modules() -> MutexGuard<'static, Vec<(usize, ReplayMode, Vec<ImportedFunction>)>> {
+    MODULES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// remember that `module` has been prepared for `mode`
+pub(crate) fn loaded(module: wasm_module_t, mode: ReplayMode, imports: Vec<ImportedFunction>) {
+    modules().push((module as usize, mode, imports));
+}
+
+/// forget an unloaded module
+pub(crate) fn unloaded(module: wasm_module_t) {
+    modules().retain(|(address, _, _)| *address != module as usize);
+}
+
+/// the imported functions of the module of `instance`, if prepared for `mode`
+fn imports_of(instance: wasm_module_inst_t, mode: ReplayMode) -> Option<Vec<ImportedFunction>> {
+    let module = unsafe { wasm_runtime_get_module(instance) } as usize;
+    modules()
+        .iter()
+        .find(|(address, prepared, _)| *address == module && *prepared == mode)
+        .map(|(_, _, imports)| imports.clone())
+}
+
+/// a recording in progress
+struct Recorder {
+    imports: Vec<ImportedFunction>,
+    /// the imports called and not returned yet, and the linear memory before
+    pending: Vec<(u32, Vec<u8>)>,
+    host_calls: Vec<RecordedCall>,
+}
+
+/// a replay in progress
+struct Replayer {
+    host_calls: Vec<RecordedCall>,
+    /// the host calls replayed so far, the ones after are still to
+    replayed: usize,
+    /// the error the recorded call failed with, raised by a host call which trapped
+    error: Option<String>,
+}
+
+static RECORDERS: Mutex<Vec<(usize, Recorder)>> = Mutex::new(Vec::new());
+static REPLAYERS: Mutex<Vec<(usize, Replayer)>> = Mutex::new(Vec::new());
+
+fn recorders() -> MutexGuard<'static, Vec<(usize, Recorder)>> {
+    RECORDERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn replayers() -> MutexGuard<'static, Vec<(usize, Replayer)>> {
+    REPLAYERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    recorders().retain(|(address, _)| *address != instance as usize);
+    replayers().retain(|(address, _)| *address != instance as usize);
+}
+
+/// the default linear memory of `instance`, empty without one
+///
+/// # Safety
+///
+/// the slice is valid until the memory grows, or the instance is dropped
+unsafe fn linear_memory<'a>(instance: wasm_module_inst_t) -> &'a mut [u8] {
+    let size = memory::data_size(instance);
+    let base = wasm_runtime_addr_app_to_native(instance, 0) as *mut u8;
+    match base.is_null() || size == 0 {
+        true => &mut [],
+        false => slice::from_raw_parts_mut(base, size),
+    }
+}
+
+/// the ranges of `after` which differ from `before`, where the memory had grown
+fn diff(before: &[u8], after: &[u8]) -> Vec<(u64, Vec<u8>)> {
+    let mut writes: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut start = None;
+    for (offset, byte) in after.iter().enumerate() {
+        let changed = before.get(offset).copied().unwrap_or(0) != *byte;
+        match (changed, start) {
+            (true, None) => start = Some(offset),
+            (false, Some(first)) => {
+                writes.push((first as u64, after[first..offset].to_vec()));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(first) = start {
+        writes.push((first as u64, after[first..].to_vec()));
+    }
+    writes
+}
+
+/// the host function the instrumented code calls around a host call
+pub(crate) struct HostCallRecorder;
+
+impl NativeModule for HostCallRecorder {
+    fn module_name(&self) -> &str {
+        MODULE
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(HOOK, host_call as extern "C" fn(ExecEnv, i32, i32, i64));
+    }
+}
+
+extern "C" fn host_call(env: ExecEnv, import: i32, event: i32, result: i64) {
+    let instance = env.module_inst();
+    let mut recorders = recorders();
+    let Some((_, recorder)) = recorders
+        .iter_mut()
+        .find(|(address, _)| *address == instance as usize)
+    else {
+        return;
+    };
+
+    let memory = unsafe { linear_memory(instance) };
+    if event == EVENT_CALL {
+        recorder.pending.push((import as u32, memory.to_vec()));
+        return;
+    }
+    let Some((_, before)) = recorder.pending.pop() else {
+        return;
+    };
+    let Some(function) = recorder.imports.get(import as usize) else {
+        return;
+    };
+    let result = match function.result {
+        ValueType::I32 => WasmValue::I32(result as i32),
+        ValueType::I64 => WasmValue::I64(result),
+        ValueType::F32 => WasmValue::F32(f32::from_bits(result as u32)),
+        ValueType::F64 => WasmValue::F64(f64::from_bits(result as u64)),
+        _ => WasmValue::Void,
+    };
+    let call = RecordedCall {
+        module: function.module.clone(),
+        name: function.name.clone(),
+        result,
+        trapped: false,
+        memory_size: memory.len() as u64,
+        writes: diff(&before, memory),
+    };
+    recorder.host_calls.push(call);
+}
+
+/// call the export `function` of `instance`, recording its host calls
+pub(crate) fn record<T>(
+    instance: &Instance<T>,
+    function: &str,
+    params: &[WasmValue],
+) -> Result<Recording, RuntimeError> {
+    let inner = instance.get_inner_instance();
+    let imports = imports_of(inner, ReplayMode::Record).ok_or_else(|| {
+        RuntimeError::ExecutionError(message!(
+            "the module hasn't been loaded to record its host calls",
+        ))
+    })?;
+    let export = Function::find_export_func(instance, function)?;
+    // a state holds the linear memory, not the GC heap the string lives in
+    #[cfg(feature = "stringref")]
+    if params.iter().any(|p| matches!(p, WasmValue::StringRef(_))) {
+        return Err(RuntimeError::TypeMismatch(message!(
+            "a stringref can't be recorded",
+        )));
+    }
+    let state = instance.serialize_state()?;
+
+    let recorder = Recorder {
+        imports,
+        pending: Vec::new(),
+        host_calls: Vec::new(),
+    };
+    recorders().push((inner as usize, recorder));
+    let outcome = export.call_args(instance, params);
+    let mut recorder = {
+        let mut recorders = recorders();
+        let position = recorders
+            .iter()
+            .position(|(address, _)| *address == inner as usize)
+            .unwrap();
+        recorders.swap_remove(position).1
+    };
+
+    // the host calls which trapped, the last one first
+    let memory = unsafe { linear_memory(inner) };
+    while let Some((import, before)) = recorder.pending.pop() {
+        let function = &recorder.imports[import as usize];
+        recorder.host_calls.push(RecordedCall {
+            module: function.module.clone(),
+            name: function.name.clone(),
+            result: WasmValue::Void,
+            trapped: true,
+            memory_size: memory.len() as u64,
+            writes: diff(&before, memory),
+        });
+    }
+
+    Ok(Recording {
+        function: String::from(function),
+        params: params.to_vec(),
+        state,
+        host_calls: recorder.host_calls,
+        outcome: outcome.map_err(|e| e.to_string()),
+    })
+}
+
+/// restore the state of `recording` into `instance`, and make the recorded call again,
+/// replaying its host calls
+pub(crate) fn replay<T>(
+    instance: &Instance<T>,
+    recording: &Recording,
+) -> Result<WasmValue, RuntimeError> {
+    let inner = instance.get_inner_instance();
+    if imports_of(inner, ReplayMode::Replay).is_none() {
+        return Err(RuntimeError::ExecutionError(message!(
+            "the module hasn't been loaded to replay its host calls",
+        )));
+    }
+    let export = Function::find_export_func(instance, &recording.function)?;
+    instance.restore_state(&recording.state)?;
+
+    let replayer = Replayer {
+        host_calls: recording.host_calls.clone(),
+        replayed: 0,
+        error: recording.outcome.clone().err(),
+    };
+    replayers().push((inner as usize, replayer));
+    let result = export.call_args(instance, &recording.params);
+    let replayed = {
+        let mut replayers = replayers();
+        let position = replayers
+            .iter()
+            .position(|(address, _)| *address == inner as usize)
+            .unwrap();
+        replayers.swap_remove(position).1.replayed
+    };
+
+    match result {
+        Ok(_) if replayed < recording.host_calls.len() => {
+            Err(RuntimeError::ExecutionError(message!(
+                "replay diverged: the call returned after {} of {} host calls",
+                replayed,
+                recording.host_calls.len()
+            )))
+        }
+        result => result,
+    }
+}
+
+fn trap(instance: wasm_module_inst_t, message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap();
+    unsafe { wasm_runtime_set_exception(instance, message.as_ptr()) };
+}
+
+/// write the bytes `call` wrote into the linear memory of `instance`
+fn write_memory(instance: wasm_module_inst_t, call: &RecordedCall) -> Result<(), String> {
+    let size = memory::data_size(instance) as u64;
+    if size < call.memory_size {
+        let pages = (call.memory_size - size).div_ceil(WASM_PAGE_SIZE as u64);
+        if !unsafe { wasm_runtime_enlarge_memory(instance, pages as _) } {
+            return Err(format!("can't grow the linear memory by {} pages", pages));
+        }
+    }
+
+    let memory = unsafe { linear_memory(instance) };
+    for (offset, written) in &call.writes {
+        let start = *offset as usize;
+        let Some(range) = memory.get_mut(start..start + written.len()) else {
+            return Err(format!("a write at offset {} is out of bounds", offset));
+        };
+        range.copy_from_slice(written);
+    }
+    Ok(())
+}
+
+/// a raw host function, getting its parameters, and returning its result, in 64-bit slots
+unsafe extern "C" fn replayed(env: wasm_exec_env_t, slots: *mut u64) {
+    let function = &*(wasm_runtime_get_function_attachment(env) as *const ImportedFunction);
+    let instance = wasm_runtime_get_module_inst(env);
+
+    let next = {
+        let mut replayers = replayers();
+        let replayer = replayers
+            .iter_mut()
+            .find(|(address, _)| *address == instance as usize)
+            .map(|(_, replayer)| replayer);
+        match replayer {
+            None => Err(format!(
+                "the import {}.{} is only replayed, via Instance::replay()",
+                function.module, function.name
+            )),
+            Some(replayer) => match replayer.host_calls.get(replayer.replayed) {
+                Some(call) if call.module == function.module && call.name == function.name => {
+                    replayer.replayed += 1;
+                    Ok((call.clone(), replayer.error.clone()))
+                }
+                Some(call) => Err(format!(
+                    "replay diverged at host call {}: {}.{} was recorded, not {}.{}",
+                    replayer.replayed, call.module, call.name, function.module, function.name
+                )),
+                None => Err(format!(
+                    "replay diverged: {}.{} was called after the {} recorded host calls",
+                    function.module,
+                    function.name,
+                    replayer.host_calls.len()
+                )),
+            },
+        }
+    };
+    let (call, error) = match next {
+        Ok(next) => next,
+        Err(message) => return trap(instance, message),
+    };
+
+    if let Err(message) = write_memory(instance, &call) {
+        return trap(instance, message);
+    }
+    if call.trapped {
+        let message = error.unwrap_or_else(|| format!("{}.{} trapped", call.module, call.name));
+        return trap(instance, message);
+    }
+    match call.result {
+        WasmValue::I32(value) => *(slots as *mut i32) = value,
+        WasmValue::I64(value) => *(slots as *mut i64) = value,
+        WasmValue::F32(value) => *(slots as *mut f32) = value,
+        WasmValue::F64(value) => *(slots as *mut f64) = value,
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        host_function::{ParamTy, ResultTy},
+        instruction::decode_body,
+        module::Module,
+        runtime::Runtime,
+    };
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    // (module
+    //   (import "host" "fill" (func $fill (param i32) (result i32)))
+    //   (memory (export "memory") 1)
+    //   (func (export "run") (result i32)
+    //     (drop (call $fill (i32.const 16)))
+    //     (i32.add (call $fill (i32.const 32)) (i32.load (i32.const 16)))))
+    const BINARY: [u8; 99] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0a, 0x02, 0x60, 0x01, 0x7f, 0x01,
+        0x7f, 0x60, 0x00, 0x01, 0x7f, 0x02, 0x0d, 0x01, 0x04, 0x68, 0x6f, 0x73, 0x74, 0x04, 0x66,
+        0x69, 0x6c, 0x6c, 0x00, 0x00, 0x03, 0x02, 0x01, 0x01, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+        0x10, 0x02, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x03, 0x72, 0x75, 0x6e,
+        0x00, 0x01, 0x0a, 0x13, 0x01, 0x11, 0x00, 0x41, 0x10, 0x10, 0x00, 0x1a, 0x41, 0x20, 0x10,
+        0x00, 0x41, 0x10, 0x28, 0x02, 0x00, 0x6a, 0x0b, 0x00, 0x0e, 0x04, 0x6e, 0x61, 0x6d, 0x65,
+        0x01, 0x07, 0x01, 0x00, 0x04, 0x66, 0x69, 0x6c, 0x6c,
+    ];
+
+    #[test]
+    fn test_prepare() {
+        let (instrumented, imports) = prepare(&BINARY, ReplayMode::Record).unwrap();
+        assert_eq!(imports[0].result, ValueType::I32);
+        let bodies = binary::function_bodies(&instrumented).unwrap();
+        let instructions = decode_body(&instrumented, bodies[0].clone()).unwrap();
+        let calls: Vec<&[u8]> = instructions
+            .iter()
+            .filter(|i| i.opcode == 0x10)
+            .map(|i| &i.bytes[..])
+            .collect();
+        // the hook, $fill and the hook, twice
+        assert_eq!(calls, [[0x10, 0x01], [0x10, 0x00], [0x10, 0x01]].repeat(2));
+
+        let renamed = rename(&BINARY, &imports).unwrap();
+        let imports = binary::imports(&renamed).unwrap();
+        assert_eq!(
+            (imports[0].module, imports[0].name),
+            ("wamr_replay:host", "fill")
+        );
+
+        assert_eq!(
+            diff(&[0, 1, 2, 3], &[0, 5, 6, 3, 0, 7]),
+            [(1, vec![5, 6]), (5, vec![7])]
+        );
+
+        let recording = Recording {
+            function: String::from("run"),
+            params: vec![WasmValue::I64(-1), WasmValue::F32(0.5)],
+            state: vec![0x2a; 3],
+            host_calls: vec![RecordedCall {
+                module: String::from("host"),
+                name: String::from("fill"),
+                result: WasmValue::I32(7),
+                trapped: false,
+                memory_size: 65536,
+                writes: vec![(16, vec![1, 2, 3, 4])],
+            }],
+            outcome: Err(String::from("Exception: unreachable")),
+        };
+        assert_eq!(
+            Recording::from_bytes(&recording.to_bytes()).unwrap(),
+            recording
+        );
+        assert!(matches!(
+            Recording::from_bytes(b"\0rpl"),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+    }
+
+    static FILLS: AtomicI32 = AtomicI32::new(0);
+
+    extern "C" fn fill(env: ExecEnv, offset: i32) -> i32 {
+        let instance = env.module_inst();
+        let memory = unsafe { linear_memory(instance) };
+        memory[offset as usize..offset as usize + 4].copy_from_slice(&[1, 2, 3, 4]);
+        FILLS.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    #[test]
+    fn test_record_and_replay() -> Result<(), RuntimeError> {
+        let bytes = {
+            let runtime = Runtime::builder()
+                .use_system_allocator()
+                .record_host_calls()
+                .register_host_function("fill", fill as *mut c_void, &[ParamTy::I32], ResultTy::I32)
+                .build()?;
+            let module = Module::from_buf(&runtime, &BINARY, "replay")?;
+            let instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+            let recording = instance.record_call("run", &[])?;
+            assert_eq!(recording.host_calls().len(), 2);
+            assert_eq!(recording.host_calls()[1].writes, [(32, vec![1, 2, 3, 4])]);
+            assert_eq!(recording.outcome(), Ok(&WasmValue::I32(2 + 0x0403_0201)));
+            recording.to_bytes()
+        };
+
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .replay_host_calls()
+            .build()?;
+        let module = Module::from_buf(&runtime, &BINARY, "replay")?;
+        let instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+        let recording = Recording::from_bytes(&bytes)?;
+        assert_eq!(
+            instance.replay(&recording)?,
+            WasmValue::I32(2 + 0x0403_0201)
+        );
+        assert_eq!(FILLS.load(Ordering::SeqCst), 2);
+
+        // the host functions aren't linked outside of a replay
+        let run = Function::find_export_func(&instance, "run")?;
+        assert!(matches!(
+            run.call(&instance, &vec![]),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+        Ok(())
+    }
+}
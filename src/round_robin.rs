@@ -0,0 +1,174 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! many instances taking turns on one thread, for devices which can't afford a thread per
+//! guest. Build a scheduler via `RoundRobin::new()`
+//!
+//! WAMR can't suspend a call half way, so a guest is sliced by the calls it is made: a task
+//! is an export called over and over, like `step() -> i32`, which does a bounded piece of
+//! work and returns nonzero once the task is done. Each call is a slice, bounded by a
+//! quantum of instructions via `Function::call_with_instruction_limit()`, so a guest which
+//! doesn't return in time is trapped and its task ended, instead of starving the others.
+//!
+//! the quanta are only enforced in the interpreters.
+
+use std::collections::VecDeque;
+
+use crate::{function::Function, instance::Instance, value::WasmValue, RuntimeError};
+
+/// identifies a task of a `RoundRobin`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TaskId(u64);
+
+/// how a task ended
+#[derive(Debug)]
+pub enum TaskEnd {
+    /// a slice returned nonzero
+    Done,
+    /// a slice ran over the quantum, and has been trapped half way
+    Overrun,
+    /// a slice trapped, or returned something else than an `i32`
+    Failed(RuntimeError),
+}
+
+struct Task<'a> {
+    id: TaskId,
+    slice: Box<dyn FnMut(u32) -> Result<WasmValue, RuntimeError> + 'a>,
+}
+
+/// runs the slices of its tasks in turn, on the thread calling it
+pub struct RoundRobin<'a> {
+    quantum: u32,
+    next_id: u64,
+    tasks: VecDeque<Task<'a>>,
+}
+
+impl<'a> RoundRobin<'a> {
+    /// a scheduler giving each slice `quantum` instructions
+    pub fn new(quantum: u32) -> Self {
+        RoundRobin {
+            quantum,
+            next_id: 0,
+            tasks: VecDeque::new(),
+        }
+    }
+
+    /// add a task calling the export `step` of `instance`, which takes no parameters and
+    /// returns an `i32`, nonzero once the task is done. It runs after the tasks added before
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export.
+    pub fn spawn<T: 'a>(
+        &mut self,
+        instance: &'a Instance<T>,
+        step: &str,
+    ) -> Result<TaskId, RuntimeError> {
+        let function = Function::find_export_func(instance, step)?;
+        let id = TaskId(self.next_id);
+        self.next_id += 1;
+        self.tasks.push_back(Task {
+            id,
+            slice: Box::new(move |quantum| {
+                function.call_with_instruction_limit(instance, &[], quantum)
+            }),
+        });
+        Ok(id)
+    }
+
+    /// the tasks not ended yet
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// run a slice of the next task, which then waits for its next turn, unless it ended.
+    /// Return the task and how it ended, if it did, or `None` without tasks
+    pub fn run_slice(&mut self) -> Option<(TaskId, Option<TaskEnd>)> {
+        let mut task = self.tasks.pop_front()?;
+        let end = match (task.slice)(self.quantum) {
+            Ok(WasmValue::I32(0)) => None,
+            Ok(WasmValue::I32(_)) => Some(TaskEnd::Done),
+            Ok(result) => Some(TaskEnd::Failed(RuntimeError::TypeMismatch(message!(
+                "a slice returned {:?} instead of an i32",
+                result
+            )))),
+            Err(RuntimeError::InstructionLimitExceeded(_)) => Some(TaskEnd::Overrun),
+            Err(e) => Some(TaskEnd::Failed(e)),
+        };
+        let id = task.id;
+        if end.is_none() {
+            self.tasks.push_back(task);
+        }
+        Some((id, end))
+    }
+
+    /// run slices until every task ended, and return how they did, in the order they did
+    pub fn run(&mut self) -> Vec<(TaskId, TaskEnd)> {
+        let mut ended = Vec::new();
+        while let Some((id, end)) = self.run_slice() {
+            if let Some(end) = end {
+                ended.push((id, end));
+            }
+        }
+        ended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{module::Module, runtime::Runtime};
+
+    #[test]
+    fn test_round_robin() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder().run_as_interpreter().build()?;
+
+        // (module
+        //   (global (mut i32) (i32.const 0))
+        //   (func (export "step") (result i32)
+        //     (global.set 0 (i32.add (global.get 0) (i32.const 1)))
+        //     (i32.ge_u (global.get 0) (i32.const 3))
+        //   )
+        //   (func (export "spin") (result i32) (loop (br 0)) (i32.const 0))
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01,
+            0x7f, 0x03, 0x03, 0x02, 0x00, 0x00, 0x06, 0x06, 0x01, 0x7f, 0x01, 0x41, 0x00, 0x0b,
+            0x07, 0x0f, 0x02, 0x04, 0x73, 0x74, 0x65, 0x70, 0x00, 0x00, 0x04, 0x73, 0x70, 0x69,
+            0x6e, 0x00, 0x01, 0x0a, 0x1a, 0x02, 0x0e, 0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24,
+            0x00, 0x23, 0x00, 0x41, 0x03, 0x4f, 0x0b, 0x09, 0x00, 0x03, 0x40, 0x0c, 0x00, 0x0b,
+            0x41, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "round_robin")?;
+        let instances = [
+            Instance::new(&runtime, &module, 1024, ())?,
+            Instance::new(&runtime, &module, 1024, ())?,
+            Instance::new(&runtime, &module, 1024, ())?,
+        ];
+
+        let mut scheduler = RoundRobin::new(1000);
+        let first = scheduler.spawn(&instances[0], "step")?;
+        let second = scheduler.spawn(&instances[1], "step")?;
+        let spinning = scheduler.spawn(&instances[2], "spin")?;
+        assert!(matches!(
+            scheduler.spawn(&instances[0], "missing"),
+            Err(RuntimeError::FunctionNotFound)
+        ));
+        assert_eq!(scheduler.len(), 3);
+
+        let ended = scheduler.run();
+        let order = ended.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+        assert_eq!(order, vec![spinning, first, second]);
+        assert!(matches!(ended[0].1, TaskEnd::Overrun));
+        assert!(matches!(ended[1].1, TaskEnd::Done));
+        assert!(scheduler.is_empty());
+
+        Ok(())
+    }
+}
@@ -7,20 +7,78 @@
 //! Every process should have only one instance of this runtime by call
 //! `Runtime::new()` or `Runtime::builder().build()` once.
 
-use std::ffi::c_void;
+use std::{
+    ffi::c_void,
+    fmt, iter,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
 
 use wamr_sys::{
-    mem_alloc_type_t_Alloc_With_Pool, mem_alloc_type_t_Alloc_With_System_Allocator,
-    wasm_runtime_destroy, wasm_runtime_full_init, wasm_runtime_init, NativeSymbol,
-    RunningMode_Mode_Interp, RunningMode_Mode_LLVM_JIT, RuntimeInitArgs,
+    mem_alloc_type_t_Alloc_With_Allocator, mem_alloc_type_t_Alloc_With_Pool,
+    mem_alloc_type_t_Alloc_With_System_Allocator, wasm_runtime_destroy, wasm_runtime_full_init,
+    wasm_runtime_init, NativeSymbol, RunningMode, RunningMode_Mode_Interp,
+    RunningMode_Mode_LLVM_JIT, RuntimeInitArgs,
+};
+
+#[cfg(feature = "config")]
+use crate::config::RuntimeConfig;
+#[cfg(feature = "signed-aot")]
+use crate::signature::{self, VerifyingKey};
+use crate::{
+    allocator::{self, AllocatorKind, AllocatorStats, WatermarkCallback, WatermarkCrossing},
+    async_host::{self, Executor},
+    bridge,
+    compiler::SegueFlags,
+    coverage::{CoverageCollector, CoverageLevel},
+    event::{EventBus, RuntimeEvent},
+    fault::{Fault, FaultInjector, FaultPlan},
+    features::WasmFeatures,
+    host_function::{HostFunctionList, HostSymbol, TypedHostFunction},
+    instance::Instance,
+    jit_stats::{self, JitStats},
+    lifecycle::{Dependent, Dependents},
+    memory::MemoryHints,
+    module::Module,
+    native_module::{NativeModule, NativeModuleEntry},
+    replay::{self, HostCallRecorder, ReplayMode},
+    signals::SavedHandlers,
+    RuntimeError,
 };
 
-use crate::{host_function::HostFunctionList, RuntimeError};
+/// the number of live `Runtime`s, which share the one WAMR runtime of the process. Locked
+/// while one is built or dropped, so both happen as a whole
+static RUNTIMES: Mutex<usize> = Mutex::new(0);
+
+fn runtimes() -> MutexGuard<'static, usize> {
+    RUNTIMES.lock().unwrap_or_else(PoisonError::into_inner)
+}
 
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct Runtime {
     host_functions: HostFunctionList,
+    // boxed, since WAMR keeps the address of each entry as an attachment
+    #[allow(clippy::vec_box)]
+    native_modules: Vec<Box<NativeModuleEntry>>,
+    // to keep the memory pool alive until the runtime is destroyed
+    memory_pool: Option<Vec<u8>>,
+    events: Arc<EventBus>,
+    modules: Dependents,
+    signal_handlers: Option<SavedHandlers>,
+    canonicalize_nans: bool,
+    abort_on_host_panic: bool,
+    profile_memory: bool,
+    meter_fuel: bool,
+    hook_memory_grow: bool,
+    coverage: Option<CoverageLevel>,
+    replay: Option<ReplayMode>,
+    faults: Vec<Fault>,
+    allocator: AllocatorKind,
+    running_mode: RunningMode,
+    app_heap: Option<u32>,
+    memory_hints: MemoryHints,
+    #[cfg(feature = "signed-aot")]
+    aot_keys: Vec<VerifyingKey>,
 }
 
 impl Runtime {
@@ -34,26 +92,311 @@ impl Runtime {
     }
 
     /// create a new `Runtime` instance with the default configuration which includes:
-    /// - system allocator mode
+    /// - system allocator mode, or a pool of `TINY_POOL_SIZE` bytes with the `tiny` feature
     /// - the default running mode
     ///
     /// # Errors
     ///
     /// if the runtime initialization failed, it will return `RuntimeError::InitializationFailure`
     pub fn new() -> Result<Self, RuntimeError> {
+        if cfg!(feature = "tiny") {
+            return Runtime::builder().build();
+        }
+        let mut runtimes = runtimes();
         match unsafe { wasm_runtime_init() } {
-            true => Ok(Runtime {
-                host_functions: HostFunctionList::new("empty"),
-            }),
+            true => {
+                *runtimes += 1;
+                Ok(Runtime {
+                    host_functions: HostFunctionList::new("empty"),
+                    native_modules: Vec::new(),
+                    memory_pool: None,
+                    events: Arc::default(),
+                    modules: Dependents::default(),
+                    signal_handlers: None,
+                    canonicalize_nans: false,
+                    abort_on_host_panic: false,
+                    profile_memory: false,
+                    meter_fuel: false,
+                    hook_memory_grow: false,
+                    coverage: None,
+                    replay: None,
+                    faults: Vec::new(),
+                    allocator: AllocatorKind::System,
+                    running_mode: 0,
+                    app_heap: Some(0),
+                    memory_hints: MemoryHints::default(),
+                    #[cfg(feature = "signed-aot")]
+                    aot_keys: Vec::new(),
+                })
+            }
             false => Err(RuntimeError::InitializationFailure),
         }
     }
+
+    /// build a runtime configured by `config`, like read from a file. Instances are left to
+    /// be instantiated with `config.stack_size()` and `config.heap_size`, and modules given
+    /// `config.wasi.wasi_context()`
+    ///
+    /// # Errors
+    ///
+    /// if the runtime initialization failed, it will return `RuntimeError::InitializationFailure`,
+    /// and if the WAMR build lacks a feature the configuration requires,
+    /// `RuntimeError::ConfigError`
+    #[cfg(feature = "config")]
+    pub fn from_config(config: &RuntimeConfig) -> Result<Self, RuntimeError> {
+        let runtime = config.builder().build()?;
+        let missing = config.features.missing(&runtime.features());
+        if !missing.is_empty() {
+            return Err(RuntimeError::ConfigError(message!(
+                "the WAMR build lacks {}",
+                missing.join(", ")
+            )));
+        }
+        Ok(runtime)
+    }
+
+    /// whether a `Runtime` is alive in the process
+    pub fn is_initialized() -> bool {
+        *runtimes() > 0
+    }
+
+    /// call `subscriber` with every event of all instances of the runtime.
+    ///
+    /// `subscriber` runs on the thread emitting the event, and must not subscribe
+    /// again from there.
+    pub fn subscribe<F>(&self, subscriber: F)
+    where
+        F: Fn(&RuntimeEvent) + Send + Sync + 'static,
+    {
+        self.events.subscribe(Box::new(subscriber));
+    }
+
+    /// the WebAssembly proposals supported by the linked WAMR build, to degrade gracefully,
+    /// or refuse a module early, when one is missing
+    pub fn features(&self) -> WasmFeatures {
+        WasmFeatures::detect()
+    }
+
+    /// the statistics of the allocator of WAMR, with a memory pool or the instrumented
+    /// allocator, `None` with the plain system allocator
+    pub fn allocator_stats(&self) -> Option<AllocatorStats> {
+        self.allocator.stats()
+    }
+
+    /// what compiling the modules loaded so far, and not dropped yet, cost, see `jit_stats`
+    pub fn jit_stats(&self) -> JitStats {
+        jit_stats::snapshot()
+    }
+
+    /// forward the calls of `importer` into its import `import`, from the module `bridge`, to
+    /// the export `export` of `exporter`, replacing where they were forwarded before, see
+    /// `bridge`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::FunctionNotFound` if there is no such export or import, or
+    /// `RuntimeError::TypeMismatch` if their types differ, or can't be forwarded.
+    pub fn bridge<A, B>(
+        &self,
+        exporter: &Instance<A>,
+        export: &str,
+        importer: &Instance<B>,
+        import: &str,
+    ) -> Result<(), RuntimeError> {
+        bridge::bridge(exporter, export, importer, import)
+    }
+
+    /// the host functions registered into the runtime, including the ones of the native
+    /// modules, to check the imports of a module before compiling it, see
+    /// `AotCompiler::host_functions()`
+    pub fn host_symbols(&self) -> Vec<HostSymbol> {
+        let mut symbols: Vec<HostSymbol> = self.host_functions.symbols().collect();
+        for entry in &self.native_modules {
+            symbols.extend(entry.host_functions().symbols());
+        }
+        symbols
+    }
+
+    /// which of the modules registered so far import from which, see `dependency`
+    #[cfg(feature = "multi-module")]
+    pub fn dependency_graph(&self) -> crate::dependency::DependencyGraph {
+        let host = self.host_functions.module_name.to_string_lossy();
+        let mut natives = vec![host.into_owned()];
+        natives.extend(self.native_modules.iter().map(|entry| entry.module_name()));
+        crate::dependency::DependencyGraph::new(&natives)
+    }
+
+    pub(crate) fn events(&self) -> &Arc<EventBus> {
+        &self.events
+    }
+
+    /// the running mode given to WAMR, `0` for its default
+    pub(crate) fn running_mode(&self) -> RunningMode {
+        self.running_mode
+    }
+
+    pub(crate) fn canonicalize_nans(&self) -> bool {
+        self.canonicalize_nans
+    }
+
+    pub(crate) fn abort_on_host_panic(&self) -> bool {
+        self.abort_on_host_panic
+    }
+
+    /// the app heap of instances which don't ask for one, `None` if it's disabled
+    pub(crate) fn app_heap(&self) -> Option<u32> {
+        self.app_heap
+    }
+
+    pub(crate) fn memory_hints(&self) -> MemoryHints {
+        self.memory_hints
+    }
+
+    /// whether the modules are instrumented to count their memory accesses
+    pub(crate) fn profiles_memory(&self) -> bool {
+        self.profile_memory
+    }
+
+    /// whether the modules are instrumented to count the instructions they execute
+    pub(crate) fn meters_fuel(&self) -> bool {
+        self.meter_fuel
+    }
+
+    /// whether the `memory.grow` of the modules is instrumented to call the host
+    pub(crate) fn hooks_memory_grow(&self) -> bool {
+        self.hook_memory_grow
+    }
+
+    /// what the modules are instrumented to record of their coverage, if anything
+    pub(crate) fn coverage_level(&self) -> Option<CoverageLevel> {
+        self.coverage
+    }
+
+    /// whether the host calls of the modules are instrumented to be recorded or replayed
+    pub(crate) fn replay_mode(&self) -> Option<ReplayMode> {
+        self.replay
+    }
+
+    /// the faults the modules are instrumented to inject into their host calls
+    pub(crate) fn faults(&self) -> &[Fault] {
+        &self.faults
+    }
+
+    /// strip the signature off an .aot, once keys are trusted, see `signature`
+    #[cfg(feature = "signed-aot")]
+    pub(crate) fn verify_aot(&self, content: Vec<u8>) -> Result<Vec<u8>, RuntimeError> {
+        match self.aot_keys.is_empty() || !signature::is_aot(&content) {
+            true => Ok(content),
+            false => signature::verify(content, &self.aot_keys),
+        }
+    }
+
+    pub(crate) fn track_module(&self) -> Dependent {
+        self.modules.track()
+    }
+}
+
+impl Runtime {
+    /// shut the runtime down, given every instance and module it has left: the instances
+    /// are dropped first, each one before the instances it bridges calls into, then the
+    /// modules, the last given first, then the runtime.
+    ///
+    /// # Error
+    ///
+    /// Return `ShutdownRefused`, with the runtime, the instances and the modules untouched,
+    /// if a module of the runtime or an instance of one of its modules is missing, or one
+    /// given belongs to another runtime.
+    pub fn shutdown<T>(
+        self,
+        mut instances: Vec<Instance<T>>,
+        mut modules: Vec<Module>,
+    ) -> Result<(), Box<ShutdownRefused<T>>> {
+        let owned = modules.len() == self.modules.count()
+            && modules.iter().all(|module| {
+                let given = instances.iter().filter(|i| i.is_of(module)).count();
+                module.is_of(&self) && given == module.instance_count()
+            })
+            && instances.iter().all(|i| modules.iter().any(|m| i.is_of(m)));
+        if !owned {
+            return Err(Box::new(ShutdownRefused {
+                runtime: self,
+                instances,
+                modules,
+            }));
+        }
+
+        while !instances.is_empty() {
+            let unused = instances.iter().position(|exporter| {
+                let exporter = exporter.get_inner_instance();
+                !instances
+                    .iter()
+                    .any(|importer| bridge::routes_to(importer.get_inner_instance(), exporter))
+            });
+            // bridged both ways, so neither goes first
+            let index = unused.unwrap_or(instances.len() - 1);
+            drop(instances.remove(index));
+        }
+        while let Some(module) = modules.pop() {
+            drop(module);
+        }
+        drop(self);
+        Ok(())
+    }
+
+    pub(crate) fn is_module(&self, module: &Dependent) -> bool {
+        module.is_of(&self.modules)
+    }
+}
+
+/// what `Runtime::shutdown()` was given, handed back since the runtime can't be shut
+/// down with them
+pub struct ShutdownRefused<T> {
+    pub runtime: Runtime,
+    pub instances: Vec<Instance<T>>,
+    pub modules: Vec<Module>,
+}
+
+impl<T> fmt::Debug for ShutdownRefused<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownRefused")
+            .field("runtime", &self.runtime)
+            .field("instances", &self.instances.len())
+            .field("modules", &self.modules)
+            .finish()
+    }
 }
 
 impl Drop for Runtime {
+    /// # Panics
+    ///
+    /// if a `Module` loaded by the runtime is still alive
     fn drop(&mut self) {
-        unsafe {
-            wasm_runtime_destroy();
+        if self.modules.release("Runtime", "Module") {
+            let mut runtimes = runtimes();
+            // another runtime may keep WAMR alive, and it mustn't call into the entries
+            for native_module in self.native_modules.iter_mut() {
+                native_module.unregister();
+            }
+            unsafe {
+                wasm_runtime_destroy();
+            }
+            if let Some(signal_handlers) = &self.signal_handlers {
+                signal_handlers.restore();
+            }
+            *runtimes -= 1;
+            if *runtimes == 0 {
+                async_host::set_executor(None);
+                allocator::set_watermarks(None, false);
+                bridge::reset();
+                crate::memory::reset();
+                replay::reset();
+                #[cfg(feature = "multi-module")]
+                crate::dependency::reset();
+                #[cfg(feature = "multi-module")]
+                crate::group::reset();
+                #[cfg(libc_wasi)]
+                crate::wasi_audit::set_recording(false);
+            }
         }
     }
 }
@@ -63,16 +406,74 @@ impl Drop for Runtime {
 pub struct RuntimeBuilder {
     args: RuntimeInitArgs,
     host_functions: HostFunctionList,
+    // boxed, since WAMR keeps the address of each entry as an attachment
+    #[allow(clippy::vec_box)]
+    native_modules: Vec<Box<NativeModuleEntry>>,
+    memory_pool: Option<Vec<u8>>,
+    restore_signal_handlers: bool,
+    canonicalize_nans: bool,
+    profile_memory: bool,
+    meter_fuel: bool,
+    hook_memory_grow: bool,
+    coverage: Option<CoverageLevel>,
+    replay: Option<ReplayMode>,
+    faults: Vec<Fault>,
+    // the first host function which failed to register, returned by `build()`
+    registration_error: Option<RuntimeError>,
+    abort_on_host_panic: bool,
+    #[cfg(libc_wasi)]
+    wasi_audit: bool,
+    allocator: AllocatorKind,
+    watermarks: Option<(usize, usize, WatermarkCallback)>,
+    executor: Option<Arc<dyn Executor>>,
+    app_heap: Option<u32>,
+    memory_hints: MemoryHints,
+    #[cfg(feature = "threads")]
+    max_threads: Option<u32>,
+    #[cfg(feature = "signed-aot")]
+    aot_keys: Vec<VerifyingKey>,
 }
 
-/// Can't build() until config allocator mode
+/// the size of the memory pool a `RuntimeBuilder` starts with, when built with the `tiny`
+/// feature
+pub const TINY_POOL_SIZE: usize = 256 * 1024;
+
+/// Can't build() until config allocator mode, except with the `tiny` feature, which starts
+/// with a pool of `TINY_POOL_SIZE` bytes
 impl Default for RuntimeBuilder {
     fn default() -> Self {
         let args = RuntimeInitArgs::default();
-        RuntimeBuilder {
+        let builder = RuntimeBuilder {
             args,
             host_functions: HostFunctionList::new("host"),
+            native_modules: Vec::new(),
+            memory_pool: None,
+            restore_signal_handlers: false,
+            canonicalize_nans: false,
+            profile_memory: false,
+            meter_fuel: false,
+            hook_memory_grow: false,
+            coverage: None,
+            replay: None,
+            faults: Vec::new(),
+            registration_error: None,
+            abort_on_host_panic: false,
+            #[cfg(libc_wasi)]
+            wasi_audit: false,
+            allocator: AllocatorKind::System,
+            watermarks: None,
+            executor: None,
+            app_heap: Some(0),
+            memory_hints: MemoryHints::default(),
+            #[cfg(feature = "threads")]
+            max_threads: None,
+            #[cfg(feature = "signed-aot")]
+            aot_keys: Vec::new(),
+        };
+        if cfg!(feature = "tiny") {
+            return builder.use_memory_pool(vec![0u8; TINY_POOL_SIZE], TINY_POOL_SIZE as u32);
         }
+        builder
     }
 }
 
@@ -81,6 +482,17 @@ impl RuntimeBuilder {
     /// allocate memory from system allocator for runtime consumed memory
     pub fn use_system_allocator(mut self) -> RuntimeBuilder {
         self.args.mem_alloc_type = mem_alloc_type_t_Alloc_With_System_Allocator;
+        self.allocator = AllocatorKind::System;
+        self
+    }
+
+    /// system allocator mode, counting allocations for `Runtime::allocator_stats()`
+    pub fn use_instrumented_allocator(mut self) -> RuntimeBuilder {
+        self.args.mem_alloc_type = mem_alloc_type_t_Alloc_With_Allocator;
+        self.args.mem_alloc_option.allocator.malloc_func = allocator::instrumented_malloc as _;
+        self.args.mem_alloc_option.allocator.realloc_func = allocator::instrumented_realloc as _;
+        self.args.mem_alloc_option.allocator.free_func = allocator::instrumented_free as _;
+        self.allocator = AllocatorKind::Instrumented;
         self
     }
 
@@ -90,6 +502,52 @@ impl RuntimeBuilder {
         self.args.mem_alloc_type = mem_alloc_type_t_Alloc_With_Pool;
         self.args.mem_alloc_option.pool.heap_buf = pool.as_mut_ptr() as *mut c_void;
         self.args.mem_alloc_option.pool.heap_size = pool_size;
+        self.memory_pool = Some(pool);
+        self.allocator = AllocatorKind::Pool;
+        self
+    }
+
+    /// call `callback` once the bytes WAMR allocated, for itself and for instances, grow
+    /// up to `high`, and then once they shrink down to `low`, and so on, for every runtime
+    /// of the process, until the last one is dropped. Like to evict idle instances before
+    /// WAMR runs out of memory. See `allocator`
+    ///
+    /// the allocations are counted by the instrumented allocator, used instead of the plain
+    /// system allocator, or by the memory pool, which is checked after each instantiation
+    /// and call. The callback may run on any thread allocating, inside WAMR, so it mustn't
+    /// call into WAMR, but rather hand the eviction over
+    pub fn memory_watermarks<F>(mut self, high: usize, low: usize, callback: F) -> RuntimeBuilder
+    where
+        F: Fn(WatermarkCrossing) + Send + Sync + 'static,
+    {
+        self.watermarks = Some((high, low, Arc::new(callback)));
+        self
+    }
+
+    /// pool allocator mode with a buffer allocated by the host, like a mmap'd or a DMA-capable
+    /// region.
+    ///
+    /// the pool is runtime-wide, not per instance: WAMR doesn't accept a host buffer as the
+    /// linear memory of a single instance. Instead, it allocates everything from the pool, its
+    /// own data and, when it is built without hardware bound checks (`no-hw-bound-check`), the
+    /// linear memories of all instances, so the host controls where they are placed. Like
+    /// every allocator option, it's ignored while another `Runtime` is alive, since WAMR is
+    /// initialized once per process.
+    ///
+    /// # Safety
+    ///
+    /// `heap_buf` must point to `heap_size` writable bytes which stay valid, and aren't
+    /// accessed by the host, until the `Runtime` is dropped.
+    pub unsafe fn use_external_memory_pool(
+        mut self,
+        heap_buf: *mut u8,
+        heap_size: u32,
+    ) -> RuntimeBuilder {
+        self.args.mem_alloc_type = mem_alloc_type_t_Alloc_With_Pool;
+        self.args.mem_alloc_option.pool.heap_buf = heap_buf as *mut c_void;
+        self.args.mem_alloc_option.pool.heap_size = heap_size;
+        self.memory_pool = None;
+        self.allocator = AllocatorKind::Pool;
         self
     }
 
@@ -107,7 +565,151 @@ impl RuntimeBuilder {
         self
     }
 
-    /// register a host function
+    /// the size of the app heap `Instance::new()` instantiates with, `Some(0)`, no heap, by
+    /// default. WAMR allocates from the app heap for the `malloc()` a `-nostdlib` module
+    /// imports from its builtin libc, and for the host, like via `Mailbox`. A WASI module
+    /// exports its own `malloc()`, and WAMR ignores the heap then.
+    ///
+    /// `None` disables the app heap entirely: instantiating a module importing an
+    /// allocator fails with `RuntimeError::AppHeapRequired`, instead of its first
+    /// `malloc()`, unless `Instance::new_with_args()` asks for a heap explicitly
+    pub fn default_app_heap(mut self, size: Option<u32>) -> RuntimeBuilder {
+        self.app_heap = size;
+        self
+    }
+
+    /// how the OS backs the linear memories of instances, see `MemoryHints`. Transparent
+    /// huge pages speed memory-bound guests up, and decommitting pages on a reset cuts
+    /// the RSS of instances kept in a pool. The hints are applied on Linux
+    pub fn memory_hints(mut self, hints: MemoryHints) -> RuntimeBuilder {
+        self.memory_hints = hints;
+        self
+    }
+
+    /// address the linear memory accesses of `flags` via a segment register in the code of
+    /// the LLVM JIT, see `SegueFlags`. Ignored elsewhere than on x86-64 Linux
+    pub fn enable_segue(mut self, flags: SegueFlags) -> RuntimeBuilder {
+        self.args.segue_flags = flags.bits();
+        self
+    }
+
+    /// enable the source debugging engine of WAMR, on the loopback interface.
+    ///
+    /// each debugged instance is served on its own port, counting up from `port`. See
+    /// `debugger::DebugController`
+    #[cfg(feature = "debug")]
+    pub fn enable_debug_engine(mut self, port: u16) -> RuntimeBuilder {
+        for (dst, src) in self.args.ip_addr.iter_mut().zip(b"127.0.0.1\0") {
+            *dst = *src as _;
+        }
+        self.args.instance_port = port as _;
+        self
+    }
+
+    /// put the handlers of `signals::owned_signals()` back as they were before the runtime,
+    /// once it is dropped, for a host handling the same signals after running guests
+    pub fn restore_signal_handlers(mut self) -> RuntimeBuilder {
+        self.restore_signal_handlers = true;
+        self
+    }
+
+    /// replace every `F32` and `F64` NaN passed to, or returned by, a call into an instance
+    /// with the canonical NaN, so hosts observe the same values on every platform.
+    /// See `WasmValue::canonicalize_nan()`
+    pub fn canonicalize_nans(mut self) -> RuntimeBuilder {
+        self.canonicalize_nans = true;
+        self
+    }
+
+    /// count the reads and writes of guests to each page of their linear memory, in the
+    /// interpreter, queried via `Instance::memory_heatmap()`. Every .wasm is instrumented
+    /// while it is loaded, which slows each access down by a host call, see
+    /// `memory_profile`
+    pub fn profile_memory_accesses(mut self) -> RuntimeBuilder {
+        self.profile_memory = true;
+        self.run_as_interpreter()
+            .register_native_module(crate::memory_profile::MemoryProfiler)
+    }
+
+    /// count the instructions guests execute as the fuel of their `ResourceAccount`. Every
+    /// .wasm is instrumented while it is loaded, which slows each run of instructions
+    /// without branches down by a host call, see `account`
+    pub fn meter_fuel(mut self) -> RuntimeBuilder {
+        self.meter_fuel = true;
+        self.register_native_module(crate::account::FuelMeter)
+    }
+
+    /// let the `memory.grow` of guests consult the grow callback of their instance and emit
+    /// a `RuntimeEvent`, like `Memory::grow()`. Every .wasm growing its memory is
+    /// instrumented while it is loaded, which turns each `memory.grow` into a host call,
+    /// see `memory`
+    pub fn hook_memory_grow(mut self) -> RuntimeBuilder {
+        self.hook_memory_grow = true;
+        self
+    }
+
+    /// record which functions of the guests ran, and with `CoverageLevel::Blocks` which
+    /// blocks, in the interpreter, queried via `Module::coverage()`. Every .wasm is
+    /// instrumented while it is loaded, see `coverage`
+    pub fn collect_coverage(mut self, level: CoverageLevel) -> RuntimeBuilder {
+        self.coverage = Some(level);
+        let builder = match level {
+            CoverageLevel::Functions => self,
+            CoverageLevel::Blocks => self.run_as_interpreter(),
+        };
+        builder.register_native_module(CoverageCollector)
+    }
+
+    /// record the host calls of guests, WASI included, during the calls made via
+    /// `Instance::record_call()`. Every .wasm is instrumented while it is loaded, which
+    /// copies the linear memory around each host call, see `replay`
+    pub fn record_host_calls(mut self) -> RuntimeBuilder {
+        self.replay = Some(ReplayMode::Record);
+        self.register_native_module(HostCallRecorder)
+    }
+
+    /// link the imported functions of guests to the host calls of a recording instead of
+    /// the host functions, to replay it via `Instance::replay()`. The host functions and
+    /// WASI don't need to be registered, see `replay`
+    pub fn replay_host_calls(mut self) -> RuntimeBuilder {
+        self.replay = Some(ReplayMode::Replay);
+        self
+    }
+
+    /// inject the faults of `plan` into the host calls of guests, WASI included, to test
+    /// how they handle failures. Every .wasm importing a function of the plan is
+    /// instrumented while it is loaded, see `fault`
+    pub fn inject_faults(mut self, plan: FaultPlan) -> RuntimeBuilder {
+        self.faults = plan.into_faults();
+        self.register_native_module(FaultInjector)
+    }
+
+    /// abort the process when a host function called by an instance of the runtime panics,
+    /// instead of trapping the instance. See `host_function::catch_panic()`
+    pub fn abort_on_host_panic(mut self) -> RuntimeBuilder {
+        self.abort_on_host_panic = true;
+        self
+    }
+
+    /// load .aot only with a valid signature by `key`, or by another trusted key.
+    /// See `signature`
+    #[cfg(feature = "signed-aot")]
+    pub fn trust_aot_key(mut self, key: VerifyingKey) -> RuntimeBuilder {
+        self.aot_keys.push(key);
+        self
+    }
+
+    /// run the futures of host functions calling `async_host::complete()` on `executor`,
+    /// for every runtime of the process, until the last one is dropped
+    pub fn set_executor<E: Executor + 'static>(mut self, executor: E) -> RuntimeBuilder {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// register a host function. Its name and its signature are checked right away, and
+    /// `build()` fails if they are invalid, see `HostFunctionList::register_host_function()`.
+    /// WAMR calls it as it is, so it's declared with `host_function!` to trap rather than
+    /// abort on a panic
     pub fn register_host_function(
         mut self,
         function_name: &str,
@@ -115,18 +717,161 @@ impl RuntimeBuilder {
         params: &[crate::host_function::ParamTy],
         result: crate::host_function::ResultTy,
     ) -> RuntimeBuilder {
-        self.host_functions
-            .register_host_function(function_name, function_ptr, params, result);
+        let registered =
+            self.host_functions
+                .register_host_function(function_name, function_ptr, params, result);
+        if let Err(error) = registered {
+            self.registration_error.get_or_insert(error);
+        }
+        self
+    }
+
+    /// register a host function with the signature derived from its type, which WAMR
+    /// calls through a trampoline catching its panics, see `TypedHostFunction`. Its name is
+    /// checked right away, and `build()` fails if it's invalid
+    pub fn register_typed_host_function<F: TypedHostFunction>(
+        mut self,
+        function_name: &str,
+        function: F,
+    ) -> RuntimeBuilder {
+        let registered = self
+            .host_functions
+            .register_typed_host_function(function_name, function);
+        if let Err(error) = registered {
+            self.registration_error.get_or_insert(error);
+        }
+        self
+    }
+
+    /// register the host functions of a WAMR symbol table, like one of C code, under
+    /// `module_name`. Their names and signatures are copied, so `symbols` may be dropped
+    /// right away, while their functions and attachments are kept as they are, so they
+    /// can't call `Caller::native_module()`. `build()` fails if one of them is invalid, see
+    /// `register_host_function()`
+    ///
+    /// # Safety
+    ///
+    /// the name and the signature of each symbol are null, or nul-terminated strings, and
+    /// each function has the signature of its symbol
+    pub unsafe fn register_native_symbols_raw(
+        mut self,
+        module_name: &str,
+        symbols: &[NativeSymbol],
+    ) -> RuntimeBuilder {
+        match NativeModuleEntry::raw(module_name, symbols) {
+            Ok(entry) => self.native_modules.push(entry),
+            Err(error) => {
+                self.registration_error.get_or_insert(error);
+            }
+        }
         self
     }
 
+    /// register all host functions of a native module, under its own module name.
+    /// `build()` fails if one of them is invalid
+    pub fn register_native_module<M: NativeModule>(mut self, module: M) -> RuntimeBuilder {
+        match NativeModuleEntry::new(module) {
+            Ok(entry) => self.native_modules.push(entry),
+            Err(error) => {
+                self.registration_error.get_or_insert(error);
+            }
+        }
+        self
+    }
+
+    /// let guests log via the `log` crate, see `host_apis::log`
+    #[cfg(feature = "host-log")]
+    pub fn with_guest_logging(self) -> RuntimeBuilder {
+        self.register_native_module(crate::host_apis::log::GuestLogger)
+    }
+
+    /// the most threads an instance runs at once, the one calling into it included, for
+    /// every instance, instead of the default of WAMR of 4. It is a global setting of WAMR,
+    /// which sizes the stacks of the threads when instantiating. See
+    /// `GuestThreads::set_quota()` to cap one instance below it
+    #[cfg(feature = "threads")]
+    pub fn max_threads(mut self, max: u32) -> RuntimeBuilder {
+        self.max_threads = Some(max);
+        self
+    }
+
+    /// let guests spawn threads via wasi-threads, see `threads`
+    #[cfg(feature = "threads")]
+    pub fn with_wasi_threads(self) -> RuntimeBuilder {
+        self.register_native_module(crate::threads::WasiThreads)
+    }
+
+    /// record the filesystem accesses of guests via WASI, see `wasi_audit`
+    #[cfg(libc_wasi)]
+    pub fn with_wasi_audit(mut self) -> RuntimeBuilder {
+        self.wasi_audit = true;
+        self.with_wasi_interposer()
+    }
+
+    /// let instances be denied WASI syscalls via `Instance::set_wasi_policy()`, see
+    /// `wasi_policy`
+    #[cfg(libc_wasi)]
+    pub fn with_wasi_policies(self) -> RuntimeBuilder {
+        self.with_wasi_interposer()
+    }
+
+    /// let files be mounted for instances via `Instance::mount_buffer()` and
+    /// `Instance::mount_memory()`, see `wasi_mount`
+    #[cfg(libc_wasi)]
+    pub fn with_wasi_mounts(self) -> RuntimeBuilder {
+        self.with_wasi_interposer()
+    }
+
+    /// the audit, the policies and the mounts share the functions wrapping libc-wasi
+    #[cfg(libc_wasi)]
+    fn with_wasi_interposer(self) -> RuntimeBuilder {
+        let registered = self.native_modules.iter().any(|native_module| {
+            native_module
+                .module()
+                .is::<crate::wasi_audit::WasiInterposer>()
+        });
+        match registered {
+            true => self,
+            false => self.register_native_module(crate::wasi_audit::WasiInterposer),
+        }
+    }
+
     /// create a `Runtime` instance with the configuration
     ///
+    /// a failed build leaves nothing behind, neither WAMR initialized nor native modules
+    /// registered, so it may be retried.
+    ///
     /// # Errors
     ///
-    /// if the runtime initialization failed, it will return `RuntimeError::InitializationFailure`
+    /// if the runtime initialization failed, it will return `RuntimeError::InitializationFailure`.
+    /// If a host function failed to register, or two native modules register different
+    /// functions under the same module and function names, it will return
+    /// `RuntimeError::HostRegistration`
     pub fn build(mut self) -> Result<Runtime, RuntimeError> {
-        match unsafe {
+        if let Some(error) = self.registration_error.take() {
+            return Err(error);
+        }
+        let natives = self
+            .native_modules
+            .iter()
+            .map(|entry| entry.host_functions());
+        let lists: Vec<_> = iter::once(&self.host_functions).chain(natives).collect();
+        for (i, list) in lists.iter().enumerate() {
+            let collision = lists[i + 1..]
+                .iter()
+                .find_map(|other| list.collision(other));
+            if let Some(name) = collision {
+                return Err(RuntimeError::HostRegistration(
+                    message!(code: "registered twice", name),
+                ));
+            }
+        }
+        if self.watermarks.is_some() && self.allocator == AllocatorKind::System {
+            self = self.use_instrumented_allocator();
+        }
+        let mut runtimes = runtimes();
+        let signal_handlers = self.restore_signal_handlers.then(SavedHandlers::save);
+        let initialized = unsafe {
             let module_name = &(self.host_functions).get_module_name();
             self.args.native_module_name = module_name.as_ptr();
 
@@ -135,12 +880,69 @@ impl RuntimeBuilder {
             self.args.native_symbols = native_symbols.as_ptr() as *mut NativeSymbol;
 
             wasm_runtime_full_init(&mut self.args)
-        } {
-            true => Ok(Runtime {
-                host_functions: self.host_functions,
-            }),
-            false => Err(RuntimeError::InitializationFailure),
+        };
+        if !initialized {
+            return Err(RuntimeError::InitializationFailure);
+        }
+
+        let registered = self
+            .native_modules
+            .iter_mut()
+            .map(|native_module| native_module.register())
+            .take_while(|registered| *registered)
+            .count();
+        if registered < self.native_modules.len() {
+            for native_module in self.native_modules[..registered].iter_mut() {
+                native_module.unregister();
+            }
+            unsafe { wasm_runtime_destroy() };
+            if let Some(signal_handlers) = &signal_handlers {
+                signal_handlers.restore();
+            }
+            return Err(RuntimeError::InitializationFailure);
+        }
+        // WAMR keeps the allocator of the runtime which initialized it
+        if *runtimes == 0 {
+            allocator::set_pooled(self.allocator == AllocatorKind::Pool);
+        }
+        *runtimes += 1;
+        if let Some(executor) = self.executor {
+            async_host::set_executor(Some(executor));
+        }
+        if let Some(watermarks) = self.watermarks {
+            allocator::set_watermarks(Some(watermarks), self.allocator == AllocatorKind::Pool);
+        }
+        #[cfg(feature = "threads")]
+        if let Some(max) = self.max_threads {
+            unsafe { wamr_sys::wasm_runtime_set_max_thread_num(max) };
         }
+        #[cfg(libc_wasi)]
+        if self.wasi_audit {
+            crate::wasi_audit::set_recording(true);
+        }
+
+        Ok(Runtime {
+            host_functions: self.host_functions,
+            native_modules: self.native_modules,
+            memory_pool: self.memory_pool,
+            events: Arc::default(),
+            modules: Dependents::default(),
+            signal_handlers,
+            canonicalize_nans: self.canonicalize_nans,
+            abort_on_host_panic: self.abort_on_host_panic,
+            profile_memory: self.profile_memory,
+            meter_fuel: self.meter_fuel,
+            hook_memory_grow: self.hook_memory_grow,
+            coverage: self.coverage,
+            replay: self.replay,
+            faults: self.faults,
+            allocator: self.allocator,
+            running_mode: self.args.running_mode,
+            app_heap: self.app_heap,
+            memory_hints: self.memory_hints,
+            #[cfg(feature = "signed-aot")]
+            aot_keys: self.aot_keys,
+        })
     }
 }
 
@@ -206,12 +1008,79 @@ mod tests {
         unsafe { wasm_runtime_free(small_buf) };
     }
 
+    #[test]
+    fn test_runtime_build_cycles() {
+        use crate::native_module::NativeExports;
+
+        struct Empty;
+
+        impl NativeModule for Empty {
+            fn module_name(&self) -> &str {
+                "empty"
+            }
+
+            fn exports(&self, _exports: &mut NativeExports) {}
+        }
+
+        for _ in 0..3 {
+            let runtime = Runtime::builder()
+                .use_system_allocator()
+                .register_native_module(Empty)
+                .build();
+            assert!(runtime.is_ok());
+            assert!(Runtime::is_initialized());
+            drop(runtime);
+        }
+    }
+
+    #[test]
+    fn test_runtime_subscribe() {
+        use crate::{event::RuntimeEvent, instance::Instance, module::Module};
+        use std::sync::Mutex;
+
+        let runtime = Runtime::new().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = events.clone();
+        runtime.subscribe(move |event| seen.lock().unwrap().push(event.clone()));
+
+        // (module
+        //   (memory (export "memory") 1)
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07,
+            0x0a, 0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "memory").unwrap();
+        let instance = Instance::new(&runtime, &module, 1024, ()).unwrap();
+        let id = instance.id();
+
+        instance.memory().grow(1).unwrap();
+        drop(instance);
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                RuntimeEvent::Instantiated {
+                    instance: id,
+                    module: String::from("memory"),
+                },
+                RuntimeEvent::MemoryGrown {
+                    instance: id,
+                    old_pages: 1,
+                    new_pages: 2,
+                },
+                RuntimeEvent::Destroyed { instance: id },
+            ]
+        );
+    }
+
     #[test]
     #[cfg(feature = "llvmjit")]
     #[ignore]
     fn test_runtime_builder_llvm_jit() {
         let runtime = Runtime::builder()
             .run_as_llvm_jit(3, 3)
+            .enable_segue(SegueFlags::all())
             .use_system_allocator()
             .build();
         assert!(runtime.is_ok());
@@ -0,0 +1,191 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a sampler thread, shared by the whole process, which terminates calls running over
+//! their time limit, in wall-clock or in CPU time. used by
+//! `Function::call_with_time_limit()` and `Function::call_with_cpu_time_limit()`
+
+use std::{
+    sync::{Condvar, Mutex, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use wamr_sys::{wasm_module_inst_t, wasm_runtime_terminate};
+
+use crate::platform::CpuClock;
+
+/// when a deadline expires
+enum Expiry {
+    /// at an instant of the wall clock
+    Wall(Instant),
+    /// once the CPU clock of the calling thread reaches a time
+    Cpu(CpuClock, Duration),
+}
+
+impl Expiry {
+    /// the wall-clock time left before the deadline expires, at least. A thread can't use
+    /// its CPU faster than the wall clock goes, so it's the CPU time left for `Cpu`
+    fn remaining(&self, now: Instant) -> Duration {
+        match self {
+            Expiry::Wall(at) => at.saturating_duration_since(now),
+            Expiry::Cpu(clock, at) => at.saturating_sub(clock.now()),
+        }
+    }
+}
+
+struct Deadline {
+    id: u64,
+    expiry: Expiry,
+    instance: usize,
+}
+
+#[derive(Default)]
+struct SamplerState {
+    next_id: u64,
+    armed: Vec<Deadline>,
+    expired: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Sampler {
+    state: Mutex<SamplerState>,
+    wakeup: Condvar,
+}
+
+impl Sampler {
+    fn run(&self, terminate: impl Fn(usize)) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let (expired, armed): (Vec<_>, Vec<_>) = state
+                .armed
+                .drain(..)
+                .partition(|deadline| deadline.expiry.remaining(now).is_zero());
+            state.armed = armed;
+            for deadline in expired {
+                terminate(deadline.instance);
+                state.expired.push(deadline.id);
+            }
+
+            let remaining = state
+                .armed
+                .iter()
+                .map(|deadline| deadline.expiry.remaining(now));
+            state = match remaining.min() {
+                Some(remaining) => self.wakeup.wait_timeout(state, remaining).unwrap().0,
+                None => self.wakeup.wait(state).unwrap(),
+            };
+        }
+    }
+
+    fn arm(&self, instance: usize, expiry: Expiry) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.armed.push(Deadline {
+            id,
+            expiry,
+            instance,
+        });
+        self.wakeup.notify_one();
+        id
+    }
+
+    /// return whether the deadline expired before it was disarmed
+    fn disarm(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.armed.retain(|deadline| deadline.id != id);
+        match state.expired.iter().position(|expired| *expired == id) {
+            Some(position) => {
+                state.expired.swap_remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn sampler() -> &'static Sampler {
+    static SAMPLER: OnceLock<&'static Sampler> = OnceLock::new();
+    SAMPLER.get_or_init(|| {
+        let sampler: &'static Sampler = Box::leak(Box::default());
+        thread::Builder::new()
+            .name(String::from("wamr-time-limit"))
+            .spawn(move || {
+                sampler.run(|instance| unsafe {
+                    wasm_runtime_terminate(instance as wasm_module_inst_t)
+                })
+            })
+            .expect("failed to spawn the time limit sampler");
+        sampler
+    })
+}
+
+/// terminate `instance` once `limit` elapsed, until `disarm()`
+pub(crate) fn arm(instance: wasm_module_inst_t, limit: Duration) -> u64 {
+    sampler().arm(instance as usize, Expiry::Wall(Instant::now() + limit))
+}
+
+/// terminate `instance` once the thread of `clock` used `limit` of CPU time, until
+/// `disarm()`, which must come before the thread exits
+pub(crate) fn arm_cpu_time(instance: wasm_module_inst_t, clock: CpuClock, limit: Duration) -> u64 {
+    let at = clock.now().saturating_add(limit);
+    sampler().arm(instance as usize, Expiry::Cpu(clock, at))
+}
+
+/// return whether the instance has been terminated
+pub(crate) fn disarm(id: u64) -> bool {
+    sampler().disarm(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_sampler() {
+        let sampler: &'static Sampler = Box::leak(Box::default());
+        let (terminated, terminations) = mpsc::channel();
+        thread::spawn(move || sampler.run(move |instance| terminated.send(instance).unwrap()));
+
+        let slow = sampler.arm(1, Expiry::Wall(Instant::now() + Duration::from_secs(60)));
+        let quick = sampler.arm(2, Expiry::Wall(Instant::now() + Duration::from_millis(10)));
+        assert_eq!(
+            terminations.recv_timeout(Duration::from_secs(5)).unwrap(),
+            2
+        );
+
+        assert!(!sampler.disarm(slow));
+        assert!(sampler.disarm(quick));
+        assert!(!sampler.disarm(quick));
+        assert!(terminations
+            .recv_timeout(Duration::from_millis(50))
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sampler_cpu_time() {
+        let sampler: &'static Sampler = Box::leak(Box::default());
+        let (terminated, terminations) = mpsc::channel();
+        thread::spawn(move || sampler.run(move |instance| terminated.send(instance).unwrap()));
+
+        let clock = CpuClock::current().unwrap();
+        let at = clock.now() + Duration::from_millis(20);
+        let deadline = sampler.arm(1, Expiry::Cpu(clock, at));
+
+        // sleeping doesn't use CPU time
+        thread::sleep(Duration::from_millis(60));
+        assert!(terminations.try_recv().is_err());
+
+        let spinning = Instant::now();
+        while terminations.try_recv().is_err() {
+            assert!(spinning.elapsed() < Duration::from_secs(5));
+        }
+        assert!(sampler.disarm(deadline));
+    }
+}
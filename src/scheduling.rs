@@ -0,0 +1,194 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! scheduling hints for the threads running the calls of an instance, like to keep a
+//! latency-sensitive guest off the cores of batch ones. Set them via
+//! `Instance::set_scheduling_hints()`
+//!
+//! a call runs on the thread calling `Function::call()`, whichever pool it belongs to, so
+//! the hints are applied to that thread for the duration of each call, and the previous
+//! affinity and nice level are restored after it. Lowering the nice level back after a
+//! call made nicer takes `CAP_SYS_NICE`, or a high enough `RLIMIT_NICE`, otherwise the
+//! thread stays nicer.
+//!
+//! the hints are applied on Linux, and ignored on other platforms.
+
+use crate::RuntimeError;
+
+/// where and how eagerly the calls of an instance are scheduled
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedulingHints {
+    cores: Option<Vec<usize>>,
+    nice: Option<i32>,
+}
+
+impl SchedulingHints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// run the calls only on `cores`, numbered like by `sched_setaffinity()`
+    pub fn pin_to_cores(mut self, cores: &[usize]) -> Self {
+        self.cores = Some(cores.to_vec());
+        self
+    }
+
+    /// run the calls at the nice level `nice`, from -20, the most favorable, to 19
+    pub fn nice(mut self, nice: i32) -> Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// apply the hints to the current thread, until the returned guard is dropped
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the thread can't be given the hints, like
+    /// a core out of range or a nice level below the one allowed.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn apply(&self) -> Result<AppliedHints, RuntimeError> {
+        let failed = |error: std::io::Error| {
+            RuntimeError::ExecutionError(message!(
+                "failed to apply the scheduling hints: {}",
+                error
+            ))
+        };
+
+        let mut applied = AppliedHints {
+            cores: None,
+            nice: None,
+        };
+        if let Some(cores) = &self.cores {
+            let previous = linux::affinity().map_err(failed)?;
+            linux::set_affinity(&linux::core_set(cores).map_err(failed)?).map_err(failed)?;
+            applied.cores = Some(previous);
+        }
+        if let Some(nice) = self.nice {
+            let previous = linux::nice().map_err(failed)?;
+            // the affinity set above is restored once `applied` is dropped
+            linux::set_nice(nice).map_err(failed)?;
+            applied.nice = Some(previous);
+        }
+        Ok(applied)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn apply(&self) -> Result<AppliedHints, RuntimeError> {
+        Ok(AppliedHints {})
+    }
+}
+
+/// the hints applied to the current thread, with what they replaced
+pub(crate) struct AppliedHints {
+    #[cfg(target_os = "linux")]
+    cores: Option<libc::cpu_set_t>,
+    #[cfg(target_os = "linux")]
+    nice: Option<i32>,
+}
+
+impl Drop for AppliedHints {
+    fn drop(&mut self) {
+        // best effort, a thread may not be allowed to lower its nice level back
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(nice) = self.nice {
+                let _ = linux::set_nice(nice);
+            }
+            if let Some(cores) = &self.cores {
+                let _ = linux::set_affinity(cores);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{io, mem};
+
+    /// the affinity of the current thread
+    pub(super) fn affinity() -> io::Result<libc::cpu_set_t> {
+        let mut cores: libc::cpu_set_t = unsafe { mem::zeroed() };
+        match unsafe { libc::sched_getaffinity(0, mem::size_of_val(&cores), &mut cores) } {
+            0 => Ok(cores),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn set_affinity(cores: &libc::cpu_set_t) -> io::Result<()> {
+        match unsafe { libc::sched_setaffinity(0, mem::size_of_val(cores), cores) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn core_set(cores: &[usize]) -> io::Result<libc::cpu_set_t> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &core in cores {
+            if core >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("core {} out of range", core),
+                ));
+            }
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+        Ok(set)
+    }
+
+    /// nice levels are per thread on Linux
+    fn thread_id() -> libc::id_t {
+        unsafe { libc::syscall(libc::SYS_gettid) as libc::id_t }
+    }
+
+    /// the nice level of the current thread
+    pub(super) fn nice() -> io::Result<i32> {
+        // -1 is a valid nice level, told apart from a failure by errno
+        unsafe { *libc::__errno_location() = 0 };
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, thread_id()) };
+        match io::Error::last_os_error().raw_os_error() {
+            Some(0) => Ok(nice),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    pub(super) fn set_nice(nice: i32) -> io::Result<()> {
+        match unsafe { libc::setpriority(libc::PRIO_PROCESS, thread_id(), nice) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scheduling_hints() {
+        let before = linux::affinity().unwrap();
+        let core = (0..libc::CPU_SETSIZE as usize)
+            .find(|&core| unsafe { libc::CPU_ISSET(core, &before) })
+            .unwrap();
+        let nice = linux::nice().unwrap();
+
+        let hints = SchedulingHints::new()
+            .pin_to_cores(&[core])
+            .nice((nice + 1).min(19));
+        let applied = hints.apply().unwrap();
+        let pinned = linux::affinity().unwrap();
+        assert_eq!(unsafe { libc::CPU_COUNT(&pinned) }, 1);
+        assert!(unsafe { libc::CPU_ISSET(core, &pinned) });
+        assert_eq!(linux::nice().unwrap(), (nice + 1).min(19));
+
+        drop(applied);
+        assert!(unsafe { libc::CPU_EQUAL(&linux::affinity().unwrap(), &before) });
+
+        let out_of_range = SchedulingHints::new().pin_to_cores(&[libc::CPU_SETSIZE as usize]);
+        assert!(matches!(
+            out_of_range.apply(),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+    }
+}
@@ -0,0 +1,87 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the signal handlers WAMR installs to turn out of bounds accesses of guest code into
+//! traps, for hosts handling the same signals themselves, like for a garbage collector.
+//!
+//! WAMR passes faults outside of guest code on to the handler installed before it. A
+//! handler installed after the runtime has to do the same with the one it replaces. Build
+//! with the `no-hw-bound-check` feature to keep WAMR away from signals altogether, at the
+//! cost of a bounds check on every memory access. See also
+//! `RuntimeBuilder::restore_signal_handlers()`.
+
+use std::{ffi::c_int, fmt};
+
+/// the signals WAMR installs a handler for, in this build
+pub fn owned_signals() -> &'static [c_int] {
+    #[cfg(all(unix, target_pointer_width = "64", not(feature = "no-hw-bound-check")))]
+    return &[libc::SIGSEGV, libc::SIGBUS];
+
+    #[cfg(not(all(unix, target_pointer_width = "64", not(feature = "no-hw-bound-check"))))]
+    return &[];
+}
+
+/// the handlers of the owned signals, as they were before the runtime
+pub(crate) struct SavedHandlers {
+    #[cfg(unix)]
+    handlers: Vec<(c_int, libc::sigaction)>,
+}
+
+impl SavedHandlers {
+    #[cfg(unix)]
+    pub(crate) fn save() -> Self {
+        let handlers = owned_signals()
+            .iter()
+            .map(|&signal| {
+                let mut handler: libc::sigaction = unsafe { std::mem::zeroed() };
+                unsafe { libc::sigaction(signal, std::ptr::null(), &mut handler) };
+                (signal, handler)
+            })
+            .collect();
+        SavedHandlers { handlers }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn restore(&self) {
+        for (signal, handler) in &self.handlers {
+            unsafe { libc::sigaction(*signal, handler, std::ptr::null_mut()) };
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn save() -> Self {
+        SavedHandlers {}
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn restore(&self) {}
+}
+
+impl fmt::Debug for SavedHandlers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SavedHandlers")
+            .field("signals", &owned_signals())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_saved_handlers() {
+        #[cfg(all(target_pointer_width = "64", not(feature = "no-hw-bound-check")))]
+        assert!(owned_signals().contains(&libc::SIGSEGV));
+
+        let saved = SavedHandlers::save();
+        saved.restore();
+        for (signal, handler) in &SavedHandlers::save().handlers {
+            let (_, before) = saved.handlers.iter().find(|(s, _)| s == signal).unwrap();
+            assert_eq!(handler.sa_sigaction, before.sa_sigaction);
+        }
+    }
+}
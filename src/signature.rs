@@ -0,0 +1,97 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! signed .aot artifacts, for deployments shipping precompiled code to devices. Once a
+//! key is trusted via `RuntimeBuilder::trust_aot_key()`, an .aot only loads with a valid
+//! signature by one of the trusted keys. .wasm is loaded as usual, since WAMR validates it.
+//!
+//! a signed artifact is the .aot, followed by its ed25519 signature, 64 bytes, and by
+//! `SIGNATURE_MAGIC`. Sign one via `signature::sign()`, and the signature is stripped
+//! before WAMR loads it.
+
+use ed25519_dalek::{Signature, Signer, Verifier, SIGNATURE_LENGTH};
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+use crate::RuntimeError;
+
+/// the end of a signed artifact
+pub const SIGNATURE_MAGIC: [u8; 8] = *b"wamrsig1";
+
+const AOT_MAGIC: &[u8] = b"\0aot";
+
+pub(crate) fn is_aot(content: &[u8]) -> bool {
+    content.starts_with(AOT_MAGIC)
+}
+
+/// `content` followed by its signature by `key`
+pub fn sign(content: &[u8], key: &SigningKey) -> Vec<u8> {
+    let signature = key.sign(content);
+    let mut signed = Vec::with_capacity(content.len() + SIGNATURE_LENGTH + SIGNATURE_MAGIC.len());
+    signed.extend_from_slice(content);
+    signed.extend_from_slice(&signature.to_bytes());
+    signed.extend_from_slice(&SIGNATURE_MAGIC);
+    signed
+}
+
+/// strip the signature off `signed`, after checking it is valid for one of `keys`
+///
+/// # Error
+///
+/// Return `RuntimeError::CompilationError` if there is no signature, or it isn't valid
+/// for any of `keys`.
+pub fn verify(mut signed: Vec<u8>, keys: &[VerifyingKey]) -> Result<Vec<u8>, RuntimeError> {
+    let trailer = SIGNATURE_LENGTH + SIGNATURE_MAGIC.len();
+    if signed.len() < trailer || !signed.ends_with(&SIGNATURE_MAGIC) {
+        return Err(RuntimeError::CompilationError(message!(
+            "the .aot isn't signed",
+        )));
+    }
+
+    let length = signed.len() - trailer;
+    let mut signature = [0u8; SIGNATURE_LENGTH];
+    signature.copy_from_slice(&signed[length..length + SIGNATURE_LENGTH]);
+    let signature = Signature::from_bytes(&signature);
+    signed.truncate(length);
+    match keys
+        .iter()
+        .any(|key| key.verify(&signed, &signature).is_ok())
+    {
+        true => Ok(signed),
+        false => Err(RuntimeError::CompilationError(message!(
+            "the signature of the .aot isn't valid for any trusted key",
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let other = SigningKey::from_bytes(&[8; 32]);
+        let aot = b"\0aot\x03\0\0\0 precompiled code".to_vec();
+        assert!(is_aot(&aot));
+
+        let signed = sign(&aot, &key);
+        assert!(is_aot(&signed));
+        assert_eq!(verify(signed.clone(), &[key.verifying_key()]).unwrap(), aot);
+        assert_eq!(
+            verify(
+                signed.clone(),
+                &[other.verifying_key(), key.verifying_key()]
+            )
+            .unwrap(),
+            aot
+        );
+        assert!(verify(signed.clone(), &[other.verifying_key()]).is_err());
+
+        let mut tampered = signed;
+        tampered[10] ^= 1;
+        assert!(verify(tampered, &[key.verifying_key()]).is_err());
+        assert!(verify(aot, &[key.verifying_key()]).is_err());
+    }
+}
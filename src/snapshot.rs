@@ -0,0 +1,311 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the state of an instance as bytes, to persist a guest across restarts of the host, like
+//! the steps of a durable workflow. Take one via `Instance::serialize_state()`, and restore
+//! it into a new instance of the same module via `Instance::restore_state()`
+//!
+//! the state is the linear memory and the mutable exported globals. WAMR offers no access
+//! to the frames of a running call, nor to the globals a module doesn't export, so a state
+//! is taken and restored between calls only, when there are no frames to lose. Globals
+//! like `__stack_pointer` are back to their initial values by then.
+
+use std::{
+    ffi::{CStr, CString},
+    mem, ptr,
+};
+
+use wamr_sys::{
+    wasm_export_t, wasm_global_inst_t, wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_GLOBAL,
+    wasm_runtime_get_export_count, wasm_runtime_get_export_global_inst,
+    wasm_runtime_get_export_type, wasm_runtime_get_module, wasm_valkind_enum_WASM_F32,
+    wasm_valkind_enum_WASM_F64, wasm_valkind_enum_WASM_I32, wasm_valkind_enum_WASM_I64,
+    wasm_valkind_enum_WASM_V128,
+};
+
+use crate::{
+    binary::{write_name, write_u32_leb, Reader},
+    instance::Instance,
+    memory::WASM_PAGE_SIZE,
+    ErrorMessage, RuntimeError,
+};
+
+const SNAPSHOT_MAGIC: [u8; 4] = [0x00, 0x73, 0x6e, 0x70];
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// the size in bytes of a global of `kind`, `None` for references, which can't be
+/// persisted
+#[allow(non_upper_case_globals)]
+fn value_size(kind: u32) -> Option<usize> {
+    match kind {
+        wasm_valkind_enum_WASM_I32 | wasm_valkind_enum_WASM_F32 => Some(4),
+        wasm_valkind_enum_WASM_I64 | wasm_valkind_enum_WASM_F64 => Some(8),
+        wasm_valkind_enum_WASM_V128 => Some(16),
+        _ => None,
+    }
+}
+
+/// the names of the globals `instance` exports
+fn exported_globals<T>(instance: &Instance<T>) -> Vec<String> {
+    let module = unsafe { wasm_runtime_get_module(instance.get_inner_instance()) };
+    let count = unsafe { wasm_runtime_get_export_count(module) };
+    (0..count)
+        .filter_map(|index| {
+            let mut export: wasm_export_t = unsafe { mem::zeroed() };
+            unsafe { wasm_runtime_get_export_type(module, index, &mut export) };
+            match export.kind == wasm_import_export_kind_t_WASM_IMPORT_EXPORT_KIND_GLOBAL {
+                true if !export.name.is_null() => Some(
+                    unsafe { CStr::from_ptr(export.name) }
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn export_global<T>(instance: &Instance<T>, name: &str) -> Option<wasm_global_inst_t> {
+    let name = CString::new(name).ok()?;
+    let mut global = wasm_global_inst_t {
+        kind: 0,
+        is_mutable: false,
+        global_data: ptr::null_mut(),
+    };
+    let found = unsafe {
+        wasm_runtime_get_export_global_inst(
+            instance.get_inner_instance(),
+            name.as_ptr(),
+            &mut global,
+        )
+    };
+    found.then_some(global)
+}
+
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if a call into `instance` is running, or
+/// `RuntimeError::TypeMismatch` if a mutable exported global holds a reference.
+pub(crate) fn serialize<T>(instance: &Instance<T>) -> Result<Vec<u8>, RuntimeError> {
+    if instance.is_running() {
+        return Err(RuntimeError::ExecutionError(message!(
+            "can't take the state of an instance during a call",
+        )));
+    }
+
+    let mut state = SNAPSHOT_MAGIC.to_vec();
+    write_u32_leb(&mut state, SNAPSHOT_VERSION);
+
+    let memory = instance.memory();
+    let pages = memory.pages();
+    write_u32_leb(&mut state, pages);
+    let start = state.len();
+    state.resize(start + pages as usize * WASM_PAGE_SIZE, 0);
+    memory.read(0, &mut state[start..])?;
+
+    let mut globals = Vec::new();
+    for name in exported_globals(instance) {
+        let Some(global) = export_global(instance, &name) else {
+            continue;
+        };
+        // the others keep the values they are instantiated with
+        if !global.is_mutable {
+            continue;
+        }
+        let size = value_size(global.kind as u32).ok_or_else(|| {
+            RuntimeError::TypeMismatch(message!("global {} holds a reference", name))
+        })?;
+        let value = unsafe { std::slice::from_raw_parts(global.global_data as *const u8, size) };
+        globals.push((name, global.kind, value));
+    }
+    write_u32_leb(&mut state, globals.len() as u32);
+    for (name, kind, value) in globals {
+        write_name(&mut state, &name);
+        state.push(kind);
+        state.extend_from_slice(value);
+    }
+
+    Ok(state)
+}
+
+/// # Error
+///
+/// Return `RuntimeError::ExecutionError` if a call into `instance` is running or `state`
+/// is malformed, `RuntimeError::MemoryAccessError` if the linear memory is larger than in
+/// `state` or can't grow to its size, or `RuntimeError::TypeMismatch` if a global of
+/// `state` isn't a mutable exported global of the same type.
+pub(crate) fn restore<T>(instance: &Instance<T>, state: &[u8]) -> Result<(), RuntimeError> {
+    if instance.is_running() {
+        return Err(RuntimeError::ExecutionError(message!(
+            "can't restore the state of an instance during a call",
+        )));
+    }
+    let malformed =
+        |e: ErrorMessage| RuntimeError::ExecutionError(message!("malformed state: {}", e));
+
+    let mut reader = Reader::new(state);
+    if reader.read_bytes(SNAPSHOT_MAGIC.len()).map_err(malformed)? != SNAPSHOT_MAGIC {
+        return Err(malformed(message!("not an instance state")));
+    }
+    let version = reader.read_u32_leb().map_err(malformed)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(malformed(message!("unsupported version {}", version)));
+    }
+
+    let pages = reader.read_u32_leb().map_err(malformed)?;
+    let data = reader
+        .read_bytes(pages as usize * WASM_PAGE_SIZE)
+        .map_err(malformed)?;
+
+    // read every global before changing anything
+    let count = reader.read_u32_leb().map_err(malformed)?;
+    let mut globals = Vec::new();
+    for _ in 0..count {
+        let name = reader.read_name().map_err(malformed)?;
+        let kind = reader.read_u8().map_err(malformed)?;
+        let size = value_size(kind as u32)
+            .ok_or_else(|| malformed(message!("global {} of unknown type", name)))?;
+        let value = reader.read_bytes(size).map_err(malformed)?;
+        let global = export_global(instance, name)
+            .filter(|global| global.is_mutable && global.kind == kind)
+            .ok_or_else(|| {
+                RuntimeError::TypeMismatch(message!(
+                    "global {} isn't a mutable exported global of the same type",
+                    name
+                ))
+            })?;
+        globals.push((global, value));
+    }
+    if !reader.is_empty() {
+        return Err(malformed(message!(
+            "trailing bytes at offset {}",
+            reader.position()
+        )));
+    }
+
+    let memory = instance.memory();
+    let current = memory.pages();
+    if current > pages {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "the linear memory has {} pages, more than the {} of the state",
+            current,
+            pages
+        )));
+    }
+    if current < pages {
+        memory.grow(pages - current)?;
+    }
+    match instance.memory_hints().decommit_on_reset {
+        true => memory.reset(data)?,
+        false => memory.write(0, data)?,
+    }
+
+    for (global, value) in globals {
+        unsafe {
+            ptr::copy_nonoverlapping(value.as_ptr(), global.global_data as *mut u8, value.len())
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        function::Function,
+        instance::Instance,
+        memory::{MemoryHints, WASM_PAGE_SIZE},
+        module::Module,
+        runtime::Runtime,
+        value::WasmValue,
+        RuntimeError,
+    };
+
+    // (module
+    //   (memory (export "memory") 1)
+    //   (global $counter (export "counter") (mut i32) (i32.const 0))
+    //   (func (export "bump") (result i32)
+    //     (global.set $counter (i32.add (global.get $counter) (i32.const 1)))
+    //     (i32.store (i32.const 0) (global.get $counter))
+    //     (global.get $counter)
+    //   )
+    // )
+    const BINARY: [u8; 83] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7f,
+        0x03, 0x02, 0x01, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x06, 0x06, 0x01, 0x7f, 0x01, 0x41,
+        0x00, 0x0b, 0x07, 0x1b, 0x03, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x07,
+        0x63, 0x6f, 0x75, 0x6e, 0x74, 0x65, 0x72, 0x03, 0x00, 0x04, 0x62, 0x75, 0x6d, 0x70, 0x00,
+        0x00, 0x0a, 0x14, 0x01, 0x12, 0x00, 0x23, 0x00, 0x41, 0x01, 0x6a, 0x24, 0x00, 0x41, 0x00,
+        0x23, 0x00, 0x36, 0x02, 0x00, 0x23, 0x00, 0x0b,
+    ];
+
+    #[test]
+    fn test_snapshot() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        let module = Module::from_buf(&runtime, &BINARY, "snapshot")?;
+        let bump = |instance: &Instance<()>| {
+            Function::find_export_func(instance, "bump")?.call(instance, &vec![])
+        };
+
+        let state = {
+            let instance = Instance::new(&runtime, &module, 1024, ())?;
+            bump(&instance)?;
+            bump(&instance)?;
+            instance.memory().grow(1)?;
+            instance.serialize_state()?
+        };
+
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        instance.restore_state(&state)?;
+        assert_eq!(instance.memory().pages(), 2);
+        let mut stored = [0; 4];
+        instance.memory().read(0, &mut stored)?;
+        assert_eq!(u32::from_le_bytes(stored), 2);
+        assert_eq!(bump(&instance)?, WasmValue::I32(3));
+
+        // can't shrink back to one page
+        let smaller = Instance::new(&runtime, &module, 1024, ())?.serialize_state()?;
+        assert!(matches!(
+            instance.restore_state(&smaller),
+            Err(RuntimeError::MemoryAccessError(_))
+        ));
+        assert!(matches!(
+            instance.restore_state(&state[..state.len() - 1]),
+            Err(RuntimeError::ExecutionError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_decommit_on_reset() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .memory_hints(MemoryHints {
+                huge_pages: true,
+                decommit_on_reset: true,
+            })
+            .build()?;
+        let module = Module::from_buf(&runtime, &BINARY, "snapshot")?;
+
+        let instance = Instance::new(&runtime, &module, 1024, ())?;
+        Function::find_export_func(&instance, "bump")?.call(&instance, &vec![])?;
+        instance.memory().grow(1)?;
+        let state = instance.serialize_state()?;
+
+        instance.memory().write(64, &[0xaa; 64])?;
+        instance
+            .memory()
+            .write(WASM_PAGE_SIZE as u64, &[0xaa; WASM_PAGE_SIZE])?;
+        instance.restore_state(&state)?;
+
+        let mut stored = vec![0; 2 * WASM_PAGE_SIZE];
+        instance.memory().read(0, &mut stored)?;
+        assert_eq!(&stored[..4], 1u32.to_le_bytes());
+        assert!(stored[4..].iter().all(|byte| *byte == 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! where the content of a module comes from.
+//! pass one to `Module::from_source()`
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::RuntimeError;
+
+/// a source of .wasm or .aot content.
+///
+/// implement it to plug in a custom loader, like a cache or a package registry.
+pub trait ModuleSource {
+    /// the name given to the loaded module
+    fn name(&self) -> String;
+
+    /// fetch the whole content of the module
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::WasmFileFSError` if the content can't be fetched.
+    fn load(&self) -> Result<Vec<u8>, RuntimeError>;
+}
+
+/// a file on disk, named after its file name
+impl ModuleSource for Path {
+    fn name(&self) -> String {
+        self.file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    fn load(&self) -> Result<Vec<u8>, RuntimeError> {
+        Ok(fs::read(self)?)
+    }
+}
+
+impl ModuleSource for PathBuf {
+    fn name(&self) -> String {
+        self.as_path().name()
+    }
+
+    fn load(&self) -> Result<Vec<u8>, RuntimeError> {
+        self.as_path().load()
+    }
+}
+
+/// module content embedded in the host binary, like via `include_bytes!()`
+pub struct EmbeddedSource<'a> {
+    name: &'a str,
+    content: &'a [u8],
+}
+
+impl<'a> EmbeddedSource<'a> {
+    pub fn new(name: &'a str, content: &'a [u8]) -> Self {
+        EmbeddedSource { name, content }
+    }
+}
+
+impl ModuleSource for EmbeddedSource<'_> {
+    fn name(&self) -> String {
+        String::from(self.name)
+    }
+
+    fn load(&self) -> Result<Vec<u8>, RuntimeError> {
+        Ok(self.content.to_vec())
+    }
+}
+
+/// a module downloaded via HTTP(S), named after the last segment of the URL
+#[cfg(feature = "http")]
+pub struct HttpSource {
+    url: String,
+}
+
+#[cfg(feature = "http")]
+impl HttpSource {
+    pub fn new(url: &str) -> Self {
+        HttpSource {
+            url: String::from(url),
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl ModuleSource for HttpSource {
+    fn name(&self) -> String {
+        let path = self.url.split(['?', '#']).next().unwrap_or_default();
+        String::from(path.rsplit('/').next().unwrap_or_default())
+    }
+
+    fn load(&self) -> Result<Vec<u8>, RuntimeError> {
+        use std::io::{self, Read};
+
+        let response = ureq::get(&self.url).call().map_err(io::Error::other)?;
+        let mut content = Vec::new();
+        response.into_reader().read_to_end(&mut content)?;
+        Ok(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_source() {
+        let mut d = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        d.push("resources/test");
+        d.push("gcd_wasm32_wasi.wasm");
+
+        assert_eq!(d.name(), "gcd_wasm32_wasi.wasm");
+        let content = d.load().unwrap();
+        assert_eq!(&content[0..4], b"\0asm");
+
+        let missing = Path::new("not_exist");
+        assert!(matches!(
+            missing.load(),
+            Err(RuntimeError::WasmFileFSError(_))
+        ));
+    }
+
+    #[test]
+    fn test_embedded_source() {
+        let source = EmbeddedSource::new("empty", b"\0asm\x01\0\0\0");
+        assert_eq!(source.name(), "empty");
+        assert_eq!(source.load().unwrap().len(), 8);
+    }
+
+    #[test]
+    #[cfg(feature = "http")]
+    fn test_http_source_name() {
+        let source = HttpSource::new("https://example.com/plugins/gcd.wasm?v=2");
+        assert_eq!(source.name(), "gcd.wasm");
+    }
+}
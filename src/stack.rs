@@ -0,0 +1,219 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! estimate the wasm stack a module needs, from its function bodies.
+//! get one via `Module::suggested_stack_size()`
+//!
+//! a call takes a frame of the wasm stack, sized by the locals and the operands of the
+//! function. The estimate follows the deepest chain of direct calls. Indirect calls are
+//! assumed to reach the largest frame of the module, and recursion to go
+//! `RECURSION_DEPTH` calls deep with it.
+
+use crate::{
+    binary::{self, Reader},
+    instruction, ErrorMessage,
+};
+
+/// the bookkeeping of WAMR for a frame, in bytes
+const FRAME_HEADER_SIZE: u64 = 64;
+/// a local or an operand, in bytes. 8 fits all but v128
+const SLOT_SIZE: u64 = 8;
+/// a block, loop or if on the control stack of a frame, in bytes
+const BLOCK_SIZE: u64 = 32;
+/// the operands of a frame, beyond the ones of its blocks
+const BASE_OPERANDS: u64 = 8;
+/// the operands assumed for each nested block
+const OPERANDS_PER_BLOCK: u64 = 4;
+/// how many calls deep recursion is assumed to go
+pub const RECURSION_DEPTH: u64 = 100;
+/// the smallest stack suggested
+pub const MIN_SUGGESTED_STACK_SIZE: u32 = 4 * 1024;
+/// the largest stack suggested, even if recursion could go deeper
+pub const MAX_SUGGESTED_STACK_SIZE: u32 = 1024 * 1024;
+
+enum Call {
+    Direct(u32),
+    Indirect,
+}
+
+struct CallGraph {
+    imported: u32,
+    frames: Vec<u64>,
+    calls: Vec<Vec<Call>>,
+}
+
+impl CallGraph {
+    fn new(binary: &[u8]) -> Result<Self, ErrorMessage> {
+        let types = binary::type_param_counts(binary)?;
+        let functions = binary::function_types(binary)?;
+        let imported = binary::imported_function_count(binary)?;
+
+        let mut graph = CallGraph {
+            imported,
+            frames: Vec::new(),
+            calls: Vec::new(),
+        };
+        for (defined, body) in binary::function_bodies(binary)?.into_iter().enumerate() {
+            let params = functions
+                .get(imported as usize + defined)
+                .and_then(|type_index| types.get(*type_index as usize))
+                .ok_or_else(|| message!("function {} has no type", imported as usize + defined))?;
+            let locals = local_count(&binary[body.clone()])?;
+
+            let mut blocks = 0u64;
+            let mut max_blocks = 0;
+            let mut calls = Vec::new();
+            for instruction in instruction::decode_body(binary, body)? {
+                match instruction.opcode {
+                    // block, loop, if, try and try_table
+                    0x02..=0x04 | 0x06 | 0x1f => {
+                        blocks += 1;
+                        max_blocks = max_blocks.max(blocks);
+                    }
+                    0x0b => blocks = blocks.saturating_sub(1),
+                    // call and return_call
+                    0x10 | 0x12 => {
+                        let index = Reader::new(&instruction.bytes[1..]).read_u32_leb()?;
+                        calls.push(Call::Direct(index));
+                    }
+                    // call_indirect, return_call_indirect, call_ref and return_call_ref
+                    0x11 | 0x13 | 0x14 | 0x15 => calls.push(Call::Indirect),
+                    _ => {}
+                }
+            }
+
+            let operands = BASE_OPERANDS + OPERANDS_PER_BLOCK * max_blocks;
+            graph.frames.push(
+                FRAME_HEADER_SIZE
+                    + SLOT_SIZE * (*params as u64 + locals + operands)
+                    + BLOCK_SIZE * (max_blocks + 1),
+            );
+            graph.calls.push(calls);
+        }
+        Ok(graph)
+    }
+
+    fn largest_frame(&self) -> u64 {
+        self.frames.iter().copied().max().unwrap_or(0)
+    }
+
+    /// the stack taken by the deepest chain of calls from `function`, a defined one, and
+    /// whether the chain recurses
+    fn deepest(
+        &self,
+        function: usize,
+        depths: &mut [Option<u64>],
+        visiting: &mut [bool],
+    ) -> (u64, bool) {
+        if let Some(depth) = depths[function] {
+            return (depth, false);
+        }
+        if visiting[function] {
+            return (0, true);
+        }
+
+        visiting[function] = true;
+        let mut callees = 0;
+        let mut recursive = false;
+        for call in &self.calls[function] {
+            let depth = match call {
+                // host functions run on the native stack
+                Call::Direct(index) if *index < self.imported => 0,
+                Call::Direct(index) => {
+                    let callee = (*index - self.imported) as usize;
+                    if callee >= self.frames.len() {
+                        continue;
+                    }
+                    let (depth, recurses) = self.deepest(callee, depths, visiting);
+                    recursive |= recurses;
+                    depth
+                }
+                Call::Indirect => self.largest_frame(),
+            };
+            callees = callees.max(depth);
+        }
+        visiting[function] = false;
+
+        let depth = self.frames[function] + callees;
+        depths[function] = Some(depth);
+        (depth, recursive)
+    }
+}
+
+/// the number of locals declared by a function body, parameters excluded
+fn local_count(body: &[u8]) -> Result<u64, ErrorMessage> {
+    let mut reader = Reader::new(body);
+    let mut locals = 0u64;
+    let groups = reader.read_u32_leb()?;
+    for _ in 0..groups {
+        locals += reader.read_u32_leb()? as u64;
+        binary::skip_valtype(&mut reader)?;
+    }
+    Ok(locals)
+}
+
+/// a stack size for a .wasm, in bytes, rounded up to whole KB
+pub(crate) fn suggested_stack_size(binary: &[u8]) -> Result<u32, ErrorMessage> {
+    let graph = CallGraph::new(binary)?;
+    let mut depths = vec![None; graph.frames.len()];
+    let mut visiting = vec![false; graph.frames.len()];
+
+    let mut deepest = 0;
+    let mut recursive = false;
+    for function in 0..graph.frames.len() {
+        let (depth, recurses) = graph.deepest(function, &mut depths, &mut visiting);
+        deepest = deepest.max(depth);
+        recursive |= recurses;
+    }
+    if recursive {
+        deepest += RECURSION_DEPTH * graph.largest_frame();
+    }
+
+    let size = deepest.div_ceil(1024) * 1024;
+    Ok(size.clamp(
+        MIN_SUGGESTED_STACK_SIZE as u64,
+        MAX_SUGGESTED_STACK_SIZE as u64,
+    ) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggested_stack_size() {
+        // (module
+        //   (func (export "add") (param i32 i32) (result i32)
+        //     (local.get 0)
+        //     (local.get 1)
+        //     (i32.add)
+        //   )
+        // )
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x07, 0x01, 0x60, 0x02, 0x7f,
+            0x7f, 0x01, 0x7f, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x61, 0x64, 0x64,
+            0x00, 0x00, 0x0a, 0x09, 0x01, 0x07, 0x00, 0x20, 0x00, 0x20, 0x01, 0x6a, 0x0b,
+        ];
+        assert_eq!(suggested_stack_size(&binary), Ok(MIN_SUGGESTED_STACK_SIZE));
+
+        // (module
+        //   (func $fac (export "fac") (param i64) (result i64) (local i64 i64 i64 i64)
+        //     (if (result i64) (i64.eqz (local.get 0))
+        //       (then (i64.const 1))
+        //       (else (i64.mul (local.get 0) (call $fac (i64.sub (local.get 0) (i64.const 1)))))
+        //     )
+        //   )
+        // )
+        let recursive = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x7e,
+            0x01, 0x7e, 0x03, 0x02, 0x01, 0x00, 0x07, 0x07, 0x01, 0x03, 0x66, 0x61, 0x63, 0x00,
+            0x00, 0x0a, 0x19, 0x01, 0x17, 0x01, 0x04, 0x7e, 0x20, 0x00, 0x50, 0x04, 0x7e, 0x42,
+            0x01, 0x05, 0x20, 0x00, 0x20, 0x00, 0x42, 0x01, 0x7d, 0x10, 0x00, 0x7e, 0x0b, 0x0b,
+        ];
+        let frame = FRAME_HEADER_SIZE + SLOT_SIZE * (5 + 12) + BLOCK_SIZE * 2;
+        let expected = (frame * (RECURSION_DEPTH + 1)).div_ceil(1024) * 1024;
+        assert_eq!(suggested_stack_size(&recursive), Ok(expected as u32));
+    }
+}
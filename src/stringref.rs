@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! the strings of the stringref proposal, which GC languages pass instead of a pointer and
+//! a length into the linear memory. Pass one as a `WasmValue::StringRef`, created via
+//! `StringRef::new()`, and read one returned by an export via `GcRef::as_string_ref()`
+//!
+//! WAMR only ships a stub of the string objects. The `stringref` feature builds the one
+//! `WAMR_STRINGREF_IMPL_SOURCE` points at, see `wamr-sys`. Like other GC objects, a string
+//! is only valid until the instance runs again, so create one right before the call it is
+//! a parameter of
+
+use std::{ffi::c_void, fmt, ptr};
+
+use wamr_sys::{wasm_obj_t, wasm_stringref_obj_get_value, wasm_stringref_obj_new};
+
+use crate::{instance::Instance, RuntimeError};
+
+/// `EncodingFlag` of *string_object.h*
+const WTF16: u32 = 2;
+
+// the string objects of *string_object.h*, which aren't part of the public headers
+extern "C" {
+    fn wasm_string_new_with_encoding(addr: *mut c_void, count: u32, flag: u32) -> *mut c_void;
+    fn wasm_string_measure(str_obj: *mut c_void, flag: u32) -> i32;
+    fn wasm_string_encode(
+        str_obj: *mut c_void,
+        pos: u32,
+        count: u32,
+        addr: *mut c_void,
+        next_pos: *mut u32,
+        flag: u32,
+    ) -> i32;
+    // from *gc_object.h*
+    fn wasm_obj_is_stringref_obj(obj: wasm_obj_t) -> bool;
+}
+
+/// whether `object` is a `stringref`
+pub(crate) fn is_stringref_obj(object: wasm_obj_t) -> bool {
+    unsafe { wasm_obj_is_stringref_obj(object) }
+}
+
+/// a `stringref` of an instance
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StringRef(usize);
+
+impl StringRef {
+    /// a string of `instance`, with the content of `value`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the string can't be created.
+    pub fn new<T>(instance: &Instance<T>, value: &str) -> Result<Self, RuntimeError> {
+        let mut units: Vec<u16> = value.encode_utf16().collect();
+        let object = unsafe {
+            let string = wasm_string_new_with_encoding(
+                units.as_mut_ptr() as *mut c_void,
+                units.len() as u32,
+                WTF16,
+            );
+            if string.is_null() {
+                ptr::null_mut()
+            } else {
+                wasm_stringref_obj_new(instance.exec_env().as_raw(), string)
+            }
+        };
+        match object.is_null() {
+            true => Err(RuntimeError::ExecutionError(message!(
+                "failed to create a string",
+            ))),
+            false => Ok(StringRef(object as usize)),
+        }
+    }
+
+    pub(crate) fn from_obj(object: wasm_obj_t) -> Self {
+        StringRef(object as usize)
+    }
+
+    /// the address of the object, as a call passes it
+    pub(crate) fn address(&self) -> usize {
+        self.0
+    }
+
+    /// the content of the string
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the string can't be read, or
+    /// `RuntimeError::TypeMismatch` if it has isolated surrogates, which Rust strings can't
+    /// hold.
+    pub fn to_string(&self) -> Result<String, RuntimeError> {
+        let string = unsafe { wasm_stringref_obj_get_value(self.0 as _) } as *mut c_void;
+        let len = unsafe { wasm_string_measure(string, WTF16) };
+        let mut units = vec![0u16; len.max(0) as usize];
+        let mut next = 0;
+        let written = unsafe {
+            wasm_string_encode(
+                string,
+                0,
+                units.len() as u32,
+                units.as_mut_ptr() as *mut c_void,
+                &mut next,
+                WTF16,
+            )
+        };
+        if len < 0 || written < 0 {
+            return Err(RuntimeError::ExecutionError(message!(
+                "failed to read a string",
+            )));
+        }
+        String::from_utf16(&units)
+            .map_err(|_| RuntimeError::TypeMismatch(message!("a string with isolated surrogates")))
+    }
+}
+
+impl fmt::Debug for StringRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StringRef({:#x})", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function,
+        module::Module,
+        runtime::Runtime,
+        value::{ValueType, WasmValue},
+    };
+
+    #[test]
+    fn test_stringref() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+
+        // (module (func (export "id") (param stringref) (result stringref) (local.get 0)))
+        let binary = [
+            0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x06, 0x01, 0x60, 0x01, 0x67,
+            0x01, 0x67, 0x03, 0x02, 0x01, 0x00, 0x07, 0x06, 0x01, 0x02, 0x69, 0x64, 0x00, 0x00,
+            0x0a, 0x06, 0x01, 0x04, 0x00, 0x20, 0x00, 0x0b,
+        ];
+        let module = Module::from_buf(&runtime, &binary, "stringref")?;
+        let mut instance = Instance::new(&runtime, &module, 1024 * 64, ())?;
+
+        let hello = StringRef::new(&instance, "héllo 🦀")?;
+        assert_eq!(WasmValue::StringRef(hello).ty(), ValueType::StringRef);
+
+        let id = Function::find_export_func(&instance, "id")?;
+        let result = id
+            .call_gc(&mut instance, &[WasmValue::StringRef(hello)])?
+            .unwrap();
+        assert_eq!(result.kind(), crate::gc::GcKind::String);
+        let string = result.as_string_ref().unwrap();
+        assert_eq!(string, hello);
+        assert_eq!(string.to_string()?, "héllo 🦀");
+
+        let empty = StringRef::new(&instance, "")?;
+        assert_eq!(empty.to_string()?, "");
+        Ok(())
+    }
+}
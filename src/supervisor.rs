@@ -0,0 +1,239 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! an instance which is instantiated again after it traps, behind a stable `call()`.
+//! get one via `Supervisor::new()`
+
+use std::{thread, time::Duration};
+
+use crate::{
+    function::Function, instance::Instance, memory::WASM_PAGE_SIZE, module::Module,
+    runtime::Runtime, value::WasmValue, RuntimeError,
+};
+
+/// how a `Supervisor` restarts its instance
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    /// the number of restarts allowed, until `Supervisor::reset_restarts()`
+    pub max_restarts: u32,
+    /// the delay before the first restart, doubled for each following one
+    pub backoff: Duration,
+    /// the longest delay before a restart
+    pub max_backoff: Duration,
+    /// restore the memory snapshot taken via `Supervisor::take_snapshot()` into a restarted
+    /// instance
+    pub restore_snapshot: bool,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            restore_snapshot: false,
+        }
+    }
+}
+
+/// owns a module and the instance running it.
+///
+/// when a call traps, the instance is dropped, and a fresh one is instantiated, with new
+/// user data, before the next call. The call which trapped still returns its error.
+pub struct Supervisor<'a, T> {
+    // declared before the module, to be dropped first
+    instance: Option<Instance<T>>,
+    runtime: &'a Runtime,
+    module: Module,
+    stack_size: u32,
+    data: Box<dyn Fn() -> T>,
+    policy: RestartPolicy,
+    restarts: u32,
+    snapshot: Option<Vec<u8>>,
+}
+
+impl<'a, T: 'static> Supervisor<'a, T> {
+    /// instantiate `module` with stack size and the user data returned by `data`
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::InstantiationFailure` if failed.
+    pub fn new<F>(
+        runtime: &'a Runtime,
+        module: Module,
+        stack_size: u32,
+        data: F,
+        policy: RestartPolicy,
+    ) -> Result<Self, RuntimeError>
+    where
+        F: Fn() -> T + 'static,
+    {
+        let instance = Instance::new(runtime, &module, stack_size, data())?;
+        Ok(Supervisor {
+            instance: Some(instance),
+            runtime,
+            module,
+            stack_size,
+            data: Box::new(data),
+            policy,
+            restarts: 0,
+            snapshot: None,
+        })
+    }
+
+    /// execute an export function of the current instance, restarting it first if the
+    /// previous call trapped
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::ExecutionError` if the call traps.
+    /// Return `RuntimeError::LimitExceeded` if the instance can't be restarted anymore.
+    pub fn call(&mut self, name: &str, params: &[WasmValue]) -> Result<WasmValue, RuntimeError> {
+        let instance = self.instance()?;
+        let function = Function::find_export_func(instance, name)?;
+
+        let result = function.call_args(instance, params);
+        if let Err(RuntimeError::ExecutionError(_)) = result {
+            self.instance = None;
+        }
+        result
+    }
+
+    /// the current instance, restarting it if the previous call trapped
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::LimitExceeded` if the instance can't be restarted anymore.
+    pub fn instance(&mut self) -> Result<&Instance<T>, RuntimeError> {
+        if self.instance.is_none() {
+            self.restart()?;
+        }
+        Ok(self.instance.as_ref().unwrap())
+    }
+
+    /// the number of restarts so far
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// allow `RestartPolicy::max_restarts` more restarts
+    pub fn reset_restarts(&mut self) {
+        self.restarts = 0;
+    }
+
+    /// copy the linear memory of the current instance, to restore it into restarted ones
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::LimitExceeded` if the instance can't be restarted anymore.
+    pub fn take_snapshot(&mut self) -> Result<(), RuntimeError> {
+        let memory = self.instance()?.memory();
+        let mut snapshot = vec![0u8; memory.data_size()];
+        memory.read(0, &mut snapshot)?;
+        self.snapshot = Some(snapshot);
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<(), RuntimeError> {
+        if self.restarts >= self.policy.max_restarts {
+            return Err(RuntimeError::LimitExceeded(message!(
+                "instance restarted {} times already",
+                self.restarts
+            )));
+        }
+
+        let backoff = self
+            .policy
+            .backoff
+            .saturating_mul(1 << self.restarts.min(31))
+            .min(self.policy.max_backoff);
+        thread::sleep(backoff);
+        self.restarts += 1;
+
+        let instance = Instance::new(self.runtime, &self.module, self.stack_size, (self.data)())?;
+        if let (true, Some(snapshot)) = (self.policy.restore_snapshot, &self.snapshot) {
+            let memory = instance.memory();
+            let missing = snapshot.len().saturating_sub(memory.data_size());
+            if missing > 0 {
+                memory.grow(missing.div_ceil(WASM_PAGE_SIZE) as u32)?;
+            }
+            memory.write(0, snapshot)?;
+        }
+        self.instance = Some(instance);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (module
+    //   (memory (export "memory") 1)
+    //   (func (export "trap") unreachable)
+    //   (func (export "load") (result i32) (i32.load (i32.const 0)))
+    //   (func (export "store") (param i32) (i32.store (i32.const 0) (local.get 0)))
+    // )
+    const SUPERVISED_MODULE: [u8; 92] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x03, 0x60, 0x00, 0x00, 0x60,
+        0x00, 0x01, 0x7f, 0x60, 0x01, 0x7f, 0x00, 0x03, 0x04, 0x03, 0x00, 0x01, 0x02, 0x05, 0x03,
+        0x01, 0x00, 0x01, 0x07, 0x20, 0x04, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+        0x04, 0x74, 0x72, 0x61, 0x70, 0x00, 0x00, 0x04, 0x6c, 0x6f, 0x61, 0x64, 0x00, 0x01, 0x05,
+        0x73, 0x74, 0x6f, 0x72, 0x65, 0x00, 0x02, 0x0a, 0x17, 0x03, 0x03, 0x00, 0x00, 0x0b, 0x07,
+        0x00, 0x41, 0x00, 0x28, 0x02, 0x00, 0x0b, 0x09, 0x00, 0x41, 0x00, 0x20, 0x00, 0x36, 0x02,
+        0x00, 0x0b,
+    ];
+
+    #[test]
+    fn test_supervisor_restart() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+        let module = Module::from_buf(&runtime, &SUPERVISED_MODULE, "supervised")?;
+        let policy = RestartPolicy {
+            max_restarts: 1,
+            backoff: Duration::ZERO,
+            ..RestartPolicy::default()
+        };
+        let mut supervisor = Supervisor::new(&runtime, module, 1024, || (), policy)?;
+
+        supervisor.call("store", &[WasmValue::I32(7)])?;
+        assert!(supervisor.call("trap", &[]).is_err());
+
+        // a fresh instance, with a fresh memory
+        assert_eq!(supervisor.call("load", &[])?, WasmValue::I32(0));
+        assert_eq!(supervisor.restarts(), 1);
+
+        assert!(supervisor.call("trap", &[]).is_err());
+        assert!(matches!(
+            supervisor.call("load", &[]),
+            Err(RuntimeError::LimitExceeded(_))
+        ));
+
+        supervisor.reset_restarts();
+        assert_eq!(supervisor.call("load", &[])?, WasmValue::I32(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_supervisor_snapshot() -> Result<(), RuntimeError> {
+        let runtime = Runtime::new()?;
+        let module = Module::from_buf(&runtime, &SUPERVISED_MODULE, "supervised")?;
+        let policy = RestartPolicy {
+            backoff: Duration::ZERO,
+            restore_snapshot: true,
+            ..RestartPolicy::default()
+        };
+        let mut supervisor = Supervisor::new(&runtime, module, 1024, || (), policy)?;
+
+        supervisor.call("store", &[WasmValue::I32(7)])?;
+        supervisor.take_snapshot()?;
+        supervisor.call("store", &[WasmValue::I32(8)])?;
+        assert!(supervisor.call("trap", &[]).is_err());
+
+        assert_eq!(supervisor.call("load", &[])?, WasmValue::I32(7));
+
+        Ok(())
+    }
+}
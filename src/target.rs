@@ -0,0 +1,292 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! what a module has been loaded from, bytecode or precompiled code, and the target the
+//! code has been compiled for, so deployment tooling can check an artifact against a
+//! device before shipping it. Get them via `Module::kind()` and `Module::target_info()`
+//!
+//! an .aot starts with its target info section, as written by wamrc. An .aot compiled
+//! with `--xip`, to execute in place from flash, is told apart by its object type.
+
+use crate::binary::Reader;
+use crate::ErrorMessage;
+
+const WASM_MAGIC: &[u8] = b"\0asm";
+const AOT_MAGIC: &[u8] = b"\0aot";
+
+/// the section of an .aot describing its target
+const AOT_SECTION_TARGET_INFO: u32 = 0;
+/// the section of an .aot with the machine code
+const AOT_SECTION_TEXT: u32 = 2;
+
+// the object types of an .aot
+const E_TYPE_XIP: u16 = 4;
+
+// the object formats of an .aot
+const BIN_TYPE_ELF32L: u16 = 0;
+const BIN_TYPE_ELF32B: u16 = 1;
+const BIN_TYPE_ELF64L: u16 = 2;
+const BIN_TYPE_ELF64B: u16 = 3;
+const BIN_TYPE_COFF32: u16 = 4;
+const BIN_TYPE_COFF64: u16 = 6;
+
+// the machines of ELF
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_XTENSA: u16 = 94;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+/// what a module has been loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModuleKind {
+    /// a .wasm, interpreted or compiled by the runtime
+    Wasm,
+    /// an .aot, precompiled by wamrc
+    Aot,
+    /// an .aot precompiled to execute in place, from read-only memory
+    Xip,
+}
+
+impl ModuleKind {
+    /// the kind of `content`, `None` if it is neither a .wasm nor an .aot
+    pub fn of(content: &[u8]) -> Option<Self> {
+        match content {
+            content if content.starts_with(WASM_MAGIC) => Some(ModuleKind::Wasm),
+            content if content.starts_with(AOT_MAGIC) => match TargetInfo::parse(content) {
+                Ok(info) if info.object_type == E_TYPE_XIP => Some(ModuleKind::Xip),
+                _ => Some(ModuleKind::Aot),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// the features an .aot has been compiled with, which the runtime has to support
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AotFeatures {
+    /// 128-bit SIMD
+    pub simd: bool,
+    pub bulk_memory: bool,
+    /// shared memories and atomic instructions
+    pub threads: bool,
+    pub reference_types: bool,
+    /// struct and array types
+    pub gc: bool,
+    pub exception_handling: bool,
+    /// frames without the details needed by the debugger or the call stack dump
+    pub tiny_stack_frame: bool,
+    pub multi_memory: bool,
+}
+
+impl AotFeatures {
+    fn from_flags(flags: u64) -> Self {
+        let has = |bit: u32| flags & (1 << bit) != 0;
+        AotFeatures {
+            simd: has(0),
+            bulk_memory: has(1),
+            threads: has(2),
+            reference_types: has(3),
+            gc: has(4),
+            exception_handling: has(5),
+            tiny_stack_frame: has(6),
+            multi_memory: has(7),
+        }
+    }
+}
+
+/// the target an .aot has been compiled for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetInfo {
+    /// the version of the .aot format
+    pub version: u32,
+    /// the architecture as given to wamrc, like `x86_64`, `aarch64v8` or `thumbv7em`
+    pub arch: String,
+    /// the object format, see `bits()` and `is_little_endian()`
+    pub binary_type: u16,
+    pub abi_type: u16,
+    /// the object type, relocatable, or to execute in place
+    pub object_type: u16,
+    /// the machine, as in ELF
+    pub machine: u16,
+    /// the flags of the machine, as in ELF, like the float ABI of ARM
+    pub machine_flags: u32,
+    /// the raw flags of `features`, including the ones not decoded
+    pub feature_flags: u64,
+    pub features: AotFeatures,
+}
+
+impl TargetInfo {
+    /// the target info section of the .aot `content`
+    ///
+    /// # Error
+    ///
+    /// Return a description of what is wrong if `content` isn't an .aot starting with its
+    /// target info.
+    pub fn parse(content: &[u8]) -> Result<Self, ErrorMessage> {
+        let mut reader = Reader::new(content);
+        if reader.read_bytes(AOT_MAGIC.len())? != AOT_MAGIC {
+            return Err(message!("not an .aot"));
+        }
+        let version = read_u32(&mut reader)?;
+        let section = read_u32(&mut reader)?;
+        if section != AOT_SECTION_TARGET_INFO {
+            return Err(message!("section {} instead of the target info", section));
+        }
+        let size = read_u32(&mut reader)?;
+        let mut section = Reader::new(reader.read_bytes(size as usize)?);
+
+        let binary_type = read_u16(&mut section)?;
+        let abi_type = read_u16(&mut section)?;
+        let object_type = read_u16(&mut section)?;
+        let machine = read_u16(&mut section)?;
+        let _object_version = read_u32(&mut section)?;
+        let machine_flags = read_u32(&mut section)?;
+        let feature_flags = read_u64(&mut section)?;
+        let _reserved = read_u64(&mut section)?;
+        let arch = section.read_bytes(16)?;
+        let arch = arch.split(|byte| *byte == 0).next().unwrap_or_default();
+
+        Ok(TargetInfo {
+            version,
+            arch: String::from_utf8_lossy(arch).into_owned(),
+            binary_type,
+            abi_type,
+            object_type,
+            machine,
+            machine_flags,
+            feature_flags,
+            features: AotFeatures::from_flags(feature_flags),
+        })
+    }
+
+    /// the width of the pointers, 32 or 64, `None` for an unknown object format
+    pub fn bits(&self) -> Option<u32> {
+        match self.binary_type {
+            BIN_TYPE_ELF32L | BIN_TYPE_ELF32B | BIN_TYPE_COFF32 => Some(32),
+            BIN_TYPE_ELF64L | BIN_TYPE_ELF64B | BIN_TYPE_COFF64 => Some(64),
+            _ => None,
+        }
+    }
+
+    pub fn is_little_endian(&self) -> bool {
+        !matches!(self.binary_type, BIN_TYPE_ELF32B | BIN_TYPE_ELF64B)
+    }
+
+    /// whether the code has been compiled for the machine the process is running on. The
+    /// CPU features beyond the architecture, and the ABI, aren't checked
+    pub fn matches_host(&self) -> bool {
+        let machine = match std::env::consts::ARCH {
+            "x86" => EM_386,
+            "x86_64" => EM_X86_64,
+            "arm" => EM_ARM,
+            "aarch64" => EM_AARCH64,
+            "mips" | "mips64" => EM_MIPS,
+            "riscv32" | "riscv64" => EM_RISCV,
+            "xtensa" => EM_XTENSA,
+            _ => return false,
+        };
+        self.machine == machine
+            && self.bits() == Some(usize::BITS)
+            && self.is_little_endian() == cfg!(target_endian = "little")
+    }
+}
+
+pub(crate) fn is_aot(content: &[u8]) -> bool {
+    content.starts_with(AOT_MAGIC)
+}
+
+/// the size of the text section of the .aot `content`, `None` if it isn't an .aot
+pub(crate) fn aot_text_size(content: &[u8]) -> Option<u64> {
+    let mut reader = Reader::new(content);
+    if reader.read_bytes(AOT_MAGIC.len()).ok()? != AOT_MAGIC {
+        return None;
+    }
+    let _version = read_u32(&mut reader).ok()?;
+    let mut size = 0;
+    while !reader.is_empty() {
+        let section = read_u32(&mut reader).ok()?;
+        let section_size = read_u32(&mut reader).ok()?;
+        reader.read_bytes(section_size as usize).ok()?;
+        if section == AOT_SECTION_TEXT {
+            size += section_size as u64;
+        }
+    }
+    Some(size)
+}
+
+fn read_u16(reader: &mut Reader) -> Result<u16, ErrorMessage> {
+    Ok(u16::from_le_bytes(
+        reader.read_bytes(2)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u32(reader: &mut Reader) -> Result<u32, ErrorMessage> {
+    Ok(u32::from_le_bytes(
+        reader.read_bytes(4)?.try_into().unwrap(),
+    ))
+}
+
+fn read_u64(reader: &mut Reader) -> Result<u64, ErrorMessage> {
+    Ok(u64::from_le_bytes(
+        reader.read_bytes(8)?.try_into().unwrap(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aot(object_type: u16, machine: u16, arch: &str) -> Vec<u8> {
+        let mut target_info = Vec::new();
+        target_info.extend_from_slice(&BIN_TYPE_ELF64L.to_le_bytes());
+        target_info.extend_from_slice(&0u16.to_le_bytes());
+        target_info.extend_from_slice(&object_type.to_le_bytes());
+        target_info.extend_from_slice(&machine.to_le_bytes());
+        target_info.extend_from_slice(&1u32.to_le_bytes());
+        target_info.extend_from_slice(&0u32.to_le_bytes());
+        target_info.extend_from_slice(&0b101u64.to_le_bytes());
+        target_info.extend_from_slice(&0u64.to_le_bytes());
+        let mut name = [0; 16];
+        name[..arch.len()].copy_from_slice(arch.as_bytes());
+        target_info.extend_from_slice(&name);
+
+        let mut content = AOT_MAGIC.to_vec();
+        content.extend_from_slice(&3u32.to_le_bytes());
+        content.extend_from_slice(&AOT_SECTION_TARGET_INFO.to_le_bytes());
+        content.extend_from_slice(&(target_info.len() as u32).to_le_bytes());
+        content.extend_from_slice(&target_info);
+        content
+    }
+
+    #[test]
+    fn test_target_info() {
+        let wasm = [0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00];
+        assert_eq!(ModuleKind::of(&wasm), Some(ModuleKind::Wasm));
+        assert_eq!(ModuleKind::of(b"ELF"), None);
+        assert!(TargetInfo::parse(&wasm).is_err());
+
+        let content = aot(1, EM_AARCH64, "aarch64v8");
+        assert_eq!(ModuleKind::of(&content), Some(ModuleKind::Aot));
+        let info = TargetInfo::parse(&content).unwrap();
+        assert_eq!(info.version, 3);
+        assert_eq!(info.arch, "aarch64v8");
+        assert_eq!(info.machine, EM_AARCH64);
+        assert_eq!(info.bits(), Some(64));
+        assert!(info.is_little_endian());
+        assert!(info.features.simd && info.features.threads);
+        assert!(!info.features.bulk_memory);
+        assert_eq!(
+            info.matches_host(),
+            cfg!(all(target_arch = "aarch64", target_endian = "little"))
+        );
+
+        let xip = aot(E_TYPE_XIP, EM_X86_64, "x86_64");
+        assert_eq!(ModuleKind::of(&xip), Some(ModuleKind::Xip));
+        assert!(TargetInfo::parse(&xip[..40]).is_err());
+    }
+}
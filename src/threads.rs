@@ -0,0 +1,498 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! threads spawned by guests via wasi-threads, visible to the host.
+//! let guests spawn them via `RuntimeBuilder::with_wasi_threads()`, and get them via
+//! `Instance::threads()`
+//!
+//! guests import, from the `wasi` module:
+//! - `thread-spawn(start_arg: i32) -> i32`. It returns the id of the new thread, or `-1`
+//!   if it couldn't be spawned. The thread calls the `wasi_thread_start(id, start_arg)`
+//!   export, on an instance of its own sharing the memory of the spawner.
+//!
+//! a thread belongs to the instance its spawner descends from, which terminates and joins
+//! the threads left once dropped. Host functions called by a thread borrow the host data
+//! of that instance, as with its own calls. `GuestThreads::set_quota()` caps the threads of an
+//! instance, and `RuntimeBuilder::max_threads()` the ones of every instance.
+
+use std::{
+    ffi::c_void,
+    fmt,
+    marker::PhantomData,
+    ptr,
+    sync::{
+        atomic::{AtomicI32, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use wamr_sys::{
+    wasm_exec_env_t, wasm_module_inst_t, wasm_runtime_call_wasm, wasm_runtime_clear_exception,
+    wasm_runtime_get_module_inst, wasm_runtime_get_user_data, wasm_runtime_join_thread,
+    wasm_runtime_lookup_function, wasm_runtime_set_user_data, wasm_runtime_spawn_thread,
+    wasm_runtime_terminate,
+};
+
+use crate::{
+    event::InstanceId,
+    host_function::catch_panic,
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+};
+
+/// the ids of wasi-threads are positive
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+
+/// how far a thread got
+#[derive(Default)]
+struct Progress {
+    /// `wasm_runtime_spawn_thread()` returned, so the handle is known, if it succeeded
+    spawned: bool,
+    /// the thread returned, or never started
+    finished: bool,
+}
+
+impl Progress {
+    fn is_done(&self) -> bool {
+        self.spawned && self.finished
+    }
+}
+
+struct ThreadState {
+    id: i32,
+    /// the `wasm_thread_t` of WAMR, `0` until spawned or if the spawn failed
+    handle: AtomicUsize,
+    progress: Mutex<Progress>,
+    done: Condvar,
+}
+
+impl ThreadState {
+    fn progress(&self) -> MutexGuard<'_, Progress> {
+        self.progress.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn spawned(&self) {
+        self.progress().spawned = true;
+        self.done.notify_all();
+    }
+
+    fn finish(&self) {
+        self.progress().finished = true;
+        self.done.notify_all();
+    }
+
+    /// whether the thread finished, and can be joined
+    fn is_finished(&self) -> bool {
+        self.progress().is_done()
+    }
+
+    /// return whether the thread finished before `deadline`, wait forever without one
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut progress = self.progress();
+        while !progress.is_done() {
+            progress = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    let (progress, _) = self
+                        .done
+                        .wait_timeout(progress, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    progress
+                }
+                None => self
+                    .done
+                    .wait(progress)
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+        }
+        true
+    }
+}
+
+/// the most threads an instance may have, see `GuestThreads::set_quota()`
+#[derive(Clone)]
+pub struct ThreadQuota {
+    max: usize,
+    on_exceeded: Option<Arc<dyn Fn(InstanceId) + Send + Sync>>,
+}
+
+impl ThreadQuota {
+    pub fn new(max: usize) -> Self {
+        ThreadQuota {
+            max,
+            on_exceeded: None,
+        }
+    }
+
+    /// call `hook` with the instance whenever its guest asks for a thread over the quota,
+    /// on the thread of the guest asking
+    pub fn on_exceeded<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(InstanceId) + Send + Sync + 'static,
+    {
+        self.on_exceeded = Some(Arc::new(hook));
+        self
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+}
+
+impl fmt::Debug for ThreadQuota {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThreadQuota")
+            .field("max", &self.max)
+            .finish_non_exhaustive()
+    }
+}
+
+struct Registry {
+    /// the threads not joined yet, with the address of the instance they belong to
+    threads: Vec<(usize, Arc<ThreadState>)>,
+    /// the address of the instance of each running thread, with the one it belongs to
+    owners: Vec<(usize, usize)>,
+    /// the quotas of instances, by their address
+    quotas: Vec<(usize, ThreadQuota)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    threads: Vec::new(),
+    owners: Vec::new(),
+    quotas: Vec::new(),
+});
+
+fn registry() -> MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// the threads spawned from an instance, and from its threads
+pub struct GuestThreads<'a> {
+    instance: wasm_module_inst_t,
+    _instance: PhantomData<&'a ()>,
+}
+
+/// a thread spawned by a guest, until joined
+#[derive(Clone)]
+pub struct GuestThread {
+    state: Arc<ThreadState>,
+}
+
+impl GuestThread {
+    /// the id the guest got from `thread-spawn`
+    pub fn id(&self) -> i32 {
+        self.state.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+}
+
+impl<'a> GuestThreads<'a> {
+    pub(crate) fn new(instance: wasm_module_inst_t) -> Self {
+        GuestThreads {
+            instance,
+            _instance: PhantomData,
+        }
+    }
+
+    /// the threads not joined yet, finished or not
+    pub fn list(&self) -> Vec<GuestThread> {
+        registry()
+            .threads
+            .iter()
+            .filter(|(owner, _)| *owner == self.instance as usize)
+            .map(|(_, state)| GuestThread {
+                state: state.clone(),
+            })
+            .collect()
+    }
+
+    /// cap the threads of the instance, `None` for no cap. A guest asking for a thread
+    /// over the quota gets `-1` from `thread-spawn`. The threads not joined yet count,
+    /// finished or not, since they hold on to their stack and instance until joined
+    pub fn set_quota(&self, quota: Option<ThreadQuota>) {
+        let mut registry = registry();
+        registry
+            .quotas
+            .retain(|(instance, _)| *instance != self.instance as usize);
+        if let Some(quota) = quota {
+            registry.quotas.push((self.instance as usize, quota));
+        }
+    }
+
+    pub fn quota(&self) -> Option<ThreadQuota> {
+        registry()
+            .quotas
+            .iter()
+            .find(|(instance, _)| *instance == self.instance as usize)
+            .map(|(_, quota)| quota.clone())
+    }
+
+    /// wait for the threads to finish, until `timeout` elapsed, and join the finished ones.
+    /// return whether they all finished
+    pub fn join_all(&self, timeout: Duration) -> bool {
+        self.join(Some(Instant::now() + timeout))
+    }
+
+    /// terminate the threads, like by a trap, and join them. A call running in the
+    /// instance itself is terminated as well
+    pub fn terminate(&self) {
+        if self.list().is_empty() {
+            return;
+        }
+        // WAMR spreads the termination over every instance sharing the memory
+        unsafe { wasm_runtime_terminate(self.instance) };
+        self.join(None);
+        unsafe { wasm_runtime_clear_exception(self.instance) };
+    }
+
+    fn join(&self, deadline: Option<Instant>) -> bool {
+        let mut all_finished = true;
+        for thread in self.list() {
+            if !thread.state.wait(deadline) {
+                all_finished = false;
+                continue;
+            }
+
+            registry()
+                .threads
+                .retain(|(_, state)| !Arc::ptr_eq(state, &thread.state));
+            // a thread whose spawn failed has nothing to join
+            let handle = thread.state.handle.load(Ordering::Acquire);
+            if handle != 0 {
+                let mut retval = ptr::null_mut();
+                unsafe { wasm_runtime_join_thread(handle as _, &mut retval) };
+            }
+        }
+        all_finished
+    }
+}
+
+/// the `wasi` native module, with `thread-spawn`
+#[derive(Debug, Default)]
+pub struct WasiThreads;
+
+impl NativeModule for WasiThreads {
+    fn module_name(&self) -> &str {
+        "wasi"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports.function(
+            "thread-spawn",
+            thread_spawn as extern "C" fn(ExecEnv, i32) -> i32,
+        );
+    }
+}
+
+struct StartArgs {
+    state: Arc<ThreadState>,
+    owner: usize,
+    /// the user data of the spawner, see `ExecEnvRef::share_user_data()`
+    user_data: *mut c_void,
+    start_arg: i32,
+}
+
+extern "C" fn thread_spawn(env: ExecEnv, start_arg: i32) -> i32 {
+    catch_panic(env, || {
+        let Some((owner, state)) = reserve(env.module_inst() as usize) else {
+            return -1;
+        };
+        let args = Box::into_raw(Box::new(StartArgs {
+            state: state.clone(),
+            owner,
+            user_data: unsafe { wasm_runtime_get_user_data(env.as_raw()) },
+            start_arg,
+        }));
+
+        // without the lock, which the new thread and the other spawns take
+        let mut handle = 0;
+        let spawned = unsafe {
+            wasm_runtime_spawn_thread(
+                env.as_raw(),
+                &mut handle,
+                Some(thread_start),
+                args as *mut c_void,
+            )
+        };
+        if spawned != 0 {
+            drop(unsafe { Box::from_raw(args) });
+            registry()
+                .threads
+                .retain(|(_, thread)| !Arc::ptr_eq(thread, &state));
+            state.finish();
+            state.spawned();
+            return -1;
+        }
+        state.handle.store(handle as _, Ordering::Release);
+        state.spawned();
+        state.id
+    })
+}
+
+/// reserve the slot of a thread spawned from `instance`, so the quota counts it while it
+/// spawns, and return the instance it belongs to. A join waits until the spawn returned
+fn reserve(instance: usize) -> Option<(usize, Arc<ThreadState>)> {
+    let mut registry = registry();
+    let owner = registry
+        .owners
+        .iter()
+        .find(|(thread, _)| *thread == instance)
+        .map_or(instance, |(_, owner)| *owner);
+    let quota = registry
+        .quotas
+        .iter()
+        .find(|(address, _)| *address == owner)
+        .map(|(_, quota)| quota.clone());
+    if let Some(quota) = quota {
+        let threads = registry.threads.iter().filter(|(o, _)| *o == owner).count();
+        if threads >= quota.max {
+            // the hook may look at the threads
+            drop(registry);
+            if let Some(hook) = &quota.on_exceeded {
+                hook(InstanceId::new(owner as wasm_module_inst_t));
+            }
+            return None;
+        }
+    }
+
+    let state = Arc::new(ThreadState {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        handle: AtomicUsize::new(0),
+        progress: Mutex::new(Progress::default()),
+        done: Condvar::new(),
+    });
+    registry.threads.push((owner, state.clone()));
+    Some((owner, state))
+}
+
+/// forget the quota of `instance`, once it's destroyed
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    registry()
+        .quotas
+        .retain(|(address, _)| *address != instance as usize);
+}
+
+unsafe extern "C" fn thread_start(env: wasm_exec_env_t, args: *mut c_void) -> *mut c_void {
+    let args = Box::from_raw(args as *mut StartArgs);
+    let instance = wasm_runtime_get_module_inst(env);
+    registry().owners.push((instance as usize, args.owner));
+    // the instance of the spawner frees the user data, once it joined the thread
+    wasm_runtime_set_user_data(env, args.user_data);
+
+    let start = wasm_runtime_lookup_function(instance, c"wasi_thread_start".as_ptr());
+    if !start.is_null() {
+        let mut argv = [args.state.id as u32, args.start_arg as u32];
+        // a trap ends the thread like a return
+        wasm_runtime_call_wasm(env, start, 2, argv.as_mut_ptr());
+    }
+
+    registry()
+        .owners
+        .retain(|(thread, _)| *thread != instance as usize);
+    args.state.finish();
+    ptr::null_mut()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
+        RuntimeError,
+    };
+
+    // (module
+    //   (import "wasi" "thread-spawn" (func (param i32) (result i32)))
+    //   (memory (export "memory") 1 1 shared)
+    //   (func (export "wasi_thread_start") (param i32 i32)
+    //     (i32.atomic.store (local.get 1) (local.get 0))
+    //   )
+    //   (func (export "spawn") (param i32) (result i32) (call 0 (local.get 0)))
+    // )
+    const SPAWN_MODULE: [u8; 116] = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x01, 0x0b, 0x02, 0x60, 0x01, 0x7f, 0x01,
+        0x7f, 0x60, 0x02, 0x7f, 0x7f, 0x00, 0x02, 0x15, 0x01, 0x04, 0x77, 0x61, 0x73, 0x69, 0x0c,
+        0x74, 0x68, 0x72, 0x65, 0x61, 0x64, 0x2d, 0x73, 0x70, 0x61, 0x77, 0x6e, 0x00, 0x00, 0x03,
+        0x03, 0x02, 0x01, 0x00, 0x05, 0x04, 0x01, 0x03, 0x01, 0x01, 0x07, 0x26, 0x03, 0x06, 0x6d,
+        0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00, 0x11, 0x77, 0x61, 0x73, 0x69, 0x5f, 0x74, 0x68,
+        0x72, 0x65, 0x61, 0x64, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x01, 0x05, 0x73, 0x70,
+        0x61, 0x77, 0x6e, 0x00, 0x02, 0x0a, 0x13, 0x02, 0x0a, 0x00, 0x20, 0x01, 0x20, 0x00, 0xfe,
+        0x17, 0x02, 0x00, 0x0b, 0x06, 0x00, 0x20, 0x00, 0x10, 0x00, 0x0b,
+    ];
+
+    #[test]
+    fn test_guest_threads() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .with_wasi_threads()
+            .build()?;
+
+        let module = Module::from_buf(&runtime, &SPAWN_MODULE, "threads")?;
+        let instance = Instance::new(&runtime, &module, 64 * 1024, ())?;
+
+        let spawn = Function::find_export_func(&instance, "spawn")?;
+        let id = i32::try_from(spawn.call_args(&instance, &[WasmValue::I32(16)])?)?;
+        assert!(id > 0);
+        assert_eq!(
+            instance
+                .threads()
+                .list()
+                .iter()
+                .map(GuestThread::id)
+                .collect::<Vec<_>>(),
+            vec![id]
+        );
+
+        assert!(instance.threads().join_all(Duration::from_secs(5)));
+        assert!(instance.threads().list().is_empty());
+        let mut stored = [0u8; 4];
+        instance.memory().read(16, &mut stored)?;
+        assert_eq!(i32::from_le_bytes(stored), id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_quota() -> Result<(), RuntimeError> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .with_wasi_threads()
+            .build()?;
+
+        let module = Module::from_buf(&runtime, &SPAWN_MODULE, "quota")?;
+        let instance = Instance::new(&runtime, &module, 64 * 1024, ())?;
+
+        let exceeded = Arc::new(Mutex::new(Vec::new()));
+        let hook = exceeded.clone();
+        instance.threads().set_quota(Some(
+            ThreadQuota::new(1).on_exceeded(move |instance| hook.lock().unwrap().push(instance)),
+        ));
+        assert_eq!(instance.threads().quota().map(|quota| quota.max()), Some(1));
+
+        let spawn = Function::find_export_func(&instance, "spawn")?;
+        assert!(i32::try_from(spawn.call_args(&instance, &[WasmValue::I32(16)])?)? > 0);
+        // the first thread isn't joined yet, finished or not
+        assert_eq!(
+            spawn.call_args(&instance, &[WasmValue::I32(16)])?,
+            WasmValue::I32(-1)
+        );
+        assert_eq!(*exceeded.lock().unwrap(), vec![instance.id()]);
+
+        assert!(instance.threads().join_all(Duration::from_secs(5)));
+        assert!(i32::try_from(spawn.call_args(&instance, &[WasmValue::I32(16)])?)? > 0);
+        assert!(instance.threads().join_all(Duration::from_secs(5)));
+
+        instance.threads().set_quota(None);
+        assert!(instance.threads().quota().is_none());
+        Ok(())
+    }
+}
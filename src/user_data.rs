@@ -1,26 +1,301 @@
-use std::{ffi::c_void, marker::PhantomData};
+use std::{
+    any::TypeId,
+    cell::Cell,
+    ffi::{c_void, CString},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicIsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
 
+use wamr_sys::{wasm_exec_env_t, wasm_module_inst_t};
+
+use crate::{
+    account,
+    event::InstanceId,
+    host_function::HostCall,
+    instance::InstanceRef,
+    native_module::{NativeModule, NativeModuleEntry},
+    platform,
+};
+
+/// the host data of the instance calling a host function, borrowed for the call. The
+/// borrow is tied to the `ExecEnv` the host function is called with, so it can't outlive
+/// the call. Like a `RefCell`, the data is borrowed mutably by one `Caller` at most, and
+/// then by no other one
 pub struct Caller<'a, T> {
-    _data: PhantomData<&'a T>,
-    ptr: *mut c_void,
+    _data: PhantomData<*mut T>,
+    user_data: *const UserData,
+    /// null if the instance has no host data of type `T`
+    ptr: *mut T,
+    env: ExecEnv<'a>,
+    borrow: Cell<Borrow>,
 }
 
-pub type ExecEnv = wamr_sys::wasm_exec_env_t;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Borrow {
+    Unused,
+    Shared,
+    Exclusive,
+}
+
+/// host data, with its type, which `Caller`s check
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HostData {
+    pub(crate) ptr: *mut c_void,
+    pub(crate) type_id: TypeId,
+}
 
-impl<'a, T> Caller<'a, T> {
-    pub fn from_env(env: ExecEnv) -> Self {
-        let ptr = unsafe { wamr_sys::wasm_runtime_get_user_data(env) };
+impl HostData {
+    pub(crate) fn new<T: 'static>(data: *mut T) -> Self {
+        HostData {
+            ptr: data as *mut c_void,
+            type_id: TypeId::of::<T>(),
+        }
+    }
+
+    /// the data, `None` unless it's a `T`
+    pub(crate) fn of<T: 'static>(&self) -> Option<*mut T> {
+        (self.type_id == TypeId::of::<T>() && !self.ptr.is_null()).then_some(self.ptr as *mut T)
+    }
+}
+
+/// what WAMR keeps as the user data of the execution environments of an instance: its
+/// host data, next to the borrows of it by live `Caller`s. Synchronized, since the threads
+/// of a guest may share it
+#[derive(Debug)]
+pub(crate) struct UserData {
+    /// the number of shared borrows, or -1 for an exclusive one
+    borrows: AtomicIsize,
+    data: Mutex<HostData>,
+}
+
+impl UserData {
+    pub(crate) fn new(data: HostData) -> Self {
+        UserData {
+            borrows: AtomicIsize::new(0),
+            data: Mutex::new(data),
+        }
+    }
+
+    pub(crate) fn data(&self) -> HostData {
+        *self.data.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// replace the host data, and return the previous one
+    pub(crate) fn replace(&self, data: HostData) -> HostData {
+        let mut current = self.data.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut current, data)
+    }
+
+    /// replace the host data with `ptr` of the same type, and return the previous one
+    pub(crate) fn replace_ptr(&self, ptr: *mut c_void) -> *mut c_void {
+        let mut current = self.data.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::replace(&mut current.ptr, ptr)
+    }
+}
+
+/// the execution environment WAMR calls a host function with, the first parameter of
+/// every host function. It has the layout of the pointer WAMR passes, and the lifetime
+/// `'call` of the call, so it can't be kept past it
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecEnv<'call> {
+    raw: wasm_exec_env_t,
+    _call: PhantomData<&'call ()>,
+}
+
+impl ExecEnv<'_> {
+    /// wrap an execution environment of WAMR
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a live execution environment, for as long as the `ExecEnv` is used.
+    pub unsafe fn from_raw(raw: wasm_exec_env_t) -> Self {
+        ExecEnv {
+            raw,
+            _call: PhantomData,
+        }
+    }
+
+    pub fn as_raw(self) -> wasm_exec_env_t {
+        self.raw
+    }
+
+    /// the calling instance, as `RuntimeEvent`s identify it
+    pub fn instance(self) -> InstanceId {
+        InstanceId::new(self.module_inst())
+    }
+
+    pub(crate) fn module_inst(self) -> wasm_module_inst_t {
+        unsafe { wamr_sys::wasm_runtime_get_module_inst(self.raw) }
+    }
+
+    /// the host data of the calling instance, borrowed for the call, `None` if it has
+    /// none of type `T`, the data type of the instance, see `Caller`
+    pub fn user_data<T: 'static>(&self) -> Option<Caller<'_, T>> {
+        let caller = Caller::<T>::from_env(self);
+        (!caller.ptr.is_null()).then_some(caller)
+    }
+
+    /// raise an exception in the calling instance, which traps with `message` once the
+    /// host function returns
+    pub fn set_exception(self, message: &str) {
+        let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+        unsafe { wamr_sys::wasm_runtime_set_exception(self.module_inst(), message.as_ptr()) };
+    }
+
+    /// the bytes of native stack left to the host function, `None` on hosts where the
+    /// bounds of a thread's stack aren't known. Deeply recursive host code checks it
+    /// before going on
+    pub fn stack_remaining(self) -> Option<usize> {
+        platform::stack_remaining()
+    }
+}
+
+impl<'a, T: 'static> Caller<'a, T> {
+    /// the caller of a host function, given the `env` it's called with. `T` must be the
+    /// data type of the calling instance, the data can't be borrowed otherwise
+    pub fn from_env(env: &'a ExecEnv<'_>) -> Self {
+        let user_data = unsafe { wamr_sys::wasm_runtime_get_user_data(env.as_raw()) };
+        let user_data = user_data as *const UserData;
+        let ptr = match user_data.is_null() {
+            true => None,
+            false => unsafe { (*user_data).data() }.of::<T>(),
+        };
         Caller {
             _data: PhantomData,
-            ptr,
+            user_data,
+            ptr: ptr.unwrap_or(std::ptr::null_mut()),
+            env: *env,
+            borrow: Cell::new(Borrow::Unused),
+        }
+    }
+
+    /// the native module which exports the running host function, `None` if the
+    /// function isn't part of a native module of type `M`, or is a symbol of a WAMR
+    /// symbol table, whose attachment is its own
+    pub fn native_module<M: NativeModule>(&self) -> Option<&M> {
+        let attachment = HostCall::find(self.env)?.attachment;
+        if attachment.is_null() {
+            return None;
+        }
+
+        let entry = unsafe { &*(attachment as *const NativeModuleEntry) };
+        entry.module().downcast_ref::<M>()
+    }
+
+    /// the instance calling the host function, to reach its memory, exports and
+    /// exception, whichever `Instance` owns it
+    pub fn instance(&self) -> InstanceRef<'_> {
+        InstanceRef::new(self.env.module_inst())
+    }
+
+    /// count a host function call into the `ResourceAccount` of the calling instance, if
+    /// any. Typed host functions are counted by their trampoline, while WAMR calls raw ones
+    /// directly, so they report themselves
+    pub fn record_host_call(&self) {
+        account::record_host_call(self.env.module_inst());
+    }
+
+    /// the host address of `[offset, offset + len)` in the linear memory of the calling
+    /// instance, `None` if it's out of bounds. Offsets are 64-bit, to take the i64
+    /// addresses of memory64 guests as well
+    pub fn app_to_native(&self, offset: u64, len: u64) -> Option<*mut u8> {
+        unsafe {
+            let instance = self.env.module_inst();
+            if !wamr_sys::wasm_runtime_validate_app_addr(instance, offset as _, len as _) {
+                return None;
+            }
+            Some(wamr_sys::wasm_runtime_addr_app_to_native(instance, offset as _) as *mut u8)
+        }
+    }
+
+    /// the offset in the linear memory of the calling instance of a host address, like the
+    /// one WAMR translated a `ParamTy::Pointer` into, `None` if it's outside of the memory
+    pub fn native_to_app(&self, native: *const u8) -> Option<u64> {
+        unsafe {
+            let instance = self.env.module_inst();
+            let native = native as *mut c_void;
+            if !wamr_sys::wasm_runtime_validate_native_addr(instance, native, 0) {
+                return None;
+            }
+            Some(wamr_sys::wasm_runtime_addr_native_to_app(instance, native) as u64)
+        }
+    }
+
+    /// the host data of the calling instance
+    ///
+    /// # Panics
+    ///
+    /// Panics if the instance has no host data of type `T`, or if another `Caller` borrows
+    /// the data mutably.
+    pub fn data(&self) -> &T {
+        if self.borrow.get() == Borrow::Unused {
+            let shared = |count| (count != -1).then_some(count + 1);
+            let borrowed = self.borrows();
+            if borrowed
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, shared)
+                .is_err()
+            {
+                panic!("the host data is already borrowed mutably");
+            }
+            self.borrow.set(Borrow::Shared);
+        }
+        unsafe { &*self.ptr }
+    }
+
+    /// the host data of the calling instance, mutably
+    ///
+    /// # Panics
+    ///
+    /// Panics if the instance has no host data of type `T`, or if another `Caller` borrows
+    /// the data.
+    pub fn data_mut(&mut self) -> &mut T {
+        if self.borrow.get() != Borrow::Exclusive {
+            let own = match self.borrow.get() {
+                Borrow::Shared => 1,
+                _ => 0,
+            };
+            let borrowed = self.borrows();
+            if borrowed
+                .compare_exchange(own, -1, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                panic!("the host data is already borrowed");
+            }
+            self.borrow.set(Borrow::Exclusive);
         }
+        unsafe { &mut *self.ptr }
     }
+}
 
-    pub fn data(&'a self) -> &'a T {
-        unsafe { &*(self.ptr as *const T) }
+impl<T> Caller<'_, T> {
+    /// the borrows of the host data
+    ///
+    /// # Panics
+    ///
+    /// Panics if the instance has no host data of type `T`.
+    fn borrows(&self) -> &AtomicIsize {
+        if self.ptr.is_null() {
+            panic!(
+                "the calling instance has no host data of type {}",
+                std::any::type_name::<T>()
+            );
+        }
+        // not null, since it holds the data
+        unsafe { &(*self.user_data).borrows }
     }
+}
 
-    pub fn data_mut(&'a mut self) -> &'a mut T {
-        unsafe { &mut *(self.ptr as *mut T) }
+impl<T> Drop for Caller<'_, T> {
+    fn drop(&mut self) {
+        let release = match self.borrow.get() {
+            Borrow::Unused => return,
+            Borrow::Shared => 1,
+            Borrow::Exclusive => -1,
+        };
+        self.borrows().fetch_sub(release, Ordering::AcqRel);
     }
-}
\ No newline at end of file
+}
@@ -5,7 +5,70 @@
 
 //! a wasm value. Always used as function parameters and results
 
-#[derive(Debug, PartialEq)]
+use std::{cmp::Ordering, fmt};
+
+#[cfg(feature = "wamr")]
+use wamr_sys::{
+    wasm_valkind_enum_WASM_F32, wasm_valkind_enum_WASM_F64, wasm_valkind_enum_WASM_I32,
+    wasm_valkind_enum_WASM_I64, wasm_valkind_enum_WASM_V128, wasm_valkind_t,
+};
+
+#[cfg(feature = "stringref")]
+use crate::stringref::StringRef;
+use crate::RuntimeError;
+
+/// the type of a `WasmValue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    Void,
+    I32,
+    I64,
+    F32,
+    F64,
+    V128,
+    #[cfg(feature = "stringref")]
+    StringRef,
+}
+
+/// like in the text format, `i32` or `v128`, and `void` for `Void`
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::Void => "void",
+            ValueType::I32 => "i32",
+            ValueType::I64 => "i64",
+            ValueType::F32 => "f32",
+            ValueType::F64 => "f64",
+            ValueType::V128 => "v128",
+            #[cfg(feature = "stringref")]
+            ValueType::StringRef => "stringref",
+        };
+        f.write_str(name)
+    }
+}
+
+impl ValueType {
+    /// the type of a `wasm_valkind_t`, `None` for references
+    #[cfg(feature = "wamr")]
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn from_kind(kind: wasm_valkind_t) -> Option<ValueType> {
+        match kind as u32 {
+            wasm_valkind_enum_WASM_I32 => Some(ValueType::I32),
+            wasm_valkind_enum_WASM_I64 => Some(ValueType::I64),
+            wasm_valkind_enum_WASM_F32 => Some(ValueType::F32),
+            wasm_valkind_enum_WASM_F64 => Some(ValueType::F64),
+            wasm_valkind_enum_WASM_V128 => Some(ValueType::V128),
+            _ => None,
+        }
+    }
+}
+
+/// a Rust type which maps to a wasm value type, `()` to `Void`
+pub trait WasmType {
+    const TYPE: ValueType;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WasmValue {
     Void,
     I32(i32),
@@ -13,9 +76,40 @@ pub enum WasmValue {
     F32(f32),
     F64(f64),
     V128(i128),
+    /// a string of the stringref proposal, see `StringRef`
+    #[cfg(feature = "stringref")]
+    StringRef(StringRef),
 }
 
 impl WasmValue {
+    /// the number of 32-bit cells the value takes in the argv of a call
+    pub(crate) fn cell_count(&self) -> usize {
+        match self {
+            WasmValue::Void => 0,
+            WasmValue::I32(_) | WasmValue::F32(_) => 1,
+            WasmValue::I64(_) | WasmValue::F64(_) => 2,
+            WasmValue::V128(_) => 4,
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(_) => std::mem::size_of::<usize>() / 4,
+        }
+    }
+
+    /// write the value into the head of `cells`, without allocating.
+    /// return the number of cells written
+    pub(crate) fn encode_into(&self, cells: &mut [u32]) -> usize {
+        match *self {
+            WasmValue::Void => {}
+            WasmValue::I32(value) => bytes_to_cells(&value.to_ne_bytes(), cells),
+            WasmValue::I64(value) => bytes_to_cells(&value.to_ne_bytes(), cells),
+            WasmValue::F32(value) => bytes_to_cells(&value.to_ne_bytes(), cells),
+            WasmValue::F64(value) => bytes_to_cells(&value.to_ne_bytes(), cells),
+            WasmValue::V128(value) => bytes_to_cells(&value.to_ne_bytes(), cells),
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(value) => bytes_to_cells(&value.address().to_ne_bytes(), cells),
+        }
+        self.cell_count()
+    }
+
     pub fn encode(&self) -> Vec<u32> {
         match *self {
             WasmValue::Void => {
@@ -46,6 +140,12 @@ impl WasmValue {
                     in_u32_array[3],
                 ]
             }
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(_) => {
+                let mut cells = vec![0; self.cell_count()];
+                self.encode_into(&mut cells);
+                cells
+            }
         }
     }
 
@@ -75,10 +175,351 @@ impl WasmValue {
     }
 }
 
+fn bytes_to_cells(bytes: &[u8], cells: &mut [u32]) {
+    for (cell, chunk) in cells.iter_mut().zip(bytes.chunks_exact(4)) {
+        *cell = u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+}
+
+/// the native-endian bytes of the head `N / 4` cells
+pub(crate) fn cells_to_bytes<const N: usize>(cells: &[u32]) -> [u8; N] {
+    let mut bytes = [0u8; N];
+    for (chunk, cell) in bytes.chunks_exact_mut(4).zip(cells) {
+        chunk.copy_from_slice(&cell.to_ne_bytes());
+    }
+    bytes
+}
+
+/// `u32` and `u64` are reinterpreted as `I32` and `I64` with the same bits, like
+/// wasm does for unsigned operations
+macro_rules! impl_from_native {
+    ($native:ty, $variant:ident, $wasm:ty) => {
+        impl WasmType for $native {
+            const TYPE: ValueType = ValueType::$variant;
+        }
+
+        impl From<$native> for WasmValue {
+            fn from(value: $native) -> Self {
+                WasmValue::$variant(value as $wasm)
+            }
+        }
+
+        impl TryFrom<WasmValue> for $native {
+            type Error = RuntimeError;
+
+            fn try_from(value: WasmValue) -> Result<Self, Self::Error> {
+                match value {
+                    WasmValue::$variant(value) => Ok(value as $native),
+                    _ => Err(RuntimeError::TypeMismatch(message!(
+                        "expect {}, got {:?}",
+                        stringify!($variant),
+                        value
+                    ))),
+                }
+            }
+        }
+    };
+}
+
+impl_from_native!(i32, I32, i32);
+impl_from_native!(u32, I32, i32);
+impl_from_native!(i64, I64, i64);
+impl_from_native!(u64, I64, i64);
+impl_from_native!(f32, F32, f32);
+impl_from_native!(f64, F64, f64);
+
+/// `true` is `I32(1)` and `false` is `I32(0)`
+impl From<bool> for WasmValue {
+    fn from(value: bool) -> Self {
+        WasmValue::I32(value as i32)
+    }
+}
+
+impl WasmType for bool {
+    const TYPE: ValueType = ValueType::I32;
+}
+
+/// any non-zero `I32` is `true`
+impl TryFrom<WasmValue> for bool {
+    type Error = RuntimeError;
+
+    fn try_from(value: WasmValue) -> Result<Self, Self::Error> {
+        i32::try_from(value).map(|value| value != 0)
+    }
+}
+
+impl WasmType for () {
+    const TYPE: ValueType = ValueType::Void;
+}
+
+/// only `Void`, the result of a function without results
+impl TryFrom<WasmValue> for () {
+    type Error = RuntimeError;
+
+    fn try_from(value: WasmValue) -> Result<Self, Self::Error> {
+        match value {
+            WasmValue::Void => Ok(()),
+            _ => Err(RuntimeError::TypeMismatch(message!(
+                "expect Void, got {:?}",
+                value
+            ))),
+        }
+    }
+}
+
+/// the parameters of a call, built one value at a time, like
+/// `Params::new().i32(8).i64(9).into_vec()`. See `params!` for a shorter form
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params(Vec<WasmValue>);
+
+impl Params {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn i32(mut self, value: i32) -> Self {
+        self.0.push(WasmValue::I32(value));
+        self
+    }
+
+    pub fn i64(mut self, value: i64) -> Self {
+        self.0.push(WasmValue::I64(value));
+        self
+    }
+
+    pub fn f32(mut self, value: f32) -> Self {
+        self.0.push(WasmValue::F32(value));
+        self
+    }
+
+    pub fn f64(mut self, value: f64) -> Self {
+        self.0.push(WasmValue::F64(value));
+        self
+    }
+
+    pub fn v128(mut self, value: i128) -> Self {
+        self.0.push(WasmValue::V128(value));
+        self
+    }
+
+    /// a value of any type converting into a `WasmValue`, like `u32` or `bool`
+    pub fn push<V: Into<WasmValue>>(mut self, value: V) -> Self {
+        self.0.push(value.into());
+        self
+    }
+
+    pub fn into_vec(self) -> Vec<WasmValue> {
+        self.0
+    }
+}
+
+impl From<Params> for Vec<WasmValue> {
+    fn from(params: Params) -> Self {
+        params.0
+    }
+}
+
+/// the parameters of a call as a `Vec<WasmValue>`, each value converted via `From`, like
+/// `params![8i32, 9i64, true]`. Literals without a suffix are `i32`s, or `f64`s
+#[macro_export]
+macro_rules! params {
+    ($($value:expr),* $(,)?) => {
+        <::std::vec::Vec<$crate::value::WasmValue>>::from([
+            $($crate::value::WasmValue::from($value)),*
+        ])
+    };
+}
+
+impl WasmValue {
+    /// the bits of an `I32` or `F32`, as an unsigned integer
+    pub fn to_u32_bits(&self) -> Option<u32> {
+        match *self {
+            WasmValue::I32(value) => Some(value as u32),
+            WasmValue::F32(value) => Some(value.to_bits()),
+            _ => None,
+        }
+    }
+
+    /// the bits of an `I64` or `F64`, as an unsigned integer
+    pub fn to_u64_bits(&self) -> Option<u64> {
+        match *self {
+            WasmValue::I64(value) => Some(value as u64),
+            WasmValue::F64(value) => Some(value.to_bits()),
+            _ => None,
+        }
+    }
+
+    /// the payload of an `F32` or `F64` NaN, which is its mantissa. `None` if the value
+    /// isn't a NaN
+    pub fn nan_payload(&self) -> Option<u64> {
+        match *self {
+            WasmValue::F32(value) if value.is_nan() => {
+                Some((value.to_bits() & F32_MANTISSA) as u64)
+            }
+            WasmValue::F64(value) if value.is_nan() => Some(value.to_bits() & F64_MANTISSA),
+            _ => None,
+        }
+    }
+
+    /// whether the value is a canonical NaN, of either sign, which only has the most
+    /// significant bit of its payload set
+    pub fn is_canonical_nan(&self) -> bool {
+        match *self {
+            WasmValue::F32(value) => value.abs().to_bits() == F32_CANONICAL_NAN,
+            WasmValue::F64(value) => value.abs().to_bits() == F64_CANONICAL_NAN,
+            _ => false,
+        }
+    }
+
+    /// the same value, except an `F32` or `F64` NaN becomes the positive canonical NaN.
+    ///
+    /// WAMR keeps the sign and payload of NaNs as the hardware produces them, so they
+    /// differ across platforms. See `RuntimeBuilder::canonicalize_nans()`
+    pub fn canonicalize_nan(&self) -> WasmValue {
+        match *self {
+            WasmValue::Void => WasmValue::Void,
+            WasmValue::I32(value) => WasmValue::I32(value),
+            WasmValue::I64(value) => WasmValue::I64(value),
+            WasmValue::F32(value) if value.is_nan() => {
+                WasmValue::F32(f32::from_bits(F32_CANONICAL_NAN))
+            }
+            WasmValue::F32(value) => WasmValue::F32(value),
+            WasmValue::F64(value) if value.is_nan() => {
+                WasmValue::F64(f64::from_bits(F64_CANONICAL_NAN))
+            }
+            WasmValue::F64(value) => WasmValue::F64(value),
+            WasmValue::V128(value) => WasmValue::V128(value),
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(value) => WasmValue::StringRef(value),
+        }
+    }
+}
+
+impl WasmValue {
+    pub fn ty(&self) -> ValueType {
+        match self {
+            WasmValue::Void => ValueType::Void,
+            WasmValue::I32(_) => ValueType::I32,
+            WasmValue::I64(_) => ValueType::I64,
+            WasmValue::F32(_) => ValueType::F32,
+            WasmValue::F64(_) => ValueType::F64,
+            WasmValue::V128(_) => ValueType::V128,
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(_) => ValueType::StringRef,
+        }
+    }
+
+    /// an `I32`, or an `I64` in range
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self {
+            WasmValue::I32(value) => Some(value),
+            WasmValue::I64(value) => i32::try_from(value).ok(),
+            _ => None,
+        }
+    }
+
+    /// an `I64`, or a sign-extended `I32`
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            WasmValue::I32(value) => Some(value as i64),
+            WasmValue::I64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// an `F32`, or an `F64` which is exactly an `f32`, a NaN included
+    pub fn as_f32(&self) -> Option<f32> {
+        match *self {
+            WasmValue::F32(value) => Some(value),
+            WasmValue::F64(value) if value.is_nan() || value as f32 as f64 == value => {
+                Some(value as f32)
+            }
+            _ => None,
+        }
+    }
+
+    /// an `F64`, or a widened `F32`
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            WasmValue::F32(value) => Some(value as f64),
+            WasmValue::F64(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_v128(&self) -> Option<i128> {
+        match *self {
+            WasmValue::V128(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// the value alone, in decimal, and a `V128` in hexadecimal. `Void` is `void`
+impl fmt::Display for WasmValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WasmValue::Void => f.write_str("void"),
+            WasmValue::I32(value) => value.fmt(f),
+            WasmValue::I64(value) => value.fmt(f),
+            WasmValue::F32(value) => value.fmt(f),
+            WasmValue::F64(value) => value.fmt(f),
+            WasmValue::V128(value) => write!(f, "{:#034x}", value),
+            #[cfg(feature = "stringref")]
+            WasmValue::StringRef(value) => write!(f, "{:?}", value),
+        }
+    }
+}
+
+/// values of the same number type compare like numbers. Values of different types, and
+/// `V128`s, whose lanes are unknown, are unordered
+impl PartialOrd for WasmValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (WasmValue::Void, WasmValue::Void) => Some(Ordering::Equal),
+            (WasmValue::I32(a), WasmValue::I32(b)) => a.partial_cmp(b),
+            (WasmValue::I64(a), WasmValue::I64(b)) => a.partial_cmp(b),
+            (WasmValue::F32(a), WasmValue::F32(b)) => a.partial_cmp(b),
+            (WasmValue::F64(a), WasmValue::F64(b)) => a.partial_cmp(b),
+            (WasmValue::V128(a), WasmValue::V128(b)) if a == b => Some(Ordering::Equal),
+            #[cfg(feature = "stringref")]
+            (WasmValue::StringRef(a), WasmValue::StringRef(b)) if a == b => Some(Ordering::Equal),
+            _ => None,
+        }
+    }
+}
+
+const F32_MANTISSA: u32 = 0x007f_ffff;
+const F64_MANTISSA: u64 = 0x000f_ffff_ffff_ffff;
+const F32_CANONICAL_NAN: u32 = 0x7fc0_0000;
+const F64_CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_nan_canonicalization() {
+        let noisy = WasmValue::F32(f32::from_bits(0xffc0_0001));
+        assert_eq!(noisy.nan_payload(), Some(0x40_0001));
+        assert!(!noisy.is_canonical_nan());
+
+        let canonical = noisy.canonicalize_nan();
+        assert_eq!(canonical.to_u32_bits(), Some(0x7fc0_0000));
+        assert!(canonical.is_canonical_nan());
+
+        let noisy = WasmValue::F64(f64::from_bits(0x7ff0_0000_0000_0001));
+        assert_eq!(noisy.nan_payload(), Some(1));
+        assert_eq!(
+            noisy.canonicalize_nan().to_u64_bits(),
+            Some(0x7ff8_0000_0000_0000)
+        );
+
+        assert_eq!(WasmValue::F32(1.5).nan_payload(), None);
+        assert_eq!(WasmValue::F32(1.5).canonicalize_nan(), WasmValue::F32(1.5));
+        assert_eq!(WasmValue::I32(-1).canonicalize_nan(), WasmValue::I32(-1));
+    }
+
     #[test]
     fn test_encode() {
         let params = vec![WasmValue::I32(1), WasmValue::I64(2)];
@@ -117,4 +558,127 @@ mod tests {
 
         assert_eq!(values, decoded_values);
     }
+
+    #[test]
+    fn test_encode_into() {
+        let values = vec![
+            WasmValue::I32(-1),
+            WasmValue::I64(2),
+            WasmValue::F32(3.0),
+            WasmValue::F64(4.0),
+            WasmValue::V128(5),
+            WasmValue::Void,
+        ];
+
+        let mut cells = [0u32; 16];
+        let mut pos = 0;
+        for v in &values {
+            pos += v.encode_into(&mut cells[pos..]);
+        }
+        assert_eq!(pos, 10);
+
+        let mut binary: Vec<u32> = Vec::new();
+        for v in &values {
+            binary.append(&mut v.encode());
+        }
+        assert_eq!(&cells[..pos], binary.as_slice());
+
+        assert_eq!(i64::from_ne_bytes(cells_to_bytes(&cells[1..3])), 2);
+        assert_eq!(f64::from_ne_bytes(cells_to_bytes(&cells[4..6])), 4.0);
+    }
+
+    #[test]
+    fn test_from_native() {
+        assert_eq!(WasmValue::from(-1i32), WasmValue::I32(-1));
+        assert_eq!(WasmValue::from(u32::MAX), WasmValue::I32(-1));
+        assert_eq!(WasmValue::from(-2i64), WasmValue::I64(-2));
+        assert_eq!(WasmValue::from(u64::MAX), WasmValue::I64(-1));
+        assert_eq!(WasmValue::from(1.5f32), WasmValue::F32(1.5));
+        assert_eq!(WasmValue::from(2.5f64), WasmValue::F64(2.5));
+        assert_eq!(WasmValue::from(true), WasmValue::I32(1));
+        assert_eq!(WasmValue::from(false), WasmValue::I32(0));
+
+        let params: Vec<WasmValue> = vec![8.into(), 9i64.into()];
+        assert_eq!(params, vec![WasmValue::I32(8), WasmValue::I64(9)]);
+    }
+
+    #[test]
+    fn test_try_into_native() {
+        assert_eq!(i32::try_from(WasmValue::I32(-1)).unwrap(), -1);
+        assert_eq!(u32::try_from(WasmValue::I32(-1)).unwrap(), u32::MAX);
+        assert_eq!(u64::try_from(WasmValue::I64(-1)).unwrap(), u64::MAX);
+        assert_eq!(f64::try_from(WasmValue::F64(2.5)).unwrap(), 2.5);
+        assert!(bool::try_from(WasmValue::I32(7)).unwrap());
+        assert!(!bool::try_from(WasmValue::I32(0)).unwrap());
+
+        assert!(i32::try_from(WasmValue::I64(1)).is_err());
+        assert!(f32::try_from(WasmValue::F64(1.0)).is_err());
+        assert!(bool::try_from(WasmValue::Void).is_err());
+    }
+
+    #[test]
+    fn test_ty_and_display() {
+        assert_eq!(WasmValue::I64(3).ty(), ValueType::I64);
+        assert_eq!(WasmValue::Void.ty().to_string(), "void");
+        assert_eq!(WasmValue::V128(0).ty().to_string(), "v128");
+
+        assert_eq!(WasmValue::I32(-7).to_string(), "-7");
+        assert_eq!(WasmValue::F64(2.5).to_string(), "2.5");
+        assert_eq!(format!("{:>4}", WasmValue::I64(42)), "  42");
+        assert_eq!(
+            WasmValue::V128(0xff).to_string(),
+            "0x000000000000000000000000000000ff"
+        );
+    }
+
+    #[test]
+    fn test_ordering() {
+        assert!(WasmValue::I32(-1) < WasmValue::I32(1));
+        assert!(WasmValue::F64(2.5) > WasmValue::F64(-0.5));
+        assert_eq!(WasmValue::I32(1).partial_cmp(&WasmValue::I64(2)), None);
+        assert_eq!(
+            WasmValue::F32(f32::NAN).partial_cmp(&WasmValue::F32(1.0)),
+            None
+        );
+        assert_eq!(WasmValue::V128(1).partial_cmp(&WasmValue::V128(2)), None);
+    }
+
+    #[test]
+    fn test_lossless_conversions() {
+        assert_eq!(WasmValue::I32(-1).as_i64(), Some(-1));
+        assert_eq!(WasmValue::I64(-1).as_i32(), Some(-1));
+        assert_eq!(WasmValue::I64(i64::MAX).as_i32(), None);
+        assert_eq!(WasmValue::F32(1.5).as_f64(), Some(1.5));
+        assert_eq!(WasmValue::F64(1.5).as_f32(), Some(1.5));
+        assert_eq!(WasmValue::F64(0.1).as_f32(), None);
+        assert!(WasmValue::F64(f64::NAN).as_f32().unwrap().is_nan());
+        assert_eq!(WasmValue::I32(1).as_f64(), None);
+        assert_eq!(WasmValue::F32(1.0).as_i32(), None);
+        assert_eq!(WasmValue::V128(5).as_v128(), Some(5));
+    }
+
+    #[test]
+    fn test_params() {
+        let built = Params::new().i32(8).i64(9).f32(1.5).push(true).into_vec();
+        assert_eq!(
+            built,
+            vec![
+                WasmValue::I32(8),
+                WasmValue::I64(9),
+                WasmValue::F32(1.5),
+                WasmValue::I32(1),
+            ]
+        );
+        assert_eq!(params![8, 9i64, 1.5f32, true], built);
+        assert_eq!(params![], Vec::<WasmValue>::new());
+    }
+
+    #[test]
+    fn test_to_bits() {
+        assert_eq!(WasmValue::I32(-1).to_u32_bits(), Some(u32::MAX));
+        assert_eq!(WasmValue::F32(1.0).to_u32_bits(), Some(0x3f80_0000));
+        assert_eq!(WasmValue::I64(-1).to_u64_bits(), Some(u64::MAX));
+        assert_eq!(WasmValue::I64(1).to_u32_bits(), None);
+        assert_eq!(WasmValue::Void.to_u64_bits(), None);
+    }
 }
@@ -0,0 +1,509 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! a record of the filesystem accesses guests make via WASI, to audit what an untrusted
+//! module touched. Turn it on via `RuntimeBuilder::with_wasi_audit()`, and get the record
+//! of an instance via `Instance::take_fs_audit()`
+//!
+//! the `path_open`, `fd_read`, `fd_write` and `path_unlink_file` functions of
+//! `wasi_snapshot_preview1` are replaced by ones recording each call, with its result,
+//! before handing it over to the libc-wasi of WAMR.
+//!
+//! paths are the ones the guest sees, resolved against the name of the preopened
+//! directory or the path a file descriptor was opened with, like `/data/input.txt`.
+//! The names of preopened directories are learnt when the guest asks for them, which
+//! wasi-libc does at startup.
+//!
+//! the same functions enforce the `WasiPolicy` of an instance, see `wasi_policy`, so a
+//! denied access is recorded with `ENOTCAPABLE`, and serve the files mounted for an
+//! instance, see `wasi_mount`, along with `fd_seek` and `fd_filestat_get`.
+
+use std::{
+    collections::HashMap,
+    ffi::{c_void, CStr},
+    mem, ptr, slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, MutexGuard, OnceLock, PoisonError,
+    },
+};
+
+use wamr_sys::{wasm_module_inst_t, NativeSymbol};
+
+use crate::{
+    host_function::{catch_panic, ParamTy, ResultTy},
+    native_module::{NativeExports, NativeModule},
+    user_data::ExecEnv,
+    wasi_mount,
+    wasi_policy::{self, ENOTCAPABLE},
+};
+
+extern "C" {
+    // the native symbols of libc-wasi, left out of wasm_export.h
+    fn get_libc_wasi_export_apis(p_libc_wasi_apis: *mut *mut NativeSymbol) -> u32;
+}
+
+/// what a guest did to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsOperation {
+    Open,
+    Read,
+    Write,
+    Unlink,
+}
+
+/// a filesystem access of a guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsAccess {
+    pub operation: FsOperation,
+    /// the path as the guest sees it, `<stdin>`, `<stdout>` and `<stderr>` for those, or
+    /// `<fd N>` for a file descriptor of unknown origin
+    pub path: String,
+    /// the bytes read or written, for a successful `Read` or `Write`
+    pub bytes: Option<u32>,
+    /// the WASI errno, `0` on success
+    pub errno: u16,
+}
+
+impl FsAccess {
+    pub fn succeeded(&self) -> bool {
+        self.errno == 0
+    }
+}
+
+#[derive(Default)]
+struct InstanceAudit {
+    /// the path of each file descriptor opened by the guest, or preopened
+    paths: HashMap<u32, String>,
+    accesses: Vec<FsAccess>,
+}
+
+/// whether accesses are recorded, set via `RuntimeBuilder::with_wasi_audit()`, for every
+/// runtime of the process, until the last one is dropped. Paths are tracked regardless,
+/// for `wasi_policy`
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_recording(recording: bool) {
+    RECORDING.store(recording, Ordering::Relaxed);
+}
+
+/// the audits of every instance which accessed a file, by the address of the instance
+static AUDITS: Mutex<Vec<(usize, InstanceAudit)>> = Mutex::new(Vec::new());
+
+fn audits() -> MutexGuard<'static, Vec<(usize, InstanceAudit)>> {
+    AUDITS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_audit<R>(instance: usize, f: impl FnOnce(&mut InstanceAudit) -> R) -> R {
+    let mut audits = audits();
+    let index = match audits.iter().position(|(address, _)| *address == instance) {
+        Some(index) => index,
+        None => {
+            audits.push((instance, InstanceAudit::default()));
+            audits.len() - 1
+        }
+    };
+    f(&mut audits[index].1)
+}
+
+/// the accesses recorded for `instance` since the last call
+pub(crate) fn take(instance: wasm_module_inst_t) -> Vec<FsAccess> {
+    audits()
+        .iter_mut()
+        .find(|(address, _)| *address == instance as usize)
+        .map(|(_, audit)| mem::take(&mut audit.accesses))
+        .unwrap_or_default()
+}
+
+/// forget the audit of a destroyed instance
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    audits().retain(|(address, _)| *address != instance as usize);
+}
+
+impl InstanceAudit {
+    fn path(&self, fd: u32) -> String {
+        match (fd, self.paths.get(&fd)) {
+            (_, Some(path)) => path.clone(),
+            (0, None) => String::from("<stdin>"),
+            (1, None) => String::from("<stdout>"),
+            (2, None) => String::from("<stderr>"),
+            (_, None) => format!("<fd {}>", fd),
+        }
+    }
+
+    fn record(&mut self, operation: FsOperation, path: String, bytes: Option<u32>, errno: u16) {
+        if !RECORDING.load(Ordering::Relaxed) {
+            return;
+        }
+        self.accesses.push(FsAccess {
+            operation,
+            path,
+            bytes,
+            errno,
+        });
+    }
+}
+
+/// `path` relative to the directory `dir`, unless absolute
+fn join(dir: &str, path: &str) -> String {
+    if path.starts_with('/') || dir.is_empty() || dir == "." {
+        return String::from(path);
+    }
+    match dir.ends_with('/') {
+        true => format!("{}{}", dir, path),
+        false => format!("{}/{}", dir, path),
+    }
+}
+
+fn guest_str(path: *const u8, path_len: u32) -> String {
+    match path.is_null() {
+        true => String::new(),
+        false => {
+            let path = unsafe { slice::from_raw_parts(path, path_len as usize) };
+            String::from_utf8_lossy(path).into_owned()
+        }
+    }
+}
+
+pub(crate) fn instance_of(env: ExecEnv) -> usize {
+    env.module_inst() as usize
+}
+
+/// `path` of `path_len` bytes, relative to the file descriptor `dirfd`, as the guest sees it
+pub(crate) fn resolve(env: ExecEnv, dirfd: u32, path: *const u8, path_len: u32) -> String {
+    let path = guest_str(path, path_len);
+    with_audit(instance_of(env), |audit| join(&audit.path(dirfd), &path))
+}
+
+// the signatures of the libc-wasi functions, `wasi_errno_t` being a `u16`
+type PathOpen =
+    unsafe extern "C" fn(ExecEnv, u32, u32, *const u8, u32, u16, u64, u64, u16, *mut u32) -> u16;
+type FdIo = unsafe extern "C" fn(ExecEnv, u32, *mut c_void, u32, *mut u32) -> u16;
+type PathUnlinkFile = unsafe extern "C" fn(ExecEnv, u32, *const u8, u32) -> u16;
+type FdClose = unsafe extern "C" fn(ExecEnv, u32) -> u16;
+type FdSeek = unsafe extern "C" fn(ExecEnv, u32, i64, u8, *mut u64) -> u16;
+type FdFilestatGet = unsafe extern "C" fn(ExecEnv, u32, *mut u8) -> u16;
+type FdPrestatDirName = unsafe extern "C" fn(ExecEnv, u32, *mut u8, u32) -> u16;
+
+/// the function of libc-wasi named `name`, to call once cast to its signature
+pub(crate) fn libc_wasi_function(name: &CStr) -> *mut c_void {
+    static FUNCTIONS: OnceLock<Vec<(&'static CStr, usize)>> = OnceLock::new();
+    let functions = FUNCTIONS.get_or_init(|| unsafe {
+        let mut symbols = ptr::null_mut();
+        let count = get_libc_wasi_export_apis(&mut symbols);
+        slice::from_raw_parts(symbols, count as usize)
+            .iter()
+            .map(|symbol| (CStr::from_ptr(symbol.symbol), symbol.func_ptr as usize))
+            .collect()
+    });
+    functions
+        .iter()
+        .find(|(function, _)| *function == name)
+        .map(|(_, function_ptr)| *function_ptr as *mut c_void)
+        .expect("libc-wasi without a function it is wrapped with")
+}
+
+/// the `wasi_snapshot_preview1` functions recording filesystem accesses, enforcing
+/// policies and serving mounted files, registered on top of the ones of libc-wasi
+#[derive(Debug, Default)]
+pub struct WasiInterposer;
+
+impl NativeModule for WasiInterposer {
+    fn module_name(&self) -> &str {
+        "wasi_snapshot_preview1"
+    }
+
+    fn exports(&self, exports: &mut NativeExports) {
+        exports
+            .raw_function(
+                "path_open",
+                path_open as *mut c_void,
+                &[
+                    ParamTy::I32,
+                    ParamTy::I32,
+                    ParamTy::Buffer,
+                    ParamTy::I32,
+                    ParamTy::I64,
+                    ParamTy::I64,
+                    ParamTy::I32,
+                    ParamTy::Pointer,
+                ],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_read",
+                fd_read as *mut c_void,
+                &[
+                    ParamTy::I32,
+                    ParamTy::Pointer,
+                    ParamTy::I32,
+                    ParamTy::Pointer,
+                ],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_write",
+                fd_write as *mut c_void,
+                &[
+                    ParamTy::I32,
+                    ParamTy::Pointer,
+                    ParamTy::I32,
+                    ParamTy::Pointer,
+                ],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "path_unlink_file",
+                path_unlink_file as *mut c_void,
+                &[ParamTy::I32, ParamTy::Buffer],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_close",
+                fd_close as *mut c_void,
+                &[ParamTy::I32],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_seek",
+                fd_seek as *mut c_void,
+                &[ParamTy::I32, ParamTy::I64, ParamTy::I32, ParamTy::Pointer],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_filestat_get",
+                fd_filestat_get as *mut c_void,
+                &[ParamTy::I32, ParamTy::Pointer],
+                ResultTy::I32,
+            )
+            .raw_function(
+                "fd_prestat_dir_name",
+                fd_prestat_dir_name as *mut c_void,
+                &[ParamTy::I32, ParamTy::Buffer],
+                ResultTy::I32,
+            );
+        wasi_policy::export_deniable(exports);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+extern "C" fn path_open(
+    env: ExecEnv,
+    dirfd: u32,
+    dirflags: u32,
+    path: *const u8,
+    path_len: u32,
+    oflags: u32,
+    fs_rights_base: u64,
+    fs_rights_inheriting: u64,
+    fs_flags: u32,
+    fd: *mut u32,
+) -> u32 {
+    catch_panic(env, || {
+        let resolved = resolve(env, dirfd, path, path_len);
+        let errno = match wasi_policy::permits(env, "path_open", &[&resolved]) {
+            true => wasi_mount::path_open(env, &resolved, oflags, fs_rights_base, fd)
+                .unwrap_or_else(|| unsafe {
+                    let path_open = libc_wasi_function(c"path_open");
+                    mem::transmute::<*mut c_void, PathOpen>(path_open)(
+                        env,
+                        dirfd,
+                        dirflags,
+                        path,
+                        path_len,
+                        oflags as u16,
+                        fs_rights_base,
+                        fs_rights_inheriting,
+                        fs_flags as u16,
+                        fd,
+                    )
+                }),
+            false => ENOTCAPABLE,
+        };
+        with_audit(instance_of(env), |audit| {
+            if errno == 0 {
+                audit.paths.insert(unsafe { *fd }, resolved.clone());
+            }
+            audit.record(FsOperation::Open, resolved, None, errno);
+        });
+        errno as u32
+    })
+}
+
+fn fd_io(
+    env: ExecEnv,
+    operation: FsOperation,
+    io: FdIo,
+    fd: u32,
+    iovs: *mut c_void,
+    iovs_len: u32,
+    transferred: *mut u32,
+) -> u32 {
+    let errno = unsafe { io(env, fd, iovs, iovs_len, transferred) };
+    let bytes = (errno == 0).then(|| unsafe { *transferred });
+    with_audit(instance_of(env), |audit| {
+        let path = audit.path(fd);
+        audit.record(operation, path, bytes, errno);
+    });
+    errno as u32
+}
+
+extern "C" fn fd_read(
+    env: ExecEnv,
+    fd: u32,
+    iovs: *mut c_void,
+    iovs_len: u32,
+    nread: *mut u32,
+) -> u32 {
+    catch_panic(env, || {
+        let read = match wasi_mount::is_mounted_fd(fd) {
+            true => wasi_mount::fd_read,
+            false => unsafe { mem::transmute::<*mut c_void, FdIo>(libc_wasi_function(c"fd_read")) },
+        };
+        fd_io(env, FsOperation::Read, read, fd, iovs, iovs_len, nread)
+    })
+}
+
+extern "C" fn fd_write(
+    env: ExecEnv,
+    fd: u32,
+    iovs: *mut c_void,
+    iovs_len: u32,
+    nwritten: *mut u32,
+) -> u32 {
+    catch_panic(env, || {
+        let write = match wasi_mount::is_mounted_fd(fd) {
+            true => wasi_mount::fd_write,
+            false => unsafe {
+                mem::transmute::<*mut c_void, FdIo>(libc_wasi_function(c"fd_write"))
+            },
+        };
+        fd_io(env, FsOperation::Write, write, fd, iovs, iovs_len, nwritten)
+    })
+}
+
+extern "C" fn path_unlink_file(env: ExecEnv, dirfd: u32, path: *const u8, path_len: u32) -> u32 {
+    catch_panic(env, || {
+        let resolved = resolve(env, dirfd, path, path_len);
+        let errno = match wasi_policy::permits(env, "path_unlink_file", &[&resolved]) {
+            true => unsafe {
+                let path_unlink_file = libc_wasi_function(c"path_unlink_file");
+                mem::transmute::<*mut c_void, PathUnlinkFile>(path_unlink_file)(
+                    env, dirfd, path, path_len,
+                )
+            },
+            false => ENOTCAPABLE,
+        };
+        with_audit(instance_of(env), |audit| {
+            audit.record(FsOperation::Unlink, resolved, None, errno);
+        });
+        errno as u32
+    })
+}
+
+extern "C" fn fd_close(env: ExecEnv, fd: u32) -> u32 {
+    catch_panic(env, || {
+        let errno = match wasi_mount::is_mounted_fd(fd) {
+            true => wasi_mount::fd_close(env, fd),
+            false => unsafe {
+                mem::transmute::<*mut c_void, FdClose>(libc_wasi_function(c"fd_close"))(env, fd)
+            },
+        };
+        if errno == 0 {
+            with_audit(instance_of(env), |audit| audit.paths.remove(&fd));
+        }
+        errno as u32
+    })
+}
+
+extern "C" fn fd_seek(env: ExecEnv, fd: u32, offset: i64, whence: u32, newoffset: *mut u64) -> u32 {
+    catch_panic(env, || {
+        let errno = match wasi_mount::is_mounted_fd(fd) {
+            true => wasi_mount::fd_seek(env, fd, offset, whence, newoffset),
+            false => unsafe {
+                mem::transmute::<*mut c_void, FdSeek>(libc_wasi_function(c"fd_seek"))(
+                    env,
+                    fd,
+                    offset,
+                    whence as u8,
+                    newoffset,
+                )
+            },
+        };
+        errno as u32
+    })
+}
+
+extern "C" fn fd_filestat_get(env: ExecEnv, fd: u32, buf: *mut u8) -> u32 {
+    catch_panic(env, || {
+        let errno = match wasi_mount::is_mounted_fd(fd) {
+            true => wasi_mount::fd_filestat_get(env, fd, buf),
+            false => unsafe {
+                let fd_filestat_get = libc_wasi_function(c"fd_filestat_get");
+                mem::transmute::<*mut c_void, FdFilestatGet>(fd_filestat_get)(env, fd, buf)
+            },
+        };
+        errno as u32
+    })
+}
+
+extern "C" fn fd_prestat_dir_name(env: ExecEnv, fd: u32, path: *mut u8, path_len: u32) -> u32 {
+    catch_panic(env, || {
+        let errno = unsafe {
+            let fd_prestat_dir_name = libc_wasi_function(c"fd_prestat_dir_name");
+            mem::transmute::<*mut c_void, FdPrestatDirName>(fd_prestat_dir_name)(
+                env, fd, path, path_len,
+            )
+        };
+        if errno == 0 {
+            let path = guest_str(path, path_len);
+            // the name isn't nul-terminated, but some guests pass a larger buffer
+            let path = path.trim_end_matches('\0').to_string();
+            with_audit(instance_of(env), |audit| audit.paths.insert(fd, path));
+        }
+        errno as u32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fs_audit() {
+        set_recording(true);
+        assert_eq!(join("/data", "input.txt"), "/data/input.txt");
+        assert_eq!(join("/", "input.txt"), "/input.txt");
+        assert_eq!(join(".", "input.txt"), "input.txt");
+        assert_eq!(join("/data", "/etc/passwd"), "/etc/passwd");
+
+        // any address, no instance is involved
+        let instance = 0x10 as wasm_module_inst_t;
+        with_audit(instance as usize, |audit| {
+            audit.paths.insert(3, String::from("/data"));
+            let path = join(&audit.path(3), "input.txt");
+            audit.record(FsOperation::Open, path, None, 0);
+            let path = audit.path(1);
+            audit.record(FsOperation::Write, path, Some(5), 0);
+            let path = audit.path(7);
+            audit.record(FsOperation::Read, path, None, 8);
+        });
+
+        let accesses = take(instance);
+        assert_eq!(accesses.len(), 3);
+        assert_eq!(accesses[0].path, "/data/input.txt");
+        assert!(accesses[0].succeeded());
+        assert_eq!(accesses[1].path, "<stdout>");
+        assert_eq!(accesses[1].bytes, Some(5));
+        assert_eq!(accesses[2].path, "<fd 7>");
+        assert!(!accesses[2].succeeded());
+        assert!(take(instance).is_empty());
+
+        remove(instance);
+        assert!(audits()
+            .iter()
+            .all(|(address, _)| *address != instance as usize));
+    }
+}
@@ -5,7 +5,14 @@
 
 //! prepare wasi context
 
-use std::{ffi::CString, vec::Vec};
+use std::{
+    env,
+    ffi::CString,
+    path::{Path, PathBuf},
+    vec::Vec,
+};
+
+use crate::{platform, RuntimeError};
 
 #[derive(Debug, Default)]
 struct PreOpen {
@@ -68,6 +75,19 @@ impl WasiCtxBuilder {
         self
     }
 
+    /// add a pre-open directory of the host, given as a `Path`, so it may be any path
+    /// the platform supports, like one with non-ASCII characters on Windows
+    ///
+    /// # Error
+    ///
+    /// Return `RuntimeError::WasmFileFSError` if WAMR can't be given the path.
+    pub fn add_pre_open_dir(mut self, dir: &Path) -> Result<WasiCtxBuilder, RuntimeError> {
+        self.pre_open
+            .real_paths
+            .push(platform::path_to_cstring(dir)?);
+        Ok(self)
+    }
+
     /// set environment variables, which are part of WASI arguments, for the module
     ///
     /// This function should be called before `Instance::new`
@@ -82,6 +102,47 @@ impl WasiCtxBuilder {
         self
     }
 
+    /// add the environment variables of the host whose name `filter` accepts, on top of
+    /// the ones set before
+    ///
+    /// This function should be called before `Instance::new`
+    pub fn inherit_env_filtered<F>(self, filter: F) -> WasiCtxBuilder
+    where
+        F: Fn(&str) -> bool,
+    {
+        self.inherit_env_with(|key, value| filter(key).then(|| String::from(value)))
+    }
+
+    /// add the environment variables of the host `rewrite` returns a value for, given
+    /// the name and the value on the host, on top of the ones set before. Like to hand
+    /// over a variable with a value of the guest, or a secret redacted
+    ///
+    /// variables which aren't valid UTF-8 are left out
+    ///
+    /// This function should be called before `Instance::new`
+    pub fn inherit_env_with<F>(mut self, rewrite: F) -> WasiCtxBuilder
+    where
+        F: Fn(&str, &str) -> Option<String>,
+    {
+        for (key, value) in env::vars_os() {
+            let (Some(key), Some(value)) = (key.to_str(), value.to_str()) else {
+                continue;
+            };
+            // like the hidden `=C:` ones on Windows
+            if key.is_empty() || key.contains('=') {
+                continue;
+            }
+            let Some(value) = rewrite(key, value) else {
+                continue;
+            };
+            if let Ok(var) = CString::new(format!("{}={}", key, value)) {
+                self.env.push(var);
+            }
+        }
+
+        self
+    }
+
     /// set allowed ns , which are part of WASI arguments, for the module
     ///
     /// This function should be called before `Instance::new`
@@ -128,6 +189,19 @@ impl WasiCtx {
         &self.pre_open.mapped_paths
     }
 
+    /// the preopened directories, as the guest names them and where they are on the host
+    pub(crate) fn preopen_dirs(&self) -> Vec<(String, PathBuf)> {
+        let real_paths = self.pre_open.real_paths.iter().map(|path| {
+            let guest = path.to_string_lossy().into_owned();
+            (guest, platform::cstr_to_path(path))
+        });
+        let mapped_paths = self.pre_open.mapped_paths.iter().filter_map(|path| {
+            let (guest, host) = path.to_str().ok()?.split_once("::")?;
+            Some((String::from(guest), PathBuf::from(host)))
+        });
+        real_paths.chain(mapped_paths).collect()
+    }
+
     pub fn get_allowed_address(&self) -> &Vec<CString> {
         &self.allowed_address
     }
@@ -188,4 +262,33 @@ mod tests {
         );
         assert_eq!(env_vars_iter.next(), None);
     }
+
+    #[test]
+    fn test_inherit_env() {
+        env::set_var("WAMR_SDK_TEST_LANG", "C");
+        env::set_var("WAMR_SDK_TEST_TOKEN", "secret");
+
+        let wasi_ctx = WasiCtxBuilder::new()
+            .set_env_vars(vec!["HOME=/home/guest"])
+            .inherit_env_filtered(|key| key == "WAMR_SDK_TEST_LANG")
+            .inherit_env_with(|key, value| match key {
+                "WAMR_SDK_TEST_TOKEN" => Some(format!("{}...", &value[..1])),
+                _ => None,
+            })
+            .build();
+
+        let env_vars = wasi_ctx
+            .get_env_vars()
+            .iter()
+            .map(|var| var.to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            env_vars,
+            vec![
+                "HOME=/home/guest",
+                "WAMR_SDK_TEST_LANG=C",
+                "WAMR_SDK_TEST_TOKEN=s..."
+            ]
+        );
+    }
 }
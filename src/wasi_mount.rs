@@ -0,0 +1,468 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! read-only files a guest sees via WASI, backed by a host buffer or by a region of the
+//! linear memory of another instance, to hand data over to a guest as a file without
+//! going through the host filesystem. Turn it on via `RuntimeBuilder::with_wasi_mounts()`,
+//! and mount a file via `Instance::mount_buffer()` or `Instance::mount_memory()`
+//!
+//! a mounted file is opened via `path_open`, and served by `fd_read`, `fd_seek`,
+//! `fd_filestat_get` and `fd_close`, which copy straight from the source into the buffers
+//! of the guest. Writing to it fails with `EBADF`, like the syscalls left to libc-wasi,
+//! which knows nothing about it.
+//!
+//! the path of a mounted file is the one the guest sees, see `wasi_audit`, like
+//! `/data/input.txt`. It has to be under a preopened directory, since wasi-libc resolves
+//! paths against those before calling `path_open`.
+//!
+//! a region of memory is read when the guest reads, so the guest sees what the other
+//! instance wrote until then, which shouldn't run meanwhile. Once the other instance is
+//! dropped, its regions are unmounted, and the files opened from them closed.
+
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    ops::Range,
+    ptr, slice,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::{
+    wasm_module_inst_t, wasm_runtime_addr_app_to_native, wasm_runtime_validate_app_addr,
+    wasm_runtime_validate_native_addr,
+};
+
+use crate::{
+    memory, user_data::ExecEnv, wasi_audit::instance_of, wasi_policy::normalize, RuntimeError,
+};
+
+// the errnos of WASI
+const EBADF: u16 = 8;
+const EEXIST: u16 = 20;
+const EFAULT: u16 = 21;
+const EINVAL: u16 = 28;
+const ENOTDIR: u16 = 54;
+const EROFS: u16 = 69;
+
+// the `oflags` of `path_open`
+const OFLAGS_DIRECTORY: u32 = 1 << 1;
+const OFLAGS_EXCL: u32 = 1 << 2;
+const OFLAGS_TRUNC: u32 = 1 << 3;
+
+const RIGHTS_FD_WRITE: u64 = 1 << 6;
+
+const WHENCE_SET: u32 = 0;
+const WHENCE_CUR: u32 = 1;
+const WHENCE_END: u32 = 2;
+
+const FILETYPE_REGULAR_FILE: u8 = 4;
+/// the size of a `filestat`
+const FILESTAT_SIZE: usize = 64;
+
+/// the first file descriptor of a mounted file, far above the ones of libc-wasi, which
+/// takes the lowest free one
+const FIRST_FD: u32 = 1 << 30;
+
+#[derive(Clone)]
+enum Source {
+    Buffer(Arc<[u8]>),
+    /// `range` of the linear memory of `instance`, checked to be in bounds when mounted,
+    /// which it stays since a linear memory never shrinks
+    Memory {
+        instance: usize,
+        range: Range<u64>,
+    },
+}
+
+impl Source {
+    fn len(&self) -> u64 {
+        match self {
+            Source::Buffer(data) => data.len() as u64,
+            Source::Memory { range, .. } => range.end - range.start,
+        }
+    }
+
+    fn is_from(&self, instance: usize) -> bool {
+        matches!(self, Source::Memory { instance: source, .. } if *source == instance)
+    }
+
+    /// copy the bytes from `position` into `buf`, and return how many were copied
+    fn read_at(&self, position: u64, buf: &mut [u8]) -> usize {
+        let len = self.len().saturating_sub(position).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return 0;
+        }
+        match self {
+            Source::Buffer(data) => {
+                buf[..len].copy_from_slice(&data[position as usize..position as usize + len])
+            }
+            Source::Memory { instance, range } => unsafe {
+                let src = wasm_runtime_addr_app_to_native(
+                    *instance as wasm_module_inst_t,
+                    (range.start + position) as _,
+                );
+                ptr::copy_nonoverlapping(src as *const u8, buf.as_mut_ptr(), len);
+            },
+        }
+        len
+    }
+}
+
+struct OpenFile {
+    source: Source,
+    position: u64,
+}
+
+impl OpenFile {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let read = self.source.read_at(self.position, buf);
+        self.position += read as u64;
+        read
+    }
+
+    /// # Error
+    ///
+    /// Return `EINVAL` for an unknown `whence`, or a position before the start.
+    fn seek(&mut self, offset: i64, whence: u32) -> Result<u64, u16> {
+        let base = match whence {
+            WHENCE_SET => 0,
+            WHENCE_CUR => self.position,
+            WHENCE_END => self.source.len(),
+            _ => return Err(EINVAL),
+        };
+        let position = base.checked_add_signed(offset).ok_or(EINVAL)?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+#[derive(Default)]
+struct InstanceMounts {
+    /// the sources by the normalized path they are mounted at
+    mounts: Vec<(String, Source)>,
+    files: HashMap<u32, OpenFile>,
+    next_fd: u32,
+}
+
+/// the files mounted for instances, by the address of the instance
+static MOUNTS: Mutex<Vec<(usize, InstanceMounts)>> = Mutex::new(Vec::new());
+
+fn mounts() -> MutexGuard<'static, Vec<(usize, InstanceMounts)>> {
+    MOUNTS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_mounts<R>(instance: usize, f: impl FnOnce(&mut InstanceMounts) -> R) -> R {
+    let mut mounts = mounts();
+    let index = match mounts.iter().position(|(address, _)| *address == instance) {
+        Some(index) => index,
+        None => {
+            mounts.push((instance, InstanceMounts::default()));
+            mounts.len() - 1
+        }
+    };
+    f(&mut mounts[index].1)
+}
+
+fn mount(instance: usize, path: &str, source: Source) {
+    let path = normalize(path);
+    with_mounts(instance, |mounts| {
+        mounts.mounts.retain(|(mounted, _)| *mounted != path);
+        mounts.mounts.push((path, source));
+    });
+}
+
+pub(crate) fn mount_buffer(instance: wasm_module_inst_t, path: &str, data: Arc<[u8]>) {
+    mount(instance as usize, path, Source::Buffer(data));
+}
+
+/// # Error
+///
+/// Return `RuntimeError::MemoryAccessError` if `range` is out of bounds of the linear
+/// memory of `source`.
+pub(crate) fn mount_memory(
+    instance: wasm_module_inst_t,
+    path: &str,
+    source: wasm_module_inst_t,
+    range: Range<u64>,
+) -> Result<(), RuntimeError> {
+    if range.start > range.end || range.end > memory::data_size(source) as u64 {
+        return Err(RuntimeError::MemoryAccessError(message!(
+            "can't mount {}..{} of a linear memory of {} bytes",
+            range.start,
+            range.end,
+            memory::data_size(source)
+        )));
+    }
+    let source = Source::Memory {
+        instance: source as usize,
+        range,
+    };
+    mount(instance as usize, path, source);
+    Ok(())
+}
+
+/// whether a file was mounted at `path`, which stays readable via the descriptors it was
+/// opened with
+pub(crate) fn unmount(instance: wasm_module_inst_t, path: &str) -> bool {
+    let path = normalize(path);
+    with_mounts(instance as usize, |mounts| {
+        let count = mounts.mounts.len();
+        mounts.mounts.retain(|(mounted, _)| *mounted != path);
+        mounts.mounts.len() < count
+    })
+}
+
+/// forget the mounts of a destroyed instance, and the regions of its linear memory
+/// mounted for other instances
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    let instance = instance as usize;
+    let mut mounts = mounts();
+    mounts.retain(|(address, _)| *address != instance);
+    for (_, mounts) in mounts.iter_mut() {
+        mounts
+            .mounts
+            .retain(|(_, source)| !source.is_from(instance));
+        mounts
+            .files
+            .retain(|_, file| !file.source.is_from(instance));
+    }
+}
+
+/// whether `fd` is the descriptor of a mounted file, rather than one of libc-wasi
+pub(crate) fn is_mounted_fd(fd: u32) -> bool {
+    fd >= FIRST_FD
+}
+
+/// open the file mounted at `path`, `None` if there is none
+///
+/// # Error
+///
+/// Return the errno of a file which can't be written, created or listed.
+fn open(instance: usize, path: &str, oflags: u32, rights: u64) -> Option<Result<u32, u16>> {
+    let path = normalize(path);
+    with_mounts(instance, |mounts| {
+        let (_, source) = mounts.mounts.iter().find(|(mounted, _)| *mounted == path)?;
+        if oflags & OFLAGS_DIRECTORY != 0 {
+            return Some(Err(ENOTDIR));
+        }
+        if oflags & OFLAGS_EXCL != 0 {
+            return Some(Err(EEXIST));
+        }
+        if oflags & OFLAGS_TRUNC != 0 || rights & RIGHTS_FD_WRITE != 0 {
+            return Some(Err(EROFS));
+        }
+        let file = OpenFile {
+            source: source.clone(),
+            position: 0,
+        };
+        let fd = FIRST_FD + mounts.next_fd;
+        mounts.next_fd += 1;
+        mounts.files.insert(fd, file);
+        Some(Ok(fd))
+    })
+}
+
+/// # Error
+///
+/// Return `EBADF` if `fd` isn't a mounted file opened by `instance`.
+fn with_file<R>(instance: usize, fd: u32, f: impl FnOnce(&mut OpenFile) -> R) -> Result<R, u16> {
+    with_mounts(instance, |mounts| {
+        mounts.files.get_mut(&fd).map(f).ok_or(EBADF)
+    })
+}
+
+fn close(instance: usize, fd: u32) -> u16 {
+    with_mounts(instance, |mounts| match mounts.files.remove(&fd) {
+        Some(_) => 0,
+        None => EBADF,
+    })
+}
+
+/// whether `[native, native + size)` is in the linear memory of the instance of `env`
+fn valid(env: ExecEnv, native: *mut c_void, size: usize) -> bool {
+    let instance = instance_of(env) as wasm_module_inst_t;
+    unsafe { wasm_runtime_validate_native_addr(instance, native, size as _) }
+}
+
+/// `path_open` of the file mounted at `path` for the instance of `env`, returning the
+/// errno, or `None` to leave it to libc-wasi
+pub(crate) fn path_open(
+    env: ExecEnv,
+    path: &str,
+    oflags: u32,
+    rights: u64,
+    fd: *mut u32,
+) -> Option<u16> {
+    if !valid(env, fd as *mut c_void, 4) {
+        return None;
+    }
+    Some(match open(instance_of(env), path, oflags, rights)? {
+        Ok(opened) => {
+            unsafe { fd.write_unaligned(opened) };
+            0
+        }
+        Err(errno) => errno,
+    })
+}
+
+/// `fd_read` of a mounted file, with the signature of libc-wasi
+pub(crate) unsafe extern "C" fn fd_read(
+    env: ExecEnv,
+    fd: u32,
+    iovs: *mut c_void,
+    iovs_len: u32,
+    nread: *mut u32,
+) -> u16 {
+    // each `iovec` is the offset and the length of a buffer
+    let size = iovs_len as usize * 8;
+    if !valid(env, iovs, size) || !valid(env, nread as *mut c_void, 4) {
+        return EFAULT;
+    }
+    let instance = instance_of(env);
+    let iovs = slice::from_raw_parts(iovs as *const u8, size);
+    let read = with_file(instance, fd, |file| {
+        let mut read = 0;
+        for iov in iovs.chunks_exact(8) {
+            let offset = u32::from_le_bytes(iov[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(iov[4..].try_into().unwrap());
+            let module_inst = instance as wasm_module_inst_t;
+            if !wasm_runtime_validate_app_addr(module_inst, offset as _, len as _) {
+                return Err(EFAULT);
+            }
+            let buf = wasm_runtime_addr_app_to_native(module_inst, offset as _) as *mut u8;
+            let copied = file.read(slice::from_raw_parts_mut(buf, len as usize));
+            read += copied as u32;
+            if copied < len as usize {
+                break;
+            }
+        }
+        Ok(read)
+    });
+    match read.and_then(|read| read) {
+        Ok(read) => {
+            nread.write_unaligned(read);
+            0
+        }
+        Err(errno) => errno,
+    }
+}
+
+/// `fd_write` of a mounted file, which is read-only
+pub(crate) unsafe extern "C" fn fd_write(
+    _env: ExecEnv,
+    _fd: u32,
+    _iovs: *mut c_void,
+    _iovs_len: u32,
+    _nwritten: *mut u32,
+) -> u16 {
+    EBADF
+}
+
+pub(crate) fn fd_seek(env: ExecEnv, fd: u32, offset: i64, whence: u32, newoffset: *mut u64) -> u16 {
+    if !valid(env, newoffset as *mut c_void, 8) {
+        return EFAULT;
+    }
+    match with_file(instance_of(env), fd, |file| file.seek(offset, whence)) {
+        Ok(Ok(position)) => {
+            unsafe { newoffset.write_unaligned(position) };
+            0
+        }
+        Ok(Err(errno)) | Err(errno) => errno,
+    }
+}
+
+pub(crate) fn fd_filestat_get(env: ExecEnv, fd: u32, buf: *mut u8) -> u16 {
+    if !valid(env, buf as *mut c_void, FILESTAT_SIZE) {
+        return EFAULT;
+    }
+    match with_file(instance_of(env), fd, |file| file.source.len()) {
+        // no device, inode or times, one link
+        Ok(size) => unsafe {
+            ptr::write_bytes(buf, 0, FILESTAT_SIZE);
+            buf.add(16).write(FILETYPE_REGULAR_FILE);
+            buf.add(24).cast::<u64>().write_unaligned(1);
+            buf.add(32).cast::<u64>().write_unaligned(size);
+            0
+        },
+        Err(errno) => errno,
+    }
+}
+
+pub(crate) fn fd_close(env: ExecEnv, fd: u32) -> u16 {
+    close(instance_of(env), fd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wasi_mount() {
+        // any addresses, no instance is involved
+        let instance = 0x20 as wasm_module_inst_t;
+        let other = 0x30 as wasm_module_inst_t;
+        mount_buffer(
+            instance,
+            "/data/./input.txt",
+            Arc::from(&b"hello mount"[..]),
+        );
+        mount(
+            instance as usize,
+            "/data/region",
+            Source::Memory {
+                instance: other as usize,
+                range: 0..4,
+            },
+        );
+
+        assert_eq!(open(instance as usize, "/data/missing", 0, 0), None);
+        assert_eq!(open(other as usize, "/data/input.txt", 0, 0), None);
+        assert_eq!(
+            open(instance as usize, "/data/input.txt", OFLAGS_DIRECTORY, 0),
+            Some(Err(ENOTDIR))
+        );
+        assert_eq!(
+            open(instance as usize, "/data/input.txt", 0, RIGHTS_FD_WRITE),
+            Some(Err(EROFS))
+        );
+
+        let fd = open(instance as usize, "/data/input.txt", 0, 0)
+            .unwrap()
+            .unwrap();
+        assert!(is_mounted_fd(fd));
+        let mut buf = [0; 5];
+        assert_eq!(
+            with_file(instance as usize, fd, |file| file.read(&mut buf)),
+            Ok(5)
+        );
+        assert_eq!(&buf, b"hello");
+        let seek = with_file(instance as usize, fd, |file| file.seek(-5, WHENCE_END));
+        assert_eq!(seek, Ok(Ok(6)));
+        let mut rest = [0; 8];
+        assert_eq!(
+            with_file(instance as usize, fd, |file| file.read(&mut rest)),
+            Ok(5)
+        );
+        assert_eq!(&rest[..5], b"mount");
+        let seek = with_file(instance as usize, fd, |file| file.seek(-1, WHENCE_SET));
+        assert_eq!(seek, Ok(Err(EINVAL)));
+
+        // still readable once unmounted
+        assert!(unmount(instance, "/data/input.txt"));
+        assert!(!unmount(instance, "/data/input.txt"));
+        assert_eq!(open(instance as usize, "/data/input.txt", 0, 0), None);
+        assert!(with_file(instance as usize, fd, |file| file.seek(0, WHENCE_SET)).is_ok());
+        assert_eq!(close(instance as usize, fd), 0);
+        assert_eq!(close(instance as usize, fd), EBADF);
+
+        // gone with the instance whose memory it is
+        remove(other);
+        assert_eq!(open(instance as usize, "/data/region", 0, 0), None);
+        remove(instance);
+        assert!(mounts()
+            .iter()
+            .all(|(address, _)| *address != instance as usize));
+    }
+}
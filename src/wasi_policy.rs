@@ -0,0 +1,528 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! WASI syscalls denied to an instance, like sockets or paths outside of an allowlist,
+//! without building WASI out of the SDK. Turn it on via
+//! `RuntimeBuilder::with_wasi_policies()`, set the policy of an instance via
+//! `Instance::set_wasi_policy()`, and get the denied attempts via
+//! `Instance::take_wasi_denials()`
+//!
+//! a denied syscall returns `ENOTCAPABLE` to the guest, without reaching libc-wasi. The
+//! syscalls which may be denied are the `path_*` ones, the `sock_*` ones opening,
+//! connecting and transferring, `poll_oneoff`, `random_get`, `clock_res_get`,
+//! `clock_time_get`, `sched_yield` and `proc_raise`. The other socket syscalls are left
+//! alone, having nothing to act on without a socket.
+//!
+//! a path is checked as the guest sees it, see `wasi_audit`, once its symlinks, `.` and
+//! `..` are resolved on the host, under the preopened directories of the module. So a
+//! link under an allowed path to a file outside of it is denied, and so are a dangling
+//! link and a path leading out of every preopened directory. A module without preopened
+//! directories has its paths resolved lexically.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fs, mem,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use wamr_sys::wasm_module_inst_t;
+
+use crate::{
+    host_function::{catch_panic, ParamTy, ResultTy},
+    native_module::NativeExports,
+    user_data::ExecEnv,
+    wasi_audit::{self, libc_wasi_function},
+};
+
+/// the errno of WASI returned by a denied syscall
+pub const ENOTCAPABLE: u16 = 76;
+
+/// the WASI syscalls an instance is denied
+#[derive(Debug, Clone, Default)]
+pub struct WasiPolicy {
+    denied: Vec<String>,
+    allowed_paths: Option<Vec<String>>,
+}
+
+/// a syscall denied to an instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WasiDenial {
+    pub syscall: &'static str,
+    /// the path denied, for a syscall denied because of it
+    pub path: Option<String>,
+}
+
+impl WasiPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// deny `syscall`, like `sock_open`, or the ones starting with a prefix followed by
+    /// `*`, like `sock_*`
+    pub fn deny(mut self, syscall: &str) -> Self {
+        self.denied.push(String::from(syscall));
+        self
+    }
+
+    /// allow the syscalls taking a path under `path`, like `/data`. Once one is allowed,
+    /// any other path is denied
+    pub fn allow_path(mut self, path: &str) -> Self {
+        self.allowed_paths
+            .get_or_insert_with(Vec::new)
+            .push(normalize(path));
+        self
+    }
+
+    fn denies_syscall(&self, syscall: &str) -> bool {
+        self.denied
+            .iter()
+            .any(|denied| match denied.strip_suffix('*') {
+                Some(prefix) => syscall.starts_with(prefix),
+                None => syscall == denied,
+            })
+    }
+
+    fn allows_path(&self, path: &str, preopens: &[Preopen]) -> bool {
+        let Some(allowed_paths) = &self.allowed_paths else {
+            return true;
+        };
+        let Some(path) = resolve_links(path, preopens) else {
+            return false;
+        };
+        allowed_paths.iter().any(|allowed| {
+            allowed.is_empty()
+                || allowed == "/"
+                || path == *allowed
+                || path
+                    .strip_prefix(allowed.as_str())
+                    .is_some_and(|rest| rest.starts_with('/'))
+        })
+    }
+
+    /// the denial of `syscall` with `paths`, under `preopens`, `None` if it is allowed
+    fn check(
+        &self,
+        syscall: &'static str,
+        paths: &[&str],
+        preopens: &[Preopen],
+    ) -> Option<WasiDenial> {
+        if self.denies_syscall(syscall) {
+            return Some(WasiDenial {
+                syscall,
+                path: paths.first().map(|path| String::from(*path)),
+            });
+        }
+        paths
+            .iter()
+            .find(|path| !self.allows_path(path, preopens))
+            .map(|path| WasiDenial {
+                syscall,
+                path: Some(String::from(*path)),
+            })
+    }
+}
+
+/// `path` with `.` and `..` resolved, and without a trailing `/`
+pub(crate) fn normalize(path: &str) -> String {
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => match components.last() {
+                Some(&"..") | None if !path.starts_with('/') => components.push(".."),
+                Some(&"..") | None => {}
+                Some(_) => {
+                    components.pop();
+                }
+            },
+            component => components.push(component),
+        }
+    }
+    match path.starts_with('/') {
+        true => format!("/{}", components.join("/")),
+        false => components.join("/"),
+    }
+}
+
+/// a directory preopened for an instance, as the guest names it, normalized, and its
+/// canonical path on the host
+#[derive(Debug, Clone)]
+pub(crate) struct Preopen {
+    guest: String,
+    host: PathBuf,
+}
+
+impl Preopen {
+    /// `None` if `host` can't be canonicalized, like when it doesn't exist
+    pub(crate) fn new(guest: &str, host: PathBuf) -> Option<Self> {
+        Some(Preopen {
+            guest: normalize(guest),
+            host: fs::canonicalize(host).ok()?,
+        })
+    }
+
+    fn components(&self) -> Vec<&str> {
+        self.guest.split('/').filter(|c| !c.is_empty()).collect()
+    }
+}
+
+/// `path`, as the guest sees it, with its symlinks and then its `..` resolved on the host
+/// like the guest's syscall would, and normalized. `None` if it leads out of every
+/// preopened directory, through a dangling link, or starts under none of `preopens`.
+/// Without `preopens`, the path is only normalized
+fn resolve_links(path: &str, preopens: &[Preopen]) -> Option<String> {
+    if preopens.is_empty() {
+        return Some(normalize(path));
+    }
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    let (preopen, depth) = preopens
+        .iter()
+        .filter(|preopen| preopen.guest.starts_with('/') == path.starts_with('/'))
+        .map(|preopen| (preopen, preopen.components()))
+        .filter(|(_, guest)| components.starts_with(guest))
+        .map(|(preopen, guest)| (preopen, guest.len()))
+        .max_by_key(|(_, depth)| *depth)?;
+
+    // the components which don't exist yet, like the file a `path_open` creates, are
+    // taken as they are
+    let mut host = preopen.host.clone();
+    for component in &components[depth..] {
+        match *component {
+            ".." => {
+                host.pop();
+            }
+            component => {
+                host.push(component);
+                match fs::canonicalize(&host) {
+                    Ok(canonical) => host = canonical,
+                    Err(_) if fs::symlink_metadata(&host).is_ok() => return None,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    let (preopen, rest) = preopens
+        .iter()
+        .filter_map(|preopen| Some((preopen, host.strip_prefix(&preopen.host).ok()?)))
+        .max_by_key(|(preopen, _)| preopen.host.as_os_str().len())?;
+    let rest = rest
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/");
+    match preopen.guest.as_str() {
+        "" => Some(rest),
+        guest => Some(normalize(&format!("{}/{}", guest, rest))),
+    }
+}
+
+struct InstancePolicy {
+    policy: WasiPolicy,
+    preopens: Vec<Preopen>,
+    denials: Vec<WasiDenial>,
+}
+
+/// the policies of instances, by the address of the instance
+static POLICIES: Mutex<Vec<(usize, InstancePolicy)>> = Mutex::new(Vec::new());
+
+fn policies() -> MutexGuard<'static, Vec<(usize, InstancePolicy)>> {
+    POLICIES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// set the policy of `instance`, which has the directories `preopens` preopened, as the
+/// guest names them and as the host does
+pub(crate) fn set(
+    instance: wasm_module_inst_t,
+    policy: WasiPolicy,
+    preopens: &[(String, PathBuf)],
+) {
+    let mut policies = policies();
+    policies.retain(|(address, _)| *address != instance as usize);
+    policies.push((
+        instance as usize,
+        InstancePolicy {
+            policy,
+            preopens: preopens
+                .iter()
+                .filter_map(|(guest, host)| Preopen::new(guest, host.clone()))
+                .collect(),
+            denials: Vec::new(),
+        },
+    ));
+}
+
+/// the syscalls denied to `instance` since the last call
+pub(crate) fn take_denials(instance: wasm_module_inst_t) -> Vec<WasiDenial> {
+    policies()
+        .iter_mut()
+        .find(|(address, _)| *address == instance as usize)
+        .map(|(_, policy)| mem::take(&mut policy.denials))
+        .unwrap_or_default()
+}
+
+/// forget the policy of a destroyed instance
+pub(crate) fn remove(instance: wasm_module_inst_t) {
+    policies().retain(|(address, _)| *address != instance as usize);
+}
+
+/// whether the instance of `env` may call `syscall` with `paths`, recording the denial
+/// otherwise
+pub(crate) fn permits(env: ExecEnv, syscall: &'static str, paths: &[&str]) -> bool {
+    let instance = wasi_audit::instance_of(env);
+    let mut policies = policies();
+    let Some((_, policy)) = policies
+        .iter_mut()
+        .find(|(address, _)| *address == instance)
+    else {
+        return true;
+    };
+    match policy.policy.check(syscall, paths, &policy.preopens) {
+        Some(denial) => {
+            policy.denials.push(denial);
+            false
+        }
+        None => true,
+    }
+}
+
+/// declare the syscalls which may be denied, and which aren't wrapped by `wasi_audit`.
+/// Each is declared with its parameters, their `ParamTy`, and the `(dirfd, path,
+/// path_len)` of the paths it takes
+macro_rules! deniable {
+    ($(
+        $name:ident($($param:ident: $ty:ty),*) [$($param_ty:ident),*]
+            $(paths [$(($dirfd:ident, $path:ident, $path_len:ident)),*])?;
+    )*) => {
+        $(
+            extern "C" fn $name(env: ExecEnv, $($param: $ty),*) -> u32 {
+                catch_panic(env, || {
+                    let paths: Vec<String> = vec![
+                        $($(wasi_audit::resolve(env, $dirfd, $path, $path_len)),*)?
+                    ];
+                    let paths = paths.iter().map(String::as_str).collect::<Vec<_>>();
+                    if !permits(env, stringify!($name), &paths) {
+                        return ENOTCAPABLE as u32;
+                    }
+                    let name = concat!(stringify!($name), "\0");
+                    let function =
+                        libc_wasi_function(CStr::from_bytes_with_nul(name.as_bytes()).unwrap());
+                    unsafe {
+                        mem::transmute::<*mut c_void, unsafe extern "C" fn(ExecEnv, $($ty),*) -> u16>(
+                            function,
+                        )(env, $($param),*) as u32
+                    }
+                })
+            }
+        )*
+
+        /// export the syscalls which may be denied, on top of the ones of libc-wasi
+        pub(crate) fn export_deniable(exports: &mut NativeExports) {
+            $(
+                exports.raw_function(
+                    stringify!($name),
+                    $name as *mut c_void,
+                    &[$(ParamTy::$param_ty),*],
+                    ResultTy::I32,
+                );
+            )*
+        }
+    };
+}
+
+deniable! {
+    path_create_directory(fd: u32, path: *const u8, path_len: u32) [I32, Buffer]
+        paths [(fd, path, path_len)];
+    path_filestat_get(fd: u32, flags: u32, path: *const u8, path_len: u32, buf: *mut u8)
+        [I32, I32, Buffer, Pointer]
+        paths [(fd, path, path_len)];
+    path_filestat_set_times(
+        fd: u32,
+        flags: u32,
+        path: *const u8,
+        path_len: u32,
+        atim: u64,
+        mtim: u64,
+        fst_flags: u32
+    ) [I32, I32, Buffer, I64, I64, I32]
+        paths [(fd, path, path_len)];
+    path_link(
+        old_fd: u32,
+        old_flags: u32,
+        old_path: *const u8,
+        old_path_len: u32,
+        new_fd: u32,
+        new_path: *const u8,
+        new_path_len: u32
+    ) [I32, I32, Buffer, I32, Buffer]
+        paths [(old_fd, old_path, old_path_len), (new_fd, new_path, new_path_len)];
+    path_readlink(
+        fd: u32,
+        path: *const u8,
+        path_len: u32,
+        buf: *mut u8,
+        buf_len: u32,
+        bufused: *mut u32
+    ) [I32, Buffer, Buffer, Pointer]
+        paths [(fd, path, path_len)];
+    path_remove_directory(fd: u32, path: *const u8, path_len: u32) [I32, Buffer]
+        paths [(fd, path, path_len)];
+    path_rename(
+        old_fd: u32,
+        old_path: *const u8,
+        old_path_len: u32,
+        new_fd: u32,
+        new_path: *const u8,
+        new_path_len: u32
+    ) [I32, Buffer, I32, Buffer]
+        paths [(old_fd, old_path, old_path_len), (new_fd, new_path, new_path_len)];
+    // the old path is the content of the link, not a file
+    path_symlink(
+        old_path: *const u8,
+        old_path_len: u32,
+        fd: u32,
+        new_path: *const u8,
+        new_path_len: u32
+    ) [Buffer, I32, Buffer]
+        paths [(fd, new_path, new_path_len)];
+    sock_accept(fd: u32, flags: u32, fd_new: *mut u32) [I32, I32, Pointer];
+    sock_addr_local(fd: u32, addr: *mut u8) [I32, Pointer];
+    sock_addr_remote(fd: u32, addr: *mut u8) [I32, Pointer];
+    sock_addr_resolve(
+        host: *const c_char,
+        service: *const c_char,
+        hints: *mut u8,
+        addr_info: *mut u8,
+        addr_info_size: u32,
+        max_info_size: *mut u32
+    ) [Str, Str, Pointer, Pointer, I32, Pointer];
+    sock_bind(fd: u32, addr: *mut u8) [I32, Pointer];
+    sock_connect(fd: u32, addr: *mut u8) [I32, Pointer];
+    sock_listen(fd: u32, backlog: u32) [I32, I32];
+    sock_open(poolfd: u32, af: u32, socktype: u32, sockfd: *mut u32) [I32, I32, I32, Pointer];
+    sock_recv(
+        fd: u32,
+        ri_data: *mut u8,
+        ri_data_len: u32,
+        ri_flags: u32,
+        ro_data_len: *mut u32,
+        ro_flags: *mut u16
+    ) [I32, Pointer, I32, I32, Pointer, Pointer];
+    sock_recv_from(
+        fd: u32,
+        ri_data: *mut u8,
+        ri_data_len: u32,
+        ri_flags: u32,
+        src_addr: *mut u8,
+        ro_data_len: *mut u32
+    ) [I32, Pointer, I32, I32, Pointer, Pointer];
+    sock_send(
+        fd: u32,
+        si_data: *const u8,
+        si_data_len: u32,
+        si_flags: u32,
+        so_data_len: *mut u32
+    ) [I32, Pointer, I32, I32, Pointer];
+    sock_send_to(
+        fd: u32,
+        si_data: *const u8,
+        si_data_len: u32,
+        si_flags: u32,
+        dest_addr: *const u8,
+        so_data_len: *mut u32
+    ) [I32, Pointer, I32, I32, Pointer, Pointer];
+    sock_shutdown(fd: u32, how: u32) [I32, I32];
+    poll_oneoff(
+        subscriptions: *const u8,
+        events: *mut u8,
+        nsubscriptions: u32,
+        nevents: *mut u32
+    ) [Pointer, Pointer, I32, Pointer];
+    random_get(buf: *mut u8, buf_len: u32) [Buffer];
+    clock_res_get(clock_id: u32, resolution: *mut u64) [I32, Pointer];
+    clock_time_get(clock_id: u32, precision: u64, time: *mut u64) [I32, I64, Pointer];
+    sched_yield() [];
+    proc_raise(sig: u32) [I32];
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wasi_policy() {
+        assert_eq!(normalize("/data/../etc/./passwd"), "/etc/passwd");
+        assert_eq!(normalize("/../../etc/"), "/etc");
+        assert_eq!(normalize("../data"), "../data");
+
+        let policy = WasiPolicy::new()
+            .deny("sock_*")
+            .deny("random_get")
+            .allow_path("/data/");
+        assert!(policy.check("sock_open", &[], &[]).is_some());
+        assert!(policy.check("random_get", &[], &[]).is_some());
+        assert!(policy.check("clock_time_get", &[], &[]).is_none());
+
+        assert!(policy.check("path_open", &["/data"], &[]).is_none());
+        assert!(policy
+            .check("path_open", &["/data/input.txt"], &[])
+            .is_none());
+        assert_eq!(
+            policy.check("path_open", &["/data/../etc/passwd"], &[]),
+            Some(WasiDenial {
+                syscall: "path_open",
+                path: Some(String::from("/data/../etc/passwd")),
+            })
+        );
+        assert!(policy.check("path_open", &["/database"], &[]).is_some());
+        assert!(policy
+            .check("path_rename", &["/data/a", "/tmp/a"], &[])
+            .is_some());
+
+        assert!(WasiPolicy::new()
+            .check("path_open", &["/etc/passwd"], &[])
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wasi_policy_symlinks() {
+        let root = std::env::temp_dir().join(format!("wasi-policy-{}", std::process::id()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        std::os::unix::fs::symlink(root.join("etc"), root.join("data/etc")).unwrap();
+        std::os::unix::fs::symlink(std::env::temp_dir(), root.join("data/tmp")).unwrap();
+        std::os::unix::fs::symlink(root.join("none"), root.join("data/none")).unwrap();
+
+        let preopens = [Preopen::new("/", root.clone()).unwrap()];
+        let policy = WasiPolicy::new().allow_path("/data");
+        assert!(policy
+            .check("path_open", &["/data/new.txt"], &preopens)
+            .is_none());
+        assert!(policy
+            .check("path_open", &["/data/etc/passwd"], &preopens)
+            .is_some());
+        assert!(policy
+            .check("path_open", &["/data/tmp/a"], &preopens)
+            .is_some());
+        assert!(policy
+            .check("path_open", &["/data/none"], &preopens)
+            .is_some());
+        assert!(policy
+            .check("path_open", &["/data/etc/../x"], &preopens)
+            .is_some());
+        assert!(policy.check("path_open", &["/../etc"], &preopens).is_some());
+        assert_eq!(
+            resolve_links("/data/etc/../data/./a", &preopens).as_deref(),
+            Some("/data/a")
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
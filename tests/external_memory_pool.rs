@@ -0,0 +1,41 @@
+/*
+ * Copyright (C) 2019 Intel Corporation. All rights reserved.
+ * SPDX-License-Identifier: Apache-2.0 WITH LLVM-exception
+ */
+
+//! WAMR is initialized once per process, with the allocator of the first `Runtime`, so the
+//! pool is checked in a test binary of its own.
+
+use wamr_rust_sdk::{instance::Instance, module::Module, runtime::Runtime};
+use wamr_sys::{wasm_runtime_free, wasm_runtime_malloc};
+
+#[test]
+fn test_external_memory_pool() {
+    let mut pool = vec![0u8; 512 * 1024];
+    let runtime = unsafe {
+        Runtime::builder()
+            .use_external_memory_pool(pool.as_mut_ptr(), pool.len() as u32)
+            .build()
+            .unwrap()
+    };
+
+    let small_buf = unsafe { wasm_runtime_malloc(16) };
+    assert!(!small_buf.is_null());
+    assert!(pool.as_ptr_range().contains(&(small_buf as *const u8)));
+    unsafe { wasm_runtime_free(small_buf) };
+
+    // (module
+    //   (memory (export "memory") 1)
+    // )
+    let binary = [
+        0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, 0x05, 0x03, 0x01, 0x00, 0x01, 0x07, 0x0a,
+        0x01, 0x06, 0x6d, 0x65, 0x6d, 0x6f, 0x72, 0x79, 0x02, 0x00,
+    ];
+    let module = Module::from_buf(&runtime, &binary, "memory").unwrap();
+    let instance = Instance::new(&runtime, &module, 1024, ()).unwrap();
+    instance.memory().write(0, &[1, 2, 3]).unwrap();
+
+    drop(instance);
+    drop(module);
+    drop(runtime);
+}